@@ -34,7 +34,7 @@ def create_start_file(nodes):
         proxy_address_base = 46058
         for node in range(nodes):
             proxy_port = proxy_address_base + node * 100
-            line = 'tmux new -d -s app{0} && tmux send-keys -t app{0} "cargo run -- --address 127.0.0.1:{1}" C-m'.format(node, proxy_port) + '\n'
+            line = 'tmux new -d -s app{0} && tmux send-keys -t app{0} "cargo run -- --address 127.0.0.1:{1} --db ~/.tendermint/cluster{2}/node{0}/relay-db" C-m'.format(node, proxy_port, nodes) + '\n'
             node_lines.append(line)
         return node_lines
 
@@ -1,15 +1,24 @@
 echo "$0: MP-EDDSA"
 #clean
 
-rm keys*
+rm key-store*
 rm log-kg*.log
 rm log-error*.log
+rm session
+
+# Key shares are encrypted with this password
+export MMPC_KEY_PASSWORD=${MMPC_KEY_PASSWORD:-demo}
 
 n=3
 
 echo "keygen part"
-for i in $(seq 1 $n);
+# The first client opens a new session, the rest join it
+cargo run -p mmpc-client --bin kg-client -- -I 1 --capacity $n &
+while [ ! -f session ]; do sleep 0.1; done
+SESSION=$(cat session)
+
+for i in $(seq 2 $n);
 do
-    #cargo run -p mmpc-client --bin kg-client -- -I $i --capacity $n -v &
-    cargo run -p mmpc-client --bin kg-client -- -I $i --capacity $n &
+    #cargo run -p mmpc-client --bin kg-client -- -I $i --capacity $n --session $SESSION -v &
+    cargo run -p mmpc-client --bin kg-client -- -I $i --capacity $n --session $SESSION &
 done
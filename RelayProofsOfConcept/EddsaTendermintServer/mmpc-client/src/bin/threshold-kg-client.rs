@@ -0,0 +1,66 @@
+use std::process;
+use std::time;
+
+use clap::Arg;
+
+use mmpc_client::cli::{self, ClientArgs};
+use mmpc_client::eddsa_peer_threshold_kg::EddsaPeer;
+use mmpc_client::key_store::KeyStoreError;
+
+fn main() {
+    better_panic::Settings::debug()
+        .most_recent_first(false)
+        .lineno_suffix(true)
+        .install();
+
+    let matches = cli::app("threshold-kg-client")
+        .arg(
+            Arg::with_name("threshold")
+                .default_value("1")
+                .long("threshold")
+                .short("T")
+                .help("Any threshold + 1 of the parties can sign with the generated key"),
+        )
+        .get_matches();
+    let args = ClientArgs::parse("threshold-kg", &matches, "threshold-key-store");
+    args.setup_logging().expect("failed to initialize logging.");
+
+    let threshold: u32 = matches
+        .value_of("threshold")
+        .unwrap()
+        .parse()
+        .expect("Invalid threshold");
+
+    let mut key_store = args.open_key_store();
+    // Key ids are unique in a key store, a used id would fail to store the share
+    if key_store.label(&args.key_id).is_some() {
+        println!("{}", KeyStoreError::Exists(args.key_id.clone()));
+        process::exit(1);
+    }
+
+    let peer = match EddsaPeer::with_threshold(args.capacity, threshold) {
+        Ok(peer) => peer,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let start_time = time::SystemTime::now();
+    // Initially do not request any index, the index is determined by the server
+    let (share, registration) = args.run_session(-1, || peer);
+
+    let total_time = start_time.elapsed().expect("Weird time");
+    println!("{:}", total_time.as_millis());
+
+    if let Err(err) = key_store.insert(share.label(&args.key_id), &share, &args.password) {
+        println!("Unable to store key share: {}", err);
+        process::exit(1);
+    }
+    args.discard_checkpoint(&registration);
+
+    if let Err(err) = args.write_to_csv(total_time.as_millis() as u32) {
+        println!("error running example: {}", err);
+        process::exit(1);
+    }
+}
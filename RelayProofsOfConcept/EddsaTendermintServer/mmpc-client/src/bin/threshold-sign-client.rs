@@ -0,0 +1,70 @@
+use std::fs;
+use std::process;
+use std::time;
+
+use clap::Arg;
+
+use mmpc_client::cli::{self, ClientArgs};
+use mmpc_client::eddsa_peer_threshold_kg::ThresholdKeys;
+use mmpc_client::eddsa_peer_threshold_sign::{encode_signature, EddsaPeer};
+
+fn main() {
+    better_panic::Settings::debug()
+        .most_recent_first(false)
+        .lineno_suffix(true)
+        .install();
+
+    let matches = cli::app("threshold-sign-client")
+        .arg(
+            Arg::with_name("message")
+                .default_value("message")
+                .long("message")
+                .short("M"),
+        )
+        .get_matches();
+    let args = ClientArgs::parse("threshold-sign", &matches, "threshold-key-store");
+    args.setup_logging().expect("failed to initialize logging.");
+
+    let message = matches.value_of("message").unwrap();
+    let message_to_sign = match hex::decode(message) {
+        Ok(x) => x,
+        Err(_) => message.as_bytes().to_vec(),
+    };
+
+    let key_store = args.open_key_store();
+    let keys = match ThresholdKeys::load(&key_store, &args.key_id, &args.password) {
+        Ok(keys) => keys,
+        Err(err) => {
+            println!(
+                "Unable to load key share, did you run threshold keygen first? {}",
+                err
+            );
+            process::exit(1);
+        }
+    };
+    // Signing peers register with the index of their key share,
+    // the capacity is the number of signing peers, more than the threshold of the key
+    let kg_index = keys.kg_index as i32;
+
+    let peer = match EddsaPeer::with_keys(args.capacity, message_to_sign, keys) {
+        Ok(peer) => peer,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let start_time = time::SystemTime::now();
+    let (signature, registration) = args.run_session(kg_index, || peer);
+    let signature_path = format!("threshold-signature{}", registration.peer_id);
+    fs::write(signature_path, encode_signature(&signature)).expect("Unable to save signature");
+    args.discard_checkpoint(&registration);
+
+    let total_time = start_time.elapsed().expect("Weird time");
+    println!("{:}", total_time.as_millis());
+
+    if let Err(err) = args.write_to_csv(total_time.as_millis() as u32) {
+        println!("error running example: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,315 @@
+//! Relays a session runs over. In each round every peer broadcasts a message to the peers
+//! of its session, with point-to-point messages only their recipients can read.
+//! The Tendermint application, the Tokio relay server and the Rocket HTTP server all carry
+//! these messages, each through a channel of its own, so a peer runs unchanged over any of them
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::error;
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Serialize};
+
+use crate::checkpoint::CheckpointError;
+use crate::http_client::{HttpChannel, HttpCheckpoint};
+use crate::session::SessionError;
+use crate::tendermint_client::{TendermintChannel, TendermintCheckpoint};
+use crate::tokio_client::{TokioChannel, TokioCheckpoint};
+use mmpc_server_common::codec::Encoding;
+use mmpc_server_common::identity::IdentityKey;
+use mmpc_server_common::{
+    MessagePayload, PeerIdentifier, ProtocolIdentifier, RelayMessage, SessionIdentifier,
+};
+
+/// What a peer registers to a session with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterRequest {
+    pub protocol_id: ProtocolIdentifier,
+    pub capacity: u32,
+    // Index of the key share of a signing peer, -1 for others
+    pub kg_index: i32,
+    pub share_count: Option<u32>,
+    // The session to join, a new session is opened if None
+    pub session_id: Option<SessionIdentifier>,
+}
+
+/// The session a peer registered to, and the identifier of the peer in it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registration {
+    pub session_id: SessionIdentifier,
+    pub peer_id: PeerIdentifier,
+}
+
+/// The message a peer broadcasts in a round
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoundMessage {
+    pub round: u32,
+    pub payload: MessagePayload,
+    // Point-to-point messages by recipient, the channel encrypts each
+    // to the identity key of its recipient
+    pub p2p: BTreeMap<PeerIdentifier, MessagePayload>,
+}
+
+/// A message of a round as received from a peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedMessage {
+    pub from: PeerIdentifier,
+    pub payload: MessagePayload,
+    // The decrypted point-to-point message to this peer, if one was sent
+    pub p2p_payload: Option<MessagePayload>,
+}
+
+pub trait BroadcastChannel {
+    /// What the channel keeps in the checkpoint of a session to rejoin it after a restart,
+    /// such as the identity key it registered with
+    type Checkpoint: Serialize + DeserializeOwned;
+
+    /// Registers to the session of the request, or opens a new session if it has none.
+    /// Returns once the peer identifier is known
+    fn register(&mut self, request: &RegisterRequest) -> Result<Registration, SessionError>;
+
+    /// Broadcasts the message of this peer for its round.
+    /// A message the relay already holds is not stored again
+    fn broadcast(&mut self, message: &RoundMessage) -> Result<(), SessionError>;
+
+    /// Waits up to the timeout for the messages of the round which are still missing,
+    /// and returns the messages of the round received so far, by sender.
+    /// Fails with SessionError::Aborted once the relay aborted the session
+    fn receive(
+        &mut self,
+        round: u32,
+        timeout: Duration,
+    ) -> Result<BTreeMap<PeerIdentifier, ReceivedMessage>, SessionError>;
+
+    fn checkpoint(&self) -> Self::Checkpoint;
+
+    /// Restores the channel of a client restarted in the session of the request.
+    /// The peer identifier is given if the client was registered before the restart
+    fn restore(
+        &mut self,
+        request: &RegisterRequest,
+        peer_id: Option<PeerIdentifier>,
+        checkpoint: Self::Checkpoint,
+    ) -> Result<(), CheckpointError>;
+}
+
+// The identity a peer announces on relays which do not keep the identity keys of the peers,
+// with the identifier it takes part as
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Announcement {
+    pub peer_id: PeerIdentifier,
+    pub identity_key: IdentityKey,
+}
+
+// Relays which number peers in the order they register can not register a signing peer
+// as its key share, the peer announces it takes part as its key share instead
+pub(crate) fn announced_peer_id(number: u32, kg_index: i32) -> PeerIdentifier {
+    if kg_index < 0 {
+        number
+    } else {
+        kg_index as PeerIdentifier
+    }
+}
+
+// Adds the point-to-point messages of a round to the relay message, each encrypted
+// to the identity key of its recipient. A message can not be sent to a recipient
+// whose key is not known, as it is not a peer of the session
+pub(crate) fn add_p2p_messages(
+    relay_message: &mut RelayMessage,
+    p2p: &BTreeMap<PeerIdentifier, MessagePayload>,
+    peer_keys: &BTreeMap<PeerIdentifier, IdentityKey>,
+) -> Result<(), SessionError> {
+    for (peer_id, p2p_payload) in p2p {
+        let added = match peer_keys.get(peer_id) {
+            Some(public_key) => relay_message.add_p2p_message(*peer_id, public_key, p2p_payload),
+            None => false,
+        };
+        if !added {
+            error!("No valid identity key of peer {}", peer_id);
+            return Err(SessionError::UnknownRecipient(*peer_id));
+        }
+    }
+    Ok(())
+}
+
+/// The relay servers a client can run a session over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Transport {
+    #[default]
+    Tendermint,
+    Tokio,
+    Http,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Transport, String> {
+        match name {
+            "tendermint" => Ok(Transport::Tendermint),
+            "tokio" => Ok(Transport::Tokio),
+            "http" => Ok(Transport::Http),
+            _ => Err(format!("Unknown relay {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transport::Tendermint => write!(f, "tendermint"),
+            Transport::Tokio => write!(f, "tokio"),
+            Transport::Http => write!(f, "http"),
+        }
+    }
+}
+
+/// A channel over any of the relays, picked when the client starts
+pub enum Relay {
+    Tendermint(TendermintChannel),
+    Tokio(TokioChannel),
+    Http(HttpChannel),
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum RelayCheckpoint {
+    Tendermint(TendermintCheckpoint),
+    Tokio(TokioCheckpoint),
+    Http(HttpCheckpoint),
+}
+
+impl Relay {
+    /// Connects to the relay at the given addresses. The Tendermint channel fails over between
+    /// the RPC endpoints of the validator nodes, the other relays are a single server
+    /// at the first address. Messages are sent in the given encoding where the relay allows it
+    pub fn connect(
+        transport: Transport,
+        addresses: &[String],
+        encoding: Encoding,
+    ) -> io::Result<Relay> {
+        let address = addresses
+            .first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No relay address given"))?;
+        match transport {
+            Transport::Tendermint => {
+                let endpoints = addresses
+                    .iter()
+                    .map(|address| format!("tcp://{}", address).parse())
+                    .collect::<Result<Vec<tendermint::net::Address>, _>>()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+                Ok(Relay::Tendermint(
+                    TendermintChannel::new(&endpoints).with_encoding(encoding),
+                ))
+            }
+            Transport::Tokio => Ok(Relay::Tokio(
+                TokioChannel::connect(address)?.with_encoding(encoding),
+            )),
+            Transport::Http => Ok(Relay::Http(HttpChannel::new(address))),
+        }
+    }
+}
+
+impl BroadcastChannel for Relay {
+    type Checkpoint = RelayCheckpoint;
+
+    fn register(&mut self, request: &RegisterRequest) -> Result<Registration, SessionError> {
+        match self {
+            Relay::Tendermint(channel) => channel.register(request),
+            Relay::Tokio(channel) => channel.register(request),
+            Relay::Http(channel) => channel.register(request),
+        }
+    }
+
+    fn broadcast(&mut self, message: &RoundMessage) -> Result<(), SessionError> {
+        match self {
+            Relay::Tendermint(channel) => channel.broadcast(message),
+            Relay::Tokio(channel) => channel.broadcast(message),
+            Relay::Http(channel) => channel.broadcast(message),
+        }
+    }
+
+    fn receive(
+        &mut self,
+        round: u32,
+        timeout: Duration,
+    ) -> Result<BTreeMap<PeerIdentifier, ReceivedMessage>, SessionError> {
+        match self {
+            Relay::Tendermint(channel) => channel.receive(round, timeout),
+            Relay::Tokio(channel) => channel.receive(round, timeout),
+            Relay::Http(channel) => channel.receive(round, timeout),
+        }
+    }
+
+    fn checkpoint(&self) -> RelayCheckpoint {
+        match self {
+            Relay::Tendermint(channel) => RelayCheckpoint::Tendermint(channel.checkpoint()),
+            Relay::Tokio(channel) => RelayCheckpoint::Tokio(channel.checkpoint()),
+            Relay::Http(channel) => RelayCheckpoint::Http(channel.checkpoint()),
+        }
+    }
+
+    fn restore(
+        &mut self,
+        request: &RegisterRequest,
+        peer_id: Option<PeerIdentifier>,
+        checkpoint: RelayCheckpoint,
+    ) -> Result<(), CheckpointError> {
+        match (self, checkpoint) {
+            (Relay::Tendermint(channel), RelayCheckpoint::Tendermint(checkpoint)) => {
+                channel.restore(request, peer_id, checkpoint)
+            }
+            (Relay::Tokio(channel), RelayCheckpoint::Tokio(checkpoint)) => {
+                channel.restore(request, peer_id, checkpoint)
+            }
+            (Relay::Http(channel), RelayCheckpoint::Http(checkpoint)) => {
+                channel.restore(request, peer_id, checkpoint)
+            }
+            _ => Err(CheckpointError::Format(serde_json::Error::custom(
+                "checkpoint of a session on another relay",
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_p2p_messages, announced_peer_id, Transport};
+    use crate::session::SessionError;
+    use mmpc_server_common::identity::{generate_keypair, identity_key};
+    use mmpc_server_common::RelayMessage;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_parse_transport() {
+        for transport in [Transport::Tendermint, Transport::Tokio, Transport::Http].iter() {
+            assert_eq!(transport.to_string().parse(), Ok(*transport));
+        }
+        assert!("rocket".parse::<Transport>().is_err());
+    }
+
+    #[test]
+    fn test_announced_peer_id() {
+        // Signing peers take part as their key share, others as they registered
+        assert_eq!(announced_peer_id(2, -1), 2);
+        assert_eq!(announced_peer_id(2, 3), 3);
+    }
+
+    #[test]
+    fn test_p2p_message_to_unknown_peer() {
+        let mut peer_keys = BTreeMap::new();
+        peer_keys.insert(2, identity_key(&generate_keypair()));
+        let mut p2p = BTreeMap::new();
+        p2p.insert(2, String::from("share of 2"));
+        let mut relay_message = RelayMessage::new(1, 1, 1, 1);
+        assert!(add_p2p_messages(&mut relay_message, &p2p, &peer_keys).is_ok());
+
+        // Peer 3 did not register its key, so it is not a peer of the session
+        p2p.insert(3, String::from("share of 3"));
+        match add_p2p_messages(&mut relay_message, &p2p, &peer_keys) {
+            Err(SessionError::UnknownRecipient(3)) => {}
+            _ => panic!("Sent a message to an unknown peer"),
+        }
+    }
+}
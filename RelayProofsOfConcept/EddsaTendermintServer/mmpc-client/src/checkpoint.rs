@@ -0,0 +1,341 @@
+//! Encrypted checkpoints of the sessions a client takes part in.
+//! A client writes the state of its peer to a checkpoint before each message it sends,
+//! so a client restarted after a crash can rejoin the session where it left off.
+//! Each session is checkpointed to a file of its own in the checkpoint directory, sealed like
+//! the shares of a key store. The session and protocol of a checkpoint are readable without
+//! the password, and are authenticated with the sealed state
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::Key;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::key_store::{random_salt, restrict_permissions, seal, unseal, KdfParams};
+use mmpc_server_common::{ProtocolIdentifier, SessionIdentifier};
+
+const CHECKPOINT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    Format(serde_json::Error),
+    // The checkpoint was written by a later version of the client
+    Version(u32),
+    NotFound(SessionIdentifier),
+    // The checkpoint is of a session of another protocol
+    Protocol(ProtocolIdentifier),
+    // Wrong password, or the checkpoint was tampered with
+    Decrypt,
+    InvalidKdf,
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::Io(err) => write!(f, "Unable to access checkpoint: {}", err),
+            CheckpointError::Format(err) => write!(f, "Invalid checkpoint: {}", err),
+            CheckpointError::Version(version) => {
+                write!(f, "Unsupported checkpoint version {}", version)
+            }
+            CheckpointError::NotFound(session_id) => {
+                write!(f, "No checkpoint of session {}", session_id)
+            }
+            CheckpointError::Protocol(protocol_id) => {
+                write!(f, "Checkpoint is of a session of protocol {}", protocol_id)
+            }
+            CheckpointError::Decrypt => write!(f, "Unable to decrypt checkpoint, wrong password?"),
+            CheckpointError::InvalidKdf => write!(f, "Invalid key derivation parameters"),
+        }
+    }
+}
+
+impl Error for CheckpointError {}
+
+impl From<io::Error> for CheckpointError {
+    fn from(err: io::Error) -> CheckpointError {
+        CheckpointError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for CheckpointError {
+    fn from(err: serde_json::Error) -> CheckpointError {
+        CheckpointError::Format(err)
+    }
+}
+
+/// The session a checkpoint is of
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointHeader {
+    pub session_id: SessionIdentifier,
+    pub protocol_id: ProtocolIdentifier,
+}
+
+// A checkpoint as written to its file
+#[derive(Serialize, Deserialize)]
+struct CheckpointFile {
+    version: u32,
+    #[serde(flatten)]
+    header: CheckpointHeader,
+    kdf: KdfParams,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+pub struct Checkpoints {
+    dir: PathBuf,
+    password: String,
+    // Parameters the key is derived with
+    kdf: KdfParams,
+    // Salt and key of the checkpoints written, the key is only derived once
+    key: Option<(String, Key)>,
+}
+
+impl Checkpoints {
+    /// Opens the checkpoint directory at the given path, creating it if there is none.
+    /// Checkpoints are sealed with a key derived from the password
+    pub fn open<P: AsRef<Path>>(dir: P, password: &str) -> Result<Checkpoints, CheckpointError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Checkpoints {
+            dir,
+            password: password.to_string(),
+            kdf: KdfParams::default(),
+            key: None,
+        })
+    }
+
+    /// Derives the key of checkpoints written from now on with the given Argon2id parameters
+    pub fn with_kdf(mut self, kdf: KdfParams) -> Checkpoints {
+        self.kdf = kdf;
+        self.key = None;
+        self
+    }
+
+    /// Path of the checkpoint of the session
+    pub fn path(&self, session_id: SessionIdentifier) -> PathBuf {
+        self.dir.join(format!("session-{}.json", session_id))
+    }
+
+    /// Seals the state with the password and writes it as the checkpoint of the session,
+    /// replacing the previous one
+    pub fn save<S: Serialize>(
+        &mut self,
+        header: CheckpointHeader,
+        state: &S,
+    ) -> Result<(), CheckpointError> {
+        if self.key.is_none() {
+            let salt = random_salt();
+            let key = self
+                .kdf
+                .derive_key(&self.password, &salt)
+                .map_err(|_| CheckpointError::InvalidKdf)?;
+            self.key = Some((hex::encode(salt), key));
+        }
+        let (salt, key) = self.key.as_ref().unwrap();
+        let plaintext = serde_json::to_vec(state)?;
+        let (nonce, ciphertext) =
+            seal(key, &plaintext, &associated_data(&header)?).ok_or(CheckpointError::Decrypt)?;
+        let file = CheckpointFile {
+            version: CHECKPOINT_VERSION,
+            header,
+            kdf: self.kdf,
+            salt: salt.clone(),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        };
+        // A failed write keeps the previous checkpoint
+        let path = self.path(header.session_id);
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&file)?)?;
+        restrict_permissions(Path::new(&tmp_path))?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Opens the checkpoint of the session, which must be of the given protocol.
+    /// Later checkpoints are sealed with the key of this one
+    pub fn load<S: DeserializeOwned>(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+        session_id: SessionIdentifier,
+    ) -> Result<S, CheckpointError> {
+        let data = match fs::read_to_string(self.path(session_id)) {
+            Ok(data) => data,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(CheckpointError::NotFound(session_id));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let file: CheckpointFile = serde_json::from_str(&data)?;
+        if file.version > CHECKPOINT_VERSION {
+            return Err(CheckpointError::Version(file.version));
+        }
+        if file.header.session_id != session_id {
+            return Err(CheckpointError::Decrypt);
+        }
+        if file.header.protocol_id != protocol_id {
+            return Err(CheckpointError::Protocol(file.header.protocol_id));
+        }
+        let salt = hex::decode(&file.salt).map_err(|_| CheckpointError::Decrypt)?;
+        let nonce = hex::decode(&file.nonce).map_err(|_| CheckpointError::Decrypt)?;
+        let ciphertext = hex::decode(&file.ciphertext).map_err(|_| CheckpointError::Decrypt)?;
+        let key = file
+            .kdf
+            .derive_key(&self.password, &salt)
+            .map_err(|_| CheckpointError::InvalidKdf)?;
+        let plaintext = unseal(&key, &nonce, &ciphertext, &associated_data(&file.header)?)
+            .ok_or(CheckpointError::Decrypt)?;
+        let state = serde_json::from_slice(&plaintext)?;
+        self.kdf = file.kdf;
+        self.key = Some((file.salt, key));
+        Ok(state)
+    }
+
+    /// Removes the checkpoint of the session, once its result is kept
+    pub fn remove(&self, session_id: SessionIdentifier) -> Result<(), CheckpointError> {
+        match fs::remove_file(self.path(session_id)) {
+            Ok(()) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+// The header is authenticated with the state, so a checkpoint can not be moved to another session
+fn associated_data(header: &CheckpointHeader) -> Result<Vec<u8>, CheckpointError> {
+    Ok(serde_json::to_vec(header)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CheckpointError, CheckpointHeader, Checkpoints};
+    use crate::eddsa_peer_kg::{self, KeyShare};
+    use crate::eddsa_peer_sign;
+    use crate::key_store::KdfParams;
+    use crate::peer::{run_in_process, Peer};
+    use multi_party_eddsa::protocols::aggsig::verify;
+
+    // Cheap parameters, the defaults take a while to derive a key
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    const HEADER: CheckpointHeader = CheckpointHeader {
+        session_id: 7,
+        protocol_id: 1,
+    };
+
+    #[test]
+    fn test_checkpoint_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut checkpoints = Checkpoints::open(dir.path(), "password")
+            .unwrap()
+            .with_kdf(TEST_KDF);
+        checkpoints.save(HEADER, &(1u32, "first")).unwrap();
+        checkpoints.save(HEADER, &(2u32, "second")).unwrap();
+
+        // The last checkpoint is restored, and later ones are sealed with the same key
+        let mut restored = Checkpoints::open(dir.path(), "password").unwrap();
+        let state: (u32, String) = restored.load(1, 7).unwrap();
+        assert_eq!(state, (2, String::from("second")));
+        restored.save(HEADER, &(3u32, "third")).unwrap();
+        let state: (u32, String) = checkpoints.load(1, 7).unwrap();
+        assert_eq!(state, (3, String::from("third")));
+
+        match checkpoints.load::<(u32, String)>(1, 8) {
+            Err(CheckpointError::NotFound(8)) => {}
+            _ => panic!("Loaded a missing checkpoint"),
+        }
+        match checkpoints.load::<(u32, String)>(2, 7) {
+            Err(CheckpointError::Protocol(1)) => {}
+            _ => panic!("Loaded the checkpoint of another protocol"),
+        }
+
+        checkpoints.remove(7).unwrap();
+        assert!(!checkpoints.path(7).exists());
+        checkpoints.remove(7).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoints_are_sealed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut checkpoints = Checkpoints::open(dir.path(), "password")
+            .unwrap()
+            .with_kdf(TEST_KDF);
+        checkpoints
+            .save(HEADER, &String::from("ephemeral-secret"))
+            .unwrap();
+        let path = checkpoints.path(7);
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(!data.contains("ephemeral-secret"));
+
+        let mut wrong = Checkpoints::open(dir.path(), "wrong").unwrap();
+        match wrong.load::<String>(1, 7) {
+            Err(CheckpointError::Decrypt) => {}
+            _ => panic!("Opened with the wrong password"),
+        }
+        // The header is authenticated with the state
+        std::fs::write(
+            checkpoints.path(8),
+            data.replace("\"session_id\": 7", "\"session_id\": 8"),
+        )
+        .unwrap();
+        match checkpoints.load::<String>(1, 8) {
+            Err(CheckpointError::Decrypt) => {}
+            _ => panic!("Opened a checkpoint moved to another session"),
+        }
+    }
+
+    #[test]
+    fn test_restore_signing_peer() {
+        // Key shares of 2 peers, sealed and opened as a key store does
+        let kg_peers = (1..=2)
+            .map(|peer_id| (peer_id, eddsa_peer_kg::EddsaPeer::new(2)))
+            .collect();
+        let shares: Vec<KeyShare> = run_in_process(kg_peers, |_, _| {});
+        let apk = shares[0].agg_key.apk.clone();
+        let message = b"message".to_vec();
+        let sign_peers = shares
+            .iter()
+            .map(|share| {
+                let stored: KeyShare =
+                    serde_json::from_str(&serde_json::to_string(share).unwrap()).unwrap();
+                let peer = eddsa_peer_sign::EddsaPeer::with_keys(2, message.clone(), stored);
+                (share.kg_index, peer)
+            })
+            .collect();
+
+        // Peer 1 restarts from its checkpoint once it has the public keys of the peers
+        // and committed to its ephemeral key
+        let dir = tempfile::tempdir().unwrap();
+        let mut checkpoints = Checkpoints::open(dir.path(), "password")
+            .unwrap()
+            .with_kdf(TEST_KDF);
+        let header = CheckpointHeader {
+            session_id: 7,
+            protocol_id: eddsa_peer_sign::EddsaPeer::PROTOCOL_ID,
+        };
+        let mut restored = false;
+        let signatures = run_in_process(sign_peers, |round, peer| {
+            if round == 0 && peer.peer_id == 1 {
+                checkpoints.save(header, &*peer).unwrap();
+                *peer = checkpoints
+                    .load(header.protocol_id, header.session_id)
+                    .unwrap();
+                restored = true;
+            }
+        });
+        assert!(restored);
+        for signature in &signatures {
+            assert!(verify(signature, &message, &apk).is_ok());
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! What the client binaries have in common: the arguments, logging, the connection
+//! to the relay and the checkpoints of the session, and the timings they record.
+//! Each binary only sets up the peer of its protocol and keeps its output.
+//! Failures are printed and exit the client, as the binaries are run from scripts
+use std::env;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use clap::{App, Arg, ArgMatches};
+use futures::future::{self, Future};
+use log::error;
+use serde::Serialize;
+
+use crate::channel::{Registration, Relay, Transport};
+use crate::checkpoint::Checkpoints;
+use crate::client::SessionClient;
+use crate::key_store::KeyStore;
+use crate::peer::Peer;
+use mmpc_server_common::codec::Encoding;
+use mmpc_server_common::SessionIdentifier;
+
+// Environment variable of the password the key shares are encrypted with
+const PASSWORD_VAR: &str = "MMPC_KEY_PASSWORD";
+
+#[derive(Debug, Serialize)]
+struct Record {
+    index: u32,
+    millis: u32,
+}
+
+/// The arguments every client takes, a binary adds the ones of its protocol
+pub fn app<'a, 'b>(name: &str) -> App<'a, 'b> {
+    App::new(name)
+        .arg(
+            Arg::with_name("index")
+                .short("I")
+                .long("index")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("capacity")
+                .default_value("2")
+                .short("C")
+                .long("capacity"),
+        )
+        .arg(
+            Arg::with_name("key-store")
+                .long("key-store")
+                .short("F")
+                .takes_value(true)
+                .help("Key store of the key shares, a file for each client index by default"),
+        )
+        .arg(
+            Arg::with_name("key-id")
+                .long("key-id")
+                .default_value("default")
+                .help("Id of the key share in the key store"),
+        )
+        .arg(
+            Arg::with_name("session")
+                .long("session")
+                .short("S")
+                .takes_value(true)
+                .help("Relay session to join, a new session is opened if not given"),
+        )
+        .arg(
+            Arg::with_name("rejoin")
+                .long("rejoin")
+                .takes_value(true)
+                .conflicts_with("session")
+                .help("Session to rejoin from its checkpoint, after the client was restarted"),
+        )
+        .arg(
+            Arg::with_name("checkpoints")
+                .long("checkpoints")
+                .takes_value(true)
+                .help("Directory of the session checkpoints, checkpoints<INDEX> by default"),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .default_value("127.0.0.1:26657")
+                .long("proxy")
+                .use_delimiter(true)
+                .help(
+                    "Addresses of the relay, for Tendermint the RPC endpoints \
+                     of the validator nodes separated by commas",
+                ),
+        )
+        .arg(
+            Arg::with_name("relay")
+                .long("relay")
+                .default_value("tendermint")
+                .possible_values(&["tendermint", "tokio", "http"])
+                .help("Relay server the session runs over"),
+        )
+        .arg(
+            Arg::with_name("codec")
+                .long("codec")
+                .default_value("json")
+                .possible_values(&["json", "cbor"])
+                .help("Encoding of the messages sent to the relay"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Increases logging verbosity each use for up to 3 times"),
+        )
+}
+
+/// A client run from the command line, named after its protocol
+/// in the files it writes, such as `threshold-kg`
+pub struct ClientArgs {
+    pub name: &'static str,
+    pub index: u32,
+    pub capacity: u32,
+    pub key_store: String,
+    pub key_id: String,
+    pub password: String,
+    pub session_id: Option<SessionIdentifier>,
+    pub rejoin: Option<SessionIdentifier>,
+    pub checkpoints: String,
+    pub proxies: Vec<String>,
+    pub transport: Transport,
+    pub encoding: Encoding,
+    pub verbosity: u64,
+}
+
+impl ClientArgs {
+    /// Reads the arguments of the app, the key store is <key_store><INDEX>.json unless given
+    pub fn parse(name: &'static str, matches: &ArgMatches, key_store: &str) -> ClientArgs {
+        let index: u32 = matches
+            .value_of("index")
+            .unwrap()
+            .parse()
+            .expect("Unable to parse index");
+        ClientArgs {
+            name,
+            index,
+            capacity: matches
+                .value_of("capacity")
+                .unwrap()
+                .parse()
+                .expect("Invalid number of participants"),
+            key_store: matches
+                .value_of("key-store")
+                .map(String::from)
+                .unwrap_or_else(|| format!("{}{}.json", key_store, index)),
+            key_id: matches.value_of("key-id").unwrap().to_string(),
+            password: read_password(),
+            session_id: matches
+                .value_of("session")
+                .map(|session| session.parse().expect("Invalid session identifier")),
+            rejoin: matches
+                .value_of("rejoin")
+                .map(|session| session.parse().expect("Invalid session identifier")),
+            checkpoints: matches
+                .value_of("checkpoints")
+                .map(String::from)
+                .unwrap_or_else(|| format!("checkpoints{}", index)),
+            proxies: matches
+                .values_of("proxy")
+                .unwrap()
+                .map(String::from)
+                .collect(),
+            transport: matches
+                .value_of("relay")
+                .unwrap()
+                .parse()
+                .expect("Invalid relay"),
+            encoding: matches
+                .value_of("codec")
+                .unwrap()
+                .parse()
+                .expect("Invalid codec"),
+            verbosity: matches.occurrences_of("verbose"),
+        }
+    }
+
+    /// Logs to the standard output and to log-<name>-<INDEX>.log
+    pub fn setup_logging(&self) -> Result<(), fern::InitError> {
+        let mut base_config = fern::Dispatch::new();
+
+        base_config = match self.verbosity {
+            0 => base_config
+                .level(log::LevelFilter::Info)
+                .level_for("abci::server", log::LevelFilter::Warn), // filter out abci::server
+            1 => base_config
+                .level(log::LevelFilter::Debug)
+                .level_for("tokio_core", log::LevelFilter::Warn) // filter out tokio
+                .level_for("tokio_reactor", log::LevelFilter::Warn)
+                .level_for("hyper", log::LevelFilter::Warn),
+            _2_or_more => base_config.level(log::LevelFilter::Trace),
+        };
+
+        // Separate file config so we can include year, month and day in file logs
+        let file_config = fern::Dispatch::new()
+            .format(|out, message, record| {
+                out.finish(format_args!(
+                    "{}[{}][{}] {} {}",
+                    chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                    record.target(),
+                    record.level(),
+                    line!(),
+                    message
+                ))
+            })
+            .chain(fern::log_file(format!(
+                "log-{}-{}.log",
+                self.name, self.index
+            ))?);
+
+        let stdout_config = fern::Dispatch::new()
+            .format(|out, message, record| {
+                // special format for debug messages coming from our own crate.
+                if record.level() > log::LevelFilter::Info && record.target() == "mmpc_client" {
+                    out.finish(format_args!(
+                        "---\nDEBUG: {}: {}\n---",
+                        chrono::Local::now().format("%H:%M:%S"),
+                        message
+                    ))
+                } else {
+                    out.finish(format_args!(
+                        "[{}][{}][{}] {} ",
+                        chrono::Local::now().format("%H:%M:%S"),
+                        record.target(),
+                        record.level(),
+                        message
+                    ))
+                }
+            })
+            .chain(io::stdout());
+
+        base_config
+            .chain(file_config)
+            .chain(stdout_config)
+            .apply()?;
+
+        Ok(())
+    }
+
+    pub fn open_key_store(&self) -> KeyStore {
+        exit_on_error(KeyStore::open(&self.key_store))
+    }
+
+    fn open_checkpoints(&self) -> Checkpoints {
+        exit_on_error(Checkpoints::open(&self.checkpoints, &self.password))
+    }
+
+    /// Runs the peer in the session of the arguments, or opens a new session,
+    /// and returns its output once the protocol completes. With --rejoin the peer
+    /// is restored from its checkpoint instead, and `new_peer` is not called.
+    /// A client which opened a session writes its identifier for the other clients
+    pub fn run_session<T, F>(&self, kg_index: i32, new_peer: F) -> (T::Output, Registration)
+    where
+        T: Peer + Send + 'static,
+        T::Output: Send + 'static,
+        F: FnOnce() -> T,
+    {
+        let relay = match Relay::connect(self.transport, &self.proxies, self.encoding) {
+            Ok(relay) => relay,
+            Err(err) => {
+                println!("Unable to connect to the relay: {}", err);
+                process::exit(1);
+            }
+        };
+        let mut session =
+            match self.rejoin {
+                // The peer is restored with the state it had before the restart
+                Some(session_id) => {
+                    let client: SessionClient<T, Relay> = exit_on_error(
+                        SessionClient::restore_with(relay, self.open_checkpoints(), session_id),
+                    );
+                    client.resume()
+                }
+                None => SessionClient::with_channel(relay, new_peer())
+                    .with_checkpoints(self.open_checkpoints())
+                    .run(kg_index, self.session_id),
+            };
+        let registration = exit_on_error(future::poll_fn(|| session.poll_registration()).wait());
+        // Let the other clients know which session was opened
+        if self.session_id.is_none() && self.rejoin.is_none() {
+            println!("Session: {}", registration.session_id);
+            fs::write("session", registration.session_id.to_string())
+                .expect("Unable to save session id");
+        }
+        match session.wait() {
+            Ok(output) => (output, registration),
+            Err(err) => {
+                error!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Removes the checkpoint of the session, once the output of the peer is kept
+    /// and the session is not rejoined anymore
+    pub fn discard_checkpoint(&self, registration: &Registration) {
+        if let Err(err) = self.open_checkpoints().remove(registration.session_id) {
+            error!("Unable to remove checkpoint: {}", err);
+        }
+    }
+
+    /// Appends the time the session took to exp-<name>-<CAPACITY>.csv
+    pub fn write_to_csv(&self, millis: u32) -> Result<(), Box<dyn Error>> {
+        let filename = format!("exp-{}-{}.csv", self.name, self.capacity);
+        let exists = Path::new(&filename).exists();
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&filename)?;
+
+        let mut wtr = csv::WriterBuilder::default()
+            .has_headers(!exists)
+            .from_writer(file);
+
+        wtr.serialize(Record {
+            index: self.index,
+            millis,
+        })?;
+        wtr.flush()?;
+
+        Ok(())
+    }
+}
+
+// The password is read from the environment, or asked for on the terminal.
+// It is not taken as an argument, which other users of the host can see
+fn read_password() -> String {
+    match env::var(PASSWORD_VAR) {
+        Ok(password) => password,
+        Err(_) => exit_on_error(rpassword::prompt_password("Key store password: ")),
+    }
+}
+
+/// Prints the error and exits the client on failure
+pub fn exit_on_error<T, E: Display>(result: Result<T, E>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    }
+}
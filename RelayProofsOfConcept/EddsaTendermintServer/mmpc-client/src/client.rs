@@ -0,0 +1,311 @@
+//! Runs a peer through a session over a broadcast channel, round by round.
+//! The client checkpoints the peer before each message it sends, and rejoins the session
+//! from the checkpoint after a restart, on any relay its channel can rejoin
+use std::slice;
+use std::time::Duration;
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::channel::{BroadcastChannel, RegisterRequest, RoundMessage};
+use crate::checkpoint::{CheckpointError, CheckpointHeader, Checkpoints};
+use crate::peer::{Peer, PeerError, ProtocolDataManager};
+use crate::session::SessionError;
+use crate::tendermint_client::TendermintChannel;
+use mmpc_server_common::codec::Encoding;
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::{MessagePayload, SessionIdentifier};
+
+// Polls for the messages of a round before the session is given up
+const MAX_POLLS: u32 = 512;
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// What a client checkpoints before each message it sends. The messages of the session
+// are stored on the relay, so they are fetched again after a restart
+#[derive(Serialize, Deserialize)]
+struct ClientCheckpoint<P, C> {
+    registered: bool,
+    kg_index: i32,
+    channel: C,
+    peer: P,
+    // The last message of the client, it may not have been relayed before the restart
+    pending: Option<RoundMessage>,
+}
+
+pub struct SessionClient<T, C = TendermintChannel>
+where
+    T: Peer,
+    C: BroadcastChannel,
+{
+    channel: C,
+    data_manager: ProtocolDataManager<T>,
+    session_id: Option<SessionIdentifier>,
+    registered: bool,
+    // Index the client registers with, kept to register again after a restart
+    kg_index: i32,
+    // The last message of the client, sent again when rejoining the session
+    pending: Option<RoundMessage>,
+    checkpoints: Option<Checkpoints>,
+}
+
+impl<T: Peer> SessionClient<T, TendermintChannel> {
+    /// Creates a client for a peer created by its protocol,
+    /// such as a threshold key generation peer with its threshold set
+    pub fn with_peer(server_addr: &tendermint::net::Address, peer: T) -> SessionClient<T> {
+        SessionClient::with_endpoints(slice::from_ref(server_addr), peer)
+    }
+
+    /// Creates a client sending its requests to any of the given validator nodes,
+    /// it fails over to another node when the node it uses stops answering
+    pub fn with_endpoints(endpoints: &[tendermint::net::Address], peer: T) -> SessionClient<T> {
+        SessionClient::with_channel(TendermintChannel::new(endpoints), peer)
+    }
+
+    /// Restores a client from the checkpoint of the session with the given identifier,
+    /// the client then rejoins the session where it left off
+    pub fn restore(
+        endpoints: &[tendermint::net::Address],
+        checkpoints: Checkpoints,
+        session_id: SessionIdentifier,
+    ) -> Result<SessionClient<T>, CheckpointError> {
+        SessionClient::restore_with(TendermintChannel::new(endpoints), checkpoints, session_id)
+    }
+
+    /// Sends transactions in the given encoding rather than JSON
+    pub fn with_encoding(self, encoding: Encoding) -> SessionClient<T> {
+        SessionClient {
+            channel: self.channel.with_encoding(encoding),
+            ..self
+        }
+    }
+}
+
+impl<T: Peer, C: BroadcastChannel> SessionClient<T, C> {
+    /// Creates a client running the peer over the given channel
+    pub fn with_channel(channel: C, peer: T) -> SessionClient<T, C> {
+        SessionClient {
+            channel,
+            data_manager: ProtocolDataManager::with_peer(peer),
+            session_id: None,
+            registered: false,
+            kg_index: -1,
+            pending: None,
+            checkpoints: None,
+        }
+    }
+
+    /// Restores a client over the given channel from the checkpoint of the session
+    /// with the given identifier, the client then rejoins the session where it left off
+    pub fn restore_with(
+        channel: C,
+        mut checkpoints: Checkpoints,
+        session_id: SessionIdentifier,
+    ) -> Result<SessionClient<T, C>, CheckpointError> {
+        let checkpoint: ClientCheckpoint<T, C::Checkpoint> =
+            checkpoints.load(T::PROTOCOL_ID, session_id)?;
+        info!(
+            "Restored session {} in round {}",
+            session_id,
+            checkpoint.peer.current_step()
+        );
+        let mut client = SessionClient::with_channel(channel, checkpoint.peer);
+        client.session_id = Some(session_id);
+        client.registered = checkpoint.registered;
+        client.kg_index = checkpoint.kg_index;
+        client.pending = checkpoint.pending;
+        let peer_id = if client.registered {
+            Some(client.peer().peer_id())
+        } else {
+            None
+        };
+        let request = client.register_request();
+        client
+            .channel
+            .restore(&request, peer_id, checkpoint.channel)?;
+        Ok(client.with_checkpoints(checkpoints))
+    }
+
+    /// Checkpoints the session before each message the client sends
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> SessionClient<T, C> {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    /// The session the client joined
+    pub fn session_id(&self) -> Option<SessionIdentifier> {
+        self.session_id
+    }
+
+    /// The peer run by the client
+    pub fn peer(&self) -> &T {
+        &self.data_manager.data_holder
+    }
+
+    /// The channel of the session
+    pub fn channel(&self) -> &C {
+        &self.channel
+    }
+}
+
+impl<T: Peer, C: BroadcastChannel> SessionClient<T, C> {
+    /// Registers to the relay session with the given identifier, or opens a new session,
+    /// and sends the first message of the protocol.
+    /// Returns the identifier of the session, for the other peers to join
+    pub fn join(
+        &mut self,
+        kg_index: i32,
+        session_id: Option<SessionIdentifier>,
+    ) -> Result<SessionIdentifier, SessionError> {
+        self.kg_index = kg_index;
+        self.session_id = session_id;
+        // The channel of a client joining a known session is checkpointed before registering,
+        // so a client restarted before its registration returned can find the peer it registered as.
+        // A new session has no identifier to checkpoint under until the relay opened it
+        self.checkpoint()?;
+        let registration = self.channel.register(&self.register_request())?;
+        info!(
+            "Registered to session {} as peer {}",
+            registration.session_id, registration.peer_id
+        );
+        self.session_id = Some(registration.session_id);
+        // Checkpointed as soon as the session is known, so a client restarted before
+        // its first message registers to the same session again
+        self.checkpoint()?;
+        self.registered = true;
+        let payload = self
+            .data_manager
+            .initialize_data(registration.peer_id)
+            .ok_or(SessionError::Protocol(PeerError::Protocol(
+                "Peer has no first message",
+            )))?;
+        self.pending = Some(self.round_message(payload));
+        debug!("Next message: {:?}", self.pending);
+        self.send_pending()?;
+        Ok(registration.session_id)
+    }
+
+    /// Rejoins the session the client was restored in. The client registers
+    /// if it was not registered yet, and sends its last message again,
+    /// the relay rejects it if it was relayed before the restart
+    pub fn rejoin(&mut self) -> Result<SessionIdentifier, SessionError> {
+        let session_id = self
+            .session_id
+            .ok_or(SessionError::Register(RelayError::CantRegister))?;
+        if !self.registered {
+            return self.join(self.kg_index, Some(session_id));
+        }
+        self.send_pending()?;
+        Ok(session_id)
+    }
+
+    /// Runs the joined session round by round until the protocol is done,
+    /// and returns its result.
+    /// The session is given up once cancelled returns true, it is checked while waiting for messages
+    pub fn complete<F>(&mut self, cancelled: F) -> Result<T::Output, SessionError>
+    where
+        F: Fn() -> bool,
+    {
+        let capacity = self.data_manager.data_holder.capacity() as usize;
+        while !self.data_manager.data_holder.is_done() {
+            let round = self.data_manager.data_holder.current_step();
+            debug!("Now on round {}", round);
+            let mut polls = 0;
+            let messages = loop {
+                if cancelled() {
+                    info!("Session cancelled in round {}", round);
+                    return Err(SessionError::Cancelled);
+                }
+                let messages = self.channel.receive(round, POLL_INTERVAL)?;
+                if messages.len() >= capacity {
+                    break messages;
+                }
+                if polls == MAX_POLLS {
+                    return Err(SessionError::TimedOut(round));
+                }
+                polls += 1;
+            };
+            let mut next_payload = None;
+            for (from, message) in messages {
+                next_payload = self
+                    .data_manager
+                    .get_next_message(from, message.payload, message.p2p_payload)
+                    .map_err(SessionError::Protocol)?;
+            }
+            // No message is sent once the last round is handled
+            if self.data_manager.data_holder.is_done() {
+                self.pending = None;
+                self.checkpoint()?;
+                break;
+            }
+            self.pending = next_payload.map(|payload| self.round_message(payload));
+            self.send_pending()?;
+        }
+        self.data_manager
+            .data_holder
+            .output()
+            .ok_or(SessionError::NoOutput)
+    }
+
+    /// Removes the checkpoint of the session, once the result of the protocol is kept
+    pub fn discard_checkpoint(&self) -> Result<(), CheckpointError> {
+        match (self.session_id, self.checkpoints.as_ref()) {
+            (Some(session_id), Some(checkpoints)) => checkpoints.remove(session_id),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<T: Peer, C: BroadcastChannel> SessionClient<T, C> {
+    fn register_request(&self) -> RegisterRequest {
+        let peer = &self.data_manager.data_holder;
+        RegisterRequest {
+            protocol_id: T::PROTOCOL_ID,
+            capacity: peer.capacity(),
+            kg_index: self.kg_index,
+            share_count: peer.share_count(),
+            session_id: self.session_id,
+        }
+    }
+
+    // The message of the current round of the peer, with its point-to-point messages
+    fn round_message(&mut self, payload: MessagePayload) -> RoundMessage {
+        RoundMessage {
+            round: self.data_manager.data_holder.current_step(),
+            payload,
+            p2p: self.data_manager.data_holder.get_next_p2p_items(),
+        }
+    }
+
+    // Checkpoints the pending message before sending it, so the state the message was
+    // computed from is never lost once the message may have been relayed
+    fn send_pending(&mut self) -> Result<(), SessionError> {
+        self.checkpoint()?;
+        if let Some(message) = &self.pending {
+            self.channel.broadcast(message)?;
+        }
+        Ok(())
+    }
+
+    // Writes the state of the client in its session to the checkpoint, if checkpointing
+    fn checkpoint(&mut self) -> Result<(), SessionError> {
+        let session_id = match (self.session_id, self.checkpoints.as_ref()) {
+            (Some(session_id), Some(_)) => session_id,
+            _ => return Ok(()),
+        };
+        let header = CheckpointHeader {
+            session_id,
+            protocol_id: T::PROTOCOL_ID,
+        };
+        let checkpoint = ClientCheckpoint {
+            registered: self.registered,
+            kg_index: self.kg_index,
+            channel: self.channel.checkpoint(),
+            peer: &self.data_manager.data_holder,
+            pending: self.pending.clone(),
+        };
+        let checkpoints = self.checkpoints.as_mut().unwrap();
+        checkpoints
+            .save(header, &checkpoint)
+            .map_err(SessionError::Checkpoint)
+    }
+}
@@ -1,15 +1,51 @@
 use std::collections::HashMap;
-use std::fs;
 
 use curv::elliptic::curves::ed25519::*;
-use log::{debug, info};
+use curv::elliptic::curves::traits::ECPoint;
+use log::{debug, info, warn};
 use multi_party_eddsa::protocols::aggsig::{EphemeralKey, KeyAgg, KeyPair};
+use serde::{Deserialize, Serialize};
 
-use crate::peer::Peer;
-use mmpc_server_common::common::*;
-use mmpc_server_common::{MessagePayload, PeerIdentifier};
+use crate::key_store::{KeyLabel, KeyStore, KeyStoreError};
+use crate::peer::{Peer, PeerError};
+use crate::points::{self, Deserialized};
+use mmpc_server_common::payload::Payload;
+use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+
+/// Key share of an aggregated key, as saved after key generation
+#[derive(Serialize, Deserialize)]
+pub struct KeyShare {
+    pub key: KeyPair,
+    pub agg_key: KeyAgg,
+    // the index of this peer in the key generation, which it registers with for signing
+    pub kg_index: u32,
+}
+
+impl Deserialized for KeyShare {
+    fn deserialized(mut self) -> KeyShare {
+        self.key = self.key.deserialized();
+        self.agg_key = self.agg_key.deserialized();
+        self
+    }
+}
+
+impl KeyShare {
+    /// Loads the key share stored with the given key id
+    pub fn load(store: &KeyStore, key_id: &str, password: &str) -> Result<KeyShare, KeyStoreError> {
+        store.load(key_id, password)
+    }
+
+    /// Label of the share in a key store, with the aggregated public key
+    pub fn label(&self, key_id: &str) -> KeyLabel {
+        KeyLabel {
+            key_id: key_id.to_string(),
+            public_key: hex::encode(self.agg_key.apk.pk_to_key_slice()),
+        }
+    }
+}
 
 #[allow(non_snake_case)]
+#[derive(Serialize, Deserialize)]
 pub struct EddsaPeer {
     // this peers identifier in this session
     pub peer_id: PeerIdentifier,
@@ -20,16 +56,24 @@ pub struct EddsaPeer {
     // is peer done with all calculations
     pub is_done: bool,
 
-    // eddsa data
+    // eddsa data, points are restored from a checkpoint as they were checkpointed
+    #[serde(deserialize_with = "points::deserialize")]
     pub client_key: KeyPair,
+    #[serde(deserialize_with = "points::deserialize")]
     pub pks: HashMap<PeerIdentifier, Ed25519Point>,
     pub commitments: HashMap<PeerIdentifier, String>,
     pub r_s: HashMap<PeerIdentifier, String>,
     pub sigs: HashMap<PeerIdentifier, String>,
+    #[serde(deserialize_with = "points::deserialize")]
     pub ephemeral_key: Option<EphemeralKey>,
 
+    #[serde(deserialize_with = "points::deserialize")]
     pub agg_key: Option<KeyAgg>,
+    #[serde(deserialize_with = "points::deserialize")]
     pub R_tot: Option<GE>,
+    // the key share of this peer, once the public keys are aggregated
+    #[serde(deserialize_with = "points::deserialize")]
+    pub share: Option<KeyShare>,
 
     // indicators for which of this peers messages were accepted
     pub pk_accepted: bool,
@@ -44,8 +88,8 @@ pub struct EddsaPeer {
     pub sig_msg: Option<MessagePayload>,
 }
 
-impl Peer for EddsaPeer {
-    fn new(capacity: u32, _message: Vec<u8>, _index: u32) -> EddsaPeer {
+impl EddsaPeer {
+    pub fn new(capacity: u32) -> EddsaPeer {
         debug!("Capacity is set to {}", capacity);
         EddsaPeer {
             client_key: KeyPair::create(),
@@ -56,6 +100,7 @@ impl Peer for EddsaPeer {
             capacity,
             peer_id: 0,
             agg_key: None,
+            share: None,
             current_step: 0,
             R_tot: None,
             ephemeral_key: None,
@@ -71,6 +116,11 @@ impl Peer for EddsaPeer {
             sig_msg: None,
         }
     }
+}
+
+impl Peer for EddsaPeer {
+    const PROTOCOL_ID: ProtocolIdentifier = 1;
+    type Output = KeyShare;
 
     fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
         self.peer_id = peer_id;
@@ -82,7 +132,7 @@ impl Peer for EddsaPeer {
 
         let pk_s = serde_json::to_string(&pk).expect("Failed in serialization");
 
-        self.pk_msg = Some(generate_pk_message_payload(&pk_s));
+        self.pk_msg = Some(Payload::PublicKey(pk_s).encode());
         return self.pk_msg.clone();
     }
 
@@ -98,7 +148,7 @@ impl Peer for EddsaPeer {
         self.peer_id
     }
 
-    fn do_step(&mut self) {
+    fn do_step(&mut self) -> Result<(), PeerError> {
         debug!("Current step is: {:}", self.current_step);
         if self.is_step_done() {
             // do the next step
@@ -114,38 +164,44 @@ impl Peer for EddsaPeer {
         } else {
             debug!("step not done");
         }
+        Ok(())
     }
 
-    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+    fn update_data(
+        &mut self,
+        from: PeerIdentifier,
+        payload: MessagePayload,
+    ) -> Result<(), PeerError> {
         // update data according to step
         debug!("Current step {}", self.current_step);
         match self.current_step {
             0 => self.update_data_step_0(from, payload),
 
-            _ => panic!("Unsupported step"),
+            _ => return Err(PeerError::Protocol("Unsupported step")),
         }
+        Ok(())
     }
     /// Does the final calculation of the protocol
     /// in this case:
     ///     collection all signatures
     ///     and verifying the message
     fn finalize(&mut self) -> Result<(), &'static str> {
-        let key = &self.client_key.clone();
-        let apk = &self.aggregate_pks();
-        let index = &self.peer_id;
-
-        let keygen_json = serde_json::to_string(&(key, apk, index)).unwrap();
-
-        let res = fs::write(format!("keys{}", self.peer_id), keygen_json);
-        match res {
-            Ok(_) => Ok(()),
-            Err(_) => Err("Failed to verify"),
-        }
+        let agg_key = self.aggregate_pks();
+        self.share = Some(KeyShare {
+            key: self.client_key.clone(),
+            agg_key,
+            kg_index: self.peer_id,
+        });
+        Ok(())
     }
     /// check that the protocol is done
     /// and that this peer can finalize its calculations
     fn is_done(&mut self) -> bool {
-        self.is_done_step_0()
+        self.is_done
+    }
+
+    fn output(&mut self) -> Option<KeyShare> {
+        self.share.take()
     }
 
     /// get the next item the peer needs to send
@@ -176,28 +232,11 @@ impl EddsaPeer {
     }
 }
 
-impl EddsaPeer {
-    pub fn resolve_payload_type(message: &MessagePayload) -> MessagePayloadType {
-        let msg_payload = message.clone();
-
-        let split_msg: Vec<&str> = msg_payload.split(RELAY_MESSAGE_DELIMITER).collect();
-        let msg_prefix = split_msg[0];
-        let msg_payload = String::from(split_msg[1].clone());
-        match msg_prefix {
-            pk_prefix if pk_prefix == String::from(PK_MESSAGE_PREFIX) => {
-                return MessagePayloadType::PublicKey(msg_payload);
-            }
-            _ => panic!("Unknown relay message prefix"),
-        }
-    }
-}
-
 impl EddsaPeer {
     /// data updaters for each step
     pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::PublicKey(pk) => {
+        match Payload::decode(&payload) {
+            Ok(Payload::PublicKey(pk)) => {
                 let peer_id = self.peer_id;
                 if from == peer_id {
                     self.pk_accepted = true;
@@ -210,6 +249,8 @@ impl EddsaPeer {
                     Err(_) => panic!("Could not serialize public key"),
                 }
             }
+            Ok(_) => warn!("Expected a public key from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
         }
     }
 }
@@ -235,10 +276,3 @@ impl EddsaPeer {
         return agg_key;
     }
 }
-
-#[derive(Debug)]
-pub enum MessagePayloadType {
-    /// Types of expected relay messages
-    /// for step 0 we expect PUBLIC_KEY_MESSAGE
-    PublicKey(String),
-}
@@ -1,34 +1,34 @@
 use std::collections::HashMap;
-use std::fs;
 
 use curv::arithmetic::traits::Converter;
 use curv::elliptic::curves::ed25519::*;
 use curv::elliptic::curves::traits::ECPoint;
 use curv::elliptic::curves::traits::ECScalar;
 use curv::{BigInt, FE, GE};
-use log::{debug, info};
+use log::{debug, info, warn};
 use multi_party_eddsa::protocols::aggsig::{
     test_com, verify, EphemeralKey, KeyAgg, KeyPair, SignFirstMsg, SignSecondMsg, Signature,
 };
+use serde::{Deserialize, Serialize};
 
-use crate::peer::Peer;
-use mmpc_server_common::common::*;
-use mmpc_server_common::{MessagePayload, PeerIdentifier};
-
-#[derive(Debug)]
-pub enum MessagePayloadType {
-    /// Types of expected relay messages
-    /// for step 0 we expect PUBLIC_KEY_MESSAGE
-    /// for step 1 we expect Commitment
-    /// for step 2 we expect RMessage
-    /// for step 3 we expect Signature
-    PublicKey(String),
-    Commitment(String),
-    RMessage(String),
-    Signature(String),
+use crate::eddsa_peer_kg::KeyShare;
+use crate::peer::{Peer, PeerError};
+use crate::points;
+use mmpc_server_common::payload::Payload;
+use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+
+/// Encodes a signature in hex, R followed by s in little endian
+#[allow(non_snake_case)]
+pub fn encode_signature(signature: &Signature) -> String {
+    let mut R_vec = signature.R.pk_to_key_slice().to_vec();
+    let mut s_vec = BigInt::to_vec(&signature.s.to_big_int());
+    s_vec.reverse();
+    R_vec.extend_from_slice(&s_vec[..]);
+    BigInt::from(&R_vec[..]).to_str_radix(16)
 }
 
 #[allow(non_snake_case)]
+#[derive(Serialize, Deserialize)]
 pub struct EddsaPeer {
     // this peers identifier in this session
     pub peer_id: PeerIdentifier,
@@ -39,18 +39,27 @@ pub struct EddsaPeer {
     // is peer done with all calculations
     pub is_done: bool,
 
-    // eddsa data
+    // eddsa data, points are restored from a checkpoint as they were checkpointed
+    #[serde(deserialize_with = "points::deserialize")]
     pub client_key: KeyPair,
+    #[serde(deserialize_with = "points::deserialize")]
     pub pks: HashMap<PeerIdentifier, Ed25519Point>,
     pub commitments: HashMap<PeerIdentifier, String>,
     pub r_s: HashMap<PeerIdentifier, String>,
     pub sigs: HashMap<PeerIdentifier, String>,
+    #[serde(deserialize_with = "points::deserialize")]
     pub ephemeral_key: Option<EphemeralKey>,
     // message to sign
     pub message: Vec<u8>,
 
+    // the aggregated key of the key generation, as stored with the key share
+    #[serde(deserialize_with = "points::deserialize")]
     pub agg_key: Option<KeyAgg>,
     pub kg_index: u32,
+    // the signature, once all parts are added and verified
+    #[serde(deserialize_with = "points::deserialize")]
+    pub signature: Option<Signature>,
+    #[serde(deserialize_with = "points::deserialize")]
     pub R_tot: Option<GE>,
 
     // indicators for which of this peers messages were accepted
@@ -148,11 +157,10 @@ impl EddsaPeer {
 impl EddsaPeer {
     /// data updaters for each step
     pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
         let eight: FE = ECScalar::from(&BigInt::from(8));
         let eight_inv = eight.invert();
-        match payload_type {
-            MessagePayloadType::PublicKey(pk) => {
+        match Payload::decode(&payload) {
+            Ok(Payload::PublicKey(pk)) => {
                 let peer_id = self.peer_id;
                 if from == peer_id {
                     self.pk_accepted = true;
@@ -163,14 +171,14 @@ impl EddsaPeer {
                 info!("-------Got peer # {:} pk! {:?}", from, pk * &eight_inv);
                 self.add_pk(from, pk * &eight_inv);
             }
-            _ => panic!("expected public key message"),
+            Ok(_) => warn!("Expected a public key from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
         }
     }
 
     pub fn update_data_step_1(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::Commitment(t) => {
+        match Payload::decode(&payload) {
+            Ok(Payload::Commitment(t)) => {
                 info!("-------Got peer # {:} commitment! {:?}", from, t);
                 let peer_id = self.peer_id;
                 if from == peer_id {
@@ -178,14 +186,14 @@ impl EddsaPeer {
                 }
                 self.add_commitment(from, t);
             }
-            _ => {} //panic!("expected commitment message")
+            Ok(_) => warn!("Expected a commitment from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
         }
     }
 
     pub fn update_data_step_2(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::RMessage(r) => {
+        match Payload::decode(&payload) {
+            Ok(Payload::RKey(r)) => {
                 info!("-------Got peer # {:} R message!", from);
                 let peer_id = self.peer_id;
                 if from == peer_id {
@@ -193,15 +201,15 @@ impl EddsaPeer {
                 }
                 self.add_r(from, r);
             }
-            _ => {} //panic!("expected R message")
+            Ok(_) => warn!("Expected an R message from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
         }
     }
 
     pub fn update_data_step_3(&mut self, from: PeerIdentifier, payload: MessagePayload) {
         debug!("updating data step 3");
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::Signature(s) => {
+        match Payload::decode(&payload) {
+            Ok(Payload::Signature(s)) => {
                 debug!("-------Got peer # {:} Signature", from);
                 let peer_id = self.peer_id;
                 if from == peer_id {
@@ -209,7 +217,8 @@ impl EddsaPeer {
                 }
                 self.add_sig(from, s);
             }
-            _ => {} //panic!("expected signature message")
+            Ok(_) => warn!("Expected a signature from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
         }
     }
 }
@@ -265,8 +274,8 @@ impl EddsaPeer {
             Ok(json_string) => {
                 //                self.add_commitment(peer_id, json_string.clone());
                 let r = serde_json::to_string(&sign_second_message).expect("couldn't create R");
-                self.commitment_msg = Some(generate_commitment_message_payload(&json_string));
-                self.r_msg = Some(generate_R_message_payload(&r));
+                self.commitment_msg = Some(Payload::Commitment(json_string).encode());
+                self.r_msg = Some(Payload::RKey(r).encode());
             }
             Err(_) => panic!("Couldn't serialize commitment"),
         }
@@ -305,7 +314,7 @@ impl EddsaPeer {
                 // sign
                 let s = Signature::partial_sign(&eph_key.r, key, &k, &agg_key.hash, &r_tot);
                 let sig_string = serde_json::to_string(&s).expect("failed to serialize signature");
-                self.sig_msg = Some(generate_signature_message_payload(&sig_string));
+                self.sig_msg = Some(Payload::Signature(sig_string).encode());
             }
             None => {}
         }
@@ -313,47 +322,21 @@ impl EddsaPeer {
 }
 
 impl EddsaPeer {
-    pub fn resolve_payload_type(message: &MessagePayload) -> MessagePayloadType {
-        let msg_payload = message.clone();
-
-        let split_msg: Vec<&str> = msg_payload.split(RELAY_MESSAGE_DELIMITER).collect();
-        let msg_prefix = split_msg[0];
-        let msg_payload = String::from(split_msg[1].clone());
-        match msg_prefix {
-            pk_prefix if pk_prefix == String::from(PK_MESSAGE_PREFIX) => {
-                return MessagePayloadType::PublicKey(msg_payload);
-            }
-            cmtmnt if cmtmnt == String::from(COMMITMENT_MESSAGE_PREFIX) => {
-                return MessagePayloadType::Commitment(msg_payload);
-            }
-            r if r == String::from(R_KEY_MESSAGE_PREFIX) => {
-                return MessagePayloadType::RMessage(msg_payload);
-            }
-            sig if sig == String::from(SIGNATURE_MESSAGE_PREFIX) => {
-                return MessagePayloadType::Signature(msg_payload);
-            }
-            _ => panic!("Unknown relay message prefix"),
-        }
-    }
-}
-
-impl Peer for EddsaPeer {
-    fn new(capacity: u32, _message: Vec<u8>, index: u32) -> EddsaPeer {
-        debug!("Index is {:?}", index);
-        let data = fs::read_to_string(format!("keys{}", index))
-            .expect("Unable to load keys, did you run keygen first? ");
-        let (key, _apk, kg_index): (KeyPair, KeyAgg, u32) = serde_json::from_str(&data).unwrap();
+    /// Creates a peer signing the message with a key share of the key generation
+    pub fn with_keys(capacity: u32, message: Vec<u8>, share: KeyShare) -> EddsaPeer {
+        debug!("KG index is {:?}", share.kg_index);
         EddsaPeer {
-            client_key: { key },
+            client_key: share.key,
             pks: HashMap::new(),
             commitments: HashMap::new(),
             r_s: HashMap::new(),
             sigs: HashMap::new(),
             capacity,
-            message: _message,
+            message,
             peer_id: 0,
-            agg_key: None,
-            kg_index,
+            agg_key: Some(share.agg_key),
+            signature: None,
+            kg_index: share.kg_index,
             current_step: 0,
             R_tot: None,
             ephemeral_key: None,
@@ -369,6 +352,11 @@ impl Peer for EddsaPeer {
             sig_msg: None,
         }
     }
+}
+
+impl Peer for EddsaPeer {
+    const PROTOCOL_ID: ProtocolIdentifier = 2;
+    type Output = Signature;
 
     fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
         self.peer_id = peer_id;
@@ -380,7 +368,7 @@ impl Peer for EddsaPeer {
 
         let pk_s = serde_json::to_string(&pk).expect("Failed in serialization");
 
-        self.pk_msg = Some(generate_pk_message_payload(&pk_s));
+        self.pk_msg = Some(Payload::PublicKey(pk_s).encode());
         return self.pk_msg.clone();
     }
 
@@ -396,7 +384,7 @@ impl Peer for EddsaPeer {
         self.peer_id
     }
 
-    fn do_step(&mut self) {
+    fn do_step(&mut self) -> Result<(), PeerError> {
         info!("Current step is: {:}", self.current_step);
         if self.is_step_done() {
             // do the next step
@@ -415,17 +403,23 @@ impl Peer for EddsaPeer {
         } else {
             info!("step not done");
         }
+        Ok(())
     }
 
-    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+    fn update_data(
+        &mut self,
+        from: PeerIdentifier,
+        payload: MessagePayload,
+    ) -> Result<(), PeerError> {
         // update data according to step
         match self.current_step {
             0 => self.update_data_step_0(from, payload),
             1 => self.update_data_step_1(from, payload),
             2 => self.update_data_step_2(from, payload),
             3 => self.update_data_step_3(from, payload),
-            _ => panic!("Unsupported step"),
+            _ => return Err(PeerError::Protocol("Unsupported step")),
         }
+        Ok(())
     }
     /// Does the final calculation of the protocol
     /// in this case:
@@ -448,15 +442,17 @@ impl Peer for EddsaPeer {
         // verify message with signature
         let apk = self.aggregate_pks();
 
-        let data = fs::read_to_string(format!("keys{}", self.peer_id))
-            .expect("Unable to load keys, did you run keygen first? ");
-        let (_key, orig_apk, _kg_index): (KeyPair, KeyAgg, u32) =
-            serde_json::from_str(&data).unwrap();
+        let orig_apk = self
+            .agg_key
+            .as_ref()
+            .ok_or("No aggregated key of the key generation")?
+            .apk
+            .clone();
 
         let eight: FE = ECScalar::from(&BigInt::from(8));
         let eight_inv = eight.invert();
 
-        let orig_apk = orig_apk.apk * &eight_inv;
+        let orig_apk = orig_apk * &eight_inv;
 
         debug!("Aggregated pk {:?}", apk);
         debug!("Orig pk {:?}", orig_apk);
@@ -466,16 +462,7 @@ impl Peer for EddsaPeer {
         // Verify signature against the original! pubkey
         match verify(&signature, &self.message[..], &orig_apk) {
             Ok(_) => {
-                let mut R_vec = signature.R.pk_to_key_slice().to_vec();
-                let mut s_vec = BigInt::to_vec(&signature.s.to_big_int());
-                s_vec.reverse();
-                R_vec.extend_from_slice(&s_vec[..]);
-
-                fs::write(
-                    format!("signature{}", self.peer_id),
-                    BigInt::from(&R_vec[..]).to_str_radix(16),
-                )
-                .expect("Unable to save !");
+                self.signature = Some(signature);
                 Ok(())
             }
             Err(_) => Err("Failed to verify"),
@@ -484,7 +471,11 @@ impl Peer for EddsaPeer {
     /// check that the protocol is done
     /// and that this peer can finalize its calculations
     fn is_done(&mut self) -> bool {
-        self.is_done_step_3()
+        self.is_done
+    }
+
+    fn output(&mut self) -> Option<Signature> {
+        self.signature.take()
     }
 
     /// get the next item the peer needs to send
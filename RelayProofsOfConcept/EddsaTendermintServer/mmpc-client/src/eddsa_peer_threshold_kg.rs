@@ -0,0 +1,480 @@
+use std::collections::{BTreeMap, HashMap};
+
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::traits::ECPoint;
+use curv::{BigInt, FE, GE};
+use log::{debug, info, warn};
+use multi_party_eddsa::protocols::thresholdsig::{
+    KeyGenBroadcastMessage1, Keys, Parameters, SharedKeys,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::key_store::{KeyLabel, KeyStore, KeyStoreError};
+use crate::peer::{Peer, PeerError};
+use crate::points::{self, deserialized_point, deserialized_vss, Deserialized};
+use mmpc_server_common::payload::Payload;
+use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+
+/// Key share of a t-of-n threshold key, as saved after key generation.
+/// Any t+1 of the n parties can sign with their shares
+#[derive(Serialize, Deserialize)]
+pub struct ThresholdKeys {
+    pub party_keys: Keys,
+    pub shared_keys: SharedKeys,
+    // VSS schemes of all parties of the key generation, ordered by party
+    pub vss_schemes: Vec<VerifiableSS>,
+    pub threshold: u32,
+    pub share_count: u32,
+    // the index of this share, which the peer registers with for signing
+    pub kg_index: u32,
+}
+
+impl ThresholdKeys {
+    /// Loads the key share stored with the given key id
+    pub fn load(
+        store: &KeyStore,
+        key_id: &str,
+        password: &str,
+    ) -> Result<ThresholdKeys, KeyStoreError> {
+        let keys: ThresholdKeys = store.load(key_id, password)?;
+        Ok(keys.deserialized())
+    }
+
+    /// Label of the share in a key store, with the shared public key
+    pub fn label(&self, key_id: &str) -> KeyLabel {
+        KeyLabel {
+            key_id: key_id.to_string(),
+            public_key: hex::encode(self.shared_keys.y.pk_to_key_slice()),
+        }
+    }
+
+    pub fn params(&self) -> Parameters {
+        Parameters {
+            threshold: self.threshold as usize,
+            share_count: self.share_count as usize,
+        }
+    }
+}
+
+impl Deserialized for ThresholdKeys {
+    fn deserialized(mut self) -> ThresholdKeys {
+        self.party_keys = self.party_keys.deserialized();
+        self.shared_keys = self.shared_keys.deserialized();
+        self.vss_schemes = self.vss_schemes.deserialized();
+        self
+    }
+}
+
+/// Deserializes the messages of the given peers, in their order
+pub fn received_messages<T: DeserializeOwned>(
+    messages: &HashMap<PeerIdentifier, String>,
+    peers: &[PeerIdentifier],
+) -> Result<Vec<T>, PeerError> {
+    peers
+        .iter()
+        .map(|peer_id| {
+            let message = messages
+                .get(peer_id)
+                .ok_or(PeerError::MissingMessage(*peer_id))?;
+            serde_json::from_str(message).map_err(|_| PeerError::InvalidMessage(*peer_id))
+        })
+        .collect()
+}
+
+/// Peer of a t-of-n key generation with Feldman VSS.
+/// Each party commits to a secret and shares it with the other parties,
+/// the shared key is the sum of all secrets
+#[derive(Serialize, Deserialize)]
+pub struct EddsaPeer {
+    // this peers identifier in this session
+    pub peer_id: PeerIdentifier,
+    // # of participants
+    pub capacity: u32,
+    // # of parties that can not sign together, any threshold + 1 parties can
+    pub threshold: u32,
+
+    pub current_step: u32,
+    // is peer done with all calculations
+    pub is_done: bool,
+
+    // eddsa data
+    #[serde(deserialize_with = "points::deserialize")]
+    pub party_keys: Option<Keys>,
+    pub commitments: HashMap<PeerIdentifier, String>,
+    pub decommitments: HashMap<PeerIdentifier, String>,
+    pub vss_schemes: HashMap<PeerIdentifier, String>,
+    // shares of the other parties secrets, sent to this peer
+    pub secret_shares: HashMap<PeerIdentifier, String>,
+    #[serde(deserialize_with = "points::deserialize")]
+    pub shared_keys: Option<SharedKeys>,
+    // the key share of this peer, once the shared key is computed
+    #[serde(deserialize_with = "points::deserialize")]
+    pub keys: Option<ThresholdKeys>,
+
+    // indicators for which of this peers messages were accepted
+    pub commitment_accepted: bool,
+    pub decommitment_accepted: bool,
+    pub vss_accepted: bool,
+
+    // messages this peer generates
+    pub commitment_msg: Option<MessagePayload>,
+    pub decommitment_msg: Option<MessagePayload>,
+    pub vss_msg: Option<MessagePayload>,
+    // shares of this peers secret, by the peer they are sent to
+    pub secret_share_msgs: BTreeMap<PeerIdentifier, MessagePayload>,
+}
+
+impl EddsaPeer {
+    /// Creates a peer of a key generation where any threshold + 1 of capacity parties can sign,
+    /// fails if the threshold is not smaller than the capacity
+    pub fn with_threshold(capacity: u32, threshold: u32) -> Result<EddsaPeer, PeerError> {
+        debug!(
+            "Capacity is set to {}, threshold to {}",
+            capacity, threshold
+        );
+        if threshold >= capacity {
+            return Err(PeerError::Protocol(
+                "Threshold must be smaller than capacity",
+            ));
+        }
+        Ok(EddsaPeer {
+            peer_id: 0,
+            capacity,
+            threshold,
+            current_step: 0,
+            is_done: false,
+
+            party_keys: None,
+            commitments: HashMap::new(),
+            decommitments: HashMap::new(),
+            vss_schemes: HashMap::new(),
+            secret_shares: HashMap::new(),
+            shared_keys: None,
+            keys: None,
+
+            commitment_accepted: false,
+            decommitment_accepted: false,
+            vss_accepted: false,
+
+            commitment_msg: None,
+            decommitment_msg: None,
+            vss_msg: None,
+            secret_share_msgs: BTreeMap::new(),
+        })
+    }
+
+    fn params(&self) -> Parameters {
+        Parameters {
+            threshold: self.threshold as usize,
+            share_count: self.capacity as usize,
+        }
+    }
+
+    // Peers are numbered from 1, parties of the protocol from 0
+    fn party_index(&self) -> usize {
+        (self.peer_id - 1) as usize
+    }
+
+    // all peers of the key generation, ordered by party
+    fn peers(&self) -> Vec<PeerIdentifier> {
+        (1..=self.capacity).collect()
+    }
+
+    // the public keys of all parties and the blinding factors of their commitments
+    fn decommitted_keys(&self) -> Result<(Vec<GE>, Vec<BigInt>), PeerError> {
+        let decommitments: Vec<(GE, BigInt)> =
+            received_messages(&self.decommitments, &self.peers())?;
+        Ok(decommitments
+            .into_iter()
+            .map(|(y_i, blind)| (deserialized_point(y_i), blind))
+            .unzip())
+    }
+
+    fn party_keys(&self) -> Result<&Keys, PeerError> {
+        self.party_keys
+            .as_ref()
+            .ok_or(PeerError::Protocol("Keys were not created"))
+    }
+}
+
+impl EddsaPeer {
+    /// data updaters for each step
+    pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        match Payload::decode(&payload) {
+            Ok(Payload::Commitment(commitment)) => {
+                info!("-------Got peer # {:} commitment!", from);
+                if from == self.peer_id {
+                    self.commitment_accepted = true;
+                }
+                self.commitments.insert(from, commitment);
+            }
+            Ok(_) => warn!("Expected a commitment from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
+        }
+    }
+
+    pub fn update_data_step_1(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        match Payload::decode(&payload) {
+            Ok(Payload::Decommitment(decommitment)) => {
+                info!("-------Got peer # {:} decommitment!", from);
+                if from == self.peer_id {
+                    self.decommitment_accepted = true;
+                }
+                self.decommitments.insert(from, decommitment);
+            }
+            Ok(_) => warn!("Expected a decommitment from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
+        }
+    }
+
+    pub fn update_data_step_2(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        match Payload::decode(&payload) {
+            Ok(Payload::Vss(vss_scheme)) => {
+                info!("-------Got peer # {:} VSS scheme!", from);
+                if from == self.peer_id {
+                    self.vss_accepted = true;
+                }
+                self.vss_schemes.insert(from, vss_scheme);
+            }
+            Ok(_) => warn!("Expected a VSS scheme from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
+        }
+    }
+}
+
+impl EddsaPeer {
+    fn is_step_done(&mut self) -> Result<bool, PeerError> {
+        match self.current_step {
+            0 => Ok(self.commitments.len() == self.capacity as usize),
+            1 => Ok(self.decommitments.len() == self.capacity as usize),
+            2 => Ok(self.vss_schemes.len() == self.capacity as usize
+                && self.secret_shares.len() == self.capacity as usize),
+            _ => Err(PeerError::Protocol("Unsupported step")),
+        }
+    }
+
+    // true if the next item is the VSS scheme, the secret shares are sent along with it
+    fn sends_vss(&self) -> bool {
+        self.current_step >= 2
+            && self.commitment_accepted
+            && self.decommitment_accepted
+            && (self.current_step == 2 || !self.vss_accepted)
+    }
+}
+
+impl EddsaPeer {
+    /// steps - in each step the client does a calculation on its
+    /// data, and updates the data holder with the new data
+
+    /// step 1 - reveal the public key committed to. No extra calculations
+    pub fn step_1(&mut self) {
+        debug!("Step 1 - no calculations required. Decommitment is ready");
+    }
+
+    /// step 2 - after validating all commitments,
+    /// share the secret of this peer with the other peers
+    pub fn step_2(&mut self) -> Result<(), PeerError> {
+        let (y_vec, blind_vec) = self.decommitted_keys()?;
+        let bc1_vec: Vec<KeyGenBroadcastMessage1> =
+            received_messages(&self.commitments, &self.peers())?;
+        let parties: Vec<usize> = (0..self.capacity as usize).collect();
+        let (vss_scheme, secret_shares, _index) = self
+            .party_keys()?
+            .phase1_verify_com_phase2_distribute(
+                &self.params(),
+                &blind_vec,
+                &y_vec,
+                &bc1_vec,
+                &parties,
+            )
+            .map_err(|_| PeerError::Protocol("Commitments not valid"))?;
+
+        let vss_s = serde_json::to_string(&vss_scheme).expect("Failed in serialization");
+        self.vss_msg = Some(Payload::Vss(vss_s).encode());
+        // the i-th share is for the i-th party
+        self.secret_share_msgs = secret_shares
+            .iter()
+            .enumerate()
+            .map(|(party, share)| {
+                let share_s = serde_json::to_string(share).expect("Failed in serialization");
+                (
+                    (party + 1) as PeerIdentifier,
+                    Payload::SecretShare(share_s).encode(),
+                )
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// step 3 - after validating all shares against the VSS schemes,
+    /// construct the share of the shared key
+    pub fn step_3(&mut self) -> Result<(), PeerError> {
+        let (y_vec, _) = self.decommitted_keys()?;
+        let vss_schemes = self.received_vss_schemes()?;
+        let secret_shares: Vec<FE> = received_messages(&self.secret_shares, &self.peers())?;
+        let shared_keys = self
+            .party_keys()?
+            .phase2_verify_vss_construct_keypair(
+                &self.params(),
+                &y_vec,
+                &secret_shares,
+                &vss_schemes,
+                &self.party_index(),
+            )
+            .map_err(|_| PeerError::Protocol("Secret shares not valid"))?;
+        info!("Shared public key: {:?}", shared_keys.y);
+        self.shared_keys = Some(shared_keys);
+        Ok(())
+    }
+
+    fn received_vss_schemes(&self) -> Result<Vec<VerifiableSS>, PeerError> {
+        let vss_schemes: Vec<VerifiableSS> = received_messages(&self.vss_schemes, &self.peers())?;
+        Ok(vss_schemes.into_iter().map(deserialized_vss).collect())
+    }
+}
+
+impl Peer for EddsaPeer {
+    const PROTOCOL_ID: ProtocolIdentifier = 3;
+    type Output = ThresholdKeys;
+
+    /// Sets the identifier of the peer in the session, which is its party in the key generation
+    fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
+        self.peer_id = peer_id;
+    }
+
+    fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+        self.peer_id = peer_id;
+        let party_keys = Keys::phase1_create(self.party_index());
+        let (bc1, blind) = party_keys.phase1_broadcast();
+
+        let bc1_s = serde_json::to_string(&bc1).expect("Failed in serialization");
+        let decommitment_s =
+            serde_json::to_string(&(&party_keys.y_i, &blind)).expect("Failed in serialization");
+        self.commitment_msg = Some(Payload::Commitment(bc1_s).encode());
+        self.decommitment_msg = Some(Payload::Decommitment(decommitment_s).encode());
+        self.party_keys = Some(party_keys);
+        self.commitment_msg.clone()
+    }
+
+    fn current_step(&self) -> u32 {
+        self.current_step
+    }
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn peer_id(&self) -> PeerIdentifier {
+        self.peer_id
+    }
+
+    fn do_step(&mut self) -> Result<(), PeerError> {
+        debug!("Current step is: {:}", self.current_step);
+        if self.is_step_done()? {
+            // do the next step
+            debug!("step {:} done!", self.current_step);
+            self.current_step += 1;
+            match self.current_step {
+                1 => self.step_1(),
+                2 => self.step_2()?,
+                3 => {
+                    self.step_3()?;
+                    self.finalize().map_err(PeerError::Protocol)?;
+                    info!("----------\nDone.\n----------");
+                    self.is_done = true;
+                }
+                _ => return Err(PeerError::Protocol("Unsupported step")),
+            }
+        } else {
+            debug!("step not done");
+        }
+        Ok(())
+    }
+
+    fn update_data(
+        &mut self,
+        from: PeerIdentifier,
+        payload: MessagePayload,
+    ) -> Result<(), PeerError> {
+        // update data according to step
+        debug!("Current step {}", self.current_step);
+        match self.current_step {
+            0 => self.update_data_step_0(from, payload),
+            1 => self.update_data_step_1(from, payload),
+            2 => self.update_data_step_2(from, payload),
+            _ => return Err(PeerError::Protocol("Unsupported step")),
+        }
+        Ok(())
+    }
+
+    fn update_p2p_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        match Payload::decode(&payload) {
+            Ok(Payload::SecretShare(share)) => {
+                debug!("-------Got peer # {:} secret share", from);
+                self.secret_shares.insert(from, share);
+            }
+            Ok(_) => warn!("Expected a secret share from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
+        }
+    }
+
+    /// Does the final calculation of the protocol
+    /// in this case:
+    ///     collecting the share of the shared key,
+    ///     along with the VSS schemes that signatures are verified with
+    fn finalize(&mut self) -> Result<(), &'static str> {
+        let keys = ThresholdKeys {
+            party_keys: self.party_keys.clone().ok_or("Keys were not created")?,
+            shared_keys: self
+                .shared_keys
+                .clone()
+                .ok_or("Shared key was not created")?,
+            vss_schemes: self
+                .received_vss_schemes()
+                .map_err(|_| "VSS schemes were not received")?,
+            threshold: self.threshold,
+            share_count: self.capacity,
+            kg_index: self.peer_id,
+        };
+        self.keys = Some(keys);
+        Ok(())
+    }
+
+    /// check that the protocol is done
+    /// and that this peer can finalize its calculations
+    fn is_done(&mut self) -> bool {
+        self.is_done
+    }
+
+    fn output(&mut self) -> Option<ThresholdKeys> {
+        self.keys.take()
+    }
+
+    /// get the next item the peer needs to send
+    /// depending on the current step and the last message
+    /// of the peer that was accepted by the server
+    fn get_next_item(&mut self) -> Option<MessagePayload> {
+        if self.current_step == 0 || !self.commitment_accepted {
+            debug!("next item is commitment: {:?}", self.commitment_msg);
+            return self.commitment_msg.clone();
+        }
+        if self.current_step == 1 || !self.decommitment_accepted {
+            debug!("next item is decommitment: {:?}", self.decommitment_msg);
+            return self.decommitment_msg.clone();
+        }
+        if self.current_step == 2 || !self.vss_accepted {
+            debug!("next item is VSS scheme: {:?}", self.vss_msg);
+            return self.vss_msg.clone();
+        }
+        None
+    }
+
+    fn get_next_p2p_items(&mut self) -> BTreeMap<PeerIdentifier, MessagePayload> {
+        if self.sends_vss() {
+            self.secret_share_msgs.clone()
+        } else {
+            BTreeMap::new()
+        }
+    }
+}
@@ -0,0 +1,582 @@
+use std::collections::{BTreeMap, HashMap};
+
+use curv::arithmetic::traits::Converter;
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::traits::ECPoint;
+use curv::elliptic::curves::traits::ECScalar;
+use curv::{BigInt, FE, GE};
+use log::{debug, info, warn};
+use multi_party_eddsa::protocols::thresholdsig::{
+    EphemeralKey, EphemeralSharedKeys, KeyGenBroadcastMessage1, LocalSig, Signature,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::eddsa_peer_threshold_kg::{received_messages, ThresholdKeys};
+use crate::peer::{Peer, PeerError};
+use crate::points::{self, deserialized_point, deserialized_vss};
+use mmpc_server_common::payload::Payload;
+use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+
+/// Encodes a signature in hex, R followed by s in little endian
+#[allow(non_snake_case)]
+pub fn encode_signature(signature: &Signature) -> String {
+    let mut R_vec = signature.R.pk_to_key_slice().to_vec();
+    let mut s_vec = BigInt::to_vec(&signature.sigma.to_big_int());
+    s_vec.reverse();
+    R_vec.extend_from_slice(&s_vec[..]);
+    BigInt::from(&R_vec[..]).to_str_radix(16)
+}
+
+// A fresh random ephemeral key for each session, so no two signatures share a nonce
+#[allow(non_snake_case)]
+fn random_ephemeral_key(party_index: usize) -> EphemeralKey {
+    let r_i: FE = ECScalar::new_random();
+    let g: GE = ECPoint::generator();
+    let R_i = g * &r_i;
+    EphemeralKey {
+        r_i,
+        R_i,
+        party_index,
+    }
+}
+
+/// Peer of a threshold signing, any threshold + 1 parties
+/// of the key generation can sign together.
+/// The parties share an ephemeral key like the key generation shares the key,
+/// and sign the message with their shares of both keys
+#[derive(Serialize, Deserialize)]
+pub struct EddsaPeer {
+    // this peers identifier in this session, the index of its key share
+    pub peer_id: PeerIdentifier,
+    // # of signing participants
+    pub capacity: u32,
+
+    pub current_step: u32,
+    // is peer done with all calculations
+    pub is_done: bool,
+
+    // eddsa data
+    #[serde(deserialize_with = "points::deserialize")]
+    pub keys: ThresholdKeys,
+    #[serde(deserialize_with = "points::deserialize")]
+    pub ephemeral_key: Option<EphemeralKey>,
+    pub commitments: HashMap<PeerIdentifier, String>,
+    pub decommitments: HashMap<PeerIdentifier, String>,
+    pub vss_schemes: HashMap<PeerIdentifier, String>,
+    // shares of the other peers ephemeral keys, sent to this peer
+    pub secret_shares: HashMap<PeerIdentifier, String>,
+    pub local_sigs: HashMap<PeerIdentifier, String>,
+    #[serde(deserialize_with = "points::deserialize")]
+    pub ephemeral_shared_keys: Option<EphemeralSharedKeys>,
+    // message to sign
+    pub message: Vec<u8>,
+    // the signature, once the local signatures are combined and verified
+    #[serde(deserialize_with = "points::deserialize")]
+    pub signature: Option<Signature>,
+
+    // indicators for which of this peers messages were accepted
+    pub commitment_accepted: bool,
+    pub decommitment_accepted: bool,
+    pub vss_accepted: bool,
+    pub local_sig_accepted: bool,
+
+    // messages this peer generates
+    pub commitment_msg: Option<MessagePayload>,
+    pub decommitment_msg: Option<MessagePayload>,
+    pub vss_msg: Option<MessagePayload>,
+    pub local_sig_msg: Option<MessagePayload>,
+    // shares of this peers ephemeral key, by the peer they are sent to
+    pub secret_share_msgs: BTreeMap<PeerIdentifier, MessagePayload>,
+}
+
+impl EddsaPeer {
+    // Peers are identified by their key share, numbered from 1, parties of the protocol from 0
+    fn party_index(&self) -> usize {
+        (self.peer_id - 1) as usize
+    }
+
+    // the signing peers, in the order of their key shares
+    fn signers(&self) -> Vec<PeerIdentifier> {
+        let mut signers: Vec<PeerIdentifier> = self.commitments.keys().cloned().collect();
+        signers.sort();
+        signers
+    }
+
+    fn parties(&self) -> Vec<usize> {
+        self.signers()
+            .iter()
+            .map(|peer_id| (peer_id - 1) as usize)
+            .collect()
+    }
+
+    // the ephemeral public keys of all signers and the blinding factors of their commitments
+    #[allow(non_snake_case)]
+    fn decommitted_keys(&self) -> Result<(Vec<GE>, Vec<BigInt>), PeerError> {
+        let decommitments: Vec<(GE, BigInt)> =
+            received_messages(&self.decommitments, &self.signers())?;
+        Ok(decommitments
+            .into_iter()
+            .map(|(R_i, blind)| (deserialized_point(R_i), blind))
+            .unzip())
+    }
+
+    fn received_vss_schemes(&self) -> Result<Vec<VerifiableSS>, PeerError> {
+        let vss_schemes: Vec<VerifiableSS> = received_messages(&self.vss_schemes, &self.signers())?;
+        Ok(vss_schemes.into_iter().map(deserialized_vss).collect())
+    }
+
+    fn ephemeral_key(&self) -> Result<&EphemeralKey, PeerError> {
+        self.ephemeral_key
+            .as_ref()
+            .ok_or(PeerError::Protocol("Ephemeral key was not created"))
+    }
+}
+
+impl EddsaPeer {
+    /// data updaters for each step
+    pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        match Payload::decode(&payload) {
+            Ok(Payload::Commitment(commitment)) => {
+                info!("-------Got peer # {:} commitment!", from);
+                if from == self.peer_id {
+                    self.commitment_accepted = true;
+                }
+                self.commitments.insert(from, commitment);
+            }
+            Ok(_) => warn!("Expected a commitment from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
+        }
+    }
+
+    pub fn update_data_step_1(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        match Payload::decode(&payload) {
+            Ok(Payload::Decommitment(decommitment)) => {
+                info!("-------Got peer # {:} decommitment!", from);
+                if from == self.peer_id {
+                    self.decommitment_accepted = true;
+                }
+                self.decommitments.insert(from, decommitment);
+            }
+            Ok(_) => warn!("Expected a decommitment from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
+        }
+    }
+
+    pub fn update_data_step_2(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        match Payload::decode(&payload) {
+            Ok(Payload::Vss(vss_scheme)) => {
+                info!("-------Got peer # {:} VSS scheme!", from);
+                if from == self.peer_id {
+                    self.vss_accepted = true;
+                }
+                self.vss_schemes.insert(from, vss_scheme);
+            }
+            Ok(_) => warn!("Expected a VSS scheme from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
+        }
+    }
+
+    pub fn update_data_step_3(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        match Payload::decode(&payload) {
+            Ok(Payload::LocalSig(local_sig)) => {
+                debug!("-------Got peer # {:} local signature", from);
+                if from == self.peer_id {
+                    self.local_sig_accepted = true;
+                }
+                self.local_sigs.insert(from, local_sig);
+            }
+            Ok(_) => warn!("Expected a local signature from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
+        }
+    }
+}
+
+impl EddsaPeer {
+    fn is_step_done(&mut self) -> Result<bool, PeerError> {
+        match self.current_step {
+            0 => Ok(self.commitments.len() == self.capacity as usize),
+            1 => Ok(self.decommitments.len() == self.capacity as usize),
+            2 => Ok(self.vss_schemes.len() == self.capacity as usize
+                && self.secret_shares.len() == self.capacity as usize),
+            3 => Ok(self.local_sigs.len() == self.capacity as usize),
+            _ => Err(PeerError::Protocol("Unsupported step")),
+        }
+    }
+
+    // true if the next item is the VSS scheme, the secret shares are sent along with it
+    fn sends_vss(&self) -> bool {
+        self.current_step >= 2
+            && self.commitment_accepted
+            && self.decommitment_accepted
+            && (self.current_step == 2 || !self.vss_accepted)
+    }
+}
+
+impl EddsaPeer {
+    /// steps - in each step the client does a calculation on its
+    /// data, and updates the data holder with the new data
+
+    /// step 1 - reveal the ephemeral key committed to. No extra calculations
+    pub fn step_1(&mut self) {
+        debug!("Step 1 - no calculations required. Decommitment is ready");
+    }
+
+    /// step 2 - after validating all commitments,
+    /// share the ephemeral key of this peer with the other signers
+    #[allow(non_snake_case)]
+    pub fn step_2(&mut self) -> Result<(), PeerError> {
+        let (R_vec, blind_vec) = self.decommitted_keys()?;
+        let bc1_vec: Vec<KeyGenBroadcastMessage1> =
+            received_messages(&self.commitments, &self.signers())?;
+        let (vss_scheme, secret_shares, _index) = self
+            .ephemeral_key()?
+            .phase1_verify_com_phase2_distribute(
+                &self.keys.params(),
+                &blind_vec,
+                &R_vec,
+                &bc1_vec,
+                &self.parties(),
+            )
+            .map_err(|_| PeerError::Protocol("Commitments not valid"))?;
+
+        let vss_s = serde_json::to_string(&vss_scheme).expect("Failed in serialization");
+        self.vss_msg = Some(Payload::Vss(vss_s).encode());
+        // the i-th share is for the i-th signer
+        self.secret_share_msgs = self
+            .signers()
+            .into_iter()
+            .zip(secret_shares.iter())
+            .map(|(peer_id, share)| {
+                let share_s = serde_json::to_string(share).expect("Failed in serialization");
+                (peer_id, Payload::SecretShare(share_s).encode())
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// step 3 - after validating all shares against the VSS schemes,
+    /// construct the share of the ephemeral key and sign with both shares
+    #[allow(non_snake_case)]
+    pub fn step_3(&mut self) -> Result<(), PeerError> {
+        let (R_vec, _) = self.decommitted_keys()?;
+        let secret_shares: Vec<FE> = received_messages(&self.secret_shares, &self.signers())?;
+        let ephemeral_shared_keys = self
+            .ephemeral_key()?
+            .phase2_verify_vss_construct_keypair(
+                &self.keys.params(),
+                &R_vec,
+                &secret_shares,
+                &self.received_vss_schemes()?,
+                &self.party_index(),
+            )
+            .map_err(|_| PeerError::Protocol("Secret shares not valid"))?;
+
+        let local_sig = LocalSig::compute(
+            &self.message[..],
+            &ephemeral_shared_keys,
+            &self.keys.shared_keys,
+        );
+        let local_sig_s = serde_json::to_string(&local_sig).expect("Failed in serialization");
+        self.local_sig_msg = Some(Payload::LocalSig(local_sig_s).encode());
+        self.ephemeral_shared_keys = Some(ephemeral_shared_keys);
+        Ok(())
+    }
+}
+
+impl EddsaPeer {
+    /// Creates a peer signing the message with a key share of the threshold key generation,
+    /// fails unless the capacity is between the threshold + 1 and the number of shares
+    pub fn with_keys(
+        capacity: u32,
+        message: Vec<u8>,
+        keys: ThresholdKeys,
+    ) -> Result<EddsaPeer, PeerError> {
+        debug!("KG index is {:?}", keys.kg_index);
+        if capacity <= keys.threshold || capacity > keys.share_count {
+            return Err(PeerError::Protocol(
+                "Signing requires between threshold + 1 and all parties",
+            ));
+        }
+        Ok(EddsaPeer {
+            peer_id: 0,
+            capacity,
+            current_step: 0,
+            is_done: false,
+
+            keys,
+            ephemeral_key: None,
+            commitments: HashMap::new(),
+            decommitments: HashMap::new(),
+            vss_schemes: HashMap::new(),
+            secret_shares: HashMap::new(),
+            local_sigs: HashMap::new(),
+            ephemeral_shared_keys: None,
+            message,
+            signature: None,
+
+            commitment_accepted: false,
+            decommitment_accepted: false,
+            vss_accepted: false,
+            local_sig_accepted: false,
+
+            commitment_msg: None,
+            decommitment_msg: None,
+            vss_msg: None,
+            local_sig_msg: None,
+            secret_share_msgs: BTreeMap::new(),
+        })
+    }
+}
+
+impl Peer for EddsaPeer {
+    const PROTOCOL_ID: ProtocolIdentifier = 4;
+    type Output = Signature;
+
+    fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
+        self.peer_id = peer_id;
+    }
+
+    fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+        self.peer_id = peer_id;
+        let ephemeral_key = random_ephemeral_key(self.party_index());
+        let (bc1, blind) = ephemeral_key.phase1_broadcast();
+
+        let bc1_s = serde_json::to_string(&bc1).expect("Failed in serialization");
+        let decommitment_s =
+            serde_json::to_string(&(&ephemeral_key.R_i, &blind)).expect("Failed in serialization");
+        self.commitment_msg = Some(Payload::Commitment(bc1_s).encode());
+        self.decommitment_msg = Some(Payload::Decommitment(decommitment_s).encode());
+        self.ephemeral_key = Some(ephemeral_key);
+        self.commitment_msg.clone()
+    }
+
+    fn current_step(&self) -> u32 {
+        self.current_step
+    }
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn peer_id(&self) -> PeerIdentifier {
+        self.peer_id
+    }
+
+    fn share_count(&self) -> Option<u32> {
+        Some(self.keys.share_count)
+    }
+
+    fn do_step(&mut self) -> Result<(), PeerError> {
+        info!("Current step is: {:}", self.current_step);
+        if self.is_step_done()? {
+            // do the next step
+            info!("step {:} done!", self.current_step);
+            self.current_step += 1;
+            match self.current_step {
+                1 => self.step_1(),
+                2 => self.step_2()?,
+                3 => self.step_3()?,
+                4 => {
+                    self.finalize().map_err(PeerError::Protocol)?;
+                    info!("----------\nDone.\n----------");
+                    self.is_done = true;
+                }
+                _ => return Err(PeerError::Protocol("Unsupported step")),
+            }
+        } else {
+            info!("step not done");
+        }
+        Ok(())
+    }
+
+    fn update_data(
+        &mut self,
+        from: PeerIdentifier,
+        payload: MessagePayload,
+    ) -> Result<(), PeerError> {
+        // update data according to step
+        match self.current_step {
+            0 => self.update_data_step_0(from, payload),
+            1 => self.update_data_step_1(from, payload),
+            2 => self.update_data_step_2(from, payload),
+            3 => self.update_data_step_3(from, payload),
+            _ => return Err(PeerError::Protocol("Unsupported step")),
+        }
+        Ok(())
+    }
+
+    fn update_p2p_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        match Payload::decode(&payload) {
+            Ok(Payload::SecretShare(share)) => {
+                debug!("-------Got peer # {:} secret share", from);
+                self.secret_shares.insert(from, share);
+            }
+            Ok(_) => warn!("Expected a secret share from peer {}", from),
+            Err(err) => warn!("Rejected payload of peer {}: {}", from, err),
+        }
+    }
+
+    /// Does the final calculation of the protocol
+    /// in this case:
+    ///     verifying the local signatures of all signers,
+    ///     combining them and verifying the signature with the shared key
+    fn finalize(&mut self) -> Result<(), &'static str> {
+        let local_sigs = self
+            .signers()
+            .iter()
+            .map(|peer_id| {
+                let local_sig = self.local_sigs.get(peer_id)?;
+                serde_json::from_str(local_sig).ok()
+            })
+            .collect::<Option<Vec<LocalSig>>>()
+            .ok_or("Failed to deserialize local signatures")?;
+        let ephemeral_shared_keys = self
+            .ephemeral_shared_keys
+            .as_ref()
+            .ok_or("Ephemeral key was not shared")?;
+        let parties = self.parties();
+        let vss_sum_local_sigs = LocalSig::verify_local_sigs(
+            &local_sigs,
+            &parties,
+            &self.keys.vss_schemes,
+            &self
+                .received_vss_schemes()
+                .map_err(|_| "VSS schemes were not received")?,
+        )
+        .map_err(|_| "Local signatures not valid")?;
+        let signature = Signature::generate(
+            &vss_sum_local_sigs,
+            &local_sigs,
+            &parties,
+            ephemeral_shared_keys.R,
+        );
+        // Verify signature against the shared key of the key generation
+        match signature.verify(&self.message[..], &self.keys.shared_keys.y) {
+            Ok(_) => {
+                self.signature = Some(signature);
+                Ok(())
+            }
+            Err(_) => Err("Failed to verify"),
+        }
+    }
+
+    /// check that the protocol is done
+    /// and that this peer can finalize its calculations
+    fn is_done(&mut self) -> bool {
+        self.is_done
+    }
+
+    fn output(&mut self) -> Option<Signature> {
+        self.signature.take()
+    }
+
+    /// get the next item the peer needs to send
+    /// depending on the current step and the last message
+    /// of the peer that was accepted by the server
+    fn get_next_item(&mut self) -> Option<MessagePayload> {
+        if self.current_step == 0 || !self.commitment_accepted {
+            info!("next item is commitment: {:?}", self.commitment_msg);
+            return self.commitment_msg.clone();
+        }
+        if self.current_step == 1 || !self.decommitment_accepted {
+            info!("next item is decommitment: {:?}", self.decommitment_msg);
+            return self.decommitment_msg.clone();
+        }
+        if self.current_step == 2 || !self.vss_accepted {
+            info!("next item is VSS scheme: {:?}", self.vss_msg);
+            return self.vss_msg.clone();
+        }
+        if self.current_step == 3 || !self.local_sig_accepted {
+            info!("next item is local signature: {:?}", self.local_sig_msg);
+            return self.local_sig_msg.clone();
+        }
+        None
+    }
+
+    fn get_next_p2p_items(&mut self) -> BTreeMap<PeerIdentifier, MessagePayload> {
+        if self.sends_vss() {
+            self.secret_share_msgs.clone()
+        } else {
+            BTreeMap::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_signature, EddsaPeer};
+    use crate::eddsa_peer_threshold_kg::{self, ThresholdKeys};
+    use crate::peer::{run_in_process, Peer, PeerError};
+
+    #[test]
+    fn test_threshold_keygen_and_sign() {
+        // Any 2 of the 3 parties can sign
+        let (threshold, share_count) = (1, 3);
+        let kg_peers = (1..=share_count)
+            .map(|peer_id| {
+                let peer =
+                    eddsa_peer_threshold_kg::EddsaPeer::with_threshold(share_count, threshold)
+                        .unwrap();
+                (peer_id, peer)
+            })
+            .collect();
+        let mut keys: Vec<ThresholdKeys> = run_in_process(kg_peers, |_, _| {});
+        let shared_key = keys[0].shared_keys.y.clone();
+        for share in &keys {
+            assert_eq!(share.shared_keys.y, shared_key);
+        }
+
+        // Parties 1 and 3 sign without party 2
+        keys.remove(1);
+        let message = b"message".to_vec();
+        let sign_peers = keys
+            .into_iter()
+            .map(|share| {
+                let kg_index = share.kg_index;
+                (
+                    kg_index,
+                    EddsaPeer::with_keys(2, message.clone(), share).unwrap(),
+                )
+            })
+            .collect();
+        let signatures = run_in_process(sign_peers, |_, _| {});
+        for signature in &signatures {
+            assert!(signature.verify(&message, &shared_key).is_ok());
+            assert!(signature.verify(b"other message", &shared_key).is_err());
+        }
+        assert_eq!(
+            encode_signature(&signatures[0]),
+            encode_signature(&signatures[1])
+        );
+    }
+
+    #[test]
+    fn test_invalid_parameters_and_steps() {
+        // The threshold must be smaller than the number of parties
+        assert!(eddsa_peer_threshold_kg::EddsaPeer::with_threshold(2, 2).is_err());
+        let kg_peers = (1..=3)
+            .map(|peer_id| {
+                let peer = eddsa_peer_threshold_kg::EddsaPeer::with_threshold(3, 1).unwrap();
+                (peer_id, peer)
+            })
+            .collect();
+        let mut keys: Vec<ThresholdKeys> = run_in_process(kg_peers, |_, _| {});
+
+        // Signing takes more than threshold parties, and no more than all of them
+        for capacity in &[1, 4] {
+            let share = keys.pop().unwrap();
+            match EddsaPeer::with_keys(*capacity, b"message".to_vec(), share) {
+                Err(PeerError::Protocol(_)) => {}
+                _ => panic!("Expected signing with {} parties to be refused", capacity),
+            }
+        }
+
+        // A message past the last step is refused rather than panicking
+        let share = keys.pop().unwrap();
+        let mut peer = EddsaPeer::with_keys(2, b"message".to_vec(), share).unwrap();
+        peer.current_step = 4;
+        assert!(peer.update_data(1, String::from("late")).is_err());
+        peer.current_step = 5;
+        assert!(peer.do_step().is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! RPC endpoints of the validator nodes a client sends its requests to.
+//! Requests go to one healthy node at a time. When a request to it fails the client checks
+//! the health of the other nodes in turn, and retries the request on the first healthy one
+use std::cell::RefCell;
+use std::fmt::Display;
+
+use log::{info, warn};
+use tendermint::net::Address;
+use tendermint::rpc::{Client, Error};
+
+/// Client of the RPC endpoint of a node
+pub trait RpcClient: Sized {
+    type Error: Display;
+
+    /// Connects to the node at the address, failing if the node is not healthy
+    fn connect(address: &Address) -> Result<Self, Self::Error>;
+}
+
+impl RpcClient for Client {
+    type Error = Error;
+
+    // Creating a client checks the health of the node
+    fn connect(address: &Address) -> Result<Client, Error> {
+        Client::new(address)
+    }
+}
+
+pub struct Endpoints<C = Client> {
+    addresses: Vec<Address>,
+    // Index and client of the node requests go to, None if no node was healthy
+    current: RefCell<Option<(usize, C)>>,
+}
+
+impl<C: RpcClient> Endpoints<C> {
+    /// Connects to the first healthy node of the given endpoints
+    pub fn new(addresses: &[Address]) -> Endpoints<C> {
+        assert!(!addresses.is_empty(), "No RPC endpoints given");
+        let endpoints = Endpoints {
+            addresses: addresses.to_vec(),
+            current: RefCell::new(None),
+        };
+        endpoints.connect(0);
+        endpoints
+    }
+
+    /// Address of the node requests go to
+    pub fn address(&self) -> Address {
+        let index = match self.current.borrow().as_ref() {
+            Some((index, _)) => *index,
+            None => 0,
+        };
+        self.addresses[index].clone()
+    }
+
+    /// Sends the request to the current node, if it fails the request is retried
+    /// on the next healthy node, at most once on each node.
+    /// Returns None if no node answered
+    pub fn request<F, R, E>(&self, request: F) -> Option<R>
+    where
+        F: Fn(&C) -> Result<R, E>,
+        E: Display,
+    {
+        for _ in 0..self.addresses.len() {
+            let failed = match self.current.borrow().as_ref() {
+                Some((index, client)) => match request(client) {
+                    Ok(response) => return Some(response),
+                    Err(err) => {
+                        warn!("Request to {} failed: {}", self.addresses[*index], err);
+                        *index
+                    }
+                },
+                // Start over from the first node
+                None => self.addresses.len() - 1,
+            };
+            if !self.connect(failed + 1) {
+                break;
+            }
+        }
+        warn!("No RPC endpoint answered");
+        None
+    }
+
+    // Connects to the first healthy node from the given index on, wrapping around
+    fn connect(&self, from: usize) -> bool {
+        let count = self.addresses.len();
+        for index in (from..from + count).map(|index| index % count) {
+            match C::connect(&self.addresses[index]) {
+                Ok(client) => {
+                    info!("Sending requests to {}", self.addresses[index]);
+                    *self.current.borrow_mut() = Some((index, client));
+                    return true;
+                }
+                Err(err) => warn!("Node {} is not healthy: {}", self.addresses[index], err),
+            }
+        }
+        *self.current.borrow_mut() = None;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Endpoints, RpcClient};
+    use std::cell::RefCell;
+    use tendermint::net::Address;
+
+    thread_local! {
+        // Ports of the nodes which are up, the others refuse connections and requests
+        static UP: RefCell<Vec<u16>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn set_up(ports: &[u16]) {
+        UP.with(|up| *up.borrow_mut() = ports.to_vec());
+    }
+
+    fn is_up(port: u16) -> bool {
+        UP.with(|up| up.borrow().contains(&port))
+    }
+
+    struct NodeClient {
+        port: u16,
+    }
+
+    impl RpcClient for NodeClient {
+        type Error = String;
+
+        fn connect(address: &Address) -> Result<NodeClient, String> {
+            let port = match address {
+                Address::Tcp { port, .. } => *port,
+                _ => return Err(String::from("not a TCP address")),
+            };
+            if is_up(port) {
+                Ok(NodeClient { port })
+            } else {
+                Err(format!("node {} is down", port))
+            }
+        }
+    }
+
+    // A request answered with the port of the node, if it is up
+    fn request(client: &NodeClient) -> Result<u16, String> {
+        if is_up(client.port) {
+            Ok(client.port)
+        } else {
+            Err(format!("node {} is down", client.port))
+        }
+    }
+
+    fn endpoints(ports: &[u16]) -> Endpoints<NodeClient> {
+        let addresses: Vec<Address> = ports
+            .iter()
+            .map(|port| format!("tcp://127.0.0.1:{}", port).parse().unwrap())
+            .collect();
+        Endpoints::new(&addresses)
+    }
+
+    #[test]
+    fn test_connect_to_first_healthy_node() {
+        set_up(&[2, 3]);
+        let endpoints = endpoints(&[1, 2, 3]);
+        assert_eq!(endpoints.address(), "tcp://127.0.0.1:2".parse().unwrap());
+        assert_eq!(endpoints.request(request), Some(2));
+    }
+
+    #[test]
+    fn test_fail_over_to_next_node() {
+        set_up(&[1, 2, 3]);
+        let endpoints = endpoints(&[1, 2, 3]);
+        assert_eq!(endpoints.request(request), Some(1));
+
+        set_up(&[2, 3]);
+        assert_eq!(endpoints.request(request), Some(2));
+        // Requests stay on the node which answers
+        set_up(&[1, 2, 3]);
+        assert_eq!(endpoints.request(request), Some(2));
+        // The nodes are tried in turn, wrapping around
+        set_up(&[1]);
+        assert_eq!(endpoints.request(request), Some(1));
+        assert_eq!(endpoints.address(), "tcp://127.0.0.1:1".parse().unwrap());
+    }
+
+    #[test]
+    fn test_no_node_answers() {
+        set_up(&[1, 2]);
+        let endpoints = endpoints(&[1, 2]);
+        set_up(&[]);
+        assert_eq!(endpoints.request(request), None);
+        // Requests start over from the first node once one is healthy again
+        set_up(&[2]);
+        assert_eq!(endpoints.request(request), Some(2));
+    }
+}
@@ -0,0 +1,343 @@
+//! Channel over the Rocket HTTP server, a key-value store which peers poll for the messages
+//! of each round. A peer signs up to the session the server has open, which fills up with
+//! the number of parties the server is built with, and sets its message of each round under
+//! its party number. Sessions are identified by a uuid, the session identifier of a channel
+//! is its first 64 bits. The server keeps no identity keys, each peer sets its own
+//! when it signs up. Every entry is kept, so a restarted client rejoins its session
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Serialize};
+
+use crate::channel::{
+    add_p2p_messages, announced_peer_id, Announcement, BroadcastChannel, ReceivedMessage,
+    RegisterRequest, Registration, RoundMessage,
+};
+use crate::checkpoint::CheckpointError;
+use crate::session::SessionError;
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::identity::{generate_keypair, identity_key, IdentityKey, Keypair};
+use mmpc_server_common::{PeerIdentifier, ProtocolIdentifier, RelayMessage, SessionIdentifier};
+
+// Polls for the identity keys of the peers before a message is sent without
+// its point-to-point messages
+const MAX_KEY_POLLS: u32 = 64;
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+const IDENTITY_KEY: &str = "identity";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TupleKey {
+    first: String,
+    second: String,
+    third: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartySignup {
+    number: u32,
+    uuid: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Index {
+    key: TupleKey,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    key: TupleKey,
+    value: String,
+}
+
+/// What the channel checkpoints, the messages of the session are stored on the server
+#[derive(Serialize, Deserialize)]
+pub struct HttpCheckpoint {
+    // Hex encoded identity keypair the client set
+    identity: String,
+    uuid: Option<String>,
+    number: u32,
+}
+
+pub struct HttpChannel {
+    client: reqwest::Client,
+    url: String,
+    // Identity of this client in the session, generated for each run
+    identity: Keypair,
+    // Uuid of the session the client signed up to
+    uuid: Option<String>,
+    protocol_id: ProtocolIdentifier,
+    capacity: u32,
+    // Party number the server signed this peer up as
+    number: u32,
+    // Identifier this peer takes part in the protocol as
+    peer_id: PeerIdentifier,
+    // Identity keys of the peers in the session, by peer identifier
+    peer_keys: BTreeMap<PeerIdentifier, IdentityKey>,
+    // Messages of the session by round and party number
+    messages: BTreeMap<u32, BTreeMap<u32, RelayMessage>>,
+}
+
+impl HttpChannel {
+    /// Creates a channel to the server at the given address, such as 127.0.0.1:8001
+    pub fn new(address: &str) -> HttpChannel {
+        let url = if address.contains("://") {
+            address.trim_end_matches('/').to_string()
+        } else {
+            format!("http://{}", address)
+        };
+        HttpChannel {
+            client: reqwest::Client::new(),
+            url,
+            identity: generate_keypair(),
+            uuid: None,
+            protocol_id: 0,
+            capacity: 0,
+            number: 0,
+            peer_id: 0,
+            peer_keys: BTreeMap::new(),
+            messages: BTreeMap::new(),
+        }
+    }
+
+    // Posts the request, returns None if the server did not answer
+    fn post<B: Serialize, R: DeserializeOwned>(&self, path: &str, body: &B) -> Option<R> {
+        let response = self
+            .client
+            .post(&format!("{}/{}", self.url, path))
+            .json(body)
+            .send()
+            .and_then(|mut response| response.json());
+        match response {
+            Ok(response) => Some(response),
+            Err(err) => {
+                warn!("Request to {} failed: {}", self.url, err);
+                None
+            }
+        }
+    }
+
+    fn key(&self, number: u32, name: &str) -> TupleKey {
+        TupleKey {
+            first: number.to_string(),
+            second: name.to_string(),
+            third: self.uuid.clone().unwrap_or_default(),
+        }
+    }
+
+    fn set(&self, name: &str, value: String) -> Result<(), SessionError> {
+        let entry = Entry {
+            key: self.key(self.number, name),
+            value,
+        };
+        match self.post::<_, Result<(), ()>>("set", &entry) {
+            Some(Ok(())) => Ok(()),
+            _ => Err(SessionError::Unavailable),
+        }
+    }
+
+    // Returns the value the party set under the name, if it set one
+    fn get(&self, number: u32, name: &str) -> Option<String> {
+        let index = Index {
+            key: self.key(number, name),
+        };
+        match self.post::<_, Result<Entry, ()>>("get", &index)? {
+            Ok(entry) => Some(entry.value),
+            Err(()) => None,
+        }
+    }
+
+    // Fetches the identity keys the peers set which are not known yet
+    fn update_peer_keys(&mut self) {
+        for number in 1..=self.capacity {
+            if let Some(value) = self.get(number, IDENTITY_KEY) {
+                match serde_json::from_str::<Announcement>(&value) {
+                    Ok(announcement) => {
+                        self.peer_keys
+                            .insert(announcement.peer_id, announcement.identity_key);
+                    }
+                    Err(err) => warn!("Invalid identity of party {}: {}", number, err),
+                }
+            }
+        }
+    }
+}
+
+// The session identifier of a session uuid
+fn session_id(uuid: &str) -> Option<SessionIdentifier> {
+    let digits: String = uuid.chars().filter(|c| *c != '-').take(16).collect();
+    SessionIdentifier::from_str_radix(&digits, 16).ok()
+}
+
+impl BroadcastChannel for HttpChannel {
+    type Checkpoint = HttpCheckpoint;
+
+    /// Signs up to the session the server has open, which must be the session
+    /// of the request if it has one, and sets the identity key of this peer
+    fn register(&mut self, request: &RegisterRequest) -> Result<Registration, SessionError> {
+        self.protocol_id = request.protocol_id;
+        self.capacity = request.capacity;
+        let signup: Result<PartySignup, ()> = self
+            .post("signup", &self.key(0, "signup"))
+            .ok_or(SessionError::Unavailable)?;
+        let signup = signup.map_err(|()| SessionError::Register(RelayError::CantRegister))?;
+        let signed_up =
+            session_id(&signup.uuid).ok_or(SessionError::Register(RelayError::CantRegister))?;
+        if request.session_id.map_or(false, |id| id != signed_up) {
+            error!("The server has session {} open", signed_up);
+            return Err(SessionError::Register(RelayError::UnknownSession));
+        }
+        info!(
+            "Signed up to session {} as party {}",
+            signup.uuid, signup.number
+        );
+        self.uuid = Some(signup.uuid);
+        self.number = signup.number;
+        self.peer_id = announced_peer_id(self.number, request.kg_index);
+        let announcement = Announcement {
+            peer_id: self.peer_id,
+            identity_key: identity_key(&self.identity),
+        };
+        self.set(IDENTITY_KEY, serde_json::to_string(&announcement).unwrap())?;
+        Ok(Registration {
+            session_id: signed_up,
+            peer_id: self.peer_id,
+        })
+    }
+
+    fn broadcast(&mut self, message: &RoundMessage) -> Result<(), SessionError> {
+        let session_id = self
+            .uuid
+            .as_ref()
+            .and_then(|uuid| session_id(uuid))
+            .ok_or(SessionError::Register(RelayError::UnknownSession))?;
+        let mut relay_message =
+            RelayMessage::new(self.peer_id, session_id, self.protocol_id, message.round);
+        relay_message.set_message_params(Vec::new(), message.payload.clone());
+        // Peers set their identity keys when they sign up, which may be after this one did
+        let mut polls = 0;
+        while message
+            .p2p
+            .keys()
+            .any(|peer| !self.peer_keys.contains_key(peer))
+            && polls < MAX_KEY_POLLS
+        {
+            if polls > 0 {
+                thread::sleep(KEY_POLL_INTERVAL);
+            }
+            self.update_peer_keys();
+            polls += 1;
+        }
+        add_p2p_messages(&mut relay_message, &message.p2p, &self.peer_keys)?;
+        debug!("Sending message of round {}", message.round);
+        self.set(
+            &format!("round{}", message.round),
+            serde_json::to_string(&relay_message).unwrap(),
+        )
+    }
+
+    fn receive(
+        &mut self,
+        round: u32,
+        timeout: Duration,
+    ) -> Result<BTreeMap<PeerIdentifier, ReceivedMessage>, SessionError> {
+        let name = format!("round{}", round);
+        let missing: Vec<u32> = (1..=self.capacity)
+            .filter(|number| {
+                self.messages
+                    .get(&round)
+                    .map_or(true, |messages| !messages.contains_key(number))
+            })
+            .collect();
+        let mut fetched = 0;
+        for number in &missing {
+            let value = match self.get(*number, &name) {
+                Some(value) => value,
+                None => continue,
+            };
+            match serde_json::from_str::<RelayMessage>(&value) {
+                Ok(message) => {
+                    self.messages
+                        .entry(round)
+                        .or_default()
+                        .insert(*number, message);
+                    fetched += 1;
+                }
+                Err(err) => warn!("Invalid message of party {}: {}", number, err),
+            }
+        }
+        // The server does not notify of new messages, so it is polled once per timeout
+        if fetched < missing.len() {
+            thread::sleep(timeout);
+        }
+        let messages = match self.messages.get(&round) {
+            Some(messages) => messages
+                .values()
+                .map(|msg| {
+                    let message = ReceivedMessage {
+                        from: msg.peer_number,
+                        payload: msg.message.clone(),
+                        p2p_payload: msg.p2p_message_to(self.peer_id, &self.identity),
+                    };
+                    (message.from, message)
+                })
+                .collect(),
+            None => BTreeMap::new(),
+        };
+        Ok(messages)
+    }
+
+    fn checkpoint(&self) -> HttpCheckpoint {
+        HttpCheckpoint {
+            identity: hex::encode(&self.identity.to_bytes()[..]),
+            uuid: self.uuid.clone(),
+            number: self.number,
+        }
+    }
+
+    fn restore(
+        &mut self,
+        request: &RegisterRequest,
+        peer_id: Option<PeerIdentifier>,
+        checkpoint: HttpCheckpoint,
+    ) -> Result<(), CheckpointError> {
+        self.identity = hex::decode(&checkpoint.identity)
+            .ok()
+            .and_then(|bytes| Keypair::from_bytes(&bytes).ok())
+            .ok_or_else(|| {
+                CheckpointError::Format(serde_json::Error::custom("invalid identity"))
+            })?;
+        self.protocol_id = request.protocol_id;
+        self.capacity = request.capacity;
+        if let Some(peer_id) = peer_id {
+            self.uuid = checkpoint.uuid;
+            self.number = checkpoint.number;
+            self.peer_id = peer_id;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{session_id, HttpChannel};
+
+    #[test]
+    fn test_session_id_of_uuid() {
+        let uuid = "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d";
+        assert_eq!(session_id(uuid), Some(0xa1b2_c3d4_e5f6_4a7b));
+        assert_eq!(session_id("not-a-uuid"), None);
+    }
+
+    #[test]
+    fn test_server_url() {
+        assert_eq!(
+            HttpChannel::new("127.0.0.1:8001").url,
+            "http://127.0.0.1:8001"
+        );
+        assert_eq!(HttpChannel::new("https://relay/").url, "https://relay");
+    }
+}
@@ -0,0 +1,393 @@
+//! Password protected storage of key shares.
+//! A key store is a JSON file holding any number of key shares, each labelled by a key id
+//! and the public key it is a share of. Every share is sealed with ChaCha20-Poly1305 under a key
+//! derived from the password and a salt of its own with Argon2id. Labels are readable without
+//! the password, so the shares of a store can be listed, and are authenticated with the share
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+const KEY_STORE_VERSION: u32 = 1;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const KEY_LENGTH: usize = 32;
+
+#[derive(Debug)]
+pub enum KeyStoreError {
+    Io(io::Error),
+    Format(serde_json::Error),
+    // The store was written by a later version of the client
+    Version(u32),
+    NotFound(String),
+    // A share with the key id is already stored
+    Exists(String),
+    // Wrong password, or the share or its label was tampered with
+    Decrypt,
+    InvalidKdf,
+}
+
+impl fmt::Display for KeyStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyStoreError::Io(err) => write!(f, "Unable to access key store: {}", err),
+            KeyStoreError::Format(err) => write!(f, "Invalid key store: {}", err),
+            KeyStoreError::Version(version) => {
+                write!(f, "Unsupported key store version {}", version)
+            }
+            KeyStoreError::NotFound(key_id) => write!(f, "No key share with id {}", key_id),
+            KeyStoreError::Exists(key_id) => {
+                write!(f, "A key share with id {} is already stored", key_id)
+            }
+            KeyStoreError::Decrypt => write!(f, "Unable to decrypt key share, wrong password?"),
+            KeyStoreError::InvalidKdf => write!(f, "Invalid key derivation parameters"),
+        }
+    }
+}
+
+impl Error for KeyStoreError {}
+
+impl From<io::Error> for KeyStoreError {
+    fn from(err: io::Error) -> KeyStoreError {
+        KeyStoreError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for KeyStoreError {
+    fn from(err: serde_json::Error) -> KeyStoreError {
+        KeyStoreError::Format(err)
+    }
+}
+
+/// Label of a stored key share
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyLabel {
+    pub key_id: String,
+    // Hex encoded public key the share is a share of, such as an aggregated key
+    pub public_key: String,
+}
+
+/// Argon2id parameters the password is stretched with, memory in KiB
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    pub(crate) fn derive_key(&self, password: &str, salt: &[u8]) -> Result<Key, KeyStoreError> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_LENGTH))
+            .map_err(|_| KeyStoreError::InvalidKdf)?;
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password.as_bytes(), salt, &mut key)
+            .map_err(|_| KeyStoreError::InvalidKdf)?;
+        Ok(key)
+    }
+}
+
+// A share as written to the store, the parameters it was sealed with are kept with it
+#[derive(Serialize, Deserialize)]
+struct SealedShare {
+    #[serde(flatten)]
+    label: KeyLabel,
+    kdf: KdfParams,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    shares: Vec<SealedShare>,
+}
+
+pub struct KeyStore {
+    path: PathBuf,
+    file: KeyFile,
+    // Parameters new shares are sealed with
+    kdf: KdfParams,
+}
+
+impl KeyStore {
+    /// Opens the key store at the given path, the store is empty if there is no file yet
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<KeyStore, KeyStoreError> {
+        let path = path.as_ref().to_path_buf();
+        let file = match fs::read_to_string(&path) {
+            Ok(data) => {
+                let file: KeyFile = serde_json::from_str(&data)?;
+                if file.version > KEY_STORE_VERSION {
+                    return Err(KeyStoreError::Version(file.version));
+                }
+                file
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => KeyFile {
+                version: KEY_STORE_VERSION,
+                shares: Vec::new(),
+            },
+            Err(err) => return Err(err.into()),
+        };
+        Ok(KeyStore {
+            path,
+            file,
+            kdf: KdfParams::default(),
+        })
+    }
+
+    /// Seals shares added from now on with the given Argon2id parameters
+    pub fn with_kdf(mut self, kdf: KdfParams) -> KeyStore {
+        self.kdf = kdf;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Labels of the stored shares, readable without the password
+    pub fn labels(&self) -> Vec<KeyLabel> {
+        self.file
+            .shares
+            .iter()
+            .map(|share| share.label.clone())
+            .collect()
+    }
+
+    pub fn label(&self, key_id: &str) -> Option<&KeyLabel> {
+        self.find(key_id).map(|share| &share.label)
+    }
+
+    /// Seals the share with the password and saves the store.
+    /// Key ids are unique, a stored share must be removed before its id is reused
+    pub fn insert<S: Serialize>(
+        &mut self,
+        label: KeyLabel,
+        share: &S,
+        password: &str,
+    ) -> Result<(), KeyStoreError> {
+        if self.find(&label.key_id).is_some() {
+            return Err(KeyStoreError::Exists(label.key_id));
+        }
+        let salt = random_salt();
+        let key = self.kdf.derive_key(password, &salt)?;
+        let plaintext = serde_json::to_vec(share)?;
+        let (nonce, ciphertext) =
+            seal(&key, &plaintext, &associated_data(&label)?).ok_or(KeyStoreError::Decrypt)?;
+        self.file.shares.push(SealedShare {
+            label,
+            kdf: self.kdf,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        });
+        self.save()
+    }
+
+    /// Opens the share with the given key id
+    pub fn load<S: DeserializeOwned>(
+        &self,
+        key_id: &str,
+        password: &str,
+    ) -> Result<S, KeyStoreError> {
+        let share = self
+            .find(key_id)
+            .ok_or_else(|| KeyStoreError::NotFound(key_id.to_string()))?;
+        let salt = hex::decode(&share.salt).map_err(|_| KeyStoreError::Decrypt)?;
+        let nonce = hex::decode(&share.nonce).map_err(|_| KeyStoreError::Decrypt)?;
+        let ciphertext = hex::decode(&share.ciphertext).map_err(|_| KeyStoreError::Decrypt)?;
+        let key = share.kdf.derive_key(password, &salt)?;
+        let plaintext = unseal(&key, &nonce, &ciphertext, &associated_data(&share.label)?)
+            .ok_or(KeyStoreError::Decrypt)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    /// Removes the share with the given key id and saves the store
+    pub fn remove(&mut self, key_id: &str) -> Result<(), KeyStoreError> {
+        let len = self.file.shares.len();
+        self.file
+            .shares
+            .retain(|share| share.label.key_id != key_id);
+        if self.file.shares.len() == len {
+            return Err(KeyStoreError::NotFound(key_id.to_string()));
+        }
+        self.save()
+    }
+
+    fn find(&self, key_id: &str) -> Option<&SealedShare> {
+        self.file
+            .shares
+            .iter()
+            .find(|share| share.label.key_id == key_id)
+    }
+
+    // Writes the store to a temporary file which then replaces the store,
+    // so a failed write does not lose the shares already stored
+    fn save(&self) -> Result<(), KeyStoreError> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&self.file)?)?;
+        restrict_permissions(Path::new(&tmp_path))?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn random_salt() -> [u8; SALT_LENGTH] {
+    let mut salt = [0u8; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+// Encrypts the plaintext under the key with a random nonce,
+// returns the nonce and the ciphertext
+pub(crate) fn seal(
+    key: &Key,
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Option<([u8; NONCE_LENGTH], Vec<u8>)> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .ok()?;
+    Some((nonce, ciphertext))
+}
+
+// Decrypts and authenticates the ciphertext, None if the key is wrong or the data was tampered with
+pub(crate) fn unseal(key: &Key, nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if nonce.len() != NONCE_LENGTH {
+        return None;
+    }
+    ChaCha20Poly1305::new(key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+// The label is authenticated with the share, so a share can not be relabelled
+fn associated_data(label: &KeyLabel) -> Result<Vec<u8>, KeyStoreError> {
+    Ok(serde_json::to_vec(label)?)
+}
+
+#[cfg(unix)]
+pub(crate) fn restrict_permissions(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn restrict_permissions(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KdfParams, KeyLabel, KeyStore, KeyStoreError};
+
+    // Cheap parameters, the defaults take a while to derive a key
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn label(key_id: &str) -> KeyLabel {
+        KeyLabel {
+            key_id: key_id.to_string(),
+            public_key: format!("{}-public", key_id),
+        }
+    }
+
+    #[test]
+    fn test_store_shares() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        let mut store = KeyStore::open(&path).unwrap().with_kdf(TEST_KDF);
+        assert!(store.labels().is_empty());
+        store
+            .insert(label("first"), &(1u32, "share"), "password")
+            .unwrap();
+        store
+            .insert(label("second"), &(2u32, "share"), "other")
+            .unwrap();
+        match store.insert(label("first"), &(3u32, "share"), "password") {
+            Err(KeyStoreError::Exists(key_id)) => assert_eq!(key_id, "first"),
+            _ => panic!("Key id reused"),
+        }
+
+        // Shares are read back after reopening the store
+        let mut store = KeyStore::open(&path).unwrap();
+        assert_eq!(store.labels(), vec![label("first"), label("second")]);
+        let first: (u32, String) = store.load("first", "password").unwrap();
+        assert_eq!(first, (1, String::from("share")));
+        let second: (u32, String) = store.load("second", "other").unwrap();
+        assert_eq!(second, (2, String::from("share")));
+        match store.load::<(u32, String)>("missing", "password") {
+            Err(KeyStoreError::NotFound(_)) => {}
+            _ => panic!("Loaded a missing share"),
+        }
+
+        store.remove("first").unwrap();
+        assert_eq!(
+            KeyStore::open(&path).unwrap().labels(),
+            vec![label("second")]
+        );
+    }
+
+    #[test]
+    fn test_shares_are_sealed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        let mut store = KeyStore::open(&path).unwrap().with_kdf(TEST_KDF);
+        store
+            .insert(label("key"), &String::from("secret-share"), "password")
+            .unwrap();
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(!data.contains("secret-share"));
+
+        match store.load::<String>("key", "wrong") {
+            Err(KeyStoreError::Decrypt) => {}
+            _ => panic!("Opened with the wrong password"),
+        }
+        // Labels are authenticated with the share
+        let relabelled = data.replace("key-public", "other-public");
+        std::fs::write(&path, relabelled).unwrap();
+        let store = KeyStore::open(&path).unwrap();
+        match store.load::<String>("key", "password") {
+            Err(KeyStoreError::Decrypt) => {}
+            _ => panic!("Opened a relabelled share"),
+        }
+    }
+}
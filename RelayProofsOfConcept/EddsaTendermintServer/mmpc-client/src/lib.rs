@@ -1,4 +1,17 @@
+pub mod channel;
+pub mod checkpoint;
+pub mod cli;
+pub mod client;
 pub mod eddsa_peer_kg;
 pub mod eddsa_peer_sign;
+pub mod eddsa_peer_threshold_kg;
+pub mod eddsa_peer_threshold_sign;
+pub mod endpoints;
+pub mod http_client;
+pub mod key_store;
 pub mod peer;
+pub mod points;
+pub mod session;
+pub mod subscription;
 pub mod tendermint_client;
+pub mod tokio_client;
@@ -1,19 +1,79 @@
-use mmpc_server_common::{MessagePayload, PeerIdentifier};
+use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
 
 pub const MAX_CLIENTS: usize = 12;
 
-pub trait Peer {
-    fn new(capacity: u32, message: Vec<u8>, index: u32) -> Self;
+/// Why a peer could not do a step of its protocol
+#[derive(Debug)]
+pub enum PeerError {
+    // A peer did not send its message of the step
+    MissingMessage(PeerIdentifier),
+    // The message of a peer does not deserialize
+    InvalidMessage(PeerIdentifier),
+    // The messages of the step did not verify, such as commitments or secret shares
+    Protocol(&'static str),
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerError::MissingMessage(peer_id) => {
+                write!(f, "Peer {} did not send its message", peer_id)
+            }
+            PeerError::InvalidMessage(peer_id) => {
+                write!(f, "Peer {} sent an invalid message", peer_id)
+            }
+            PeerError::Protocol(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for PeerError {}
+
+/// A party of a protocol. Peers are serializable, so the state of a peer
+/// can be checkpointed after each step and restored by a restarted client
+pub trait Peer: Serialize + DeserializeOwned {
+    /// Identifier of the protocol in the registry of the relay, which checks the messages
+    /// of each round are the ones the protocol sends
+    const PROTOCOL_ID: ProtocolIdentifier;
+    /// Result of the protocol, such as the key share of a key generation or a signature
+    type Output;
     fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload>;
     fn current_step(&self) -> u32;
     fn capacity(&self) -> u32;
     fn peer_id(&self) -> PeerIdentifier;
     fn set_peer_id(&mut self, peer_id: PeerIdentifier);
-    fn do_step(&mut self);
-    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload);
+    /// Does the next step once the messages of the current step are all in,
+    /// fails if the messages of the other peers are missing or do not verify
+    fn do_step(&mut self) -> Result<(), PeerError>;
+    /// Handles the message of a peer in the current step,
+    /// fails if the protocol has no such step
+    fn update_data(
+        &mut self,
+        from: PeerIdentifier,
+        payload: MessagePayload,
+    ) -> Result<(), PeerError>;
     fn get_next_item(&mut self) -> Option<MessagePayload>;
+    /// Point-to-point messages of the current step by recipient,
+    /// they are encrypted to the identity key of each recipient
+    fn get_next_p2p_items(&mut self) -> BTreeMap<PeerIdentifier, MessagePayload> {
+        BTreeMap::new()
+    }
+    /// Handles a decrypted point-to-point message sent to this peer
+    fn update_p2p_data(&mut self, _from: PeerIdentifier, _payload: MessagePayload) {}
+    /// Number of key shares, for peers which sign with a share of a threshold key.
+    /// A threshold signing session may have fewer peers than shares
+    fn share_count(&self) -> Option<u32> {
+        None
+    }
     fn finalize(&mut self) -> Result<(), &'static str>;
     fn is_done(&mut self) -> bool;
+    /// Takes the result of the protocol once it is done
+    fn output(&mut self) -> Option<Self::Output>;
 }
 
 pub struct ProtocolDataManager<T: Peer> {
@@ -23,12 +83,10 @@ pub struct ProtocolDataManager<T: Peer> {
 }
 
 impl<T: Peer> ProtocolDataManager<T> {
-    pub fn new(capacity: u32, message: Vec<u8>, index: u32) -> ProtocolDataManager<T>
-    where
-        T: Peer,
-    {
+    /// Manager of a peer created by its protocol, such as a signing peer with its key share
+    pub fn with_peer(data_holder: T) -> ProtocolDataManager<T> {
         ProtocolDataManager {
-            data_holder: Peer::new(capacity, message, index),
+            data_holder,
             client_data: None,
             new_client_data: false,
         }
@@ -49,9 +107,67 @@ impl<T: Peer> ProtocolDataManager<T> {
         &mut self,
         from: PeerIdentifier,
         payload: MessagePayload,
-    ) -> Option<MessagePayload> {
-        self.data_holder.update_data(from, payload);
-        self.data_holder.do_step();
-        self.data_holder.get_next_item()
+        p2p_payload: Option<MessagePayload>,
+    ) -> Result<Option<MessagePayload>, PeerError> {
+        self.data_holder.update_data(from, payload)?;
+        if let Some(p2p_payload) = p2p_payload {
+            self.data_holder.update_p2p_data(from, p2p_payload);
+        }
+        self.data_holder.do_step()?;
+        Ok(self.data_holder.get_next_item())
+    }
+}
+
+// The message of a peer in a round, with its point-to-point messages by recipient
+#[cfg(test)]
+type InProcessMessage = (
+    PeerIdentifier,
+    MessagePayload,
+    BTreeMap<PeerIdentifier, MessagePayload>,
+);
+
+/// Runs the peers in process, relaying the messages of each round to all of them,
+/// and returns their outputs. Each peer is passed to `after_round` once it handled
+/// the messages of a round, before it sends its next message, so tests can restart it
+#[cfg(test)]
+pub(crate) fn run_in_process<P, F>(
+    peers: Vec<(PeerIdentifier, P)>,
+    mut after_round: F,
+) -> Vec<P::Output>
+where
+    P: Peer,
+    F: FnMut(u32, &mut P),
+{
+    let mut managers = Vec::new();
+    let mut round: Vec<InProcessMessage> = Vec::new();
+    for (peer_id, peer) in peers {
+        let mut manager = ProtocolDataManager::with_peer(peer);
+        let payload = manager.initialize_data(peer_id).unwrap();
+        round.push((peer_id, payload, manager.data_holder.get_next_p2p_items()));
+        managers.push(manager);
+    }
+    let mut round_number = 0;
+    while !round.is_empty() {
+        let mut next_round = Vec::new();
+        for manager in managers.iter_mut() {
+            let peer_id = manager.data_holder.peer_id();
+            for (from, payload, p2p) in &round {
+                manager
+                    .get_next_message(*from, payload.clone(), p2p.get(&peer_id).cloned())
+                    .unwrap();
+            }
+            after_round(round_number, &mut manager.data_holder);
+            if !manager.data_holder.is_done() {
+                let payload = manager.data_holder.get_next_item().unwrap();
+                let p2p = manager.data_holder.get_next_p2p_items();
+                next_round.push((peer_id, payload, p2p));
+            }
+        }
+        round = next_round;
+        round_number += 1;
     }
+    managers
+        .into_iter()
+        .map(|mut manager| manager.data_holder.output().unwrap())
+        .collect()
 }
@@ -0,0 +1,140 @@
+//! Points of the ed25519 curve are multiplied by the cofactor when curv deserializes them.
+//! Peers decode the points in messages of other peers through these functions,
+//! and restore the points of a checkpoint with them, so a restored peer holds the points
+//! it held when it was checkpointed
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::traits::ECScalar;
+use curv::{BigInt, FE, GE};
+use multi_party_eddsa::protocols::aggsig::{self, KeyAgg, KeyPair};
+use multi_party_eddsa::protocols::thresholdsig::{self, EphemeralSharedKeys, Keys, SharedKeys};
+use serde::{Deserialize, Deserializer};
+
+/// Points are multiplied by the cofactor when deserialized,
+/// returns the point that was serialized
+pub fn deserialized_point(point: GE) -> GE {
+    let eight: FE = ECScalar::from(&BigInt::from(8));
+    point * &eight.invert()
+}
+
+/// Returns the VSS scheme that was serialized, with the original commitments
+pub fn deserialized_vss(mut vss_scheme: VerifiableSS) -> VerifiableSS {
+    vss_scheme.commitments = vss_scheme
+        .commitments
+        .into_iter()
+        .map(deserialized_point)
+        .collect();
+    vss_scheme
+}
+
+/// Values holding points, which are restored to the value that was serialized
+pub trait Deserialized {
+    fn deserialized(self) -> Self;
+}
+
+/// Deserializes a field holding points as it was serialized,
+/// for `#[serde(deserialize_with = "points::deserialize")]`
+pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Deserialized,
+{
+    T::deserialize(deserializer).map(Deserialized::deserialized)
+}
+
+impl Deserialized for GE {
+    fn deserialized(self) -> GE {
+        deserialized_point(self)
+    }
+}
+
+impl Deserialized for VerifiableSS {
+    fn deserialized(self) -> VerifiableSS {
+        deserialized_vss(self)
+    }
+}
+
+impl<T: Deserialized> Deserialized for Option<T> {
+    fn deserialized(self) -> Option<T> {
+        self.map(Deserialized::deserialized)
+    }
+}
+
+impl<T: Deserialized> Deserialized for Vec<T> {
+    fn deserialized(self) -> Vec<T> {
+        self.into_iter().map(Deserialized::deserialized).collect()
+    }
+}
+
+impl<K: Eq + Hash, T: Deserialized> Deserialized for HashMap<K, T> {
+    fn deserialized(self) -> HashMap<K, T> {
+        self.into_iter()
+            .map(|(key, value)| (key, value.deserialized()))
+            .collect()
+    }
+}
+
+impl Deserialized for KeyPair {
+    fn deserialized(mut self) -> KeyPair {
+        self.public_key = deserialized_point(self.public_key);
+        self
+    }
+}
+
+impl Deserialized for KeyAgg {
+    fn deserialized(mut self) -> KeyAgg {
+        self.apk = deserialized_point(self.apk);
+        self
+    }
+}
+
+impl Deserialized for aggsig::EphemeralKey {
+    fn deserialized(mut self) -> aggsig::EphemeralKey {
+        self.R = deserialized_point(self.R);
+        self
+    }
+}
+
+impl Deserialized for aggsig::Signature {
+    fn deserialized(mut self) -> aggsig::Signature {
+        self.R = deserialized_point(self.R);
+        self
+    }
+}
+
+impl Deserialized for Keys {
+    fn deserialized(mut self) -> Keys {
+        self.y_i = deserialized_point(self.y_i);
+        self
+    }
+}
+
+impl Deserialized for SharedKeys {
+    fn deserialized(mut self) -> SharedKeys {
+        self.y = deserialized_point(self.y);
+        self
+    }
+}
+
+impl Deserialized for thresholdsig::EphemeralKey {
+    fn deserialized(mut self) -> thresholdsig::EphemeralKey {
+        self.R_i = deserialized_point(self.R_i);
+        self
+    }
+}
+
+impl Deserialized for EphemeralSharedKeys {
+    fn deserialized(mut self) -> EphemeralSharedKeys {
+        self.R = deserialized_point(self.R);
+        self
+    }
+}
+
+impl Deserialized for thresholdsig::Signature {
+    fn deserialized(mut self) -> thresholdsig::Signature {
+        self.R = deserialized_point(self.R);
+        self
+    }
+}
@@ -0,0 +1,504 @@
+//! Running a protocol session to its end, for services which embed the client.
+//! The client is blocking, so an asynchronous session runs on a thread of its own
+//! and its result is returned through a future. Each session running at once holds
+//! a blocking OS thread until it ends, so a service bounds its sessions by the threads it can spare
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use futures::sync::oneshot;
+use futures::task::AtomicTask;
+use futures::{Async, Future, Poll};
+use log::warn;
+
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::{PeerIdentifier, SessionIdentifier, SessionStatus};
+
+use crate::channel::{BroadcastChannel, Registration};
+use crate::checkpoint::CheckpointError;
+use crate::client::SessionClient;
+use crate::peer::{Peer, PeerError};
+
+/// Why a session ended without the result of its protocol
+#[derive(Debug)]
+pub enum SessionError {
+    // The relay rejected the registration
+    Register(RelayError),
+    // None of the nodes answered with a response the client could read,
+    // or the connection to the relay failed
+    Unavailable,
+    // The relay rejected a message of the client
+    Rejected(RelayError),
+    // A point-to-point message is addressed to a peer without a valid identity key,
+    // so it can not be encrypted to it
+    UnknownRecipient(PeerIdentifier),
+    // The relay aborted the session, the status holds the blamed peers
+    Aborted(SessionStatus),
+    // The messages of the round did not all arrive in time
+    TimedOut(u32),
+    // The protocol failed on the messages of the other peers
+    Protocol(PeerError),
+    // The protocol finished without a result
+    NoOutput,
+    // The state of the client could not be checkpointed
+    Checkpoint(CheckpointError),
+    Cancelled,
+    // The thread running the session panicked, with the message of the panic
+    Panicked(String),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Register(err) => write!(f, "Unable to register: {}", err),
+            SessionError::Unavailable => write!(f, "No relay node is available"),
+            SessionError::Rejected(err) => write!(f, "Message rejected: {}", err),
+            SessionError::UnknownRecipient(peer_id) => {
+                write!(f, "No valid identity key of peer {} to send to", peer_id)
+            }
+            SessionError::Aborted(status) => write!(
+                f,
+                "Session aborted in round {}, peers {:?} did not send their messages",
+                status.round, status.blame
+            ),
+            SessionError::TimedOut(round) => {
+                write!(f, "Timed out waiting for the messages of round {}", round)
+            }
+            SessionError::Protocol(err) => write!(f, "Protocol failed: {}", err),
+            SessionError::NoOutput => write!(f, "Protocol finished without a result"),
+            SessionError::Checkpoint(err) => write!(f, "Unable to checkpoint session: {}", err),
+            SessionError::Cancelled => write!(f, "Session was cancelled"),
+            SessionError::Panicked(message) => write!(f, "Session panicked: {}", message),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+/// The result of a session run on a thread of its own.
+/// Dropping the future, or cancelling it, stops the session the next time
+/// the client waits for messages, and the future resolves as soon as it is cancelled
+pub struct SessionFuture<O> {
+    receiver: oneshot::Receiver<Result<O, SessionError>>,
+    registered: oneshot::Receiver<Result<Registration, SessionError>>,
+    registration: Option<Registration>,
+    cancel: CancelHandle,
+}
+
+impl<O> SessionFuture<O> {
+    /// Stops the session, the future then resolves to SessionError::Cancelled
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// A handle to cancel the session from elsewhere, such as another task awaiting it
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Polls for the session and peer the client registered as, which are known
+    /// before the protocol completes, so other peers can be told which session to join.
+    /// Fails if the session ended before the client registered, the future then
+    /// resolves to the reason
+    pub fn poll_registration(&mut self) -> Poll<Registration, SessionError> {
+        if let Some(registration) = self.registration {
+            return Ok(Async::Ready(registration));
+        }
+        match self.registered.poll() {
+            Ok(Async::Ready(Ok(registration))) => {
+                self.registration = Some(registration);
+                Ok(Async::Ready(registration))
+            }
+            Ok(Async::Ready(Err(err))) => Err(err),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(SessionError::Register(RelayError::CantRegister)),
+        }
+    }
+}
+
+impl<O> Drop for SessionFuture<O> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl<O> Future for SessionFuture<O> {
+    type Item = O;
+    type Error = SessionError;
+
+    fn poll(&mut self) -> Poll<O, SessionError> {
+        // Registered before checking, so a cancel in between still wakes the task
+        self.cancel.inner.task.register();
+        if self.cancel.is_cancelled() {
+            return Err(SessionError::Cancelled);
+        }
+        match self.receiver.poll() {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // Panics are caught on the session thread, so it only ends without a result if it was killed
+            Err(oneshot::Canceled) => Err(SessionError::Panicked(String::from(
+                "Session thread ended without a result",
+            ))),
+        }
+    }
+}
+
+/// Cancels a session run on a thread of its own, waking the task awaiting its future
+#[derive(Clone)]
+pub struct CancelHandle {
+    inner: Arc<Cancel>,
+}
+
+struct Cancel {
+    cancelled: AtomicBool,
+    task: AtomicTask,
+}
+
+impl CancelHandle {
+    fn new() -> CancelHandle {
+        CancelHandle {
+            inner: Arc::new(Cancel {
+                cancelled: AtomicBool::new(false),
+                task: AtomicTask::new(),
+            }),
+        }
+    }
+
+    /// Stops the session, its future then resolves to SessionError::Cancelled
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.task.notify();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl<T, C> SessionClient<T, C>
+where
+    T: Peer + Send + 'static,
+    T::Output: Send + 'static,
+    C: BroadcastChannel + Send + 'static,
+{
+    /// Joins the relay session with the given identifier, or opens a new session,
+    /// and runs the protocol to its end on a thread of its own, which blocks until the session ends.
+    /// Peers of a signing session join with the index of their key share, others with -1
+    pub fn run(
+        self,
+        kg_index: i32,
+        session_id: Option<SessionIdentifier>,
+    ) -> SessionFuture<T::Output> {
+        self.spawn(move |client| client.join(kg_index, session_id))
+    }
+
+    /// Rejoins the session the client was restored in, and runs the protocol
+    /// to its end on a thread of its own
+    pub fn resume(self) -> SessionFuture<T::Output> {
+        self.spawn(|client| client.rejoin())
+    }
+
+    // Runs the session on a thread of its own once the client has joined it
+    fn spawn<F>(mut self, join: F) -> SessionFuture<T::Output>
+    where
+        F: FnOnce(&mut SessionClient<T, C>) -> Result<SessionIdentifier, SessionError>
+            + Send
+            + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let (registered_sender, registered) = oneshot::channel();
+        let cancel = CancelHandle::new();
+        let stop = cancel.clone();
+        thread::spawn(move || {
+            let mut registered_sender = Some(registered_sender);
+            // A panic of the peer or the channel is reported through the future
+            // rather than as a failed registration or a cancelled session
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let session_id = join(&mut self)?;
+                let registration = Registration {
+                    session_id,
+                    peer_id: self.peer().peer_id(),
+                };
+                if let Some(registered_sender) = registered_sender.take() {
+                    // The registration is not awaited by every caller
+                    let _ = registered_sender.send(Ok(registration));
+                }
+                self.complete(|| stop.is_cancelled())
+            }))
+            .unwrap_or_else(|payload| {
+                let message = panic_message(payload.as_ref());
+                if let Some(registered_sender) = registered_sender.take() {
+                    let _ = registered_sender.send(Err(SessionError::Panicked(message.clone())));
+                }
+                Err(SessionError::Panicked(message))
+            });
+            if sender.send(result).is_err() {
+                warn!("Session result was not awaited");
+            }
+        });
+        SessionFuture {
+            receiver,
+            registered,
+            registration: None,
+            cancel,
+        }
+    }
+}
+
+// The message a panic was raised with, which is a string unless the panic carries another payload
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("Unknown panic")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionError;
+    use crate::channel::{
+        BroadcastChannel, ReceivedMessage, RegisterRequest, Registration, RoundMessage,
+    };
+    use crate::checkpoint::{CheckpointError, Checkpoints};
+    use crate::client::SessionClient;
+    use crate::key_store::KdfParams;
+    use crate::peer::{Peer, PeerError};
+
+    use futures::future::{self, Future};
+    use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    // A peer which sends its first message, and then waits for the other peers
+    #[derive(Serialize, Deserialize)]
+    struct WaitingPeer {
+        peer_id: PeerIdentifier,
+        sends_first: bool,
+    }
+
+    impl Peer for WaitingPeer {
+        const PROTOCOL_ID: ProtocolIdentifier = 0;
+        type Output = ();
+
+        fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+            self.peer_id = peer_id;
+            if self.sends_first {
+                Some(String::from("first"))
+            } else {
+                None
+            }
+        }
+
+        fn current_step(&self) -> u32 {
+            0
+        }
+
+        fn capacity(&self) -> u32 {
+            2
+        }
+
+        fn peer_id(&self) -> PeerIdentifier {
+            self.peer_id
+        }
+
+        fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
+            self.peer_id = peer_id;
+        }
+
+        fn do_step(&mut self) -> Result<(), PeerError> {
+            Ok(())
+        }
+
+        fn update_data(
+            &mut self,
+            _from: PeerIdentifier,
+            _payload: MessagePayload,
+        ) -> Result<(), PeerError> {
+            Ok(())
+        }
+
+        fn get_next_item(&mut self) -> Option<MessagePayload> {
+            None
+        }
+
+        fn finalize(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn is_done(&mut self) -> bool {
+            false
+        }
+
+        fn output(&mut self) -> Option<()> {
+            None
+        }
+    }
+
+    // A relay which registers the peer, and then stalls well past the timeout of a receive
+    struct StalledChannel;
+
+    impl BroadcastChannel for StalledChannel {
+        type Checkpoint = ();
+
+        fn register(&mut self, request: &RegisterRequest) -> Result<Registration, SessionError> {
+            Ok(Registration {
+                session_id: request.session_id.unwrap_or(7),
+                peer_id: 2,
+            })
+        }
+
+        fn broadcast(&mut self, _message: &RoundMessage) -> Result<(), SessionError> {
+            Ok(())
+        }
+
+        fn receive(
+            &mut self,
+            _round: u32,
+            _timeout: Duration,
+        ) -> Result<BTreeMap<PeerIdentifier, ReceivedMessage>, SessionError> {
+            thread::sleep(Duration::from_secs(30));
+            Ok(BTreeMap::new())
+        }
+
+        fn checkpoint(&self) -> Self::Checkpoint {}
+
+        fn restore(
+            &mut self,
+            _request: &RegisterRequest,
+            _peer_id: Option<PeerIdentifier>,
+            _checkpoint: Self::Checkpoint,
+        ) -> Result<(), CheckpointError> {
+            Ok(())
+        }
+    }
+
+    // A relay whose registration panics
+    struct PanickingChannel;
+
+    impl BroadcastChannel for PanickingChannel {
+        type Checkpoint = ();
+
+        fn register(&mut self, _request: &RegisterRequest) -> Result<Registration, SessionError> {
+            panic!("relay went away")
+        }
+
+        fn broadcast(&mut self, _message: &RoundMessage) -> Result<(), SessionError> {
+            Ok(())
+        }
+
+        fn receive(
+            &mut self,
+            _round: u32,
+            _timeout: Duration,
+        ) -> Result<BTreeMap<PeerIdentifier, ReceivedMessage>, SessionError> {
+            Ok(BTreeMap::new())
+        }
+
+        fn checkpoint(&self) -> Self::Checkpoint {}
+
+        fn restore(
+            &mut self,
+            _request: &RegisterRequest,
+            _peer_id: Option<PeerIdentifier>,
+            _checkpoint: Self::Checkpoint,
+        ) -> Result<(), CheckpointError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_session_future() {
+        let client = SessionClient::with_channel(
+            StalledChannel,
+            WaitingPeer {
+                peer_id: 0,
+                sends_first: true,
+            },
+        );
+        let mut session = client.run(-1, None);
+        let registration = future::poll_fn(|| session.poll_registration())
+            .wait()
+            .unwrap();
+        assert_eq!(
+            registration,
+            Registration {
+                session_id: 7,
+                peer_id: 2
+            }
+        );
+
+        // The future resolves once cancelled, while the client is still waiting on the relay
+        let cancel = session.cancel_handle();
+        let start = Instant::now();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            cancel.cancel();
+        });
+        match session.wait() {
+            Err(SessionError::Cancelled) => {}
+            _ => panic!("Session was not cancelled"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_session_panic() {
+        let client = SessionClient::with_channel(
+            PanickingChannel,
+            WaitingPeer {
+                peer_id: 0,
+                sends_first: true,
+            },
+        );
+        let mut session = client.run(-1, None);
+        // Reported as a panic both while awaiting the registration and the result
+        match future::poll_fn(|| session.poll_registration()).wait() {
+            Err(SessionError::Panicked(message)) => assert_eq!(message, "relay went away"),
+            _ => panic!("Registration did not report the panic"),
+        }
+        match session.wait() {
+            Err(SessionError::Panicked(message)) => assert_eq!(message, "relay went away"),
+            _ => panic!("Session did not report the panic"),
+        }
+    }
+
+    #[test]
+    fn test_join_checkpoints_new_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoints = Checkpoints::open(dir.path(), "password")
+            .unwrap()
+            .with_kdf(TEST_KDF);
+        let peer = WaitingPeer {
+            peer_id: 0,
+            sends_first: false,
+        };
+        let mut client =
+            SessionClient::with_channel(StalledChannel, peer).with_checkpoints(checkpoints);
+        // The client fails after the relay opened session 7, before its first message
+        match client.join(-1, None) {
+            Err(SessionError::Protocol(PeerError::Protocol(_))) => {}
+            _ => panic!("Joined without a first message"),
+        }
+
+        // The restarted client finds the session it registered to
+        let checkpoints = Checkpoints::open(dir.path(), "password").unwrap();
+        let restored: SessionClient<WaitingPeer, StalledChannel> =
+            SessionClient::restore_with(StalledChannel, checkpoints, 7).unwrap();
+        assert_eq!(restored.session_id(), Some(7));
+    }
+}
@@ -0,0 +1,100 @@
+/// Subscription to the relay messages of a session over the Tendermint websocket RPC.
+/// The application indexes each delivered relay message by session, round and peer,
+/// so messages are pushed to the client as soon as the block including them is committed
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde_json::json;
+use tungstenite::{connect, Message};
+
+use mmpc_server_common::codec::{Codec, Encoding};
+use mmpc_server_common::common::{RELAY_EVENT_TYPE, SESSION_EVENT_KEY};
+use mmpc_server_common::{ClientMessage, SessionIdentifier};
+
+pub struct Subscription {
+    receiver: Receiver<ClientMessage>,
+}
+
+impl Subscription {
+    /// Subscribes to the relay messages of the session,
+    /// returns None if unable to connect to the websocket of the node
+    pub fn new(
+        server_addr: &tendermint::net::Address,
+        session_id: SessionIdentifier,
+    ) -> Option<Subscription> {
+        let url = match server_addr {
+            tendermint::net::Address::Tcp { host, port, .. } => {
+                format!("ws://{}:{}/websocket", host, port)
+            }
+            _ => return None,
+        };
+        let (mut socket, _) = match connect(url.as_str()) {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("Unable to subscribe to {}: {}", url, err);
+                return None;
+            }
+        };
+        let query = format!(
+            "tm.event='Tx' AND {}.{}='{}'",
+            RELAY_EVENT_TYPE, SESSION_EVENT_KEY, session_id
+        );
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": "0",
+            "method": "subscribe",
+            "params": { "query": query },
+        });
+        if let Err(err) = socket.write_message(Message::Text(request.to_string())) {
+            warn!("Unable to subscribe to {}: {}", url, err);
+            return None;
+        }
+        debug!("Subscribed to {}", query);
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || loop {
+            let message = match socket.read_message() {
+                Ok(message) => message,
+                Err(err) => {
+                    warn!("Subscription closed: {}", err);
+                    return;
+                }
+            };
+            if let Message::Text(event) = message {
+                if let Some(client_message) = relayed_message(&event) {
+                    // The client dropped the subscription
+                    if sender.send(client_message).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Some(Subscription { receiver })
+    }
+
+    /// Waits up to the timeout for a message, and returns all messages received.
+    /// Returns None if the subscription was closed
+    pub fn receive(&self, timeout: Duration) -> Option<Vec<ClientMessage>> {
+        let first = match self.receiver.recv_timeout(timeout) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => return Some(Vec::new()),
+            Err(RecvTimeoutError::Disconnected) => return None,
+        };
+        let mut messages = vec![first];
+        messages.extend(self.receiver.try_iter());
+        Some(messages)
+    }
+}
+
+// The relay message of a Tx event, which holds the base64 encoded transaction
+// in the encoding its sender registered with
+fn relayed_message(event: &str) -> Option<ClientMessage> {
+    let event: serde_json::Value = serde_json::from_str(event).ok()?;
+    let tx = event["result"]["data"]["value"]["TxResult"]["tx"].as_str()?;
+    let tx = subtle_encoding::base64::decode(tx).ok()?;
+    let client_message: ClientMessage = Encoding::detect(&tx).decode(&tx)?;
+    client_message.relay_message.as_ref()?;
+    Some(client_message)
+}
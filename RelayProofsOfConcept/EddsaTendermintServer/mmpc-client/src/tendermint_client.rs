@@ -1,331 +1,517 @@
-use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::thread;
+use std::time::Duration;
 
-use crate::peer::{Peer, ProtocolDataManager, MAX_CLIENTS};
+use crate::channel::{
+    add_p2p_messages, BroadcastChannel, ReceivedMessage, RegisterRequest, Registration,
+    RoundMessage,
+};
+use crate::checkpoint::CheckpointError;
+use crate::endpoints::Endpoints;
+use crate::session::SessionError;
+use crate::subscription::Subscription;
 use log::{debug, error, info, warn};
 
+use mmpc_server_common::codec::{Codec, Encoding};
 use mmpc_server_common::common::*;
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::identity::{generate_keypair, identity_key, IdentityKey, Keypair};
 use mmpc_server_common::{
-    ClientMessage, MessagePayload, MissingMessagesRequest, PeerIdentifier, ProtocolIdentifier,
-    RelayMessage, ServerMessage, ServerMessageType, ServerResponse, StoredMessages,
+    ClientMessage, MessagesPage, PageRequest, PeerIdentifier, ProtocolIdentifier, RelayMessage,
+    ServerMessage, ServerResponse, SessionIdentifier, SessionStatus, StoredMessages,
 };
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Serialize};
+use tendermint::abci::transaction::Transaction;
+use tendermint::rpc::endpoint::broadcast::tx_commit;
+use tendermint::rpc::Client;
 
-pub struct SessionClient<T>
-where
-    T: Peer,
-{
-    pub state: State<T>,
-    pub client: tendermint::rpc::Client,
+// What the relay answered to a transaction
+enum Delivery<T> {
+    // The response of the relay to the delivered transaction
+    Delivered(T),
+    // The code the transaction was rejected with
+    Rejected(u32),
 }
 
-impl<T: Peer> SessionClient<T> {
-    pub fn new(
-        client_addr: SocketAddr,
-        server_addr: &tendermint::net::Address,
-        client_index: u32,
-        capacity: u32,
-        message: Vec<u8>,
-    ) -> SessionClient<T> {
-        let protocol_id = 1;
-        SessionClient {
-            state: State::new(protocol_id, capacity, client_addr, client_index, message),
-            client: tendermint::rpc::Client::new(server_addr).unwrap(),
-        }
-    }
+// Returns the code the server rejected a transaction with, if it was rejected
+fn rejection_code(response: &tx_commit::Response) -> Option<u32> {
+    [response.check_tx.code, response.deliver_tx.code]
+        .iter()
+        .find(|code| code.is_err())
+        .map(|code| code.value())
 }
 
-impl<T: Peer> SessionClient<T> {
-    pub fn query(&self) -> BTreeMap<u32, ClientMessage> {
-        let current_step = self.state.data_manager.data_holder.current_step();
-        debug!("Current step {}", current_step);
-        let capacity = self.state.data_manager.data_holder.capacity();
-        debug!("Capacity {}", capacity);
-        let mut missing_clients = self
-            .state
-            .stored_messages
-            .get_missing_clients_vector(current_step, capacity);
+/// What the channel checkpoints, the messages of the session are stored on the relay
+#[derive(Serialize, Deserialize)]
+pub struct TendermintCheckpoint {
+    // Hex encoded identity keypair the client registered with
+    identity: String,
+    encoding: Encoding,
+}
 
-        debug!("Missing: {:?}", missing_clients);
+/// Channel over the relay application of a Tendermint cluster. Messages are transactions
+/// signed by the identity key of the peer, committed in blocks and stored by every node
+pub struct TendermintChannel {
+    endpoints: Endpoints,
+    subscription: Option<Subscription>,
+    // Hashes of the transactions committed by this client, a message is only submitted once
+    submitted: HashSet<u64>,
+    // Encoding of the transactions sent by this client, the server keeps the encoding
+    // of the register message for the rest of the session
+    encoding: Encoding,
+    // Identity of this client in the relay session, generated for each run
+    identity: Keypair,
+    session_id: Option<SessionIdentifier>,
+    protocol_id: ProtocolIdentifier,
+    capacity: u32,
+    peer_id: PeerIdentifier,
+    // Identity keys of the peers in the session, by peer identifier
+    peer_keys: BTreeMap<PeerIdentifier, IdentityKey>,
+    stored_messages: StoredMessages,
+}
 
-        // No need to query if nothing is missing
-        if missing_clients.is_empty() {
-            return BTreeMap::new();
+impl TendermintChannel {
+    /// Creates a channel sending its requests to any of the given validator nodes,
+    /// it fails over to another node when the node it uses stops answering
+    pub fn new(endpoints: &[tendermint::net::Address]) -> TendermintChannel {
+        TendermintChannel {
+            endpoints: Endpoints::new(endpoints),
+            subscription: None,
+            submitted: HashSet::new(),
+            encoding: Encoding::default(),
+            identity: generate_keypair(),
+            session_id: None,
+            protocol_id: 0,
+            capacity: 0,
+            peer_id: 0,
+            peer_keys: BTreeMap::new(),
+            stored_messages: StoredMessages::new(),
         }
+    }
 
-        if missing_clients.len() > MAX_CLIENTS {
-            missing_clients.truncate(MAX_CLIENTS);
+    /// Sends transactions in the given encoding rather than JSON
+    pub fn with_encoding(mut self, encoding: Encoding) -> TendermintChannel {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Subscribes to the messages relayed in the registered session,
+    /// if the subscription fails messages are polled with queries
+    pub fn subscribe(&mut self) {
+        if let Some(session_id) = self.session_id {
+            self.subscription = Subscription::new(&self.endpoints.address(), session_id);
         }
-        debug!("Missing requested: {:?}", missing_clients);
+    }
 
-        let request = MissingMessagesRequest {
-            round: current_step,
-            missing_clients: missing_clients,
-        };
-        let tx = serde_json::to_string(&request).unwrap();
-        match self.client.abci_query(None, tx, None, false) {
-            Ok(response) => {
-                let response_log = response.log;
-                let server_response: BTreeMap<u32, ClientMessage> =
-                    match serde_json::from_str(&response_log.to_string()) {
-                        Ok(server_response) => server_response,
-                        Err(_) => BTreeMap::new(),
-                    };
-                server_response
+    /// Queries the status of the registered session
+    pub fn status(&self) -> Option<SessionStatus> {
+        let session_id = self.session_id?;
+        let path = session_status_path(session_id).parse().ok();
+        let request = |client: &Client| client.abci_query(path.clone(), "", None, false);
+        match self.endpoints.request(request) {
+            Some(response) => {
+                if response.code.is_err() {
+                    warn!(
+                        "Status query rejected: {:?}",
+                        RelayError::from_code(response.code.value())
+                    );
+                    return None;
+                }
+                serde_json::from_str(&response.log.to_string()).ok()
             }
-            Err(_) => {
-                warn!("Query not successful, returning empty message");
-                BTreeMap::new()
+            None => {
+                warn!("Status query not successful");
+                None
             }
         }
     }
 
-    pub fn register(&mut self, index: u32, capacity: u32, kg_index: i32) -> ServerMessage {
-        let mut msg = ClientMessage::new();
-        let port = 8080 + index;
-        let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-        // No index to begin with
-        msg.set_register(client_addr, self.state.protocol_id, capacity, kg_index);
-
-        debug!("Register message {:?}", msg);
-        let tx =
-            tendermint::abci::transaction::Transaction::new(serde_json::to_string(&msg).unwrap());
-        let response = self.client.broadcast_tx_commit(tx).unwrap();
-        let server_response = response.clone().deliver_tx.log.unwrap();
-        info!("Registered OK");
-        debug!("ServerResponse {:?}", server_response);
-        let server_response: ServerMessage =
-            serde_json::from_str(&response.deliver_tx.log.unwrap().to_string()).unwrap();
-        debug!("ServerResponse {:?}", server_response);
-        // TODO Add Error checks etc
-        self.state.registered = true;
-        return server_response;
+    /// Fetches the identity keys of the peers registered to the session,
+    /// point-to-point messages are encrypted to these keys
+    pub fn update_peer_keys(&mut self) {
+        if let Some(peer_keys) = self.session_id.and_then(|id| self.peer_keys(id)) {
+            self.peer_keys = peer_keys;
+        }
     }
 
-    pub fn send_message(&self, msg: ClientMessage) -> BTreeMap<u32, ClientMessage> {
-        debug!("Sending message {:?}", msg);
-        let tx =
-            tendermint::abci::transaction::Transaction::new(serde_json::to_string(&msg).unwrap());
-        let server_response = match self.client.broadcast_tx_commit(tx) {
-            Ok(response) => {
-                let server_response = response.clone().deliver_tx.log.unwrap();
-                debug!("ServerResponse {:?}", server_response);
-                let server_response: BTreeMap<u32, ClientMessage> =
-                    serde_json::from_str(&response.deliver_tx.log.unwrap().to_string()).unwrap();
-                server_response
+    // Waits up to the timeout for messages pushed by the subscription and stores them.
+    // Missing messages of the round are queried if none were pushed, or if there is
+    // no subscription. Returns the status of the session if it was aborted,
+    // as no more messages will be relayed
+    fn wait_for_messages(&mut self, round: u32, timeout: Duration) -> Option<SessionStatus> {
+        let mut closed = false;
+        let pushed = match &self.subscription {
+            Some(subscription) => subscription.receive(timeout),
+            None => None,
+        };
+        match pushed {
+            Some(messages) => {
+                if !messages.is_empty() {
+                    self.store_relayed_messages(messages);
+                    return None;
+                }
             }
-            Err(_) => {
-                warn!("Unable to include message in block, returning empty response");
-                BTreeMap::new()
+            None => {
+                if self.subscription.take().is_some() {
+                    warn!("Subscription closed, polling for messages");
+                    closed = true;
+                }
+                thread::sleep(timeout);
             }
-        };
-        return server_response;
+        }
+        let server_response = self.query(round);
+        // The node of the subscription may have failed, the query moved to a healthy one
+        if closed {
+            self.subscribe();
+        }
+        if server_response.is_empty() {
+            return self.status().filter(|status| status.aborted);
+        }
+        self.store_server_response(round, &server_response);
+        None
     }
 
-    // Stores the server response to the stored messages
-    pub fn store_server_response(&mut self, messages: &BTreeMap<u32, ClientMessage>) {
-        let round = self.state.data_manager.data_holder.current_step();
-        for (client_idx, msg) in messages {
-            self.state
-                .stored_messages
-                .update(round, *client_idx, msg.clone());
+    // Messages pushed by the subscription may belong to any round
+    fn store_relayed_messages(&mut self, messages: Vec<ClientMessage>) {
+        for msg in messages {
+            if let Some(relay_message) = &msg.relay_message {
+                let (round, peer_number) = (relay_message.round, relay_message.peer_number);
+                self.stored_messages.update(round, peer_number, msg);
+            }
         }
     }
 
-    pub fn handle_relay_message(&mut self, client_msg: ClientMessage) -> Option<ClientMessage> {
-        let msg = client_msg.relay_message.unwrap();
-        let new_message;
-        let next = self.state.handle_relay_message(msg.clone());
-        match next {
-            Some(next_msg) => {
-                new_message = Some(self.state.generate_relay_message(next_msg.clone()));
-            }
+    // Queries the messages of the round which are not stored yet
+    fn query(&mut self, round: u32) -> BTreeMap<u32, ClientMessage> {
+        let session_id = match self.session_id {
+            Some(session_id) => session_id,
             None => {
-                new_message = Some(ClientMessage::new());
+                warn!("Not registered to a session, nothing to query");
+                return BTreeMap::new();
+            }
+        };
+        debug!("Querying round {}", round);
+        // Peers of a threshold signing session are identified by their key share
+        if self.peer_keys.len() < self.capacity as usize {
+            self.update_peer_keys();
+        }
+        let missing_clients = if self.peer_keys.len() == self.capacity as usize {
+            let peers: Vec<PeerIdentifier> = self.peer_keys.keys().cloned().collect();
+            self.stored_messages.get_missing_peers(round, &peers)
+        } else {
+            self.stored_messages
+                .get_missing_clients_vector(round, self.capacity)
+        };
+
+        debug!("Missing: {:?}", missing_clients);
+
+        // No need to query if nothing is missing
+        if missing_clients.is_empty() {
+            return BTreeMap::new();
+        }
+
+        // The messages of the round are fetched page by page, as many as fit in a response
+        let mut request = PageRequest {
+            peers: missing_clients,
+            after: None,
+            max_bytes: None,
+        };
+        let mut server_response = BTreeMap::new();
+        while let Some(page) = self.messages_page(session_id, round, &request) {
+            server_response.extend(page.messages);
+            request.after = page.next;
+            if request.after.is_none() {
+                break;
             }
         }
-        new_message
+        server_response
     }
 
-    pub fn generate_client_answer(&mut self, msg: ServerMessage) -> Option<ClientMessage> {
-        // let last_message = self.state.last_message.clone();
-        let mut new_message = None;
-        let msg_type = msg.msg_type();
-        match msg_type {
-            ServerMessageType::Response => {
-                let next = self.state.handle_server_response(&msg);
-                match next {
-                    Ok(next_msg) => {
-                        new_message = Some(next_msg.clone());
-                    }
-                    Err(_) => {
-                        error!("Error in handle_server_response");
-                    }
+    // Queries a page of stored messages, returns None if the query failed
+    fn messages_page(
+        &self,
+        session_id: SessionIdentifier,
+        round: u32,
+        request: &PageRequest,
+    ) -> Option<MessagesPage> {
+        let data = serde_json::to_string(request).unwrap();
+        let path = session_round_path(session_id, round).parse().ok();
+        let request = |client: &Client| client.abci_query(path.clone(), data.clone(), None, false);
+        match self.endpoints.request(request) {
+            Some(response) => {
+                if response.code.is_err() {
+                    warn!(
+                        "Query rejected: {:?}",
+                        RelayError::from_code(response.code.value())
+                    );
+                    return None;
                 }
+                serde_json::from_str(&response.log.to_string()).ok()
             }
-            // TODO: better cases separation, this is a placeholder
-            ServerMessageType::RelayMessage => {
-                new_message = Some(ClientMessage::new());
+            None => {
+                warn!("Query not successful, returning empty message");
+                None
             }
-            ServerMessageType::Abort => {
-                info!("Got abort message");
-                //Ok(MessageProcessResult::NoMessage)
-                new_message = Some(ClientMessage::new());
+        }
+    }
+
+    // Queries the identity keys of the peers registered to the session
+    fn peer_keys(
+        &self,
+        session_id: SessionIdentifier,
+    ) -> Option<BTreeMap<PeerIdentifier, IdentityKey>> {
+        let path = session_peers_path(session_id).parse().ok();
+        let request = |client: &Client| client.abci_query(path.clone(), "", None, false);
+        match self.endpoints.request(request) {
+            Some(response) => {
+                if response.code.is_err() {
+                    warn!(
+                        "Peer keys query rejected: {:?}",
+                        RelayError::from_code(response.code.value())
+                    );
+                    return None;
+                }
+                let peer_keys = serde_json::from_str(&response.log.to_string());
+                if peer_keys.is_err() {
+                    warn!("Unable to parse peer keys");
+                }
+                peer_keys.ok()
             }
-            ServerMessageType::Undefined => {
-                new_message = Some(ClientMessage::new());
-                //panic!("Got undefined message: {:?}",msg);
+            None => {
+                warn!("Peer keys query not successful");
+                None
             }
-        };
-        new_message
+        }
     }
-}
 
-/// Inner client state, responsible for parsing server responses and producing the next message
-pub struct State<T>
-where
-    T: Peer,
-{
-    pub registered: bool,
-    pub protocol_id: ProtocolIdentifier,
-    pub client_addr: SocketAddr,
-    pub data_manager: ProtocolDataManager<T>,
-    pub last_message: ClientMessage,
-    pub bc_dests: Vec<ProtocolIdentifier>,
-    pub stored_messages: StoredMessages,
-}
+    // The peer the identity key of this client is registered as in the session
+    fn registered_as(&self, session_id: SessionIdentifier) -> Option<PeerIdentifier> {
+        let own_key = identity_key(&self.identity);
+        self.peer_keys(session_id)?
+            .into_iter()
+            .find(|(_, key)| *key == own_key)
+            .map(|(peer_id, _)| peer_id)
+    }
 
-impl<T: Peer> State<T> {
-    pub fn new(
-        protocol_id: ProtocolIdentifier,
-        capacity: u32,
-        client_addr: SocketAddr,
-        client_index: u32,
-        message: Vec<u8>,
-    ) -> State<T>
-    where
-        T: Peer,
-    {
-        let data_m: ProtocolDataManager<T> =
-            ProtocolDataManager::new(capacity, message, client_index);
-        State {
-            registered: false,
-            protocol_id,
-            client_addr,
-            last_message: ClientMessage::new(),
-            bc_dests: vec![0],
-            data_manager: data_m,
-            stored_messages: StoredMessages::new(),
-        }
+    // Broadcasts the transaction through the first node that answers, and reads the response
+    // of the relay from the log of the delivered transaction. A node whose response can not
+    // be read is failed over like a node which does not answer, the next node rejects
+    // the transaction if the failed node committed it
+    fn broadcast_tx<T: DeserializeOwned>(
+        &self,
+        tx: &Transaction,
+    ) -> Result<Delivery<T>, SessionError> {
+        let request = |client: &Client| {
+            let response = client
+                .broadcast_tx_commit(tx.clone())
+                .map_err(|err| err.to_string())?;
+            if let Some(code) = rejection_code(&response) {
+                return Ok(Delivery::Rejected(code));
+            }
+            let log = response
+                .deliver_tx
+                .log
+                .ok_or_else(|| String::from("Delivered transaction without a response"))?;
+            serde_json::from_str(&log.to_string())
+                .map(Delivery::Delivered)
+                .map_err(|err| format!("Unable to read response: {}", err))
+        };
+        self.endpoints
+            .request(request)
+            .ok_or(SessionError::Unavailable)
     }
-}
 
-impl<T: Peer> State<T> {
-    fn handle_relay_message(&mut self, relay_msg: RelayMessage) -> Option<MessagePayload> {
-        // parse relay message
-        let from = relay_msg.peer_number;
-        if from == self.data_manager.data_holder.peer_id() {
-            debug!("-------self message accepted ------\n ");
+    // Sends the message, and returns the messages of the round stored by the server.
+    // A message committed before is not sent again
+    fn send_message(
+        &mut self,
+        mut msg: ClientMessage,
+    ) -> Result<BTreeMap<u32, ClientMessage>, SessionError> {
+        // The server only relays messages signed by the identity this client registered with
+        msg.sign(&self.identity);
+        debug!("Sending message {:?}", msg);
+        let tx = Transaction::new(self.encoding.encode(&msg));
+        let mut hasher = DefaultHasher::new();
+        tx.as_bytes().hash(&mut hasher);
+        let tx_hash = hasher.finish();
+        if self.submitted.contains(&tx_hash) {
+            debug!("Message already submitted");
+            return Ok(BTreeMap::new());
+        }
+        match self.broadcast_tx(&tx)? {
+            Delivery::Delivered(server_response) => {
+                self.submitted.insert(tx_hash);
+                debug!("ServerResponse {:?}", server_response);
+                Ok(server_response)
+            }
+            // A rejected message is not stored, unless it is rejected as a duplicate
+            // of this message, committed through a node which failed to answer
+            Delivery::Rejected(code) => match RelayError::from_code(code) {
+                Some(RelayError::DuplicateMessage) => {
+                    self.submitted.insert(tx_hash);
+                    Ok(BTreeMap::new())
+                }
+                err => {
+                    warn!("Message rejected: {:?}", err);
+                    Err(SessionError::Rejected(
+                        err.unwrap_or(RelayError::InvalidTransaction),
+                    ))
+                }
+            },
         }
-        let payload = relay_msg.message;
-        self.data_manager.get_next_message(from, payload)
     }
 
-    fn generate_relay_message(&self, payload: MessagePayload) -> ClientMessage {
-        let _msg = ClientMessage::new();
-        // create relay message
-        let mut relay_message = RelayMessage::new(
-            self.data_manager.data_holder.peer_id(),
-            self.protocol_id,
-            self.client_addr,
-        );
-        let to: Vec<u32> = self.bc_dests.clone();
-
-        let mut client_message = ClientMessage::new();
-
-        relay_message.set_message_params(to, String::from(payload));
-        client_message.relay_message = Some(relay_message);
-        client_message
+    // Stores the messages of the round returned by the server
+    fn store_server_response(&mut self, round: u32, messages: &BTreeMap<u32, ClientMessage>) {
+        for (client_idx, msg) in messages {
+            self.stored_messages.update(round, *client_idx, msg.clone());
+        }
     }
+}
 
-    fn handle_register_response(&mut self, peer_id: PeerIdentifier) -> Result<ClientMessage, ()> {
-        info!("Peer identifier: {}", peer_id);
-        // Set the session parameters
-        let message = self
-            .data_manager
-            .initialize_data(peer_id)
-            .unwrap_or_else(|| panic!("failed to initialize"));
-        Ok(self.generate_relay_message(message.clone()))
-    }
+impl BroadcastChannel for TendermintChannel {
+    type Checkpoint = TendermintCheckpoint;
 
-    fn get_last_message(&self) -> Option<ClientMessage> {
-        let last_msg = self.last_message.clone();
-        return Some(last_msg.clone());
-    }
+    /// Registers to the relay session with the given identifier,
+    /// or opens a new session if no identifier is given.
+    /// Fails with the error the server rejected the registration with.
+    /// A registration retried on another node may be rejected if the failed node committed it,
+    /// the client is then registered as the peer its identity key is registered as
+    fn register(&mut self, request: &RegisterRequest) -> Result<Registration, SessionError> {
+        self.protocol_id = request.protocol_id;
+        self.capacity = request.capacity;
+        let mut msg = ClientMessage::new();
+        msg.set_register(
+            identity_key(&self.identity),
+            request.session_id,
+            request.protocol_id,
+            request.capacity,
+            request.kg_index,
+            request.share_count,
+        );
+        msg.sign(&self.identity);
 
-    fn handle_error_response(&mut self, err_msg: &str) -> Result<ClientMessage, &'static str> {
-        match err_msg {
-            resp if resp == String::from(NOT_YOUR_TURN) => {
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(msg) => {
-                        return Ok(msg.clone());
-                    }
-                    None => {
-                        panic!("No message to resend");
+        debug!("Register message {:?}", msg);
+        let tx = Transaction::new(self.encoding.encode(&msg));
+        let registration = match self.broadcast_tx::<ServerMessage>(&tx)? {
+            Delivery::Rejected(code) => {
+                let err = RelayError::from_code(code).unwrap_or(RelayError::CantRegister);
+                let registered = match request.session_id {
+                    Some(session_id) if err == RelayError::CantRegister => {
+                        self.registered_as(session_id).map(|peer_id| Registration {
+                            session_id,
+                            peer_id,
+                        })
                     }
-                }
-            }
-            not_initialized_resp if not_initialized_resp == String::from(STATE_NOT_INITIALIZED) => {
-                debug!("Not initialized, sending again");
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(_) => {
-                        // If protocol is not initialized, wait for a message from the server
-                        return Ok(ClientMessage::new());
+                    _ => None,
+                };
+                match registered {
+                    Some(registration) => {
+                        info!("Already registered as peer {}", registration.peer_id);
+                        registration
                     }
                     None => {
-                        panic!("No message to resend");
+                        error!("Registration rejected: {}", err);
+                        return Err(SessionError::Register(err));
                     }
                 }
             }
-            _ => {
-                warn!("didn't handle error correctly");
-                return Err("error response handling failed");
+            Delivery::Delivered(server_response) => {
+                debug!("ServerResponse {:?}", server_response);
+                match server_response.response {
+                    Some(ServerResponse::Register(session_id, peer_id)) => Registration {
+                        session_id,
+                        peer_id,
+                    },
+                    _ => return Err(SessionError::Register(RelayError::CantRegister)),
+                }
             }
+        };
+        self.session_id = Some(registration.session_id);
+        self.peer_id = registration.peer_id;
+        // Messages of the session are pushed to the client once relayed
+        self.subscribe();
+        Ok(registration)
+    }
+
+    fn broadcast(&mut self, message: &RoundMessage) -> Result<(), SessionError> {
+        let session_id = self
+            .session_id
+            .ok_or(SessionError::Register(RelayError::UnknownSession))?;
+        let mut relay_message =
+            RelayMessage::new(self.peer_id, session_id, self.protocol_id, message.round);
+        relay_message.set_message_params(vec![0], message.payload.clone());
+        // All peers are registered once messages are relayed, so their keys are known
+        if !message.p2p.is_empty() && self.peer_keys.len() < self.capacity as usize {
+            self.update_peer_keys();
         }
+        add_p2p_messages(&mut relay_message, &message.p2p, &self.peer_keys)?;
+        let mut client_message = ClientMessage::new();
+        client_message.relay_message = Some(relay_message);
+        let server_response = self.send_message(client_message)?;
+        self.store_server_response(message.round, &server_response);
+        Ok(())
     }
 
-    fn handle_server_response(
+    fn receive(
         &mut self,
-        msg: &ServerMessage,
-    ) -> Result<ClientMessage, &'static str> {
-        let server_response = msg.response.clone().unwrap();
-        match server_response {
-            ServerResponse::Register(peer_id) => {
-                let client_message = self.handle_register_response(peer_id);
-                match client_message {
-                    Ok(_msg) => {
-                        debug!("sending peers first message: {:#?}", _msg);
-                        return Ok(_msg.clone());
-                    }
-                    Err(_) => {
-                        error!("error occured");
-                        return Ok(ClientMessage::new());
-                    }
-                }
+        round: u32,
+        timeout: Duration,
+    ) -> Result<BTreeMap<PeerIdentifier, ReceivedMessage>, SessionError> {
+        if self.stored_messages.get_number_messages(round) < self.capacity as usize {
+            if let Some(status) = self.wait_for_messages(round, timeout) {
+                return Err(SessionError::Aborted(status));
             }
-            ServerResponse::ErrorResponse(err_msg) => {
-                let err_msg_slice: &str = &err_msg[..];
-                let msg = self.handle_error_response(err_msg_slice);
-                match msg {
-                    Ok(_msg) => return Ok(_msg),
-                    Err(_) => {
-                        error!("error occured");
-                        return Ok(ClientMessage::new());
-                    }
-                }
-            }
-            ServerResponse::NoResponse => unimplemented!(),
         }
+        let messages = self
+            .stored_messages
+            .get_messages_vector_client_message(round)
+            .into_iter()
+            .filter_map(|msg| msg.relay_message)
+            .map(|msg| {
+                // Only the point-to-point message to this peer can be decrypted
+                let p2p_payload = msg.p2p_message_to(self.peer_id, &self.identity);
+                let message = ReceivedMessage {
+                    from: msg.peer_number,
+                    payload: msg.message,
+                    p2p_payload,
+                };
+                (message.from, message)
+            })
+            .collect();
+        Ok(messages)
+    }
+
+    fn checkpoint(&self) -> TendermintCheckpoint {
+        TendermintCheckpoint {
+            identity: hex::encode(&self.identity.to_bytes()[..]),
+            encoding: self.encoding,
+        }
+    }
+
+    fn restore(
+        &mut self,
+        request: &RegisterRequest,
+        peer_id: Option<PeerIdentifier>,
+        checkpoint: TendermintCheckpoint,
+    ) -> Result<(), CheckpointError> {
+        self.identity = hex::decode(&checkpoint.identity)
+            .ok()
+            .and_then(|bytes| Keypair::from_bytes(&bytes).ok())
+            .ok_or_else(|| {
+                CheckpointError::Format(serde_json::Error::custom("invalid identity"))
+            })?;
+        self.encoding = checkpoint.encoding;
+        self.protocol_id = request.protocol_id;
+        self.capacity = request.capacity;
+        if let Some(peer_id) = peer_id {
+            self.session_id = request.session_id;
+            self.peer_id = peer_id;
+            self.subscribe();
+        }
+        Ok(())
     }
 }
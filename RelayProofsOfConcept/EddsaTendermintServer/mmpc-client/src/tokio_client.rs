@@ -0,0 +1,770 @@
+//! Channel over the Tokio relay server, which forwards the messages of each of its sessions
+//! to the peers of the session once the session is full. A peer joins a session
+//! by its identifier, or the open session of its protocol and capacity.
+//! Sessions relay in rounds by default: peers send their message of a round at once,
+//! and the server relays the messages of the round together once all are in.
+//! Sessions relaying in turns forward each message as it arrives, and peers send one
+//! message at a time in the order of their peer numbers.
+//! The server only relays opaque payloads, so each payload carries a relay message
+//! of the session with its round and point-to-point messages.
+//! Each peer registers with its identity key and signs every message it sends
+//! with it, and the server only relays the messages signed by the key registered
+//! for the peer. The peers announce their keys to each other in a round of their own
+//! before the protocol starts. A peer which loses its connection reconnects within
+//! the reconnect window, with the token the server gave it on joining, and the server
+//! sends it the messages it missed. The token is checkpointed with the messages
+//! the peer received, so a restarted client rejoins its session as well,
+//! as long as it restarts within the grace period of the server.
+//! The connection runs over TLS if the server does, and authenticates the client
+//! with its certificate if the server requires mutual TLS
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
+
+use crate::channel::{
+    add_p2p_messages, announced_peer_id, Announcement, BroadcastChannel, ReceivedMessage,
+    RegisterRequest, Registration, RoundMessage,
+};
+use crate::checkpoint::CheckpointError;
+use crate::session::SessionError;
+use mmpc_server_common::codec::{Codec, Encoding};
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::identity::{generate_keypair, identity_key, IdentityKey, Keypair};
+use mmpc_server_common::{
+    PeerIdentifier, ProtocolIdentifier, RelayMessage, RelaySessionState, SessionIdentifier,
+    SessionStatus,
+};
+use relay_server_common::error::ServerError;
+use relay_server_common::tls::rustls::{ClientConfig, ClientSession, StreamOwned};
+use relay_server_common::tls::webpki::{DNSName, DNSNameRef};
+use relay_server_common::{ClientMessage, RelayMode, ServerMessage, ServerResponse};
+
+const READ_BUFFER_SIZE: usize = 4096;
+// The grace period of the server by default
+const RECONNECT_WINDOW: Duration = Duration::from_secs(30);
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+// What a peer sends as the payload of a message relayed by the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum TunnelMessage {
+    Identity(Announcement),
+    Relay(RelayMessage),
+}
+
+// The connection to the server, in TLS or in the clear
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientSession, TcpStream>>),
+}
+
+impl Connection {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// What the channel checkpoints, for a restarted client to take its place
+/// in the session back. The server relays the messages after the ones received
+#[derive(Serialize, Deserialize)]
+pub struct TokioCheckpoint {
+    // Hex encoded identity keypair the client announced
+    identity: String,
+    encoding: Encoding,
+    mode: RelayMode,
+    session_id: Option<SessionIdentifier>,
+    token: Option<String>,
+    number: u32,
+    relayed: u64,
+    sent: u32,
+    peer_keys: BTreeMap<PeerIdentifier, IdentityKey>,
+    // Messages of the rounds the peer did not handle yet
+    messages: BTreeMap<u32, BTreeMap<PeerIdentifier, RelayMessage>>,
+    outbox: VecDeque<TunnelMessage>,
+}
+
+pub struct TokioChannel {
+    stream: Connection,
+    // TLS config and name of the server, when the server runs over TLS
+    tls: Option<(Arc<ClientConfig>, DNSName)>,
+    // Addresses of the server, to reconnect to
+    addrs: Vec<SocketAddr>,
+    // How long to try reconnecting for once the connection is lost
+    reconnect_window: Duration,
+    // Bytes read which do not make a whole message yet
+    buffer: Vec<u8>,
+    // Encoding of the messages of the connection, the server takes the encoding
+    // of the register message
+    encoding: Encoding,
+    // Identity of this client in the session, generated for each run
+    identity: Keypair,
+    // The session this peer joined, and the token to reconnect to it with
+    session_id: Option<SessionIdentifier>,
+    token: Option<String>,
+    protocol_id: ProtocolIdentifier,
+    capacity: u32,
+    // Number the server registered this peer as, which sets its turn
+    number: u32,
+    // Peers send their messages once the session is full
+    started: bool,
+    mode: RelayMode,
+    // Identifier this peer takes part in the protocol as
+    peer_id: PeerIdentifier,
+    // Identity keys of the peers in the session, by peer identifier
+    peer_keys: BTreeMap<PeerIdentifier, IdentityKey>,
+    // Messages of the session by round and sender
+    messages: BTreeMap<u32, BTreeMap<PeerIdentifier, RelayMessage>>,
+    // Number of messages relayed in the session, which tells whose turn it is
+    relayed: u64,
+    // Number of messages of this peer relayed, the relay round of its next message
+    sent: u32,
+    // Messages of this peer waiting for its turn, the first one is sent
+    // when it is its turn and stays queued until the server relays it
+    outbox: VecDeque<TunnelMessage>,
+    in_flight: bool,
+    // The server refused the message in flight, it is sent again once a message is relayed
+    refused: bool,
+    // The last round this peer waited for
+    round: u32,
+    // Set once the server aborted the session, messages read before the abort
+    // are still handed out
+    aborted: Option<SessionStatus>,
+}
+
+impl TokioChannel {
+    /// Connects to the relay server at the given address
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TokioChannel> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let stream = TcpStream::connect(&addrs[..])?;
+        stream.set_nodelay(true)?;
+        Ok(TokioChannel {
+            stream: Connection::Plain(stream),
+            tls: None,
+            addrs,
+            reconnect_window: RECONNECT_WINDOW,
+            buffer: Vec::new(),
+            encoding: Encoding::default(),
+            identity: generate_keypair(),
+            session_id: None,
+            token: None,
+            protocol_id: 0,
+            capacity: 0,
+            number: 0,
+            started: false,
+            mode: RelayMode::Rounds,
+            peer_id: 0,
+            peer_keys: BTreeMap::new(),
+            messages: BTreeMap::new(),
+            relayed: 0,
+            sent: 0,
+            outbox: VecDeque::new(),
+            in_flight: false,
+            refused: false,
+            round: 0,
+            aborted: None,
+        })
+    }
+
+    /// Sends messages in the given encoding rather than JSON
+    pub fn with_encoding(mut self, encoding: Encoding) -> TokioChannel {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Joins sessions relaying in the given mode rather than in rounds
+    pub fn with_mode(mut self, mode: RelayMode) -> TokioChannel {
+        self.mode = mode;
+        self
+    }
+
+    /// Runs the connection over TLS with the given config, checking the certificate
+    /// of the server against the server name. The config authenticates the client
+    /// as well if it has a client certificate
+    pub fn with_tls(mut self, config: ClientConfig, server_name: &str) -> io::Result<TokioChannel> {
+        let server_name = DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid server name"))?;
+        self.tls = Some((Arc::new(config), server_name.to_owned()));
+        let stream = self.stream.tcp().try_clone()?;
+        self.stream = self.open(stream);
+        Ok(self)
+    }
+
+    // Runs the TLS handshake over a new connection to the server, if the server runs over TLS
+    fn open(&self, stream: TcpStream) -> Connection {
+        match &self.tls {
+            Some((config, server_name)) => {
+                let session = ClientSession::new(config, server_name.as_ref());
+                Connection::Tls(Box::new(StreamOwned::new(session, stream)))
+            }
+            None => Connection::Plain(stream),
+        }
+    }
+
+    /// Tries reconnecting for the given window once the connection is lost,
+    /// which should not be longer than the grace period of the server
+    pub fn with_reconnect_window(mut self, reconnect_window: Duration) -> TokioChannel {
+        self.reconnect_window = reconnect_window;
+        self
+    }
+
+    // In turns peers send in the order of their numbers, and every peer receives every message
+    fn is_my_turn(&self) -> bool {
+        if !self.started || self.number == 0 {
+            return false;
+        }
+        match self.mode {
+            RelayMode::Rounds => true,
+            RelayMode::Turns => {
+                self.relayed % u64::from(self.capacity) == u64::from(self.number - 1)
+            }
+        }
+    }
+
+    // Signs the message with the identity of this peer, which the server checks
+    // against the key the peer registered with
+    fn send(&mut self, mut msg: ClientMessage) -> Result<(), SessionError> {
+        msg.sign(&self.identity);
+        let bytes = self.encoding.encode(&msg);
+        self.stream.write_all(&bytes).map_err(|err| {
+            warn!("Unable to send to the relay: {}", err);
+            SessionError::Unavailable
+        })
+    }
+
+    // Takes the place of this peer back with its token, over a new connection.
+    // Messages in flight are sent again, and the server sends the messages
+    // after the ones relayed to this peer
+    fn send_reconnect(&mut self) -> Result<SessionIdentifier, SessionError> {
+        let (session_id, token) = match (self.session_id, self.token.clone()) {
+            (Some(session_id), Some(token)) => (session_id, token),
+            _ => return Err(SessionError::Unavailable),
+        };
+        self.buffer.clear();
+        self.in_flight = false;
+        let mut msg = ClientMessage::new();
+        msg.reconnect(session_id, self.number, token, self.relayed);
+        self.send(msg)?;
+        Ok(session_id)
+    }
+
+    // Connects to the server again once the connection is lost, and reconnects
+    // to the session of this peer
+    fn reconnect(&mut self) -> Result<(), SessionError> {
+        if self.session_id.is_none() || self.token.is_none() {
+            return Err(SessionError::Unavailable);
+        }
+        let deadline = Instant::now() + self.reconnect_window;
+        while Instant::now() < deadline {
+            thread::sleep(RECONNECT_INTERVAL);
+            let connected = TcpStream::connect(&self.addrs[..])
+                .and_then(|stream| stream.set_nodelay(true).map(|_| stream));
+            match connected {
+                Ok(stream) => self.stream = self.open(stream),
+                Err(err) => {
+                    debug!("Unable to reconnect to the relay: {}", err);
+                    continue;
+                }
+            }
+            if let Ok(session_id) = self.send_reconnect() {
+                info!("Reconnected to session {}", session_id);
+                return Ok(());
+            }
+        }
+        error!("Unable to reconnect to the relay");
+        Err(SessionError::Unavailable)
+    }
+
+    // Sends the first queued message if it is the turn of this peer
+    fn flush(&mut self) -> Result<(), SessionError> {
+        if self.in_flight || self.refused || !self.is_my_turn() {
+            return Ok(());
+        }
+        let payload = match self.outbox.front() {
+            Some(message) => serde_json::to_string(message).unwrap(),
+            None => return Ok(()),
+        };
+        let mut relay_message =
+            relay_server_common::RelayMessage::new(self.number, self.protocol_id);
+        relay_message.set_message_params((1..=self.capacity).collect(), payload);
+        relay_message.set_round(self.sent);
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(relay_message);
+        if self.send(msg).is_err() {
+            return self.reconnect();
+        }
+        self.in_flight = true;
+        Ok(())
+    }
+
+    // Reads from the server for up to the timeout, handles the messages read
+    // and sends the next queued message when it is the turn of this peer
+    fn pump(&mut self, timeout: Option<Duration>) -> Result<(), SessionError> {
+        self.flush()?;
+        let mut chunk = [0; READ_BUFFER_SIZE];
+        let read = self
+            .stream
+            .tcp()
+            .set_read_timeout(timeout)
+            .and_then(|_| self.stream.read(&mut chunk));
+        match read {
+            Ok(0) => {
+                self.check_aborted()?;
+                warn!("The relay closed the connection");
+                return self.reconnect();
+            }
+            Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Ok(());
+            }
+            Err(err) => {
+                warn!("Unable to read from the relay: {}", err);
+                return self.reconnect();
+            }
+        }
+        loop {
+            let decoded = self.encoding.decode_from(&self.buffer).map_err(|err| {
+                error!("Invalid message from the relay: {}", err);
+                SessionError::Unavailable
+            })?;
+            let (msg, len): (ServerMessage, usize) = match decoded {
+                Some(decoded) => decoded,
+                None => break,
+            };
+            self.buffer.drain(..len);
+            self.handle(msg)?;
+        }
+        self.flush()
+    }
+
+    // The server aborts the session once any peer leaves it, which the first peer
+    // to finish does, so the abort only fails the rounds still missing messages
+    fn check_aborted(&self) -> Result<(), SessionError> {
+        match &self.aborted {
+            Some(status) => Err(SessionError::Aborted(status.clone())),
+            None => Ok(()),
+        }
+    }
+
+    // Handles a message of the server
+    fn handle(&mut self, msg: ServerMessage) -> Result<(), SessionError> {
+        if msg.abort.is_some() {
+            warn!("The relay aborted the session");
+            self.aborted = Some(SessionStatus {
+                state: RelaySessionState::Aborted,
+                protocol_id: self.protocol_id,
+                capacity: self.capacity,
+                registered: self.capacity,
+                round: self.round,
+                aborted: true,
+                blame: Vec::new(),
+            });
+            return Ok(());
+        }
+        if let Some(relay_message) = msg.relay_message {
+            self.relayed += 1;
+            self.refused = false;
+            if relay_message.peer_number == self.number {
+                self.outbox.pop_front();
+                self.in_flight = false;
+                self.sent += 1;
+            }
+            match serde_json::from_str(&relay_message.message) {
+                Ok(TunnelMessage::Identity(announcement)) => {
+                    self.peer_keys
+                        .insert(announcement.peer_id, announcement.identity_key);
+                }
+                Ok(TunnelMessage::Relay(message)) => {
+                    self.messages
+                        .entry(message.round)
+                        .or_default()
+                        .insert(message.peer_number, message);
+                }
+                Err(err) => warn!(
+                    "Invalid message from peer {}: {}",
+                    relay_message.peer_number, err
+                ),
+            }
+            return Ok(());
+        }
+        match &msg.response {
+            Some(ServerResponse::Register(number)) if *number > 0 => {
+                debug!("Session {:?} is full", self.session_id);
+                self.number = *number;
+                self.started = true;
+                Ok(())
+            }
+            Some(ServerResponse::Joined(session_id, number, token)) => {
+                self.session_id = Some(*session_id);
+                self.number = *number;
+                self.token = Some(token.clone());
+                Ok(())
+            }
+            Some(ServerResponse::ErrorResponse(err)) => match ServerError::from_response(err) {
+                Some(ServerError::NotAPeer) => Err(SessionError::Register(RelayError::NotAPeer)),
+                Some(ServerError::CantRegister) => {
+                    Err(SessionError::Register(RelayError::CantRegister))
+                }
+                Some(ServerError::UnknownSession) => {
+                    Err(SessionError::Register(RelayError::UnknownSession))
+                }
+                Some(ServerError::InvalidSignature) => {
+                    error!("The relay refused the signature of this peer");
+                    Err(SessionError::Rejected(RelayError::InvalidSignature))
+                }
+                Some(ServerError::CantReconnect) => {
+                    error!("The relay did not keep the place of this peer");
+                    Err(SessionError::Unavailable)
+                }
+                Some(ServerError::TooManyConnections) => {
+                    error!("The relay refused the connection: {}", err);
+                    Err(SessionError::Unavailable)
+                }
+                error => {
+                    if error != Some(ServerError::NotYourTurn) {
+                        warn!("Message refused: {}", err);
+                    }
+                    self.in_flight = false;
+                    self.refused = true;
+                    Ok(())
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl BroadcastChannel for TokioChannel {
+    type Checkpoint = TokioCheckpoint;
+
+    /// Registers to the session of the request, or to the open session of its protocol
+    /// and capacity, and returns once the server numbered this peer.
+    /// The identity key of the peer is announced once the session is full
+    fn register(&mut self, request: &RegisterRequest) -> Result<Registration, SessionError> {
+        self.protocol_id = request.protocol_id;
+        self.capacity = request.capacity;
+        let mut msg = ClientMessage::new();
+        match request.session_id {
+            Some(session_id) => msg.join(
+                identity_key(&self.identity),
+                session_id,
+                request.protocol_id,
+                request.capacity,
+            ),
+            None => msg.register(
+                identity_key(&self.identity),
+                request.protocol_id,
+                request.capacity,
+            ),
+        }
+        msg.set_mode(self.mode);
+        self.send(msg)?;
+        let session_id = loop {
+            if let Some(session_id) = self.session_id {
+                break session_id;
+            }
+            self.check_aborted()?;
+            self.pump(None)?;
+        };
+        self.peer_id = announced_peer_id(self.number, request.kg_index);
+        info!("Joined session {} as peer {}", session_id, self.number);
+        self.outbox.push_back(TunnelMessage::Identity(Announcement {
+            peer_id: self.peer_id,
+            identity_key: identity_key(&self.identity),
+        }));
+        Ok(Registration {
+            session_id,
+            peer_id: self.peer_id,
+        })
+    }
+
+    fn broadcast(&mut self, message: &RoundMessage) -> Result<(), SessionError> {
+        if self.number == 0 {
+            return Err(SessionError::Register(RelayError::NotAPeer));
+        }
+        let relayed = self
+            .messages
+            .get(&message.round)
+            .map_or(false, |messages| messages.contains_key(&self.peer_id));
+        let queued = self.outbox.iter().any(|queued| match queued {
+            TunnelMessage::Relay(queued) => queued.round == message.round,
+            _ => false,
+        });
+        if relayed || queued {
+            debug!("Message of round {} already sent", message.round);
+            return Ok(());
+        }
+        // The peers announce their identity keys once the session is full
+        while message
+            .p2p
+            .keys()
+            .any(|peer| !self.peer_keys.contains_key(peer))
+        {
+            self.check_aborted()?;
+            self.pump(None)?;
+        }
+        let session_id = self.session_id.unwrap_or_default();
+        let mut relay_message =
+            RelayMessage::new(self.peer_id, session_id, self.protocol_id, message.round);
+        relay_message.set_message_params(Vec::new(), message.payload.clone());
+        add_p2p_messages(&mut relay_message, &message.p2p, &self.peer_keys)?;
+        self.outbox.push_back(TunnelMessage::Relay(relay_message));
+        self.flush()
+    }
+
+    fn receive(
+        &mut self,
+        round: u32,
+        timeout: Duration,
+    ) -> Result<BTreeMap<PeerIdentifier, ReceivedMessage>, SessionError> {
+        self.round = round;
+        let received = self.messages.get(&round).map_or(0, BTreeMap::len);
+        if received < self.capacity as usize {
+            self.check_aborted()?;
+            self.pump(Some(timeout))?;
+        }
+        let messages = match self.messages.get(&round) {
+            Some(messages) => messages
+                .iter()
+                .map(|(from, msg)| {
+                    let message = ReceivedMessage {
+                        from: *from,
+                        payload: msg.message.clone(),
+                        p2p_payload: msg.p2p_message_to(self.peer_id, &self.identity),
+                    };
+                    (*from, message)
+                })
+                .collect(),
+            None => BTreeMap::new(),
+        };
+        Ok(messages)
+    }
+
+    fn checkpoint(&self) -> TokioCheckpoint {
+        TokioCheckpoint {
+            identity: hex::encode(&self.identity.to_bytes()[..]),
+            encoding: self.encoding,
+            mode: self.mode,
+            session_id: self.session_id,
+            token: self.token.clone(),
+            number: self.number,
+            relayed: self.relayed,
+            sent: self.sent,
+            peer_keys: self.peer_keys.clone(),
+            // In turns the messages of the round waited for may already be in
+            messages: self
+                .messages
+                .range(self.round..)
+                .map(|(round, messages)| (*round, messages.clone()))
+                .collect(),
+            outbox: self.outbox.clone(),
+        }
+    }
+
+    /// Restores the identity of the peer, and reconnects to its session
+    /// if it was registered. The server keeps the place of the peer
+    /// for its grace period only
+    fn restore(
+        &mut self,
+        request: &RegisterRequest,
+        peer_id: Option<PeerIdentifier>,
+        checkpoint: TokioCheckpoint,
+    ) -> Result<(), CheckpointError> {
+        self.identity = hex::decode(&checkpoint.identity)
+            .ok()
+            .and_then(|bytes| Keypair::from_bytes(&bytes).ok())
+            .ok_or_else(|| {
+                CheckpointError::Format(serde_json::Error::custom("invalid identity"))
+            })?;
+        self.encoding = checkpoint.encoding;
+        self.mode = checkpoint.mode;
+        self.protocol_id = request.protocol_id;
+        self.capacity = request.capacity;
+        let peer_id = match peer_id {
+            Some(peer_id) => peer_id,
+            None => return Ok(()),
+        };
+        self.peer_id = peer_id;
+        self.session_id = checkpoint.session_id;
+        self.token = checkpoint.token;
+        self.number = checkpoint.number;
+        self.relayed = checkpoint.relayed;
+        self.sent = checkpoint.sent;
+        self.peer_keys = checkpoint.peer_keys;
+        self.messages = checkpoint.messages;
+        self.outbox = checkpoint.outbox;
+        // The server numbers the peer again once the session is full
+        self.send_reconnect().map_err(|_| {
+            CheckpointError::Io(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Unable to reconnect to the relay",
+            ))
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    use super::{TokioChannel, TokioCheckpoint};
+    use crate::channel::{BroadcastChannel, RegisterRequest};
+    use mmpc_server_common::codec::{Codec, Encoding};
+    use mmpc_server_common::identity::identity_key;
+    use mmpc_server_common::RelayMessage;
+    use relay_server_common::{ClientMessage, RelayMode};
+
+    fn request() -> RegisterRequest {
+        RegisterRequest {
+            protocol_id: 1,
+            capacity: 3,
+            kg_index: -1,
+            share_count: None,
+            session_id: Some(7),
+        }
+    }
+
+    // Reads the first message the client sends, none if it sends nothing for a while
+    fn read_message(stream: &mut TcpStream) -> Option<ClientMessage> {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            let len = stream.read(&mut chunk).ok().filter(|len| *len > 0)?;
+            buffer.extend_from_slice(&chunk[..len]);
+            if let Some((msg, _)) = Encoding::Json.decode_from(&buffer).unwrap() {
+                return Some(msg);
+            }
+        }
+    }
+
+    #[test]
+    fn test_restore_reconnects_to_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut channel = TokioChannel::connect(addr)
+            .unwrap()
+            .with_mode(RelayMode::Turns);
+        channel.session_id = Some(7);
+        channel.token = Some(String::from("token"));
+        channel.number = 2;
+        channel.relayed = 4;
+        channel.sent = 1;
+        channel.round = 1;
+        for round in 0..3 {
+            channel
+                .messages
+                .entry(round)
+                .or_default()
+                .insert(1, RelayMessage::new(1, 7, 1, round));
+        }
+        let checkpoint = serde_json::to_string(&channel.checkpoint()).unwrap();
+        let checkpoint: TokioCheckpoint = serde_json::from_str(&checkpoint).unwrap();
+        // Messages of the rounds handled before the checkpoint are not kept
+        assert_eq!(
+            checkpoint.messages.keys().cloned().collect::<Vec<u32>>(),
+            vec![1, 2]
+        );
+
+        let mut restored = TokioChannel::connect(addr).unwrap();
+        let _ = listener.accept().unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        restored.restore(&request(), Some(2), checkpoint).unwrap();
+        assert_eq!(
+            identity_key(&restored.identity),
+            identity_key(&channel.identity)
+        );
+        assert_eq!(restored.mode, RelayMode::Turns);
+        assert_eq!(restored.sent, 1);
+        assert_eq!(restored.peer_id, 2);
+
+        let reconnect = read_message(&mut server).unwrap();
+        // The server checks the reconnect against the key the peer registered with
+        assert!(reconnect.verify_signature(&identity_key(&channel.identity)));
+        let reconnect = reconnect.reconnect.unwrap();
+        assert_eq!(reconnect.session_id, 7);
+        assert_eq!(reconnect.peer_number, 2);
+        assert_eq!(reconnect.token, "token");
+        assert_eq!(reconnect.received, 4);
+    }
+
+    #[test]
+    fn test_restore_unregistered_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let channel = TokioChannel::connect(addr).unwrap();
+        let checkpoint = channel.checkpoint();
+
+        let mut restored = TokioChannel::connect(addr).unwrap();
+        let _ = listener.accept().unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        // A peer which was not registered registers again, on the session of the request
+        restored.restore(&request(), None, checkpoint).unwrap();
+        assert!(restored.session_id.is_none());
+        assert_eq!(restored.capacity, 3);
+        assert!(read_message(&mut server).is_none());
+    }
+
+    #[test]
+    fn test_turns_follow_peer_numbers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut channel = TokioChannel::connect(listener.local_addr().unwrap())
+            .unwrap()
+            .with_mode(RelayMode::Turns);
+        channel.capacity = 3;
+        channel.number = 2;
+        // Nobody sends before the session is full
+        assert!(!channel.is_my_turn());
+        channel.started = true;
+        let turns: Vec<bool> = (0..6)
+            .map(|relayed| {
+                channel.relayed = relayed;
+                channel.is_my_turn()
+            })
+            .collect();
+        assert_eq!(turns, vec![false, true, false, false, true, false]);
+        channel.mode = RelayMode::Rounds;
+        assert!(channel.is_my_turn());
+    }
+}
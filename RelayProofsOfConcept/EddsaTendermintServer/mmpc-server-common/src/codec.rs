@@ -0,0 +1,368 @@
+/// Wire encodings of client and server messages.
+/// JSON is the default, CBOR is a compact binary encoding which stores strings and payloads
+/// without escaping, and struct fields by index rather than by name.
+/// A CBOR encoded message starts with the CBOR self-describe tag, so the encoding of a message
+/// is told from its first bytes: a peer uses the encoding of the message it registered with
+use bytes::BytesMut;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use tokio_codec::{Decoder, Encoder};
+
+/// The self-describe tag (55799) every CBOR encoded message starts with
+pub const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+pub trait Codec {
+    /// Encodes a message
+    fn encode<T: Serialize>(&self, message: &T) -> Vec<u8>;
+
+    /// Decodes the message at the start of a stream, and returns it with the number of bytes
+    /// it was encoded in. Returns None if the stream ends before the message does
+    fn decode_from<T: DeserializeOwned>(&self, stream: &[u8]) -> io::Result<Option<(T, usize)>>;
+
+    /// Decodes a message, which must take all the bytes
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Option<T> {
+        match self.decode_from(bytes) {
+            Ok(Some((message, len))) if len == bytes.len() => Some(message),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, message: &T) -> Vec<u8> {
+        serde_json::to_vec(message).expect("Failed to serialize message")
+    }
+
+    fn decode_from<T: DeserializeOwned>(&self, stream: &[u8]) -> io::Result<Option<(T, usize)>> {
+        let mut messages = serde_json::Deserializer::from_slice(stream).into_iter();
+        match messages.next() {
+            Some(Ok(message)) => Ok(Some((message, messages.byte_offset()))),
+            Some(Err(ref err)) if err.is_eof() => Ok(None),
+            Some(Err(err)) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+            None => Ok(None),
+        }
+    }
+
+    // Trailing whitespace is accepted, as it is by serde_json
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Option<T> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, message: &T) -> Vec<u8> {
+        let mut bytes = CBOR_MAGIC.to_vec();
+        bytes.extend(serde_cbor::ser::to_vec_packed(message).expect("Failed to serialize message"));
+        bytes
+    }
+
+    fn decode_from<T: DeserializeOwned>(&self, stream: &[u8]) -> io::Result<Option<(T, usize)>> {
+        if stream.len() < CBOR_MAGIC.len() {
+            return Ok(None);
+        }
+        if !stream.starts_with(&CBOR_MAGIC) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Message is not CBOR encoded",
+            ));
+        }
+        let mut messages = serde_cbor::Deserializer::from_slice(stream).into_iter();
+        match messages.next() {
+            Some(Ok(message)) => Ok(Some((message, messages.byte_offset()))),
+            Some(Err(ref err)) if err.is_eof() => Ok(None),
+            Some(Err(err)) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoding {
+    /// Returns the encoding of an encoded message, or of the start of one
+    pub fn detect(bytes: &[u8]) -> Encoding {
+        let len = bytes.len().min(CBOR_MAGIC.len());
+        if len > 0 && bytes[..len] == CBOR_MAGIC[..len] {
+            Encoding::Cbor
+        } else {
+            Encoding::Json
+        }
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::Json
+    }
+}
+
+impl Codec for Encoding {
+    fn encode<T: Serialize>(&self, message: &T) -> Vec<u8> {
+        match self {
+            Encoding::Json => Json.encode(message),
+            Encoding::Cbor => Cbor.encode(message),
+        }
+    }
+
+    fn decode_from<T: DeserializeOwned>(&self, stream: &[u8]) -> io::Result<Option<(T, usize)>> {
+        match self {
+            Encoding::Json => Json.decode_from(stream),
+            Encoding::Cbor => Cbor.decode_from(stream),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Option<T> {
+        match self {
+            Encoding::Json => Json.decode(bytes),
+            Encoding::Cbor => Cbor.decode(bytes),
+        }
+    }
+}
+
+/// The error of a message which takes more bytes than the maximum frame size of the codec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTooLarge(pub usize);
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Message exceeds the maximum frame size of {} bytes",
+            self.0
+        )
+    }
+}
+
+impl Error for FrameTooLarge {}
+
+impl FrameTooLarge {
+    /// Returns whether a decode error is one of a message too large
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref()
+            .map_or(false, |inner| inner.is::<FrameTooLarge>())
+    }
+}
+
+/// Frames messages of a connection in the encoding of its first message,
+/// which for a client is its register message.
+/// Messages are sent in JSON until the encoding is negotiated, unless it was set on creation
+pub struct NegotiatedCodec<D, E> {
+    encoding: Option<Encoding>,
+    // Bytes a message may take at most, unbounded if None
+    max_frame_size: Option<usize>,
+    _messages: PhantomData<(D, E)>,
+}
+
+impl<D, E> NegotiatedCodec<D, E> {
+    /// Creates a codec which takes the encoding of the first message it decodes
+    pub fn new() -> NegotiatedCodec<D, E> {
+        NegotiatedCodec {
+            encoding: None,
+            max_frame_size: None,
+            _messages: PhantomData,
+        }
+    }
+
+    /// Creates a codec of the given encoding, as a client which registers in it
+    pub fn with_encoding(encoding: Encoding) -> NegotiatedCodec<D, E> {
+        NegotiatedCodec {
+            encoding: Some(encoding),
+            max_frame_size: None,
+            _messages: PhantomData,
+        }
+    }
+
+    /// Bounds the bytes a message may take, a message which takes more fails to decode
+    /// with a FrameTooLarge error rather than being buffered until it ends
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> NegotiatedCodec<D, E> {
+        self.max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    /// Returns the encoding of the connection, or None if it was not negotiated yet
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+}
+
+impl<D, E> Default for NegotiatedCodec<D, E> {
+    fn default() -> NegotiatedCodec<D, E> {
+        NegotiatedCodec::new()
+    }
+}
+
+impl<D: DeserializeOwned, E> Decoder for NegotiatedCodec<D, E> {
+    type Item = D;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<D>> {
+        // Messages in another encoding than the negotiated one fail to decode
+        let encoding = self.encoding.unwrap_or_else(|| Encoding::detect(src));
+        if encoding == Encoding::Json && src.iter().all(u8::is_ascii_whitespace) {
+            src.clear();
+            return Ok(None);
+        }
+        match encoding.decode_from(src)? {
+            Some((message, len)) => {
+                src.split_to(len);
+                self.encoding = Some(encoding);
+                Ok(Some(message))
+            }
+            None => match self.max_frame_size {
+                // the message has not ended within the bytes it may take
+                Some(max_frame_size) if src.len() > max_frame_size => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    FrameTooLarge(max_frame_size),
+                )),
+                _ => Ok(None),
+            },
+        }
+    }
+}
+
+impl<D, E: Serialize> Encoder for NegotiatedCodec<D, E> {
+    type Item = E;
+    type Error = io::Error;
+
+    fn encode(&mut self, message: E, dst: &mut BytesMut) -> io::Result<()> {
+        let bytes = self.encoding.unwrap_or_default().encode(&message);
+        dst.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Encoding, String> {
+        match name {
+            "json" => Ok(Encoding::Json),
+            "cbor" => Ok(Encoding::Cbor),
+            _ => Err(format!("Unknown encoding {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Encoding::Json => write!(f, "json"),
+            Encoding::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, Encoding, FrameTooLarge, NegotiatedCodec};
+    use crate::{ClientMessage, RelayMessage, ServerMessage};
+    use bytes::BytesMut;
+    use tokio_codec::{Decoder, Encoder};
+
+    fn relay_message() -> ClientMessage {
+        let mut relay_message = RelayMessage::new(1, 7, 1, 2);
+        relay_message.set_message_params(vec![2, 3], "{\"version\":1,\"payload\":\"\\\"12\\\"\"}");
+        let mut client_message = ClientMessage::new();
+        client_message.relay_message = Some(relay_message);
+        client_message
+    }
+
+    #[test]
+    fn test_encoding_round_trip() {
+        let client_message = relay_message();
+        let json = Encoding::Json.encode(&client_message);
+        let cbor = Encoding::Cbor.encode(&client_message);
+        assert!(cbor.len() < json.len());
+        assert_eq!(Encoding::detect(&json), Encoding::Json);
+        assert_eq!(Encoding::detect(&cbor), Encoding::Cbor);
+        for (encoding, bytes) in &[(Encoding::Json, json), (Encoding::Cbor, cbor)] {
+            let decoded: ClientMessage = encoding.decode(bytes).unwrap();
+            assert_eq!(decoded.signed_bytes(), client_message.signed_bytes());
+            // A message is not decoded in the other encoding
+            let other = match encoding {
+                Encoding::Json => Encoding::Cbor,
+                Encoding::Cbor => Encoding::Json,
+            };
+            assert!(other.decode::<ClientMessage>(bytes).is_none());
+        }
+    }
+
+    #[test]
+    fn test_decode_from_stream() {
+        for encoding in &[Encoding::Json, Encoding::Cbor] {
+            let mut stream = encoding.encode(&relay_message());
+            let len = stream.len();
+            stream.extend(encoding.encode(&relay_message()));
+            let (_, first): (ClientMessage, usize) =
+                encoding.decode_from(&stream).unwrap().unwrap();
+            assert_eq!(first, len);
+            // The stream ends in the middle of the second message
+            let partial = &stream[first..stream.len() - 1];
+            assert!(encoding
+                .decode_from::<ClientMessage>(partial)
+                .unwrap()
+                .is_none());
+            assert!(encoding.decode::<ClientMessage>(&stream).is_none());
+        }
+    }
+
+    #[test]
+    fn test_encoding_negotiated_by_first_message() {
+        let mut codec: NegotiatedCodec<ClientMessage, ServerMessage> = NegotiatedCodec::new();
+        let cbor = Encoding::Cbor.encode(&relay_message());
+        // The first message arrives in parts
+        let mut src = BytesMut::from(&cbor[..2]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(codec.encoding(), None);
+        src.extend_from_slice(&cbor[2..]);
+        src.extend_from_slice(&cbor);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert!(src.is_empty());
+        assert_eq!(codec.encoding(), Some(Encoding::Cbor));
+
+        // Responses are sent in the negotiated encoding
+        let mut dst = BytesMut::new();
+        codec.encode(ServerMessage::new(), &mut dst).unwrap();
+        assert_eq!(Encoding::detect(&dst), Encoding::Cbor);
+
+        // and messages in another encoding are rejected
+        let mut src = BytesMut::from(&Encoding::Json.encode(&relay_message())[..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        for encoding in &[Encoding::Json, Encoding::Cbor] {
+            let bytes = encoding.encode(&relay_message());
+            let mut codec: NegotiatedCodec<ClientMessage, ServerMessage> =
+                NegotiatedCodec::new().with_max_frame_size(bytes.len());
+            let mut src = BytesMut::from(&bytes[..]);
+            assert!(codec.decode(&mut src).unwrap().is_some());
+
+            // A message which does not end within the maximum frame size is refused
+            let mut codec: NegotiatedCodec<ClientMessage, ServerMessage> =
+                NegotiatedCodec::new().with_max_frame_size(bytes.len() / 2);
+            let mut src = BytesMut::from(&bytes[..bytes.len() / 2]);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+            src.extend_from_slice(&bytes[bytes.len() / 2..bytes.len() - 1]);
+            let err = codec.decode(&mut src).unwrap_err();
+            assert!(FrameTooLarge::is(&err));
+        }
+    }
+}
@@ -1,55 +1,48 @@
 /// common constants and structures for relay communication
-use super::MessagePayload;
+use crate::SessionIdentifier;
+
 // Error responses
 pub static CANT_REGISTER_RESPONSE: &str = "Can't register peer";
 pub static RELAY_ERROR_RESPONSE: &str = "Can't relay message";
 pub static STATE_NOT_INITIALIZED: &str = "Relay sessions state is not initialized";
-pub static RELAY_MESSAGE_DELIMITER: &str = ":::";
 pub static NOT_YOUR_TURN: &str = "Not this peers turn";
 pub static NOT_A_PEER: &str = "Not a peer";
+pub static INVALID_TRANSACTION: &str = "Unable to parse transaction";
+pub static INVALID_QUERY: &str = "Unable to parse query";
+pub static INVALID_SIGNATURE: &str = "Invalid signature";
+pub static UNKNOWN_SESSION: &str = "Relay session does not exist";
+pub static WRONG_PROTOCOL: &str = "Message is not for the protocol of this session";
+pub static WRONG_ROUND: &str = "Message is not for the current round";
+pub static DUPLICATE_MESSAGE: &str = "Peer already sent a message for this round";
+pub static UNSUPPORTED_MESSAGE: &str = "Message type is not supported";
+pub static UNKNOWN_RECIPIENT: &str = "Recipient is not a peer of this session";
+pub static SESSION_ABORTED: &str = "Relay session was aborted after a timeout";
+pub static WRONG_ENCODING: &str = "Message is not in the encoding the peer registered with";
+pub static UNEXPECTED_MESSAGE: &str = "Message is not one the protocol expects in this round";
 
-/// eddsa constants
-pub static PK_MESSAGE_PREFIX: &str = "PUBLIC_KEY";
-pub static COMMITMENT_MESSAGE_PREFIX: &str = "COMMITMENT";
-pub static R_KEY_MESSAGE_PREFIX: &str = "R_KEY";
-pub static R_KEY_MESSAGE_DELIMITER: &str = "@";
-pub static SIGNATURE_MESSAGE_PREFIX: &str = "SIGNATURE";
+// Query paths, the default query returns missing messages
+pub static PEER_KEYS_PATH: &str = "/peers";
+pub static SESSION_STATUS_PATH: &str = "/status";
+pub static MESSAGES_PATH: &str = "/messages";
 
-pub static EMPTY_MESSAGE_PAYLOAD: &str = "";
+// Routed query paths name the session in the path rather than in the query data
+pub static PROTOCOLS_PATH: &str = "/protocols";
 
-pub fn generate_pk_message_payload(pk: &String) -> MessagePayload {
-    return format!(
-        "{}{}{}",
-        PK_MESSAGE_PREFIX,
-        RELAY_MESSAGE_DELIMITER,
-        pk.clone()
-    );
+pub fn session_status_path(session_id: SessionIdentifier) -> String {
+    format!("/session/{}/status", session_id)
 }
 
-pub fn generate_commitment_message_payload(cmtnmt: &String) -> MessagePayload {
-    return format!(
-        "{prefix}{delimiter}{message}",
-        prefix = COMMITMENT_MESSAGE_PREFIX,
-        delimiter = RELAY_MESSAGE_DELIMITER,
-        message = cmtnmt.clone()
-    );
+pub fn session_peers_path(session_id: SessionIdentifier) -> String {
+    format!("/session/{}/peers", session_id)
 }
 
-#[allow(non_snake_case)]
-pub fn generate_R_message_payload(r: &String) -> MessagePayload {
-    return format!(
-        "{prefix}{delimiter}{message}",
-        prefix = R_KEY_MESSAGE_PREFIX,
-        delimiter = RELAY_MESSAGE_DELIMITER,
-        message = r.clone()
-    );
+// Messages of a round are returned a page at a time, the query data may request a later page
+pub fn session_round_path(session_id: SessionIdentifier, round: u32) -> String {
+    format!("/session/{}/round/{}", session_id, round)
 }
 
-pub fn generate_signature_message_payload(sig: &String) -> MessagePayload {
-    return format!(
-        "{prefix}{delimiter}{message}",
-        prefix = SIGNATURE_MESSAGE_PREFIX,
-        delimiter = RELAY_MESSAGE_DELIMITER,
-        message = sig.clone()
-    );
-}
+// Events of delivered relay messages, indexed as relay.session, relay.round and relay.peer
+pub static RELAY_EVENT_TYPE: &str = "relay";
+pub static SESSION_EVENT_KEY: &str = "session";
+pub static ROUND_EVENT_KEY: &str = "round";
+pub static PEER_EVENT_KEY: &str = "peer";
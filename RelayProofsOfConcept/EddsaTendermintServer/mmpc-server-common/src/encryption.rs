@@ -0,0 +1,137 @@
+/// Encryption of point-to-point messages to the identity key of the recipient.
+/// Messages are encrypted with ECIES over curve25519: the Ed25519 identity keys are used
+/// in their X25519 form, the sender agrees on a shared secret using an ephemeral key,
+/// and seals the message with ChaCha20-Poly1305 under a key derived from the secret.
+/// The associated data binds the sealed message to where it was sent, so it does not
+/// decrypt if it is replayed in another session, round, or between other peers
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::constants::X25519_BASEPOINT;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::ExpandedSecretKey;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+
+use crate::identity::{identity_key, IdentityKey, Keypair};
+
+/// Hex encoded ephemeral public key followed by the sealed message
+pub type EncryptedPayload = String;
+
+const POINT_LENGTH: usize = 32;
+
+// The key of a message is derived from a fresh ephemeral secret, and the ephemeral
+// public key is hashed into it, so no two messages are sealed under the same key.
+// A nonce only has to be unique for its key, so a fixed nonce never repeats under a key
+const NONCE: [u8; 12] = [0u8; 12];
+
+/// Encrypts the message to the given identity key, authenticating the associated data
+/// along with it. Returns None if the identity key is not a valid public key
+pub fn encrypt(recipient: &IdentityKey, message: &[u8], aad: &[u8]) -> Option<EncryptedPayload> {
+    let recipient_point = montgomery_point(recipient)?;
+    let ephemeral_secret = Scalar::random(&mut OsRng);
+    let ephemeral_public = X25519_BASEPOINT * ephemeral_secret;
+    let key = derive_key(
+        &(recipient_point * ephemeral_secret),
+        &ephemeral_public,
+        &recipient_point,
+    )?;
+    let sealed = ChaCha20Poly1305::new(&key)
+        .encrypt(&Nonce::from(NONCE), Payload { msg: message, aad })
+        .ok()?;
+    let mut payload = ephemeral_public.as_bytes().to_vec();
+    payload.extend(sealed);
+    Some(hex::encode(payload))
+}
+
+/// Decrypts a message encrypted to the identity key of the given key pair,
+/// returns None if the message was not encrypted to this key, was tampered with,
+/// or was encrypted with other associated data
+pub fn decrypt(keypair: &Keypair, payload: &str, aad: &[u8]) -> Option<Vec<u8>> {
+    let payload = hex::decode(payload).ok()?;
+    if payload.len() < POINT_LENGTH {
+        return None;
+    }
+    let (ephemeral_public, sealed) = payload.split_at(POINT_LENGTH);
+    let mut ephemeral_bytes = [0u8; POINT_LENGTH];
+    ephemeral_bytes.copy_from_slice(ephemeral_public);
+    let ephemeral_public = MontgomeryPoint(ephemeral_bytes);
+
+    let own_point = montgomery_point(&identity_key(keypair))?;
+    let key = derive_key(
+        &(ephemeral_public * secret_scalar(keypair)),
+        &ephemeral_public,
+        &own_point,
+    )?;
+    ChaCha20Poly1305::new(&key)
+        .decrypt(&Nonce::from(NONCE), Payload { msg: sealed, aad })
+        .ok()
+}
+
+// The X25519 form of a hex encoded Ed25519 public key
+fn montgomery_point(identity_key: &IdentityKey) -> Option<MontgomeryPoint> {
+    let bytes = hex::decode(identity_key).ok()?;
+    if bytes.len() != POINT_LENGTH {
+        return None;
+    }
+    CompressedEdwardsY::from_slice(&bytes)
+        .decompress()
+        .map(|point| point.to_montgomery())
+}
+
+// The X25519 form of an Ed25519 secret key, the clamped half of the expanded secret key
+fn secret_scalar(keypair: &Keypair) -> Scalar {
+    let expanded = ExpandedSecretKey::from(&keypair.secret).to_bytes();
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&expanded[..32]);
+    Scalar::from_bits(bytes)
+}
+
+// Derives the message key from the shared secret and both public keys.
+// Returns None for a shared secret of a low order point
+fn derive_key(
+    shared_secret: &MontgomeryPoint,
+    ephemeral_public: &MontgomeryPoint,
+    recipient: &MontgomeryPoint,
+) -> Option<Key> {
+    if shared_secret.as_bytes() == &[0u8; 32] {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(shared_secret.as_bytes());
+    hasher.update(ephemeral_public.as_bytes());
+    hasher.update(recipient.as_bytes());
+    Some(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt, encrypt};
+    use crate::identity::{generate_keypair, identity_key};
+
+    #[test]
+    fn test_encrypt_to_recipient() {
+        let recipient = generate_keypair();
+        let other = generate_keypair();
+        let payload = encrypt(&identity_key(&recipient), b"share", b"round 1").unwrap();
+        assert_eq!(
+            decrypt(&recipient, &payload, b"round 1"),
+            Some(b"share".to_vec())
+        );
+        // Only the recipient can decrypt
+        assert_eq!(decrypt(&other, &payload, b"round 1"), None);
+        // Associated data of another message
+        assert_eq!(decrypt(&recipient, &payload, b"round 2"), None);
+        // Tampered message
+        let mut tampered = hex::decode(&payload).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(
+            decrypt(&recipient, &hex::encode(tampered), b"round 1"),
+            None
+        );
+        // Not a valid identity key
+        assert_eq!(encrypt(&String::from("abcd"), b"share", b"round 1"), None);
+    }
+}
@@ -0,0 +1,117 @@
+/// Errors of the relay application.
+/// Each error has an ABCI response code of its own, so clients can tell
+/// why a transaction or a query was rejected from the code of the response
+use std::error::Error;
+use std::fmt;
+
+use crate::common::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayError {
+    // Decode errors
+    InvalidTransaction,
+    InvalidQuery,
+
+    // Validation errors
+    InvalidSignature,
+    NotAPeer,
+    WrongProtocol,
+    WrongRound,
+    DuplicateMessage,
+    UnsupportedMessage,
+    UnknownRecipient,
+    WrongEncoding,
+    UnexpectedMessage,
+
+    // State errors
+    UnknownSession,
+    CantRegister,
+    SessionAborted,
+}
+
+/// Response code of a successful transaction or query
+pub const CODE_OK: u32 = 0;
+
+const ALL_ERRORS: [RelayError; 14] = [
+    RelayError::CantRegister,
+    RelayError::InvalidTransaction,
+    RelayError::InvalidSignature,
+    RelayError::UnknownSession,
+    RelayError::NotAPeer,
+    RelayError::WrongProtocol,
+    RelayError::WrongRound,
+    RelayError::DuplicateMessage,
+    RelayError::UnsupportedMessage,
+    RelayError::InvalidQuery,
+    RelayError::UnknownRecipient,
+    RelayError::SessionAborted,
+    RelayError::WrongEncoding,
+    RelayError::UnexpectedMessage,
+];
+
+impl RelayError {
+    /// Returns the ABCI response code of this error
+    pub fn code(self) -> u32 {
+        match self {
+            RelayError::CantRegister => 1,
+            RelayError::InvalidTransaction => 2,
+            RelayError::InvalidSignature => 3,
+            RelayError::UnknownSession => 4,
+            RelayError::NotAPeer => 5,
+            RelayError::WrongProtocol => 6,
+            RelayError::WrongRound => 7,
+            RelayError::DuplicateMessage => 8,
+            RelayError::UnsupportedMessage => 9,
+            RelayError::InvalidQuery => 10,
+            RelayError::UnknownRecipient => 11,
+            RelayError::SessionAborted => 12,
+            RelayError::WrongEncoding => 13,
+            RelayError::UnexpectedMessage => 14,
+        }
+    }
+
+    /// Returns the error of an ABCI response code,
+    /// or None if the code is OK or not a relay error
+    pub fn from_code(code: u32) -> Option<RelayError> {
+        ALL_ERRORS.iter().find(|err| err.code() == code).copied()
+    }
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            RelayError::InvalidTransaction => INVALID_TRANSACTION,
+            RelayError::InvalidQuery => INVALID_QUERY,
+            RelayError::InvalidSignature => INVALID_SIGNATURE,
+            RelayError::NotAPeer => NOT_A_PEER,
+            RelayError::WrongProtocol => WRONG_PROTOCOL,
+            RelayError::WrongRound => WRONG_ROUND,
+            RelayError::DuplicateMessage => DUPLICATE_MESSAGE,
+            RelayError::UnsupportedMessage => UNSUPPORTED_MESSAGE,
+            RelayError::UnknownRecipient => UNKNOWN_RECIPIENT,
+            RelayError::WrongEncoding => WRONG_ENCODING,
+            RelayError::UnexpectedMessage => UNEXPECTED_MESSAGE,
+            RelayError::UnknownSession => UNKNOWN_SESSION,
+            RelayError::CantRegister => CANT_REGISTER_RESPONSE,
+            RelayError::SessionAborted => SESSION_ABORTED,
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl Error for RelayError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{RelayError, ALL_ERRORS, CODE_OK};
+
+    #[test]
+    fn test_codes_are_distinct() {
+        for err in ALL_ERRORS.iter() {
+            assert_ne!(err.code(), CODE_OK);
+            assert_eq!(RelayError::from_code(err.code()), Some(*err));
+        }
+        assert_eq!(RelayError::from_code(CODE_OK), None);
+        assert_eq!(RelayError::from_code(1000), None);
+    }
+}
@@ -0,0 +1,65 @@
+/// Identity keys of the peers.
+/// Each peer declares an Ed25519 public key when registering to a relay session,
+/// and signs every message it sends with the matching secret key
+use ed25519_dalek::{PublicKey, Signature, Signer, Verifier};
+use rand::rngs::OsRng;
+
+pub use ed25519_dalek::Keypair;
+
+/// Hex encoded Ed25519 public key
+pub type IdentityKey = String;
+
+/// Generates a new random identity key pair
+pub fn generate_keypair() -> Keypair {
+    Keypair::generate(&mut OsRng)
+}
+
+/// Returns the hex encoded public key of a key pair
+pub fn identity_key(keypair: &Keypair) -> IdentityKey {
+    hex::encode(keypair.public.as_bytes())
+}
+
+/// Signs the given bytes, returns a hex encoded signature
+pub fn sign(keypair: &Keypair, bytes: &[u8]) -> String {
+    hex::encode(&keypair.sign(bytes).to_bytes()[..])
+}
+
+/// Returns true if the hex encoded signature is a valid signature
+/// of the bytes by the hex encoded public key
+pub fn verify(public_key: &IdentityKey, bytes: &[u8], signature: &str) -> bool {
+    let public_key = match hex::decode(public_key)
+        .ok()
+        .and_then(|key| PublicKey::from_bytes(&key).ok())
+    {
+        Some(public_key) => public_key,
+        None => return false,
+    };
+    let signature = match hex::decode(signature)
+        .ok()
+        .and_then(|sig| Signature::from_bytes(&sig).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+    public_key.verify(bytes, &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_keypair, identity_key, sign, verify};
+
+    #[test]
+    fn test_sign_verify() {
+        let keypair = generate_keypair();
+        let public_key = identity_key(&keypair);
+        let signature = sign(&keypair, b"message");
+        assert!(verify(&public_key, b"message", &signature));
+        assert!(!verify(&public_key, b"other message", &signature));
+        // Signature by a different key
+        let other = generate_keypair();
+        assert!(!verify(&identity_key(&other), b"message", &signature));
+        // Malformed key and signature
+        assert!(!verify(&String::from("not hex"), b"message", &signature));
+        assert!(!verify(&public_key, b"message", "00"));
+    }
+}
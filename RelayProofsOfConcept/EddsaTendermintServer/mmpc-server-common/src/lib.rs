@@ -1,39 +1,54 @@
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::ops::Bound;
 use std::vec::Vec;
-use tokio_jsoncodec::Codec as JsonCodec;
 
+pub mod codec;
 pub mod common;
+pub mod encryption;
+pub mod error;
+pub mod identity;
+pub mod payload;
 pub mod protocol;
 
+use codec::NegotiatedCodec;
+use encryption::EncryptedPayload;
+use identity::{IdentityKey, Keypair};
+
 pub type ProtocolIdentifier = u32;
 pub type PeerIdentifier = u32;
+pub type SessionIdentifier = u64;
 pub type MessagePayload = String;
 
-const MAX_CLIENTS: u32 = 12;
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RelayMessage {
     pub peer_number: PeerIdentifier,
+    pub session_id: SessionIdentifier,
     pub protocol_id: ProtocolIdentifier,
-    pub from: SocketAddr,
+    pub round: u32,
     pub to: Vec<PeerIdentifier>,
     pub message: MessagePayload,
+
+    // Point-to-point messages by recipient, each encrypted to the identity key of its recipient
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub p2p_messages: BTreeMap<PeerIdentifier, EncryptedPayload>,
 }
 
 impl RelayMessage {
     pub fn new(
         peer_number: PeerIdentifier,
+        session_id: SessionIdentifier,
         protocol_id: ProtocolIdentifier,
-        from: SocketAddr,
+        round: u32,
     ) -> RelayMessage {
         RelayMessage {
             peer_number,
+            session_id,
             protocol_id,
-            from,
+            round,
             to: Vec::new(),
             message: String::from(""),
+            p2p_messages: BTreeMap::new(),
         }
     }
 
@@ -42,12 +57,46 @@ impl RelayMessage {
         self.to = to;
         self.message = message.into();
     }
+
+    /// Adds a point-to-point message to the given peer, encrypted to its identity key.
+    /// Returns false if the identity key is not a valid public key
+    pub fn add_p2p_message(
+        &mut self,
+        to: PeerIdentifier,
+        public_key: &IdentityKey,
+        message: &MessagePayload,
+    ) -> bool {
+        match encryption::encrypt(public_key, message.as_bytes(), &self.p2p_aad(to)) {
+            Some(encrypted) => {
+                self.p2p_messages.insert(to, encrypted);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the point-to-point message to the owner of the key pair, if there is one
+    pub fn p2p_message_to(&self, to: PeerIdentifier, keypair: &Keypair) -> Option<MessagePayload> {
+        let encrypted = self.p2p_messages.get(&to)?;
+        let decrypted = encryption::decrypt(keypair, encrypted, &self.p2p_aad(to))?;
+        String::from_utf8(decrypted).ok()
+    }
+
+    // Associated data of the point-to-point message to the given peer, which binds it
+    // to the session, round, sender and recipient of the message
+    fn p2p_aad(&self, to: PeerIdentifier) -> Vec<u8> {
+        let mut aad = self.session_id.to_be_bytes().to_vec();
+        aad.extend_from_slice(&self.round.to_be_bytes());
+        aad.extend_from_slice(&self.peer_number.to_be_bytes());
+        aad.extend_from_slice(&to.to_be_bytes());
+        aad
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ServerResponse {
-    // Register response containing peer number
-    Register(PeerIdentifier),
+    // Register response containing the session identifier and peer number
+    Register(SessionIdentifier, PeerIdentifier),
 
     // Error message
     ErrorResponse(String),
@@ -73,13 +122,23 @@ impl AbortMessage {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RegisterMessage {
-    pub addr: SocketAddr,
+    // Identity key the peer signs its messages with
+    pub public_key: IdentityKey,
+
+    // Session to join, a new session is opened if no identifier is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<SessionIdentifier>,
 
     pub protocol_id: ProtocolIdentifier,
 
     pub capacity: u32,
 
     pub index: i32,
+
+    // Number of key shares of a threshold signing session, where peers register
+    // with the index of their share. Same as the capacity if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share_count: Option<u32>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -104,10 +163,89 @@ pub struct ServerMessage {
 
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
 pub struct MissingMessagesRequest {
+    pub session_id: SessionIdentifier,
     pub round: u32,
     pub missing_clients: Vec<u32>,
 }
 
+/// Request for the identity keys of the peers registered to a session
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct PeerKeysRequest {
+    pub session_id: SessionIdentifier,
+}
+
+/// Request for the status of a session
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct SessionStatusRequest {
+    pub session_id: SessionIdentifier,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RelaySessionState {
+    Empty,
+
+    Uninitialized,
+
+    Initialized,
+
+    // A round or the session itself timed out, no more messages are relayed
+    Aborted,
+}
+
+impl Default for RelaySessionState {
+    fn default() -> RelaySessionState {
+        RelaySessionState::Empty
+    }
+}
+
+/// Status of a session: its state, protocol and current round,
+/// and if it was aborted after a timeout, the peers which did not send their message for that round
+#[derive(Default, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SessionStatus {
+    pub state: RelaySessionState,
+    pub protocol_id: ProtocolIdentifier,
+    pub capacity: u32,
+    pub registered: u32,
+    pub round: u32,
+    pub aborted: bool,
+    pub blame: Vec<PeerIdentifier>,
+}
+
+/// Request for a page of the messages stored for a round, in order of their senders
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct MessagesPageRequest {
+    pub session_id: SessionIdentifier,
+    pub round: u32,
+
+    #[serde(flatten)]
+    pub page: PageRequest,
+}
+
+/// The page of messages requested: a page starts after the sender given as cursor,
+/// and holds as many messages as fit in the byte limit, which the server lowers to its own limit
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct PageRequest {
+    // Only messages of these peers are returned, or of every peer if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<PeerIdentifier>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<PeerIdentifier>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+}
+
+/// A page of stored messages by sender,
+/// and the cursor to request the next page with if there are more messages
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct MessagesPage {
+    pub messages: BTreeMap<PeerIdentifier, ClientMessage>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<PeerIdentifier>,
+}
+
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
 pub struct MissingMessagesReply {
     pub missing_messages: BTreeMap<u32, ClientMessage>,
@@ -161,6 +299,13 @@ impl StoredMessages {
         }
     }
 
+    // Return true if a message of the given party is stored for the round
+    pub fn contains(&self, round: u32, party: u32) -> bool {
+        self.messages
+            .get(&round)
+            .map_or(false, |messages| messages.contains_key(&party))
+    }
+
     // Return the current number of stored messages
     pub fn get_number_messages(&self, round: u32) -> usize {
         match self.messages.get(&round) {
@@ -184,28 +329,6 @@ impl StoredMessages {
         }
     }
 
-    // Returns the messages of the current round as client messages format,
-    // or an empty hashmap if no messages are stored for the round
-    pub fn get_messages_map_client_message(&self, round: u32) -> BTreeMap<u32, ClientMessage> {
-        match self.messages.get(&round) {
-            Some(round_messages) => {
-                let mut response = BTreeMap::new();
-                // Only return a response on the first MAX clients
-                let mut max_counter = 0;
-                for (client_idx, msg) in round_messages.iter() {
-                    let idx = *client_idx as u32;
-                    response.insert(idx, msg.clone());
-                    max_counter += 1;
-                    if max_counter > MAX_CLIENTS {
-                        break;
-                    }
-                }
-                return response;
-            }
-            None => return BTreeMap::new(),
-        }
-    }
-
     // Returns the messages of the current round as client messages format,
     // or an empty hashmap if no messages are stored for the round
     pub fn get_messages_map_from_vector(
@@ -229,20 +352,52 @@ impl StoredMessages {
         }
     }
 
-    // Return a vector of all clients whos messages are not yet stored for a given round
-    pub fn get_missing_clients_vector(&self, round: u32, capacity: u32) -> Vec<u32> {
-        let return_vec;
-        match self.messages.get(&round) {
-            Some(keys) => {
-                return_vec = (1..capacity + 1)
-                    .filter(|x| !keys.contains_key(x))
-                    .collect();
-            }
-            None => {
-                return_vec = (1..capacity + 1).collect();
+    // Returns a page of the messages of a round sent by the given peers (or by every peer if none
+    // are given) after the cursor peer, with as many messages as fit in max_bytes of JSON.
+    // A page holds at least one message, so a message over the limit is returned on its own page
+    pub fn get_messages_page(
+        &self,
+        round: u32,
+        peers: &[PeerIdentifier],
+        after: Option<PeerIdentifier>,
+        max_bytes: usize,
+    ) -> MessagesPage {
+        let mut page = MessagesPage::default();
+        let round_messages = match self.messages.get(&round) {
+            Some(round_messages) => round_messages,
+            None => return page,
+        };
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut page_bytes = 0;
+        for (peer, msg) in round_messages
+            .range((start, Bound::Unbounded))
+            .filter(|(peer, _)| peers.is_empty() || peers.contains(peer))
+        {
+            let msg_bytes = serde_json::to_vec(msg).map_or(0, |bytes| bytes.len());
+            if !page.messages.is_empty() && page_bytes + msg_bytes > max_bytes {
+                page.next = page.messages.keys().next_back().copied();
+                break;
             }
+            page_bytes += msg_bytes;
+            page.messages.insert(*peer, msg.clone());
         }
-        return_vec
+        page
+    }
+
+    // Return a vector of all clients whos messages are not yet stored for a given round
+    pub fn get_missing_clients_vector(&self, round: u32, capacity: u32) -> Vec<u32> {
+        let clients: Vec<u32> = (1..capacity + 1).collect();
+        self.get_missing_peers(round, &clients)
+    }
+
+    // Return the given peers whose messages are not yet stored for a given round,
+    // peers of a threshold signing session are not numbered from 1 to the capacity
+    pub fn get_missing_peers(&self, round: u32, peers: &[PeerIdentifier]) -> Vec<u32> {
+        peers
+            .iter()
+            .filter(|peer| !self.contains(round, **peer))
+            .cloned()
+            .collect()
     }
 }
 
@@ -256,6 +411,10 @@ pub struct ClientMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_message: Option<RelayMessage>,
+
+    // Hex encoded signature by the identity key of the sender
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 impl ClientMessage {
@@ -266,24 +425,51 @@ impl ClientMessage {
             abort: None,
 
             relay_message: None,
+
+            signature: None,
         }
     }
 
     pub fn set_register(
         &mut self,
-        addr: SocketAddr,
+        public_key: IdentityKey,
+        session_id: Option<SessionIdentifier>,
         protocol_id: ProtocolIdentifier,
         capacity: u32,
         index: i32,
+        share_count: Option<u32>,
     ) {
         self.register = Some(RegisterMessage {
-            addr,
+            public_key,
+            session_id,
             protocol_id,
             capacity,
             index,
+            share_count,
         });
     }
 
+    /// The bytes covered by the signature: the message serialized without its signature
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        unsigned.signature = None;
+        serde_json::to_vec(&unsigned).expect("Failed to serialize client message")
+    }
+
+    /// Signs the message with the identity key of the sender
+    pub fn sign(&mut self, keypair: &Keypair) {
+        self.signature = None;
+        self.signature = Some(identity::sign(keypair, &self.signed_bytes()));
+    }
+
+    /// Returns true if the message is signed by the given identity key
+    pub fn verify_signature(&self, public_key: &IdentityKey) -> bool {
+        match &self.signature {
+            Some(signature) => identity::verify(public_key, &self.signed_bytes(), signature),
+            None => false,
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         self.relay_message.is_none() && self.abort.is_none() && self.register.is_none()
     }
@@ -330,12 +516,14 @@ struct RegisterResponse {
 }
 
 // in: clientMessage out:serverMessage
-pub type ServerToClientCodec = JsonCodec<ClientMessage, ServerMessage>;
-pub type ClientToServerCodec = JsonCodec<ServerMessage, ClientMessage>;
+pub type ServerToClientCodec = NegotiatedCodec<ClientMessage, ServerMessage>;
+pub type ClientToServerCodec = NegotiatedCodec<ServerMessage, ClientMessage>;
 
 #[cfg(test)]
 mod tests {
+    use super::identity::{generate_keypair, identity_key};
     use super::ClientMessage;
+    use super::RelayMessage;
     use super::StoredMessages;
 
     #[test]
@@ -345,6 +533,15 @@ mod tests {
         stored_messages.update(1, 2, ClientMessage::new());
     }
 
+    #[test]
+    fn test_contains() {
+        let mut stored_messages = StoredMessages::new();
+        stored_messages.update(1, 3, ClientMessage::new());
+        assert!(stored_messages.contains(1, 3));
+        assert!(!stored_messages.contains(1, 2));
+        assert!(!stored_messages.contains(2, 3));
+    }
+
     #[test]
     fn test_get_number_messages() {
         let mut stored_messages = StoredMessages::new();
@@ -375,49 +572,77 @@ mod tests {
     }
 
     #[test]
-    fn test_get_messages_map_client_message() {
+    fn test_get_missing_peers() {
         let mut stored_messages = StoredMessages::new();
         let round = 1;
-        stored_messages.update(round, 3, ClientMessage::new());
-        stored_messages.update(round, 2, ClientMessage::new());
-        let mut i: u32 = 2;
-        // Assert all messages are stored in order of round and client
-        for (idx, _) in stored_messages.get_messages_map_client_message(round) {
-            assert_eq!(i, idx);
-            i += 1;
-        }
-        // Assert sorted order for non sequential client messages
-        let mut stored_messages = StoredMessages::new();
+        // Signing peers 2, 4 and 5 of 5 key shares
+        let peers = [2, 4, 5];
         stored_messages.update(round, 4, ClientMessage::new());
-        stored_messages.update(round, 2, ClientMessage::new());
-        let mut i: u32 = 2;
-        for (idx, _) in stored_messages.get_messages_map_client_message(round) {
-            assert_eq!(i, idx);
-            i += 2;
-        }
-        // Test for more that MAX clients
+        assert_eq!(stored_messages.get_missing_peers(round, &peers), [2, 5]);
+        assert_eq!(stored_messages.get_missing_peers(round + 1, &peers), peers);
+    }
+
+    #[test]
+    fn test_get_messages_page() {
         let mut stored_messages = StoredMessages::new();
-        stored_messages.update(round, 1, ClientMessage::new());
-        stored_messages.update(round, 2, ClientMessage::new());
-        stored_messages.update(round, 3, ClientMessage::new());
-        stored_messages.update(round, 4, ClientMessage::new());
-        stored_messages.update(round, 5, ClientMessage::new());
-        stored_messages.update(round, 6, ClientMessage::new());
-        stored_messages.update(round, 7, ClientMessage::new());
-        stored_messages.update(round, 8, ClientMessage::new());
-        stored_messages.update(round, 9, ClientMessage::new());
-        stored_messages.update(round, 10, ClientMessage::new());
-        stored_messages.update(round, 11, ClientMessage::new());
-        stored_messages.update(round, 12, ClientMessage::new());
-        stored_messages.update(round, 13, ClientMessage::new());
-        stored_messages.update(round, 14, ClientMessage::new());
-        assert_eq!(
-            stored_messages
-                .get_messages_map_client_message(round)
-                .into_iter()
-                .len(),
-            13
-        );
+        let round = 1;
+        for peer in 1..6 {
+            let mut relay_message = RelayMessage::new(peer, 1, 1, round);
+            relay_message.set_message_params(Vec::new(), "x".repeat(100));
+            let mut msg = ClientMessage::new();
+            msg.relay_message = Some(relay_message);
+            stored_messages.update(round, peer, msg);
+        }
+        let msg_bytes = serde_json::to_vec(&stored_messages.messages[&round][&1])
+            .unwrap()
+            .len();
+
+        // Two messages fit in a page, the cursor of the last page is unset
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = stored_messages.get_messages_page(round, &[], after, 2 * msg_bytes);
+            pages.push(page.messages.keys().cloned().collect::<Vec<u32>>());
+            after = page.next;
+            if after.is_none() {
+                break;
+            }
+        }
+        assert_eq!(pages, vec![vec![1, 2], vec![3, 4], vec![5]]);
+
+        // Only the requested peers are returned, and a page holds a message over the limit
+        let page = stored_messages.get_messages_page(round, &[2, 5], None, 1);
+        assert_eq!(page.messages.keys().collect::<Vec<_>>(), [&2]);
+        assert_eq!(page.next, Some(2));
+        let page = stored_messages.get_messages_page(round, &[2, 5], page.next, 1);
+        assert_eq!(page.messages.keys().collect::<Vec<_>>(), [&5]);
+        assert_eq!(page.next, None);
+
+        let page = stored_messages.get_messages_page(round + 1, &[], None, msg_bytes);
+        assert!(page.messages.is_empty());
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn test_sign_client_message() {
+        let keypair = generate_keypair();
+        let public_key = identity_key(&keypair);
+        let mut msg = ClientMessage::new();
+        msg.set_register(public_key.clone(), None, 1, 2, -1, None);
+        assert!(!msg.verify_signature(&public_key));
+        msg.sign(&keypair);
+        assert!(msg.verify_signature(&public_key));
+
+        // The signature survives the wire encoding
+        let decoded: ClientMessage =
+            serde_json::from_str(&serde_json::to_string(&msg).unwrap()).unwrap();
+        assert!(decoded.verify_signature(&public_key));
+
+        // Tampering with the message invalidates the signature
+        let mut tampered = decoded.clone();
+        tampered.register.as_mut().unwrap().capacity = 3;
+        assert!(!tampered.verify_signature(&public_key));
+        assert!(!decoded.verify_signature(&identity_key(&generate_keypair())));
     }
 
     #[test]
@@ -432,4 +657,32 @@ mod tests {
             stored_messages.get_messages_map_from_vector(round, &[2])
         );
     }
+
+    #[test]
+    fn test_p2p_message() {
+        let recipient = generate_keypair();
+        let other = generate_keypair();
+        let mut msg = RelayMessage::new(1, 1, 1, 0);
+        assert!(msg.add_p2p_message(2, &identity_key(&recipient), &String::from("share")));
+        assert_eq!(
+            msg.p2p_message_to(2, &recipient),
+            Some(String::from("share"))
+        );
+        assert_eq!(msg.p2p_message_to(2, &other), None);
+        assert_eq!(msg.p2p_message_to(3, &recipient), None);
+        // A message replayed in another round, session or from another sender does not decrypt
+        for replayed in &[
+            RelayMessage::new(1, 1, 1, 1),
+            RelayMessage::new(1, 2, 1, 0),
+            RelayMessage::new(3, 1, 1, 0),
+        ] {
+            let mut replayed = replayed.clone();
+            replayed.p2p_messages = msg.p2p_messages.clone();
+            assert_eq!(replayed.p2p_message_to(2, &recipient), None);
+        }
+        // Nor does one moved to another recipient with the same key
+        let mut moved = msg.clone();
+        moved.p2p_messages.insert(3, msg.p2p_messages[&2].clone());
+        assert_eq!(moved.p2p_message_to(3, &recipient), None);
+    }
 }
@@ -0,0 +1,144 @@
+/// Typed payloads of the eddsa protocols.
+/// A payload is sent as a JSON envelope holding the schema version and the tagged payload,
+/// each payload holding the JSON of its protocol message.
+/// The relay only reads the payload type, to check it is one the protocol expects in the round,
+/// so peers check the version when decoding
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+use crate::MessagePayload;
+
+/// Schema version of the payloads this peer sends and accepts
+pub const PAYLOAD_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Payload {
+    // Multi-party eddsa
+    PublicKey(String),
+    Commitment(String),
+    RKey(String),
+    Signature(String),
+
+    // Threshold eddsa
+    Decommitment(String),
+    Vss(String),
+    SecretShare(String),
+    LocalSig(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadError {
+    // The payload is not a JSON envelope of a known payload type
+    Malformed,
+    // The payload was encoded with a schema version this peer does not support
+    UnsupportedVersion(u32),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    payload: Payload,
+}
+
+// The version is read on its own first, so payloads of other versions are told apart
+// from malformed ones, even if their payload types differ
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+// The type of a payload is read whatever its version
+#[derive(Deserialize)]
+struct Tagged {
+    payload: Tag,
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+impl Payload {
+    /// Encodes the payload with the current schema version
+    pub fn encode(&self) -> MessagePayload {
+        let envelope = Envelope {
+            version: PAYLOAD_VERSION,
+            payload: self.clone(),
+        };
+        serde_json::to_string(&envelope).expect("Failed to serialize payload")
+    }
+
+    /// Decodes a payload, rejecting payloads of other schema versions
+    pub fn decode(message: &str) -> Result<Payload, PayloadError> {
+        let Version { version } =
+            serde_json::from_str(message).map_err(|_| PayloadError::Malformed)?;
+        if version != PAYLOAD_VERSION {
+            return Err(PayloadError::UnsupportedVersion(version));
+        }
+        let envelope: Envelope =
+            serde_json::from_str(message).map_err(|_| PayloadError::Malformed)?;
+        Ok(envelope.payload)
+    }
+
+    /// Returns the type of an encoded payload of any schema version,
+    /// or None if it is not a payload envelope
+    pub fn kind_of(message: &str) -> Option<String> {
+        let tagged: Tagged = serde_json::from_str(message).ok()?;
+        Some(tagged.payload.kind)
+    }
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayloadError::Malformed => write!(f, "Malformed payload"),
+            PayloadError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported payload version {}", version)
+            }
+        }
+    }
+}
+
+impl Error for PayloadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Payload, PayloadError, PAYLOAD_VERSION};
+
+    #[test]
+    fn test_payload_round_trip() {
+        let payload = Payload::Commitment(String::from("{\"commitment\":\"12\"}"));
+        let encoded = payload.encode();
+        assert_eq!(Payload::decode(&encoded), Ok(payload));
+        assert_eq!(Payload::kind_of(&encoded), Some(String::from("Commitment")));
+    }
+
+    #[test]
+    fn test_payload_rejected() {
+        let next_version = format!(
+            "{{\"version\":{},\"payload\":{{\"type\":\"Proof\",\"data\":\"\"}}}}",
+            PAYLOAD_VERSION + 1
+        );
+        assert_eq!(
+            Payload::decode(&next_version),
+            Err(PayloadError::UnsupportedVersion(PAYLOAD_VERSION + 1))
+        );
+        let unknown_type = next_version.replace(
+            &format!("{}", PAYLOAD_VERSION + 1),
+            &format!("{}", PAYLOAD_VERSION),
+        );
+        assert_eq!(Payload::decode(&unknown_type), Err(PayloadError::Malformed));
+        // The type is read whatever the version
+        assert_eq!(Payload::kind_of(&next_version), Some(String::from("Proof")));
+        // Payloads of the delimited format are not accepted
+        assert_eq!(
+            Payload::decode("PUBLIC_KEY:::{}"),
+            Err(PayloadError::Malformed)
+        );
+        assert_eq!(Payload::decode(""), Err(PayloadError::Malformed));
+        assert_eq!(Payload::kind_of("PUBLIC_KEY:::{}"), None);
+    }
+}
@@ -1,19 +1,26 @@
-/// Structures for supported protocols for relay-server
+/// Structures for supported protocols for relay-server.
+/// The supported protocols are listed in a registry file the server loads on startup.
+/// The registry decides which sessions are opened and which messages are relayed,
+/// so every replica must load the same registry
 use log::debug;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 
-use crate::ProtocolIdentifier;
-
-static PROTOCOLS_F: &str = r#"./protocols.json"#;
+use crate::error::RelayError;
+use crate::payload::Payload;
+use crate::{ProtocolIdentifier, RelayMessage};
 
 #[derive(Debug, Clone)]
 pub struct ProtocolDescriptor {
     pub id: ProtocolIdentifier,
     pub capacity: u32,
+    // Number of key shares, a threshold signing session may have fewer peers than shares
+    pub share_count: u32,
     pub turn: Arc<RwLock<u32>>,
 }
 
@@ -22,10 +29,18 @@ impl ProtocolDescriptor {
         ProtocolDescriptor {
             id,
             capacity,
+            share_count: capacity,
             turn: Arc::new(RwLock::new(1)),
         }
     }
 
+    /// Sets the number of key shares the peers of the session hold,
+    /// peers then register with the index of their share
+    pub fn with_share_count(mut self, share_count: u32) -> ProtocolDescriptor {
+        self.share_count = share_count;
+        self
+    }
+
     // Advances the peer whose turn it is to transmit.
     // If the peer is 0, initializes state to 1, else, advances turn by 1
     pub fn advance_turn(&self) -> u32 {
@@ -45,52 +60,307 @@ impl ProtocolDescriptor {
     }
 }
 
-/// Returns true if the protocol is a valid protocol as determined by the
-/// protocols.json file
-pub fn is_valid_protocol(p: &ProtocolDescriptor) -> bool {
-    let all_protocols = get_protocols();
-    match all_protocols {
-        Ok(_protocols) => {
-            for prot in _protocols.protocols {
-                debug!("Checking if fits protocol: {:?}", prot);
-                if prot.id == p.id {
-                    if prot.capacities.contains(&(p.capacity)) {
-                        return true;
-                    } else {
-                        return false;
-                    }
-                }
-            }
-            return false;
+/// An inclusive range of values, a single value in the registry file is the range of that value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "RangeEntry")]
+pub struct Range {
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RangeEntry {
+    Value(u32),
+    Range { min: u32, max: u32 },
+}
+
+impl From<RangeEntry> for Range {
+    fn from(entry: RangeEntry) -> Range {
+        match entry {
+            RangeEntry::Value(value) => Range {
+                min: value,
+                max: value,
+            },
+            RangeEntry::Range { min, max } => Range { min, max },
         }
-        Err(_) => panic!("Corrupt protocols file"),
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Protocolss {
-    pub protocols: Vec<Protocol>,
+impl Range {
+    pub fn contains(&self, value: u32) -> bool {
+        self.min <= value && value <= self.max
+    }
+}
+
+/// The messages each peer sends in a round of a protocol
+#[derive(Deserialize, Debug, Clone, PartialEq, Serialize)]
+pub struct Round {
+    // Payload types the broadcast message may have, any payload is relayed if none are listed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kinds: Vec<String>,
+
+    // Whether peers send point-to-point messages in this round
+    #[serde(default)]
+    pub p2p: bool,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
-struct Protocol {
-    pub id: u32,
-    pub capacities: Vec<u32>,
+/// A protocol supported by the relay
+#[derive(Deserialize, Debug, Clone, PartialEq, Serialize)]
+pub struct Protocol {
+    pub id: ProtocolIdentifier,
+
     pub names: Vec<String>,
+
+    // Numbers of peers a session of the protocol may have
+    pub capacities: Vec<Range>,
+
+    // Thresholds t a session of t + 1 peers may sign with, when there are more key shares
+    // than peers. A session of a protocol without thresholds has a peer for each key share
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thresholds: Vec<Range>,
+
+    // A protocol without rounds may run any number of rounds, with any messages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rounds: Vec<Round>,
+}
+
+impl Protocol {
+    /// Returns the number of rounds of the protocol, or None if it is not restricted
+    pub fn round_count(&self) -> Option<u32> {
+        if self.rounds.is_empty() {
+            None
+        } else {
+            Some(self.rounds.len() as u32)
+        }
+    }
+
+    /// Returns true if a session of this protocol may have the capacity and share count
+    /// of the descriptor
+    pub fn accepts(&self, p: &ProtocolDescriptor) -> bool {
+        if !self
+            .capacities
+            .iter()
+            .any(|range| range.contains(p.capacity))
+        {
+            return false;
+        }
+        if p.share_count == p.capacity {
+            return true;
+        }
+        // A threshold session has fewer peers than shares
+        p.share_count > p.capacity
+            && p.capacity > 0
+            && self
+                .thresholds
+                .iter()
+                .any(|range| range.contains(p.capacity - 1))
+    }
+
+    /// Checks the relay message fits the round it is sent in:
+    /// the round is one of the protocol, the broadcast payload is of a kind expected in it,
+    /// and it has point-to-point messages only if the round allows them
+    pub fn check_message(&self, msg: &RelayMessage) -> Result<(), RelayError> {
+        if self.rounds.is_empty() {
+            return Ok(());
+        }
+        let round = self
+            .rounds
+            .get(msg.round as usize)
+            .ok_or(RelayError::WrongRound)?;
+        if !round.p2p && !msg.p2p_messages.is_empty() {
+            return Err(RelayError::UnexpectedMessage);
+        }
+        if round.kinds.is_empty() {
+            return Ok(());
+        }
+        match Payload::kind_of(&msg.message) {
+            Some(kind) if round.kinds.contains(&kind) => Ok(()),
+            _ => Err(RelayError::UnexpectedMessage),
+        }
+    }
 }
 
-// Reutrn all avaliable protocols
-fn get_protocols() -> Result<Protocolss, Box<dyn Error>> {
-    debug!("Getting protocols");
+#[derive(Deserialize)]
+struct ProtocolsFile {
+    protocols: Vec<Protocol>,
+}
+
+/// The protocols supported by the relay, by their identifier
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolRegistry {
+    protocols: BTreeMap<ProtocolIdentifier, Protocol>,
+}
+
+impl ProtocolRegistry {
+    /// Loads the registry from a protocols file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ProtocolRegistry, Box<dyn Error>> {
+        debug!("Loading protocols from {}", path.as_ref().display());
+        let file = File::open(path)?;
+        ProtocolRegistry::from_reader(BufReader::new(file))
+    }
+
+    /// Reads the registry from the JSON of a protocols file
+    pub fn from_reader<R: Read>(reader: R) -> Result<ProtocolRegistry, Box<dyn Error>> {
+        let file: ProtocolsFile = serde_json::from_reader(reader)?;
+        ProtocolRegistry::from_protocols(file.protocols)
+    }
+
+    /// Creates a registry of the given protocols, each must have a distinct identifier
+    /// and non-empty capacity and threshold ranges
+    pub fn from_protocols(protocols: Vec<Protocol>) -> Result<ProtocolRegistry, Box<dyn Error>> {
+        let mut registry = ProtocolRegistry::default();
+        for protocol in protocols {
+            if protocol.capacities.is_empty() {
+                return Err(format!("Protocol {} has no capacities", protocol.id).into());
+            }
+            let mut ranges = protocol.capacities.iter().chain(&protocol.thresholds);
+            if ranges.any(|range| range.min > range.max) {
+                return Err(format!("Protocol {} has an empty range", protocol.id).into());
+            }
+            if registry.protocols.contains_key(&protocol.id) {
+                return Err(format!("Protocol {} is listed twice", protocol.id).into());
+            }
+            registry.protocols.insert(protocol.id, protocol);
+        }
+        Ok(registry)
+    }
+
+    pub fn get(&self, id: ProtocolIdentifier) -> Option<&Protocol> {
+        self.protocols.get(&id)
+    }
+
+    /// Returns the supported protocols, ordered by their identifier
+    pub fn protocols(&self) -> Vec<Protocol> {
+        self.protocols.values().cloned().collect()
+    }
 
-    // Open the file in read-only mode with buffer.
-    let path = PROTOCOLS_F;
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+    /// Returns true if the descriptor is of a supported protocol,
+    /// with a capacity and share count a session of it may have
+    pub fn is_valid(&self, p: &ProtocolDescriptor) -> bool {
+        debug!("Checking if fits protocol: {:?}", p);
+        match self.get(p.id) {
+            Some(protocol) => protocol.accepts(p),
+            None => false,
+        }
+    }
+}
 
-    // Read the JSON contents of the file as an instance of `Protocols`.
-    let p = serde_json::from_reader(reader)?;
+#[cfg(test)]
+mod tests {
+    use super::{ProtocolDescriptor, ProtocolRegistry};
+    use crate::error::RelayError;
+    use crate::payload::Payload;
+    use crate::RelayMessage;
+
+    fn registry() -> ProtocolRegistry {
+        ProtocolRegistry::load(concat!(env!("CARGO_MANIFEST_DIR"), "/../protocols.json")).unwrap()
+    }
 
-    //debug!("Got protocols: {:?}", p);
-    Ok(p)
+    // The protocol of the tests of the server, which runs any rounds with any messages
+    fn test_registry() -> ProtocolRegistry {
+        ProtocolRegistry::load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../testdata/test-protocol.json"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_load_registry() {
+        let registry = registry();
+        let ids: Vec<u32> = registry.protocols().iter().map(|p| p.id).collect();
+        assert_eq!(ids, [1, 2, 3, 4]);
+        assert_eq!(registry.get(2).unwrap().round_count(), Some(4));
+        assert!(ProtocolRegistry::load("missing-protocols.json").is_err());
+        // The test protocol is not shipped
+        assert!(!registry.is_valid(&ProtocolDescriptor::new(0, 2)));
+
+        // Capacities are listed as values or ranges
+        let test_registry = test_registry();
+        assert_eq!(test_registry.get(0).unwrap().round_count(), None);
+        assert!(test_registry.is_valid(&ProtocolDescriptor::new(0, 5)));
+        assert!(!test_registry.is_valid(&ProtocolDescriptor::new(0, 6)));
+        assert!(registry.is_valid(&ProtocolDescriptor::new(1, 1024)));
+        assert!(!registry.is_valid(&ProtocolDescriptor::new(1, 1025)));
+        assert!(!registry.is_valid(&ProtocolDescriptor::new(100, 5)));
+    }
+
+    #[test]
+    fn test_invalid_registry() {
+        let twice = r#"{"protocols": [
+            {"id": 1, "names": [], "capacities": [2]},
+            {"id": 1, "names": [], "capacities": [3]}
+        ]}"#;
+        assert!(ProtocolRegistry::from_reader(twice.as_bytes()).is_err());
+        let empty_range = r#"{"protocols": [
+            {"id": 1, "names": [], "capacities": [{"min": 3, "max": 2}]}
+        ]}"#;
+        assert!(ProtocolRegistry::from_reader(empty_range.as_bytes()).is_err());
+        let no_capacities = r#"{"protocols": [{"id": 1, "names": [], "capacities": []}]}"#;
+        assert!(ProtocolRegistry::from_reader(no_capacities.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_threshold_sessions() {
+        let registry = registry();
+        // 3 of 5 key shares sign
+        let signing = |id| ProtocolDescriptor::new(id, 3).with_share_count(5);
+        assert!(registry.is_valid(&signing(4)));
+        assert!(!registry.is_valid(&signing(2)));
+        assert!(registry.is_valid(&ProtocolDescriptor::new(4, 5)));
+        // Fewer shares than peers
+        let fewer_shares = ProtocolDescriptor::new(4, 5).with_share_count(3);
+        assert!(!registry.is_valid(&fewer_shares));
+    }
+
+    #[test]
+    fn test_check_message() {
+        let registry = registry();
+        let protocol = registry.get(3).unwrap();
+        let message = |round, payload: Payload| {
+            let mut msg = RelayMessage::new(1, 1, 3, round);
+            msg.message = payload.encode();
+            msg
+        };
+        let commitment = Payload::Commitment(String::from("{}"));
+        let vss = Payload::Vss(String::from("{}"));
+        assert_eq!(
+            protocol.check_message(&message(0, commitment.clone())),
+            Ok(())
+        );
+        assert_eq!(
+            protocol.check_message(&message(0, vss.clone())),
+            Err(RelayError::UnexpectedMessage)
+        );
+        let mut untyped = message(0, commitment.clone());
+        untyped.message = String::from("message");
+        assert_eq!(
+            protocol.check_message(&untyped),
+            Err(RelayError::UnexpectedMessage)
+        );
+
+        // Point-to-point messages are only sent with the VSS scheme
+        let mut p2p_msg = message(0, commitment.clone());
+        p2p_msg.p2p_messages.insert(2, String::from("encrypted"));
+        assert_eq!(
+            protocol.check_message(&p2p_msg),
+            Err(RelayError::UnexpectedMessage)
+        );
+        let mut p2p_msg = message(2, vss);
+        p2p_msg.p2p_messages.insert(2, String::from("encrypted"));
+        assert_eq!(protocol.check_message(&p2p_msg), Ok(()));
+
+        // The protocol has 3 rounds
+        assert_eq!(
+            protocol.check_message(&message(3, commitment.clone())),
+            Err(RelayError::WrongRound)
+        );
+        // A protocol without rounds relays any message
+        let test_registry = test_registry();
+        assert_eq!(
+            test_registry.get(0).unwrap().check_message(&untyped),
+            Ok(())
+        );
+    }
 }
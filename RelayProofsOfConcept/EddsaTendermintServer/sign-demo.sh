@@ -4,11 +4,20 @@ echo "$0: MP-EDDSA"
 rm signature?
 rm signature??
 rm log*.log
+rm session
+
+# Key shares are encrypted with this password
+export MMPC_KEY_PASSWORD=${MMPC_KEY_PASSWORD:-demo}
 
 n=3
 
 echo "sign part"
-for i in $(seq 1 $n);
+# The first client opens a new session, the rest join it
+cargo run -p mmpc-client --bin sign-client -- -I 1 -C $n -M "message"&
+while [ ! -f session ]; do sleep 0.1; done
+SESSION=$(cat session)
+
+for i in $(seq 2 $n);
 do
-    cargo run -p mmpc-client --bin sign-client -- -I $i -C $n -M "message"&
+    cargo run -p mmpc-client --bin sign-client -- -I $i -C $n -S $SESSION -M "message"&
 done
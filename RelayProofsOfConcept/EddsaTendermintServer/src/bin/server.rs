@@ -10,7 +10,7 @@
 //! this will run a client that utilizes the server in some way
 //!
 use clap::{App, Arg, ArgMatches};
-use mmpc_server::RelayApp;
+use mmpc_server::{Deadline, ProtocolRegistry, RelayApp, RelayStore, DEFAULT_MAX_PAGE_BYTES};
 use std::io;
 use std::net::SocketAddr;
 
@@ -25,10 +25,53 @@ fn arg_matches<'a>() -> ArgMatches<'a> {
                 .value_name("<HOST:PORT>"),
         )
         .arg(
-            Arg::with_name("capacity")
-                .default_value("2")
-                .short("P")
-                .long("participants"),
+            Arg::with_name("db")
+                .long("db")
+                .takes_value(true)
+                .value_name("<PATH>")
+                .help("Path of the relay sessions store, relay-db-<PORT> by default"),
+        )
+        .arg(
+            Arg::with_name("protocols")
+                .long("protocols")
+                .default_value("protocols.json")
+                .value_name("<PATH>")
+                .help("Path of the registry of supported protocols, the same on every node"),
+        )
+        .arg(
+            Arg::with_name("round-timeout-blocks")
+                .long("round-timeout-blocks")
+                .takes_value(true)
+                .value_name("<BLOCKS>")
+                .help("Aborts a session if a round is not completed within this many blocks"),
+        )
+        .arg(
+            Arg::with_name("round-timeout-secs")
+                .long("round-timeout-secs")
+                .takes_value(true)
+                .value_name("<SECONDS>")
+                .help("Aborts a session if a round is not completed within this block time"),
+        )
+        .arg(
+            Arg::with_name("session-timeout-blocks")
+                .long("session-timeout-blocks")
+                .takes_value(true)
+                .value_name("<BLOCKS>")
+                .help("Aborts a session which stalls this many blocks after it was opened"),
+        )
+        .arg(
+            Arg::with_name("session-timeout-secs")
+                .long("session-timeout-secs")
+                .takes_value(true)
+                .value_name("<SECONDS>")
+                .help("Aborts a session which stalls this block time after it was opened"),
+        )
+        .arg(
+            Arg::with_name("max-page-bytes")
+                .long("max-page-bytes")
+                .takes_value(true)
+                .value_name("<BYTES>")
+                .help("Byte limit of the messages in a query response, 256KiB by default"),
         )
         .arg(
             Arg::with_name("verbose")
@@ -40,6 +83,19 @@ fn arg_matches<'a>() -> ArgMatches<'a> {
         .get_matches()
 }
 
+// A deadline of the given number of blocks or seconds, where either may be unset
+fn deadline(matches: &ArgMatches, blocks: &str, seconds: &str) -> Deadline {
+    let parse = |name: &str| {
+        matches
+            .value_of(name)
+            .map(|value| value.parse().expect("Unable to parse timeout"))
+    };
+    Deadline {
+        blocks: parse(blocks),
+        seconds: parse(seconds),
+    }
+}
+
 fn setup_logging(verbosity: u64, port: String) -> Result<(), fern::InitError> {
     let mut base_config = fern::Dispatch::new();
 
@@ -108,17 +164,35 @@ fn main() {
         .parse()
         .expect("Unable to parse socket address");
 
-    let capacity: u32 = matches
-        .value_of("capacity")
-        .unwrap()
-        .parse()
-        .expect("Invalid number of participants");
-
     let port = addr.port().to_string();
 
+    let db_path: String = match matches.value_of("db") {
+        Some(path) => path.to_string(),
+        None => format!("relay-db-{}", port),
+    };
+
+    let round_deadline = deadline(&matches, "round-timeout-blocks", "round-timeout-secs");
+    let session_deadline = deadline(&matches, "session-timeout-blocks", "session-timeout-secs");
+
+    let max_page_bytes: usize = matches
+        .value_of("max-page-bytes")
+        .map_or(DEFAULT_MAX_PAGE_BYTES, |bytes| {
+            bytes.parse().expect("Unable to parse page size")
+        });
+
     let verbosity: u64 = matches.occurrences_of("verbose");
 
     setup_logging(verbosity, port).expect("failed to initialize logging.");
 
-    abci::run(addr, RelayApp::new(capacity));
+    let protocols_path = matches.value_of("protocols").unwrap();
+    let protocols = ProtocolRegistry::load(protocols_path)
+        .unwrap_or_else(|err| panic!("Unable to load protocols from {}: {}", protocols_path, err));
+
+    let store = RelayStore::open(&db_path).expect("Unable to open relay sessions store");
+    let app = RelayApp::with_store(store, protocols)
+        .unwrap_or_else(|err| panic!("Unable to load relay sessions store: {}", err))
+        .with_deadlines(round_deadline, session_deadline)
+        .with_max_page_bytes(max_page_bytes);
+
+    abci::run(addr, app);
 }
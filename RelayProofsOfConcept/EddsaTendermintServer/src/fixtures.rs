@@ -0,0 +1,21 @@
+//! Fixtures of the tests of the server
+use mmpc_server_common::protocol::ProtocolRegistry;
+use mmpc_server_common::ProtocolIdentifier;
+
+/// The test protocol runs any rounds, with any messages. Only the tests register it,
+/// the registry the server ships with has the protocols of the clients alone
+pub(crate) const TEST_PROTOCOL: ProtocolIdentifier = 0;
+
+/// The registry the server ships with, along with the test protocol
+pub(crate) fn protocols() -> ProtocolRegistry {
+    let shipped =
+        ProtocolRegistry::load(concat!(env!("CARGO_MANIFEST_DIR"), "/protocols.json")).unwrap();
+    let test_protocol = ProtocolRegistry::load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/test-protocol.json"
+    ))
+    .unwrap();
+    let mut protocols = shipped.protocols();
+    protocols.extend(test_protocol.protocols());
+    ProtocolRegistry::from_protocols(protocols).unwrap()
+}
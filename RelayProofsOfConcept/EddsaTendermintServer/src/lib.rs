@@ -1,4 +1,13 @@
+#[cfg(test)]
+mod fixtures;
+mod merkle;
+mod query;
 mod relay_app;
 mod relay_session;
+mod relay_store;
+mod timeout;
 
-pub use crate::relay_app::RelayApp;
+pub use crate::relay_app::{RelayApp, DEFAULT_MAX_PAGE_BYTES};
+pub use crate::relay_store::RelayStore;
+pub use crate::timeout::Deadline;
+pub use mmpc_server_common::protocol::ProtocolRegistry;
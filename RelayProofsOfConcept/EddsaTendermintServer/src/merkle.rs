@@ -0,0 +1,76 @@
+//! A simple Merkle tree, following the construction of Tendermint's simple Merkle tree:
+//! leaves and inner nodes are hashed with distinct prefixes (as in RFC 6962),
+//! and the leaves are split at the largest power of two smaller than their number
+use sha2::{Digest, Sha256};
+
+const LEAF_PREFIX: u8 = 0;
+const INNER_PREFIX: u8 = 1;
+
+/// Returns the Merkle root of the given leaves.
+/// The root of an empty tree is the hash of an empty input
+pub fn merkle_root(leaves: &[Vec<u8>]) -> Vec<u8> {
+    match leaves.len() {
+        0 => Sha256::digest(&[]).to_vec(),
+        1 => leaf_hash(&leaves[0]),
+        n => {
+            let k = split_point(n);
+            inner_hash(&merkle_root(&leaves[..k]), &merkle_root(&leaves[k..]))
+        }
+    }
+}
+
+fn leaf_hash(leaf: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input([LEAF_PREFIX]);
+    hasher.input(leaf);
+    hasher.result().to_vec()
+}
+
+fn inner_hash(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input([INNER_PREFIX]);
+    hasher.input(left);
+    hasher.input(right);
+    hasher.result().to_vec()
+}
+
+// Largest power of two strictly smaller than n, for n > 1
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k * 2 < n {
+        k *= 2;
+    }
+    k
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leaf_hash, merkle_root, split_point};
+
+    #[test]
+    fn test_split_point() {
+        assert_eq!(split_point(2), 1);
+        assert_eq!(split_point(3), 2);
+        assert_eq!(split_point(4), 2);
+        assert_eq!(split_point(5), 4);
+        assert_eq!(split_point(9), 8);
+    }
+
+    #[test]
+    fn test_single_leaf_root() {
+        let leaves = vec![b"session".to_vec()];
+        assert_eq!(merkle_root(&leaves), leaf_hash(&leaves[0]));
+        // A leaf is never hashed as is
+        assert_ne!(merkle_root(&[]), merkle_root(&[Vec::new()]));
+    }
+
+    #[test]
+    fn test_root_depends_on_order() {
+        let leaves: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i]).collect();
+        let mut reversed = leaves.clone();
+        reversed.reverse();
+        assert_eq!(merkle_root(&leaves), merkle_root(&leaves.clone()));
+        assert_ne!(merkle_root(&leaves), merkle_root(&reversed));
+        assert_ne!(merkle_root(&leaves), merkle_root(&leaves[..4]));
+    }
+}
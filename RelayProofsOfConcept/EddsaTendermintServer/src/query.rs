@@ -0,0 +1,94 @@
+//! Paths of the queries the relay app answers.
+//! Routed paths such as /session/{id}/status name the session in the path,
+//! the earlier paths take a request naming the session as the query data
+use mmpc_server_common::common::{
+    MESSAGES_PATH, PEER_KEYS_PATH, PROTOCOLS_PATH, SESSION_STATUS_PATH,
+};
+use mmpc_server_common::SessionIdentifier;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryPath {
+    // The default path, returns the messages requested by a missing messages request
+    MissingMessages,
+
+    // Paths of a request naming the session
+    Messages,
+    PeerKeys,
+    Status,
+
+    // Routed paths
+    SessionStatus(SessionIdentifier),
+    SessionPeers(SessionIdentifier),
+    SessionRound(SessionIdentifier, u32),
+    Protocols,
+}
+
+impl QueryPath {
+    /// Parses the path of a query, returns None if it is not a known path
+    pub fn parse(path: &str) -> Option<QueryPath> {
+        if path.is_empty() || path == "/" {
+            return Some(QueryPath::MissingMessages);
+        }
+        if path == MESSAGES_PATH {
+            return Some(QueryPath::Messages);
+        }
+        if path == PEER_KEYS_PATH {
+            return Some(QueryPath::PeerKeys);
+        }
+        if path == SESSION_STATUS_PATH {
+            return Some(QueryPath::Status);
+        }
+        if path == PROTOCOLS_PATH {
+            return Some(QueryPath::Protocols);
+        }
+        let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["", "session", session_id, "status"] => {
+                Some(QueryPath::SessionStatus(session_id.parse().ok()?))
+            }
+            ["", "session", session_id, "peers"] => {
+                Some(QueryPath::SessionPeers(session_id.parse().ok()?))
+            }
+            ["", "session", session_id, "round", round] => Some(QueryPath::SessionRound(
+                session_id.parse().ok()?,
+                round.parse().ok()?,
+            )),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryPath;
+    use mmpc_server_common::common::{session_peers_path, session_round_path, session_status_path};
+
+    #[test]
+    fn test_parse_query_path() {
+        assert_eq!(QueryPath::parse(""), Some(QueryPath::MissingMessages));
+        assert_eq!(QueryPath::parse("/status"), Some(QueryPath::Status));
+        assert_eq!(QueryPath::parse("/protocols"), Some(QueryPath::Protocols));
+        assert_eq!(
+            QueryPath::parse(&session_status_path(7)),
+            Some(QueryPath::SessionStatus(7))
+        );
+        assert_eq!(
+            QueryPath::parse(&session_peers_path(7)),
+            Some(QueryPath::SessionPeers(7))
+        );
+        assert_eq!(
+            QueryPath::parse(&session_round_path(7, 2)),
+            Some(QueryPath::SessionRound(7, 2))
+        );
+        assert_eq!(
+            QueryPath::parse("/session/7/round/2/"),
+            Some(QueryPath::SessionRound(7, 2))
+        );
+
+        assert_eq!(QueryPath::parse("/session/seven/status"), None);
+        assert_eq!(QueryPath::parse("/session/7/round/-1"), None);
+        assert_eq!(QueryPath::parse("/session/7"), None);
+        assert_eq!(QueryPath::parse("/session/7/peers/1"), None);
+        assert_eq!(QueryPath::parse("/blocks"), None);
+    }
+}
@@ -1,85 +1,523 @@
+use crate::merkle::merkle_root;
+use crate::query::QueryPath;
 use crate::relay_session::RelaySession;
+use crate::relay_session::RelaySessionState;
+use crate::relay_store::{CommitInfo, RelayStore, StoreError};
+use crate::timeout::{BlockTime, Deadline};
 use abci::{
-    RequestCheckTx, RequestDeliverTx, RequestQuery, ResponseCheckTx, ResponseDeliverTx,
-    ResponseQuery,
+    Event, KVPair, RequestBeginBlock, RequestCheckTx, RequestCommit, RequestDeliverTx,
+    RequestEndBlock, RequestInfo, RequestQuery, ResponseBeginBlock, ResponseCheckTx,
+    ResponseCommit, ResponseDeliverTx, ResponseEndBlock, ResponseInfo, ResponseQuery,
 };
-use log::{debug, info, warn};
-use mmpc_server_common::protocol::ProtocolDescriptor;
+use log::{debug, error, info, warn};
+use mmpc_server_common::codec::{Codec, Encoding};
+use mmpc_server_common::common::{
+    PEER_EVENT_KEY, RELAY_EVENT_TYPE, ROUND_EVENT_KEY, SESSION_EVENT_KEY,
+};
+use mmpc_server_common::error::{RelayError, CODE_OK};
+use mmpc_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
 use mmpc_server_common::{
-    ClientMessage, ClientMessageType, MissingMessagesRequest, ServerMessage, ServerResponse,
+    ClientMessage, ClientMessageType, MessagesPage, MessagesPageRequest, MissingMessagesRequest,
+    PageRequest, PeerKeysRequest, RegisterMessage, ServerMessage, ServerResponse,
+    SessionIdentifier, SessionStatus, SessionStatusRequest,
 };
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
 
-const MAX_CLIENTS: usize = 12;
+// Default byte limit of the messages in a query response. Tendermint limits RPC responses
+// to 1MB by default, and the messages are escaped within the JSON response
+pub const DEFAULT_MAX_PAGE_BYTES: usize = 256 * 1024;
 
 pub struct RelayApp {
-    relay_session: RelaySession,
+    relay_sessions: BTreeMap<SessionIdentifier, RelaySession>,
+
+    // Identifier given to the next session opened by a register message.
+    // Sessions are numbered sequentially so every replica assigns the same identifiers
+    next_session_id: SessionIdentifier,
+
+    // Sessions changed since the last commit
+    updated_sessions: BTreeSet<SessionIdentifier>,
+
+    // Hash of the state of each session, rehashed only once the session is updated
+    session_hashes: BTreeMap<SessionIdentifier, Vec<u8>>,
+
+    last_block_height: i64,
+
+    last_app_hash: Vec<u8>,
+
+    // On-disk store of the committed state, if the app is persistent
+    store: Option<RelayStore>,
+
+    // The block being executed, as given by its header
+    current_block: BlockTime,
+
+    // Sessions which stall past a deadline are aborted at the end of the block
+    round_deadline: Deadline,
+
+    session_deadline: Deadline,
+
+    // Byte limit of the messages returned by a query
+    max_page_bytes: usize,
+
+    // Protocols sessions may be opened for, loaded once on startup
+    protocols: Arc<ProtocolRegistry>,
 }
 
 impl RelayApp {
-    pub fn new(capacity: u32) -> RelayApp {
+    /// Creates an app of the given protocols, which keeps its state in memory only
+    pub fn new(protocols: ProtocolRegistry) -> RelayApp {
         RelayApp {
-            relay_session: RelaySession::new(capacity),
+            relay_sessions: BTreeMap::new(),
+            next_session_id: 1,
+            updated_sessions: BTreeSet::new(),
+            session_hashes: BTreeMap::new(),
+            last_block_height: 0,
+            last_app_hash: Vec::new(),
+            store: None,
+            current_block: BlockTime::default(),
+            round_deadline: Deadline::default(),
+            session_deadline: Deadline::default(),
+            max_page_bytes: DEFAULT_MAX_PAGE_BYTES,
+            protocols: Arc::new(protocols),
+        }
+    }
+
+    /// Sets the deadlines of each round of a session, and of the session as a whole.
+    /// By default sessions never time out
+    pub fn with_deadlines(
+        mut self,
+        round_deadline: Deadline,
+        session_deadline: Deadline,
+    ) -> RelayApp {
+        self.round_deadline = round_deadline;
+        self.session_deadline = session_deadline;
+        self
+    }
+
+    /// Sets the byte limit of the messages returned by a query,
+    /// clients page through the messages of a round which do not fit
+    pub fn with_max_page_bytes(mut self, max_page_bytes: usize) -> RelayApp {
+        self.max_page_bytes = max_page_bytes;
+        self
+    }
+
+    /// Creates an app of the given protocols backed by the given store,
+    /// resuming from the last block committed to the store
+    /// Fails if the store does not hold a state it can resume from
+    pub fn with_store(
+        store: RelayStore,
+        protocols: ProtocolRegistry,
+    ) -> Result<RelayApp, StoreError> {
+        let mut app = RelayApp::new(protocols);
+        if let Some(commit_info) = store.commit_info()? {
+            info!(
+                "Resuming from height {}, app hash {}",
+                commit_info.height,
+                hex::encode(&commit_info.app_hash)
+            );
+            app.relay_sessions = store.sessions(&app.protocols)?;
+            app.session_hashes = app
+                .relay_sessions
+                .iter()
+                .map(|(session_id, relay_session)| (*session_id, relay_session.state_hash()))
+                .collect();
+            app.next_session_id = commit_info.next_session_id;
+            app.last_block_height = commit_info.height;
+            app.last_app_hash = commit_info.app_hash;
+        }
+        app.store = Some(store);
+        Ok(app)
+    }
+
+    /// Opens a new, empty relay session and returns its identifier
+    fn open_session(&mut self, capacity: u32) -> SessionIdentifier {
+        let session_id = self.next_session_id;
+        self.next_session_id += 1;
+        let relay_session = RelaySession::new(capacity, Arc::clone(&self.protocols));
+        relay_session.start_session(self.current_block);
+        self.relay_sessions.insert(session_id, relay_session);
+        self.updated_sessions.insert(session_id);
+        info!("Opened relay session {}", session_id);
+        session_id
+    }
+
+    /// Returns the Merkle root of all relay sessions, ordered by their identifier.
+    /// Each leaf is the session identifier followed by the hash of the session state,
+    /// only the sessions updated since the last commit are hashed again
+    pub fn app_hash(&mut self) -> Vec<u8> {
+        for session_id in &self.updated_sessions {
+            if let Some(relay_session) = self.relay_sessions.get(session_id) {
+                self.session_hashes
+                    .insert(*session_id, relay_session.state_hash());
+            }
+        }
+        let leaves: Vec<Vec<u8>> = self
+            .session_hashes
+            .iter()
+            .map(|(session_id, state_hash)| {
+                let mut leaf = session_id.to_be_bytes().to_vec();
+                leaf.extend(state_hash);
+                leaf
+            })
+            .collect();
+        merkle_root(&leaves)
+    }
+
+    /// Aborts the sessions which stalled past a deadline as of the current block
+    fn abort_timed_out_sessions(&mut self) {
+        let now = self.current_block;
+        for (session_id, relay_session) in &self.relay_sessions {
+            if relay_session.timed_out(&self.round_deadline, &self.session_deadline, &now) {
+                let blame = relay_session.abort();
+                warn!(
+                    "Aborted relay session {} in round {}, missing messages of peers {:?}",
+                    session_id,
+                    relay_session.round(),
+                    blame
+                );
+                self.updated_sessions.insert(*session_id);
+            }
         }
     }
 }
 
-// Convert incoming tx data to the proper BigEndian size. txs.len() > 8 will return 0
+// Convert incoming tx data to a string for logging
 fn convert_tx(bytes: &[u8]) -> String {
-    String::from_utf8(bytes.to_vec()).expect("Found invalid UTF-8")
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// The protocol a peer registers for, signing peers of a threshold key
+// register with the number of key shares
+fn protocol_of(register: &RegisterMessage) -> ProtocolDescriptor {
+    ProtocolDescriptor::new(register.protocol_id, register.capacity)
+        .with_share_count(register.share_count.unwrap_or(register.capacity))
+}
+
+// Events of a delivered relay message, clients subscribe to the messages of their session
+fn relay_events(client_message: &ClientMessage) -> Vec<Event> {
+    let msg = match &client_message.relay_message {
+        Some(msg) => msg,
+        None => return Vec::new(),
+    };
+    let attributes: Vec<KVPair> = [
+        (SESSION_EVENT_KEY, msg.session_id.to_string()),
+        (ROUND_EVENT_KEY, msg.round.to_string()),
+        (PEER_EVENT_KEY, msg.peer_number.to_string()),
+    ]
+    .iter()
+    .map(|(key, value)| {
+        let mut attribute = KVPair::new();
+        attribute.set_key(key.as_bytes().to_vec());
+        attribute.set_value(value.as_bytes().to_vec());
+        attribute
+    })
+    .collect();
+    let mut event = Event::new();
+    event.set_field_type(RELAY_EVENT_TYPE.to_owned());
+    event.set_attributes(attributes.into());
+    vec![event]
+}
+
+// Decode a transaction, which is a client message in either of the wire encodings
+fn decode_tx(bytes: &[u8]) -> Result<(ClientMessage, Encoding), RelayError> {
+    let encoding = Encoding::detect(bytes);
+    let client_message = encoding
+        .decode(bytes)
+        .ok_or(RelayError::InvalidTransaction)?;
+    Ok((client_message, encoding))
+}
+
+// Parse the data of a query
+fn parse_query<T: DeserializeOwned>(data: &[u8]) -> Result<T, RelayError> {
+    serde_json::from_slice(data).map_err(|_| RelayError::InvalidQuery)
+}
+
+// Query responses are returned in the log, as is the response to a transaction
+fn to_log<T: Serialize>(response: &T) -> String {
+    serde_json::to_string(response).unwrap()
 }
 
 impl RelayApp {
-    fn can_relay(&self, client_message: &ClientMessage) -> u32 {
+    /// Checks the message can be applied to the current state,
+    /// and is signed by the identity key of its sender:
+    /// the key being registered for a register message,
+    /// or the key registered for the sending peer for a relay message.
+    /// A relay message must be in the encoding its sender registered with
+    fn is_valid(
+        &self,
+        client_message: &ClientMessage,
+        encoding: Encoding,
+    ) -> Result<(), RelayError> {
         match client_message.msg_type() {
+            ClientMessageType::Register => {
+                let register = client_message.register.as_ref().unwrap();
+                info!(
+                    "Got register message. protocol id requested: {}",
+                    register.protocol_id
+                );
+                if !client_message.verify_signature(&register.public_key) {
+                    return Err(RelayError::InvalidSignature);
+                }
+                let protocol_descriptor = protocol_of(register);
+                let can_register = match register.session_id {
+                    Some(session_id) => match self.relay_sessions.get(&session_id) {
+                        Some(relay_session) => relay_session.can_register(
+                            &register.public_key,
+                            register.index,
+                            protocol_descriptor,
+                        ),
+                        None => return Err(RelayError::UnknownSession),
+                    },
+                    // A new session is opened, so only the protocol itself is checked
+                    None => RelaySession::new(register.capacity, Arc::clone(&self.protocols))
+                        .can_register(&register.public_key, register.index, protocol_descriptor),
+                };
+                if can_register {
+                    Ok(())
+                } else {
+                    Err(RelayError::CantRegister)
+                }
+            }
             ClientMessageType::RelayMessage => {
-                let msg = client_message.clone().relay_message.unwrap();
-                let can_relay = self.relay_session.can_relay(&msg.from, &msg);
-                match can_relay {
-                    Ok(()) => debug!("Can relay this message"),
-                    _ => (),
+                let msg = client_message.relay_message.as_ref().unwrap();
+                let relay_session = self
+                    .relay_sessions
+                    .get(&msg.session_id)
+                    .ok_or(RelayError::UnknownSession)?;
+                relay_session.can_relay(msg)?;
+                if relay_session.encoding_of(msg.peer_number) != encoding {
+                    return Err(RelayError::WrongEncoding);
+                }
+                // The sender is registered, as checked by can_relay
+                let public_key = relay_session.public_key_of(msg.peer_number).unwrap();
+                if !client_message.verify_signature(&public_key) {
+                    return Err(RelayError::InvalidSignature);
                 }
+                debug!("Can relay this message");
+                Ok(())
             }
-            _ => (),
+            _ => Err(RelayError::UnsupportedMessage),
         }
-        0
     }
 
-    fn is_valid(&self, client_message: &ClientMessage) -> u32 {
+    /// Applies a valid message to the state, returns the response to the client
+    fn deliver(
+        &mut self,
+        client_message: ClientMessage,
+        encoding: Encoding,
+    ) -> Result<String, RelayError> {
+        // Blocks may contain transactions which were not checked by this node,
+        // or which became invalid after an earlier transaction in the block
+        self.is_valid(&client_message, encoding)?;
+
         match client_message.msg_type() {
             ClientMessageType::Register => {
-                let register = client_message.clone().register.unwrap();
+                let register = client_message.register.unwrap();
                 info!(
                     "Got register message. protocol id requested: {}",
                     register.protocol_id
                 );
-                let protocol_descriptor =
-                    ProtocolDescriptor::new(register.protocol_id, register.capacity);
-                if self
-                    .relay_session
-                    .can_register(&register.addr, protocol_descriptor)
-                {
-                    0
-                } else {
-                    1
+                let session_id = match register.session_id {
+                    Some(session_id) => session_id,
+                    None => self.open_session(register.capacity),
+                };
+                let protocol = protocol_of(&register);
+                let relay_session = &self.relay_sessions[&session_id];
+                let client_index = relay_session
+                    .register_new_peer(register.public_key, protocol, register.index)
+                    .ok_or(RelayError::CantRegister)?;
+                // The peer keeps relaying in the encoding it registered with
+                relay_session.set_encoding(client_index, encoding);
+                // The first round starts once every peer has registered
+                if relay_session.state() == RelaySessionState::Initialized {
+                    relay_session.start_round(self.current_block);
                 }
+                self.updated_sessions.insert(session_id);
+                let mut server_msg = ServerMessage::new();
+                server_msg.response = Some(ServerResponse::Register(session_id, client_index));
+                // TODO: Currently using log and not data, data is expecting a different encoding,
+                // sigh
+                Ok(serde_json::to_string(&server_msg).unwrap())
             }
             ClientMessageType::RelayMessage => {
-                // TODO: Check validity of relay message here
-                0
+                let relay_msg = client_message.clone().relay_message.unwrap();
+                let peer_id = relay_msg.peer_number;
+                let session_id = relay_msg.session_id;
+                info!(
+                    "Got relay message from {} in session {}",
+                    peer_id, session_id
+                );
+                let relay_session = self
+                    .relay_sessions
+                    .get_mut(&session_id)
+                    .ok_or(RelayError::UnknownSession)?;
+                let round = relay_msg.round;
+                relay_session.update_stored_messages(round, peer_id, client_message);
+                self.updated_sessions.insert(session_id);
+                info!("Stored message of client {}", peer_id);
+
+                // Like a query, the response holds the messages which fit the byte limit,
+                // the client queries for the rest
+                let response = relay_session
+                    .stored_messages()
+                    .get_messages_page(round, &[], None, self.max_page_bytes)
+                    .messages;
+                // If received a message from each party, increase round
+                relay_session.try_increase_round(relay_session.protocol().capacity);
+                if relay_session.round() != round {
+                    relay_session.start_round(self.current_block);
+                }
+                Ok(serde_json::to_string(&response).unwrap())
             }
-            _ => unimplemented!("This is not yet implemented"),
+            _ => Err(RelayError::UnsupportedMessage),
         }
     }
+
+    /// Answers a query at the given path, with the JSON of the response
+    fn answer_query(&self, path: &str, data: &[u8]) -> Result<String, RelayError> {
+        let path = QueryPath::parse(path).ok_or(RelayError::InvalidQuery)?;
+        debug!("Query: {:?}", path);
+        let response = match path {
+            QueryPath::MissingMessages => {
+                let request: MissingMessagesRequest = parse_query(data)?;
+                to_log(&self.missing_messages(&request)?)
+            }
+            QueryPath::Messages => {
+                let request: MessagesPageRequest = parse_query(data)?;
+                to_log(&self.messages_page(request.session_id, request.round, &request.page)?)
+            }
+            QueryPath::PeerKeys => {
+                let request: PeerKeysRequest = parse_query(data)?;
+                to_log(&self.relay_session(request.session_id)?.peer_keys())
+            }
+            QueryPath::Status => {
+                let request: SessionStatusRequest = parse_query(data)?;
+                to_log(&self.session_status(request.session_id)?)
+            }
+            QueryPath::SessionStatus(session_id) => to_log(&self.session_status(session_id)?),
+            QueryPath::SessionPeers(session_id) => {
+                to_log(&self.relay_session(session_id)?.peer_keys())
+            }
+            QueryPath::SessionRound(session_id, round) => {
+                // The first page is returned if no page is requested
+                let page = if data.is_empty() {
+                    PageRequest::default()
+                } else {
+                    parse_query(data)?
+                };
+                to_log(&self.messages_page(session_id, round, &page)?)
+            }
+            QueryPath::Protocols => to_log(&self.protocols.protocols()),
+        };
+        Ok(response)
+    }
+
+    fn relay_session(&self, session_id: SessionIdentifier) -> Result<&RelaySession, RelayError> {
+        self.relay_sessions
+            .get(&session_id)
+            .ok_or(RelayError::UnknownSession)
+    }
+
+    /// Returns the stored messages requested by a missing messages query
+    fn missing_messages(
+        &self,
+        missing_messages: &MissingMessagesRequest,
+    ) -> Result<BTreeMap<u32, ClientMessage>, RelayError> {
+        debug!(
+            "Requested round {} of session {}",
+            missing_messages.round, missing_messages.session_id
+        );
+
+        // Messages over the byte limit are left to the following queries
+        let page = self
+            .relay_session(missing_messages.session_id)?
+            .stored_messages()
+            .get_messages_page(
+                missing_messages.round,
+                &missing_messages.missing_clients,
+                None,
+                self.max_page_bytes,
+            );
+        Ok(page.messages)
+    }
+
+    /// Returns a page of the messages stored for a round,
+    /// limited to the bytes requested or to the limit of the app if lower
+    fn messages_page(
+        &self,
+        session_id: SessionIdentifier,
+        round: u32,
+        page: &PageRequest,
+    ) -> Result<MessagesPage, RelayError> {
+        let max_bytes = page.max_bytes.map_or(self.max_page_bytes, |max_bytes| {
+            max_bytes.min(self.max_page_bytes)
+        });
+        Ok(self
+            .relay_session(session_id)?
+            .stored_messages()
+            .get_messages_page(round, &page.peers, page.after, max_bytes))
+    }
+
+    /// Returns the status of a session, with the peers blamed if it was aborted
+    fn session_status(&self, session_id: SessionIdentifier) -> Result<SessionStatus, RelayError> {
+        let relay_session = self.relay_session(session_id)?;
+        let protocol = relay_session.protocol();
+        Ok(SessionStatus {
+            state: relay_session.state(),
+            protocol_id: protocol.id,
+            capacity: protocol.capacity,
+            registered: relay_session.peer_keys().len() as u32,
+            round: relay_session.round(),
+            aborted: relay_session.state() == RelaySessionState::Aborted,
+            blame: relay_session.blame(),
+        })
+    }
 }
 
 impl abci::Application for RelayApp {
+    // Tendermint compares the reported height and app hash with its own on handshake,
+    // and replays the blocks the app has not committed yet
+    fn info(&mut self, _req: &RequestInfo) -> ResponseInfo {
+        let mut resp = ResponseInfo::new();
+        info!(
+            "Info: last block height {}, app hash {}",
+            self.last_block_height,
+            hex::encode(&self.last_app_hash)
+        );
+        resp.set_data(String::from("mmpc-relay"));
+        resp.set_last_block_height(self.last_block_height);
+        resp.set_last_block_app_hash(self.last_app_hash.clone());
+        resp
+    }
+
+    // Timeouts are measured from the header of the block, which is the same on every replica
+    fn begin_block(&mut self, req: &RequestBeginBlock) -> ResponseBeginBlock {
+        let header = req.get_header();
+        self.current_block = BlockTime {
+            height: header.get_height(),
+            time: header.get_time().get_seconds(),
+        };
+        ResponseBeginBlock::new()
+    }
+
     fn check_tx(&mut self, req: &RequestCheckTx) -> ResponseCheckTx {
         let mut resp = ResponseCheckTx::new();
         let c = convert_tx(req.get_tx());
         debug!("CheckTX: Received {:?}", c);
-        let client_message: ClientMessage = serde_json::from_slice(req.get_tx()).unwrap();
-        debug!("Value is {:?}", client_message);
-        resp.set_code(self.is_valid(&client_message));
+        let result = decode_tx(req.get_tx()).and_then(|(client_message, encoding)| {
+            debug!("Value is {:?}", client_message);
+            self.is_valid(&client_message, encoding)
+        });
+        match result {
+            Ok(()) => resp.set_code(CODE_OK),
+            Err(err) => {
+                warn!("CheckTX: rejected, {}", err);
+                resp.set_code(err.code());
+                resp.set_log(err.to_string());
+            }
+        }
         resp
     }
 
@@ -87,96 +525,751 @@ impl abci::Application for RelayApp {
         let mut resp = ResponseDeliverTx::new();
         let c = convert_tx(req.get_tx());
         info!("DeliverTX: Received {:?}", c);
-        let client_message: ClientMessage = serde_json::from_slice(req.get_tx()).unwrap();
-        info!("Value is {:?} In DeliverTx", client_message);
-
-        debug!("Message type is {:?}", client_message.msg_type());
-
-        match client_message.msg_type() {
-            ClientMessageType::Register => {
-                if self.is_valid(&client_message) != 0 {
-                    resp.set_code(1);
-                    return resp;
-                }
-                let register = client_message.register.unwrap();
-                warn!(
-                    "Got register message. protocol id requested: {}",
-                    register.protocol_id
-                );
-                let client_index = self
-                    .relay_session
-                    .register_new_peer(
-                        register.addr,
-                        register.protocol_id,
-                        register.capacity,
-                        register.index,
-                    )
-                    .unwrap();
-                resp.set_code(0);
-                info!("Setting data to {:?}", resp.data);
-                let mut server_msg = ServerMessage::new();
-                server_msg.response = Some(ServerResponse::Register(client_index));
-                // TODO: Currently using log and not data, data is expecting a different encoding,
-                // sigh
-                resp.set_log(serde_json::to_string(&server_msg).unwrap().to_owned());
+        let result = decode_tx(req.get_tx()).and_then(|(client_message, encoding)| {
+            info!("Value is {:?} In DeliverTx", client_message);
+            debug!("Message type is {:?}", client_message.msg_type());
+            let events = relay_events(&client_message);
+            self.deliver(client_message, encoding)
+                .map(|log| (log, events))
+        });
+        match result {
+            Ok((log, events)) => {
+                resp.set_code(CODE_OK);
+                resp.set_log(log);
+                resp.set_events(events.into());
+                debug!("Response log {:?}", resp.log);
             }
-            ClientMessageType::RelayMessage => {
-                let relay_msg = client_message.clone().relay_message.unwrap();
-                let peer_id = relay_msg.peer_number;
-                info!("Got relay message from {}", peer_id);
-                if self.can_relay(&client_message) == 0 {
-                    debug!("I can relay this")
-                }
-                let round = self.relay_session.round();
-                self.relay_session
-                    .update_stored_messages(round, peer_id, client_message);
-                info!("Stored message of client {}", peer_id);
+            Err(err) => {
+                warn!("DeliverTX: rejected, {}", err);
+                resp.set_code(err.code());
+                resp.set_log(err.to_string());
+            }
+        }
+        resp
+    }
 
-                let response = self
-                    .relay_session
-                    .stored_messages()
-                    .get_messages_map_client_message(round);
-                resp.set_log(serde_json::to_string(&response).unwrap().to_owned());
-                debug!("Response log {:?}", resp.log);
-                self.relay_session
-                    .try_increase_round(self.relay_session.protocol().capacity);
-                // If received a message from each party, increase round
+    fn query(&mut self, req: &RequestQuery) -> ResponseQuery {
+        let mut resp = ResponseQuery::new();
+        let response = self.answer_query(req.get_path(), req.get_data());
+        match response {
+            Ok(response) => {
+                debug!("Server response {:?}", response);
+                resp.set_log(response);
                 debug!("Response log {:?}", resp.log);
+                resp.set_code(CODE_OK);
+            }
+            Err(err) => {
+                warn!("Query: rejected, {}", err);
+                resp.set_code(err.code());
+                resp.set_log(err.to_string());
             }
-            _ => unimplemented!("This is not yet implemented"),
         }
+        resp.set_index(-1);
+        resp.set_height(1_i64);
+        resp
+    }
+
+    fn end_block(&mut self, _req: &RequestEndBlock) -> ResponseEndBlock {
+        self.abort_timed_out_sessions();
+        ResponseEndBlock::new()
+    }
 
+    fn commit(&mut self, _req: &RequestCommit) -> ResponseCommit {
+        let mut resp = ResponseCommit::new();
+        let app_hash = self.app_hash();
+        self.last_block_height = self.current_block.height;
+        info!(
+            "Commit: height {}, app hash {}",
+            self.last_block_height,
+            hex::encode(&app_hash)
+        );
+        let persisted = match &self.store {
+            Some(store) => {
+                let commit_info = CommitInfo {
+                    height: self.last_block_height,
+                    app_hash: app_hash.clone(),
+                    next_session_id: self.next_session_id,
+                };
+                store.commit(&commit_info, &self.relay_sessions, &self.updated_sessions)
+            }
+            None => Ok(()),
+        };
+        match persisted {
+            Ok(()) => self.updated_sessions.clear(),
+            // The sessions stay updated, so they are stored again with the next commit.
+            // Until then a restart resumes from an earlier height, and Tendermint replays the rest
+            Err(err) => error!(
+                "Commit: failed to persist height {}, {}",
+                self.last_block_height, err
+            ),
+        }
+        self.last_app_hash = app_hash.clone();
+        resp.set_data(app_hash);
         resp
     }
+}
 
-    fn query(&mut self, req: &RequestQuery) -> ResponseQuery {
-        let mut resp = ResponseQuery::new();
+#[cfg(test)]
+mod tests {
+    use super::RelayApp;
+    use crate::fixtures::{protocols, TEST_PROTOCOL};
+    use crate::relay_session::{RelaySession, RelaySessionState};
+
+    use crate::relay_store::RelayStore;
+    use crate::timeout::Deadline;
+    use abci::{
+        Application, RequestBeginBlock, RequestCheckTx, RequestCommit, RequestDeliverTx,
+        RequestEndBlock, RequestInfo, RequestQuery,
+    };
+    use ed25519_dalek::{PublicKey, SecretKey};
+    use mmpc_server_common::codec::{Codec, Encoding};
+    use mmpc_server_common::common::{
+        session_peers_path, session_round_path, session_status_path, MESSAGES_PATH, PEER_EVENT_KEY,
+        PEER_KEYS_PATH, PROTOCOLS_PATH, RELAY_EVENT_TYPE, ROUND_EVENT_KEY, SESSION_EVENT_KEY,
+        SESSION_STATUS_PATH,
+    };
+    use mmpc_server_common::error::{RelayError, CODE_OK};
+    use mmpc_server_common::identity::{identity_key, Keypair};
+    use mmpc_server_common::payload::Payload;
+    use mmpc_server_common::protocol::Protocol;
+    use mmpc_server_common::{
+        ClientMessage, MessagesPage, MessagesPageRequest, RelayMessage, ServerMessage,
+        ServerResponse, SessionIdentifier, SessionStatus,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
 
-        let missing_messages: MissingMessagesRequest = serde_json::from_slice(&req.data).unwrap();
-        debug!("Query: Received {:?}", missing_messages);
+    // Keys are derived from a seed, so that separate apps see the same transactions
+    fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
 
-        // TODO: Error handle
-        let requested_round = missing_messages.round;
-        let mut missing_clients = missing_messages.missing_clients;
-        debug!("Requested round {}", requested_round);
+    fn deliver(app: &mut RelayApp, msg: &ClientMessage) -> (u32, String) {
+        deliver_encoded(app, msg, Encoding::Json)
+    }
 
-        let stored_messages = self.relay_session.stored_messages();
-        // debug!("Query: All Stored Messages: {:?}", stored_messages);
+    fn deliver_encoded(
+        app: &mut RelayApp,
+        msg: &ClientMessage,
+        encoding: Encoding,
+    ) -> (u32, String) {
+        let mut req = RequestDeliverTx::new();
+        req.set_tx(encoding.encode(msg));
+        let resp = app.deliver_tx(&req);
+        (resp.get_code(), resp.get_log().to_owned())
+    }
+
+    fn register(
+        app: &mut RelayApp,
+        session_id: Option<SessionIdentifier>,
+        capacity: u32,
+        seed: u8,
+    ) -> (u32, ServerMessage) {
+        let identity = keypair(seed);
+        let mut msg = ClientMessage::new();
+        msg.set_register(
+            identity_key(&identity),
+            session_id,
+            TEST_PROTOCOL,
+            capacity,
+            -1,
+            None,
+        );
+        msg.sign(&identity);
+        let (code, log) = deliver(app, &msg);
+        (code, serde_json::from_str(&log).unwrap_or_default())
+    }
 
-        if missing_clients.len() > MAX_CLIENTS {
-            missing_clients.truncate(MAX_CLIENTS);
+    fn check(app: &mut RelayApp, tx: Vec<u8>) -> (u32, String) {
+        let mut req = RequestCheckTx::new();
+        req.set_tx(tx);
+        let resp = app.check_tx(&req);
+        (resp.get_code(), resp.get_log().to_owned())
+    }
+
+    fn relay_message(session_id: SessionIdentifier, peer_number: u32) -> ClientMessage {
+        relay_message_for(session_id, peer_number, TEST_PROTOCOL, 0)
+    }
+
+    fn relay_message_for(
+        session_id: SessionIdentifier,
+        peer_number: u32,
+        protocol_id: u32,
+        round: u32,
+    ) -> ClientMessage {
+        let mut relay_msg = RelayMessage::new(peer_number, session_id, protocol_id, round);
+        relay_msg.message = String::from("message");
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(relay_msg);
+        msg
+    }
+
+    fn registered_as(server_msg: &ServerMessage) -> (SessionIdentifier, u32) {
+        match server_msg.response {
+            Some(ServerResponse::Register(session_id, peer_id)) => (session_id, peer_id),
+            _ => panic!("Expected a register response"),
         }
-        let response =
-            stored_messages.get_messages_map_from_vector(requested_round, &missing_clients);
+    }
 
-        debug!("Server response {:?}", response);
+    #[test]
+    fn test_register_without_session_opens_session() {
+        let mut app = RelayApp::new(protocols());
+        let (code, first) = register(&mut app, None, 2, 1);
+        assert_eq!(code, 0);
+        let (code, second) = register(&mut app, None, 2, 2);
+        assert_eq!(code, 0);
+        // Each register without an identifier opens a session of its own
+        let (first_session, first_peer) = registered_as(&first);
+        let (second_session, second_peer) = registered_as(&second);
+        assert_ne!(first_session, second_session);
+        assert_eq!(first_peer, 1);
+        assert_eq!(second_peer, 1);
+    }
 
-        resp.set_log(serde_json::to_string(&response).unwrap().to_owned());
-        debug!("Response log {:?}", resp.log);
+    #[test]
+    fn test_register_to_existing_session() {
+        let mut app = RelayApp::new(protocols());
+        let (_, opened) = register(&mut app, None, 2, 1);
+        let (session_id, _) = registered_as(&opened);
+        let (code, joined) = register(&mut app, Some(session_id), 2, 2);
+        assert_eq!(code, 0);
+        assert_eq!(registered_as(&joined), (session_id, 2));
+        // The session is full
+        let (code, _) = register(&mut app, Some(session_id), 2, 3);
+        assert_eq!(code, RelayError::CantRegister.code());
+    }
 
-        resp.set_code(0);
-        resp.set_index(-1);
-        resp.set_height(1_i64);
-        resp
+    #[test]
+    fn test_app_hash_is_deterministic() {
+        let mut first = RelayApp::new(protocols());
+        let mut second = RelayApp::new(protocols());
+        assert_eq!(first.app_hash(), second.app_hash());
+
+        for seed in 1..3 {
+            register(&mut first, None, 2, seed);
+            register(&mut second, None, 2, seed);
+            assert_eq!(first.app_hash(), second.app_hash());
+        }
+
+        let before = first.app_hash();
+        register(&mut first, Some(1), 2, 3);
+        assert_ne!(first.app_hash(), before);
+        assert_ne!(first.app_hash(), second.app_hash());
+    }
+
+    #[test]
+    fn test_resume_from_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RelayStore::open(dir.path()).unwrap();
+        let app_hash = {
+            let mut app = RelayApp::with_store(store.clone(), protocols()).unwrap();
+            empty_block(&mut app, 1);
+            register(&mut app, None, 2, 1);
+            app.commit(&RequestCommit::new());
+            empty_block(&mut app, 2);
+            register(&mut app, Some(1), 2, 2);
+            app.commit(&RequestCommit::new());
+            // Not committed, should be replayed by Tendermint
+            register(&mut app, None, 2, 3);
+            app.last_app_hash.clone()
+        };
+
+        // Restart on the same store, the app only reads back what was committed to it
+        let mut app = RelayApp::with_store(store, protocols()).unwrap();
+        let resp = app.info(&RequestInfo::new());
+        assert_eq!(resp.get_last_block_height(), 2);
+        assert_eq!(resp.get_last_block_app_hash(), &app_hash[..]);
+        assert_eq!(app.app_hash(), app_hash);
+        // The uncommitted session is opened again on replay
+        let (_, replayed) = register(&mut app, None, 2, 3);
+        assert_eq!(registered_as(&replayed), (2, 1));
+    }
+
+    #[test]
+    fn test_register_to_unknown_session() {
+        let mut app = RelayApp::new(protocols());
+        let (code, _) = register(&mut app, Some(42), 2, 1);
+        assert_eq!(code, RelayError::UnknownSession.code());
+    }
+
+    #[test]
+    fn test_register_with_invalid_signature() {
+        let mut app = RelayApp::new(protocols());
+        let mut msg = ClientMessage::new();
+        // Signed by a key other than the one being registered
+        msg.set_register(identity_key(&keypair(1)), None, TEST_PROTOCOL, 2, -1, None);
+        msg.sign(&keypair(2));
+        let (code, log) = check(&mut app, serde_json::to_vec(&msg).unwrap());
+        assert_eq!(code, RelayError::InvalidSignature.code());
+        assert_eq!(log, "Invalid signature");
+        let (code, _) = deliver(&mut app, &msg);
+        assert_eq!(code, RelayError::InvalidSignature.code());
+    }
+
+    #[test]
+    fn test_relay_message_signed_by_registered_peer() {
+        let mut app = RelayApp::new(protocols());
+        let (_, opened) = register(&mut app, None, 2, 1);
+        let (session_id, peer_id) = registered_as(&opened);
+        register(&mut app, Some(session_id), 2, 2);
+
+        // Not signed
+        let mut msg = relay_message(session_id, peer_id);
+        assert_eq!(
+            deliver(&mut app, &msg).0,
+            RelayError::InvalidSignature.code()
+        );
+        // Signed by the other peer
+        msg.sign(&keypair(2));
+        assert_eq!(
+            deliver(&mut app, &msg).0,
+            RelayError::InvalidSignature.code()
+        );
+        // Signed by a peer which is not registered
+        let mut msg = relay_message(session_id, 3);
+        msg.sign(&keypair(3));
+        assert_eq!(deliver(&mut app, &msg).0, RelayError::NotAPeer.code());
+
+        let mut msg = relay_message(session_id, peer_id);
+        msg.sign(&keypair(1));
+        assert_eq!(deliver(&mut app, &msg).0, CODE_OK);
+    }
+
+    #[test]
+    fn test_encoding_negotiated_at_registration() {
+        let mut app = RelayApp::new(protocols());
+        let mut opened = ClientMessage::new();
+        opened.set_register(identity_key(&keypair(1)), None, TEST_PROTOCOL, 2, -1, None);
+        opened.sign(&keypair(1));
+        let (code, log) = deliver_encoded(&mut app, &opened, Encoding::Cbor);
+        assert_eq!(code, CODE_OK);
+        // Responses are JSON whatever the encoding of the transaction
+        let (session_id, cbor_peer) = registered_as(&serde_json::from_str(&log).unwrap());
+        let (_, joined) = register(&mut app, Some(session_id), 2, 2);
+        let (_, json_peer) = registered_as(&joined);
+
+        let mut msg = relay_message(session_id, cbor_peer);
+        msg.sign(&keypair(1));
+        assert_eq!(deliver(&mut app, &msg).0, RelayError::WrongEncoding.code());
+        assert_eq!(deliver_encoded(&mut app, &msg, Encoding::Cbor).0, CODE_OK);
+        let mut msg = relay_message(session_id, json_peer);
+        msg.sign(&keypair(2));
+        assert_eq!(
+            deliver_encoded(&mut app, &msg, Encoding::Cbor).0,
+            RelayError::WrongEncoding.code()
+        );
+        assert_eq!(deliver(&mut app, &msg).0, CODE_OK);
+
+        let restored = RelaySession::from_state(
+            &app.relay_sessions[&session_id].serialize_state(),
+            Arc::clone(&app.protocols),
+        )
+        .unwrap();
+        assert_eq!(restored.encoding_of(cbor_peer), Encoding::Cbor);
+        assert_eq!(restored.encoding_of(json_peer), Encoding::Json);
+    }
+
+    #[test]
+    fn test_threshold_signing_session() {
+        let mut app = RelayApp::new(protocols());
+        // Peers 1 and 3 of 3 key shares sign together
+        let mut opened = ClientMessage::new();
+        opened.set_register(
+            identity_key(&keypair(1)),
+            None,
+            TEST_PROTOCOL,
+            2,
+            1,
+            Some(3),
+        );
+        opened.sign(&keypair(1));
+        let (code, log) = deliver(&mut app, &opened);
+        assert_eq!(code, CODE_OK);
+        let (session_id, peer_id) = registered_as(&serde_json::from_str(&log).unwrap());
+        assert_eq!(peer_id, 1);
+
+        let mut joined = ClientMessage::new();
+        joined.set_register(
+            identity_key(&keypair(3)),
+            Some(session_id),
+            TEST_PROTOCOL,
+            2,
+            3,
+            Some(3),
+        );
+        joined.sign(&keypair(3));
+        let (code, log) = deliver(&mut app, &joined);
+        assert_eq!(code, CODE_OK);
+        assert_eq!(
+            registered_as(&serde_json::from_str(&log).unwrap()),
+            (session_id, 3)
+        );
+
+        // The round ends once both signing peers sent their message
+        for seed in [1, 3].iter() {
+            let mut msg = relay_message(session_id, u32::from(*seed));
+            msg.sign(&keypair(*seed));
+            assert_eq!(deliver(&mut app, &msg).0, CODE_OK);
+        }
+        assert_eq!(app.relay_sessions[&session_id].round(), 1);
+    }
+
+    #[test]
+    fn test_protocol_round_structure() {
+        let mut app = RelayApp::new(protocols());
+        // Multi-party key generation sends a public key in its only round
+        let mut session_id = None;
+        for seed in 1..3 {
+            let mut msg = ClientMessage::new();
+            msg.set_register(identity_key(&keypair(seed)), session_id, 1, 2, -1, None);
+            msg.sign(&keypair(seed));
+            let (code, log) = deliver(&mut app, &msg);
+            assert_eq!(code, CODE_OK);
+            session_id = Some(registered_as(&serde_json::from_str(&log).unwrap()).0);
+        }
+        let session_id = session_id.unwrap();
+        let mut msg = relay_message_for(session_id, 1, 1, 0);
+        msg.sign(&keypair(1));
+        assert_eq!(
+            deliver(&mut app, &msg).0,
+            RelayError::UnexpectedMessage.code()
+        );
+        for seed in 1..3 {
+            let mut msg = relay_message_for(session_id, u32::from(seed), 1, 0);
+            let relay_msg = msg.relay_message.as_mut().unwrap();
+            relay_msg.message = Payload::PublicKey(String::from("{}")).encode();
+            msg.sign(&keypair(seed));
+            assert_eq!(deliver(&mut app, &msg).0, CODE_OK);
+        }
+        // There is no round after the last one
+        let mut msg = relay_message_for(session_id, 1, 1, 1);
+        msg.sign(&keypair(1));
+        assert_eq!(deliver(&mut app, &msg).0, RelayError::WrongRound.code());
+
+        // Sessions are only opened for supported protocols and capacities
+        let mut msg = ClientMessage::new();
+        msg.set_register(identity_key(&keypair(3)), None, 3, 1, -1, None);
+        msg.sign(&keypair(3));
+        assert_eq!(deliver(&mut app, &msg).0, RelayError::CantRegister.code());
+    }
+
+    #[test]
+    fn test_relay_message_events() {
+        let mut app = RelayApp::new(protocols());
+        let (_, opened) = register(&mut app, None, 2, 1);
+        let (session_id, peer_id) = registered_as(&opened);
+        register(&mut app, Some(session_id), 2, 2);
+
+        let mut msg = relay_message(session_id, peer_id);
+        msg.sign(&keypair(1));
+        let mut req = RequestDeliverTx::new();
+        req.set_tx(serde_json::to_vec(&msg).unwrap());
+        let resp = app.deliver_tx(&req);
+        assert_eq!(resp.get_code(), CODE_OK);
+        let events = resp.get_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].get_field_type(), RELAY_EVENT_TYPE);
+        let attributes: Vec<(&[u8], &[u8])> = events[0]
+            .get_attributes()
+            .iter()
+            .map(|attribute| (attribute.get_key(), attribute.get_value()))
+            .collect();
+        let session = session_id.to_string();
+        let peer = peer_id.to_string();
+        assert_eq!(
+            attributes,
+            vec![
+                (SESSION_EVENT_KEY.as_bytes(), session.as_bytes()),
+                (ROUND_EVENT_KEY.as_bytes(), "0".as_bytes()),
+                (PEER_EVENT_KEY.as_bytes(), peer.as_bytes()),
+            ]
+        );
+
+        // Rejected messages and registrations have no events
+        let resp = app.deliver_tx(&req);
+        assert_eq!(resp.get_code(), RelayError::DuplicateMessage.code());
+        assert!(resp.get_events().is_empty());
+        let mut msg = ClientMessage::new();
+        msg.set_register(identity_key(&keypair(3)), None, TEST_PROTOCOL, 2, -1, None);
+        msg.sign(&keypair(3));
+        req.set_tx(serde_json::to_vec(&msg).unwrap());
+        assert!(app.deliver_tx(&req).get_events().is_empty());
+    }
+
+    #[test]
+    fn test_check_tx_rejections() {
+        let mut app = RelayApp::new(protocols());
+        let (_, opened) = register(&mut app, None, 2, 1);
+        let (session_id, peer_id) = registered_as(&opened);
+        register(&mut app, Some(session_id), 2, 2);
+
+        let signed = |mut msg: ClientMessage| {
+            msg.sign(&keypair(1));
+            serde_json::to_vec(&msg).unwrap()
+        };
+
+        // Unparsable transactions
+        assert_eq!(
+            check(&mut app, b"not json".to_vec()).0,
+            RelayError::InvalidTransaction.code()
+        );
+        assert_eq!(
+            check(&mut app, vec![0xff, 0xfe]).0,
+            RelayError::InvalidTransaction.code()
+        );
+        // Unknown session
+        let tx = signed(relay_message(session_id + 1, peer_id));
+        assert_eq!(check(&mut app, tx).0, RelayError::UnknownSession.code());
+        // Wrong protocol
+        let tx = signed(relay_message_for(session_id, peer_id, TEST_PROTOCOL + 1, 0));
+        assert_eq!(check(&mut app, tx).0, RelayError::WrongProtocol.code());
+        // Wrong round
+        let tx = signed(relay_message_for(session_id, peer_id, TEST_PROTOCOL, 1));
+        assert_eq!(check(&mut app, tx).0, RelayError::WrongRound.code());
+        // Unsupported message
+        assert_eq!(
+            check(&mut app, signed(ClientMessage::new())).0,
+            RelayError::UnsupportedMessage.code()
+        );
+
+        let msg = relay_message(session_id, peer_id);
+        let tx = signed(msg.clone());
+        assert_eq!(check(&mut app, tx.clone()).0, CODE_OK);
+        let mut req = RequestDeliverTx::new();
+        req.set_tx(tx.clone());
+        assert_eq!(app.deliver_tx(&req).get_code(), CODE_OK);
+        // The slot of this peer in the round is taken
+        let (code, log) = check(&mut app, tx);
+        assert_eq!(code, RelayError::DuplicateMessage.code());
+        assert_eq!(log, "Peer already sent a message for this round");
+        assert_eq!(
+            deliver(&mut app, &msg).0,
+            RelayError::DuplicateMessage.code()
+        );
+    }
+
+    #[test]
+    fn test_malformed_input_is_rejected() {
+        let mut app = RelayApp::new(protocols());
+        let mut req = RequestDeliverTx::new();
+        req.set_tx(vec![0xff, 0xfe]);
+        let resp = app.deliver_tx(&req);
+        assert_eq!(resp.get_code(), RelayError::InvalidTransaction.code());
+        assert_eq!(resp.get_log(), "Unable to parse transaction");
+
+        let mut req = RequestQuery::new();
+        req.set_data(b"{}".to_vec());
+        let resp = app.query(&req);
+        assert_eq!(
+            RelayError::from_code(resp.get_code()),
+            Some(RelayError::InvalidQuery)
+        );
+        req.set_data(br#"{"session_id":1,"round":0,"missing_clients":[1]}"#.to_vec());
+        assert_eq!(
+            app.query(&req).get_code(),
+            RelayError::UnknownSession.code()
+        );
+
+        register(&mut app, None, 2, 1);
+        assert_eq!(app.query(&req).get_code(), CODE_OK);
+    }
+
+    #[test]
+    fn test_query_peer_keys() {
+        let mut app = RelayApp::new(protocols());
+        let (_, opened) = register(&mut app, None, 2, 1);
+        let (session_id, _) = registered_as(&opened);
+        register(&mut app, Some(session_id), 2, 2);
+
+        let mut req = RequestQuery::new();
+        req.set_path(String::from(PEER_KEYS_PATH));
+        req.set_data(format!("{{\"session_id\":{}}}", session_id).into_bytes());
+        let resp = app.query(&req);
+        assert_eq!(resp.get_code(), CODE_OK);
+        let keys: BTreeMap<u32, String> = serde_json::from_str(resp.get_log()).unwrap();
+        assert_eq!(keys[&1], identity_key(&keypair(1)));
+        assert_eq!(keys[&2], identity_key(&keypair(2)));
+
+        // Point-to-point messages are stored like any other message
+        let mut msg = relay_message(session_id, 1);
+        {
+            let relay_msg = msg.relay_message.as_mut().unwrap();
+            assert!(relay_msg.add_p2p_message(2, &keys[&2], &String::from("share")));
+        }
+        msg.sign(&keypair(1));
+        let (code, log) = deliver(&mut app, &msg);
+        assert_eq!(code, CODE_OK);
+        let stored: BTreeMap<u32, ClientMessage> = serde_json::from_str(&log).unwrap();
+        let stored = stored[&1].relay_message.clone().unwrap();
+        assert_eq!(
+            stored.p2p_message_to(2, &keypair(2)),
+            Some(String::from("share"))
+        );
+        assert_eq!(stored.p2p_message_to(2, &keypair(1)), None);
+    }
+
+    #[test]
+    fn test_query_messages_pages() {
+        let mut signed = relay_message(1, 1);
+        signed.sign(&keypair(1));
+        let msg_bytes = serde_json::to_vec(&signed).unwrap().len();
+        // A page holds a single message
+        let mut app = RelayApp::new(protocols()).with_max_page_bytes(msg_bytes + msg_bytes / 2);
+        let (_, opened) = register(&mut app, None, 3, 1);
+        let (session_id, _) = registered_as(&opened);
+        register(&mut app, Some(session_id), 3, 2);
+        register(&mut app, Some(session_id), 3, 3);
+        for seed in 1..4 {
+            let mut msg = relay_message(session_id, u32::from(seed));
+            msg.sign(&keypair(seed));
+            let (code, log) = deliver(&mut app, &msg);
+            assert_eq!(code, CODE_OK);
+            // The messages returned on delivery are limited like a page
+            let stored: BTreeMap<u32, ClientMessage> = serde_json::from_str(&log).unwrap();
+            assert_eq!(stored.len(), 1);
+        }
+
+        let mut query = |request: MessagesPageRequest| {
+            let mut req = RequestQuery::new();
+            req.set_path(String::from(MESSAGES_PATH));
+            req.set_data(serde_json::to_vec(&request).unwrap());
+            let resp = app.query(&req);
+            assert_eq!(resp.get_code(), CODE_OK);
+            serde_json::from_str::<MessagesPage>(resp.get_log()).unwrap()
+        };
+        let mut request = MessagesPageRequest {
+            session_id,
+            round: 0,
+            ..Default::default()
+        };
+        let mut senders = Vec::new();
+        loop {
+            let page = query(request.clone());
+            assert_eq!(page.messages.len(), 1);
+            senders.extend(page.messages.keys().cloned());
+            request.page.after = page.next;
+            if request.page.after.is_none() {
+                break;
+            }
+        }
+        assert_eq!(senders, vec![1, 2, 3]);
+
+        // A client may ask for more bytes than the limit of the app, but is not given them
+        request.page.max_bytes = Some(10 * msg_bytes);
+        assert_eq!(query(request.clone()).messages.len(), 1);
+        request.page.max_bytes = None;
+        request.page.peers = vec![3];
+        assert_eq!(query(request).next, None);
+    }
+
+    fn routed_query(app: &mut RelayApp, path: &str) -> (u32, String) {
+        let mut req = RequestQuery::new();
+        req.set_path(path.to_owned());
+        let resp = app.query(&req);
+        (resp.get_code(), resp.get_log().to_owned())
+    }
+
+    #[test]
+    fn test_routed_query_paths() {
+        let mut app = RelayApp::new(protocols());
+        let (_, opened) = register(&mut app, None, 2, 1);
+        let (session_id, _) = registered_as(&opened);
+
+        let (code, log) = routed_query(&mut app, &session_status_path(session_id));
+        assert_eq!(code, CODE_OK);
+        let status: SessionStatus = serde_json::from_str(&log).unwrap();
+        assert_eq!(status.state, RelaySessionState::Uninitialized);
+        assert_eq!(
+            (status.protocol_id, status.capacity, status.registered),
+            (TEST_PROTOCOL, 2, 1)
+        );
+
+        register(&mut app, Some(session_id), 2, 2);
+        let mut msg = relay_message(session_id, 1);
+        msg.sign(&keypair(1));
+        assert_eq!(deliver(&mut app, &msg).0, CODE_OK);
+        let (code, log) = routed_query(&mut app, &session_peers_path(session_id));
+        assert_eq!(code, CODE_OK);
+        let keys: BTreeMap<u32, String> = serde_json::from_str(&log).unwrap();
+        assert_eq!(keys[&2], identity_key(&keypair(2)));
+        let (code, log) = routed_query(&mut app, &session_round_path(session_id, 0));
+        assert_eq!(code, CODE_OK);
+        let page: MessagesPage = serde_json::from_str(&log).unwrap();
+        assert_eq!(page.messages.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(page.next, None);
+
+        let (code, log) = routed_query(&mut app, PROTOCOLS_PATH);
+        assert_eq!(code, CODE_OK);
+        let protocols: Vec<Protocol> = serde_json::from_str(&log).unwrap();
+        assert_eq!(protocols, app.protocols.protocols());
+
+        assert_eq!(
+            routed_query(&mut app, &session_status_path(session_id + 1)).0,
+            RelayError::UnknownSession.code()
+        );
+        assert_eq!(
+            routed_query(&mut app, "/session/1/blocks").0,
+            RelayError::InvalidQuery.code()
+        );
+    }
+
+    // Executes an empty block at the given height, a second apart from the previous one
+    fn empty_block(app: &mut RelayApp, height: i64) {
+        let mut req = RequestBeginBlock::new();
+        let header = req.mut_header();
+        header.set_height(height);
+        header.mut_time().set_seconds(height);
+        app.begin_block(&req);
+        app.end_block(&RequestEndBlock::new());
+    }
+
+    fn status(app: &mut RelayApp, session_id: SessionIdentifier) -> SessionStatus {
+        let mut req = RequestQuery::new();
+        req.set_path(String::from(SESSION_STATUS_PATH));
+        req.set_data(format!("{{\"session_id\":{}}}", session_id).into_bytes());
+        let resp = app.query(&req);
+        assert_eq!(resp.get_code(), CODE_OK);
+        serde_json::from_str(resp.get_log()).unwrap()
+    }
+
+    #[test]
+    fn test_round_timeout_aborts_session() {
+        let round_deadline = Deadline {
+            blocks: Some(3),
+            seconds: None,
+        };
+        let mut app =
+            RelayApp::new(protocols()).with_deadlines(round_deadline, Deadline::default());
+        empty_block(&mut app, 1);
+        let (_, opened) = register(&mut app, None, 3, 1);
+        let (session_id, _) = registered_as(&opened);
+        register(&mut app, Some(session_id), 3, 2);
+        // The round only starts once every peer registered
+        empty_block(&mut app, 4);
+        register(&mut app, Some(session_id), 3, 3);
+        let mut msg = relay_message(session_id, 2);
+        msg.sign(&keypair(2));
+        assert_eq!(deliver(&mut app, &msg).0, CODE_OK);
+
+        empty_block(&mut app, 6);
+        assert!(!status(&mut app, session_id).aborted);
+        empty_block(&mut app, 7);
+        let expected = SessionStatus {
+            state: RelaySessionState::Aborted,
+            protocol_id: TEST_PROTOCOL,
+            capacity: 3,
+            registered: 3,
+            round: 0,
+            aborted: true,
+            blame: vec![1, 3],
+        };
+        assert_eq!(status(&mut app, session_id), expected);
+
+        // No more messages are relayed in an aborted session
+        let mut msg = relay_message(session_id, 1);
+        msg.sign(&keypair(1));
+        assert_eq!(deliver(&mut app, &msg).0, RelayError::SessionAborted.code());
     }
 }
@@ -1,42 +1,41 @@
 use log::{debug, info, warn};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, RwLock};
 
+use mmpc_server_common::codec::Encoding;
+use mmpc_server_common::error::RelayError;
+use mmpc_server_common::identity::IdentityKey;
 use mmpc_server_common::{ClientMessage, StoredMessages};
 use mmpc_server_common::{PeerIdentifier, ProtocolIdentifier, RelayMessage};
 
-use mmpc_server_common::protocol::ProtocolDescriptor;
+use mmpc_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
+
+use crate::timeout::{BlockTime, Deadline};
 
 #[derive(Clone, Debug)]
 pub struct Peer {
     pub peer_id: PeerIdentifier,
-    pub addr: SocketAddr,
+    pub public_key: IdentityKey,
     pub registered: bool,
 }
 
 impl Peer {
-    pub fn new(addr: SocketAddr) -> Peer {
+    pub fn new(public_key: IdentityKey) -> Peer {
         Peer {
             peer_id: 0,
-            addr: addr,
+            public_key,
             registered: false,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum RelaySessionState {
-    Empty,
-
-    Uninitialized,
-
-    Initialized,
-}
+pub use mmpc_server_common::RelaySessionState;
 
 #[derive(Debug, Clone)]
 pub struct RelaySession {
-    peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    peers: Arc<RwLock<HashMap<IdentityKey, Peer>>>,
 
     active_peers: Arc<RwLock<u32>>,
 
@@ -47,6 +46,52 @@ pub struct RelaySession {
     round: Arc<RwLock<u32>>,
 
     stored_messages: Arc<RwLock<StoredMessages>>,
+
+    // Blocks the session and its current round started at
+    session_start: Arc<RwLock<BlockTime>>,
+
+    round_start: Arc<RwLock<BlockTime>>,
+
+    // Peers which did not send their message for the round the session was aborted in
+    blame: Arc<RwLock<Vec<PeerIdentifier>>>,
+
+    // Encodings of the peers which did not register with a JSON message
+    encodings: Arc<RwLock<BTreeMap<PeerIdentifier, Encoding>>>,
+
+    // Protocols supported by the relay, shared by all sessions
+    protocols: Arc<ProtocolRegistry>,
+}
+
+/// A deterministic view of the relay session state.
+/// Peers are ordered by their identity key, and stored messages by round and peer
+#[derive(Serialize, Deserialize)]
+struct RelaySessionSnapshot {
+    peers: BTreeMap<IdentityKey, PeerIdentifier>,
+
+    protocol_id: ProtocolIdentifier,
+
+    capacity: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    share_count: Option<u32>,
+
+    state: RelaySessionState,
+
+    round: u32,
+
+    stored_messages: StoredMessages,
+
+    #[serde(default)]
+    session_start: BlockTime,
+
+    #[serde(default)]
+    round_start: BlockTime,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    blame: Vec<PeerIdentifier>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    encodings: BTreeMap<PeerIdentifier, Encoding>,
 }
 
 impl RelaySession {
@@ -62,34 +107,36 @@ impl RelaySession {
     }
 
     /// Register a new peer to this relay session
-    /// after adding this address as a peer,
+    /// after adding this identity key as a peer,
     /// the state might change to either Uninitialized (if this is the first peer registering)
     /// or Initialized (meaning session has reached the required # of participants)
     pub fn register_new_peer(
         &self,
-        addr: SocketAddr,
-        protocol_id: ProtocolIdentifier,
-        capacity: u32,
+        public_key: IdentityKey,
+        protocol: ProtocolDescriptor,
         index: i32,
     ) -> Option<u32> {
-        let _addr = &addr;
         let number_of_active_peers = self.get_number_of_active_peers();
 
-        let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
         debug!("-----------------PEERS: {:?}---------------", self.peers);
-        if self.can_register(_addr, protocol_descriptor) {
-            let mut peer = Peer::new(addr);
+        if self.can_register(&public_key, index, protocol.clone()) {
+            let peer_id = if index == -1 {
+                self.next_free_peer_id()
+            } else {
+                index as u32
+            };
+            let mut peer = Peer::new(public_key.clone());
             peer.registered = true;
-            peer.peer_id = number_of_active_peers + 1;
+            peer.peer_id = peer_id;
 
-            self.peers.write().unwrap().insert(addr, peer);
+            self.peers.write().unwrap().insert(public_key, peer);
 
             // activate this connection as a peer
             // if needed, set the ProtocolDescriptor for this sessuib
             // and change the state
             let state = self.state();
             if let RelaySessionState::Empty = state {
-                self.set_protocol(ProtocolDescriptor::new(protocol_id, capacity));
+                self.set_protocol(protocol);
                 info!("Relay session state is now Uninitialized");
                 self.set_state(RelaySessionState::Uninitialized);
             }
@@ -98,27 +145,28 @@ impl RelaySession {
                 info!("Relay session state is now Initialized");
                 self.set_state(RelaySessionState::Initialized);
             }
-            info!("Registered peer {}", number_of_active_peers + 1);
-            if index == -1 {
-                Some(number_of_active_peers + 1) //peer_id
-            } else {
-                Some(index as u32) //peer_id
-            }
+            info!("Registered peer {}", peer_id);
+            Some(peer_id)
         } else {
-            warn!("Unable to register {:}", addr); // error
+            warn!("Unable to register {:}", public_key); // error
             None
         }
     }
 
-    /// Checks if it is possible for this address
-    /// to register as a peer in this session
-    pub fn can_register(&self, _addr: &SocketAddr, protocol: ProtocolDescriptor) -> bool {
+    /// Checks if it is possible for this identity key
+    /// to register as a peer in this session, with the requested index (-1 for any index)
+    pub fn can_register(
+        &self,
+        public_key: &IdentityKey,
+        index: i32,
+        protocol: ProtocolDescriptor,
+    ) -> bool {
         match self.state() {
             // if this is the first peer to register
             // check that the protocol is valid
             RelaySessionState::Empty => {
                 debug!("Checking if protocol description is valid");
-                if !mmpc_server_common::protocol::is_valid_protocol(&protocol) {
+                if !self.protocols.is_valid(&protocol) {
                     warn!("Protocol is invalid");
 
                     return false;
@@ -129,7 +177,10 @@ impl RelaySession {
             RelaySessionState::Uninitialized => {
                 debug!("Checking if protocol description is same as at protocol description");
                 let prot = self.protocol();
-                if !(prot.id == protocol.id && prot.capacity == protocol.capacity) {
+                if !(prot.id == protocol.id
+                    && prot.capacity == protocol.capacity
+                    && prot.share_count == protocol.share_count)
+                {
                     warn!("Protocol description does not fit current configuration");
                     return false;
                 }
@@ -139,14 +190,76 @@ impl RelaySession {
                 return false;
             }
         }
+        // a requested index is the index of a key share
+        if index != -1 && (index < 1 || index as u32 > protocol.share_count) {
+            warn!("Peer index {} is out of range", index);
+            return false;
+        }
+        // each identity key and peer index can only be registered once
+        if self.peers.read().unwrap().contains_key(public_key) {
+            warn!("Identity key is already registered");
+            return false;
+        }
+        if index != -1 && self.public_key_of(index as PeerIdentifier).is_some() {
+            warn!("Peer index {} is already registered", index);
+            return false;
+        }
         true
     }
+
+    // The lowest peer identifier not taken by a peer, which registered with an index
+    fn next_free_peer_id(&self) -> PeerIdentifier {
+        (1..)
+            .find(|peer_id| self.public_key_of(*peer_id).is_none())
+            .unwrap()
+    }
+
+    /// Returns the identity keys of all registered peers
+    pub fn peer_keys(&self) -> BTreeMap<PeerIdentifier, IdentityKey> {
+        self.peers
+            .read()
+            .unwrap()
+            .values()
+            .filter(|p| p.registered)
+            .map(|p| (p.peer_id, p.public_key.clone()))
+            .collect()
+    }
+
+    /// Returns the identity key registered for the given peer
+    pub fn public_key_of(&self, peer_id: PeerIdentifier) -> Option<IdentityKey> {
+        self.peers
+            .read()
+            .unwrap()
+            .values()
+            .find(|p| p.registered && p.peer_id == peer_id)
+            .map(|p| p.public_key.clone())
+    }
 }
 
 impl RelaySession {
+    /// Returns the encoding the peer registered with
+    pub fn encoding_of(&self, peer_id: PeerIdentifier) -> Encoding {
+        self.encodings
+            .read()
+            .unwrap()
+            .get(&peer_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets the encoding of the messages of a peer, as negotiated by its register message
+    pub fn set_encoding(&self, peer_id: PeerIdentifier, encoding: Encoding) {
+        let mut encodings = self.encodings.write().unwrap();
+        if encoding == Encoding::default() {
+            encodings.remove(&peer_id);
+        } else {
+            encodings.insert(peer_id, encoding);
+        }
+    }
+
     /// Creates a new Relay Session with default (empty) fields
-    /// and an Empty state
-    pub fn new(capacity: u32) -> RelaySession {
+    /// and an Empty state, for one of the given protocols
+    pub fn new(capacity: u32, protocols: Arc<ProtocolRegistry>) -> RelaySession {
         RelaySession {
             peers: Arc::new(RwLock::new(HashMap::new())),
 
@@ -161,19 +274,70 @@ impl RelaySession {
             round: Arc::new(RwLock::new(0)),
 
             stored_messages: Arc::new(RwLock::new(StoredMessages::new())),
+
+            session_start: Arc::new(RwLock::new(BlockTime::default())),
+
+            round_start: Arc::new(RwLock::new(BlockTime::default())),
+
+            blame: Arc::new(RwLock::new(Vec::new())),
+
+            encodings: Arc::new(RwLock::new(BTreeMap::new())),
+
+            protocols,
         }
     }
 
-    /// Check if this relay message sent from the given SocketAddr
-    /// and is valid to send to rest of the peers
-    pub fn can_relay(&self, _from: &SocketAddr, msg: &RelayMessage) -> Result<(), &'static str> {
+    /// Check if this relay message is valid to send to rest of the peers:
+    /// it is sent by a registered peer, for the protocol and current round of this session,
+    /// the peer has not sent a message for this round yet,
+    /// its point-to-point messages are addressed to registered peers,
+    /// and it is a message the protocol expects in this round
+    pub fn can_relay(&self, msg: &RelayMessage) -> Result<(), RelayError> {
         debug!("Checking if {:} can relay", msg.peer_number);
         debug!("Server state: {:?}", self.state());
         debug!("Turn of peer #: {:}", self.protocol().next());
 
-        // TODO: Add some checks of what messages can be stored
+        if self.state() == RelaySessionState::Aborted {
+            return Err(RelayError::SessionAborted);
+        }
+        if self.public_key_of(msg.peer_number).is_none() {
+            return Err(RelayError::NotAPeer);
+        }
+        if msg.protocol_id != self.protocol().id {
+            return Err(RelayError::WrongProtocol);
+        }
+        let round = self.round();
+        if msg.round != round {
+            return Err(RelayError::WrongRound);
+        }
+        if self
+            .stored_messages
+            .read()
+            .unwrap()
+            .contains(round, msg.peer_number)
+        {
+            return Err(RelayError::DuplicateMessage);
+        }
+        if msg
+            .p2p_messages
+            .keys()
+            .any(|to| self.public_key_of(*to).is_none())
+        {
+            return Err(RelayError::UnknownRecipient);
+        }
+        // A session restored from the store may be of a protocol no longer supported
+        self.protocols
+            .get(msg.protocol_id)
+            .ok_or(RelayError::WrongProtocol)?
+            .check_message(msg)
+    }
 
-        return Ok(());
+    /// Returns the number of rounds of the protocol of this session,
+    /// or None if the protocol does not restrict them
+    pub fn round_count(&self) -> Option<u32> {
+        self.protocols
+            .get(self.protocol().id)
+            .and_then(|protocol| protocol.round_count())
     }
 
     // Return the current state of the relay session
@@ -209,6 +373,73 @@ impl RelaySession {
         self.stored_messages.read().unwrap().clone()
     }
 
+    /// Serializes the session state in a form that is identical on every replica
+    pub fn serialize_state(&self) -> Vec<u8> {
+        let protocol = self.protocol();
+        let snapshot = RelaySessionSnapshot {
+            peers: self
+                .peers
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(_, p)| p.registered)
+                .map(|(public_key, p)| (public_key.clone(), p.peer_id))
+                .collect(),
+            protocol_id: protocol.id,
+            capacity: protocol.capacity,
+            share_count: if protocol.share_count == protocol.capacity {
+                None
+            } else {
+                Some(protocol.share_count)
+            },
+            state: self.state(),
+            round: self.round(),
+            stored_messages: self.stored_messages(),
+            session_start: self.session_start(),
+            round_start: self.round_start(),
+            blame: self.blame(),
+            encodings: self.encodings.read().unwrap().clone(),
+        };
+        serde_json::to_vec(&snapshot).expect("Failed to serialize relay session")
+    }
+
+    /// Restores a relay session of one of the given protocols from its serialized state
+    pub fn from_state(
+        bytes: &[u8],
+        protocols: Arc<ProtocolRegistry>,
+    ) -> serde_json::Result<RelaySession> {
+        let snapshot: RelaySessionSnapshot = serde_json::from_slice(bytes)?;
+        let peers = snapshot
+            .peers
+            .into_iter()
+            .map(|(public_key, peer_id)| {
+                let mut peer = Peer::new(public_key.clone());
+                peer.peer_id = peer_id;
+                peer.registered = true;
+                (public_key, peer)
+            })
+            .collect();
+        let relay_session = RelaySession::new(snapshot.capacity, protocols);
+        *relay_session.peers.write().unwrap() = peers;
+        relay_session.set_protocol(
+            ProtocolDescriptor::new(snapshot.protocol_id, snapshot.capacity)
+                .with_share_count(snapshot.share_count.unwrap_or(snapshot.capacity)),
+        );
+        relay_session.set_state(snapshot.state);
+        *relay_session.round.write().unwrap() = snapshot.round;
+        *relay_session.stored_messages.write().unwrap() = snapshot.stored_messages;
+        *relay_session.session_start.write().unwrap() = snapshot.session_start;
+        *relay_session.round_start.write().unwrap() = snapshot.round_start;
+        *relay_session.blame.write().unwrap() = snapshot.blame;
+        *relay_session.encodings.write().unwrap() = snapshot.encodings;
+        Ok(relay_session)
+    }
+
+    /// Returns the hash of the serialized session state
+    pub fn state_hash(&self) -> Vec<u8> {
+        Sha256::digest(&self.serialize_state()).to_vec()
+    }
+
     pub fn try_increase_round(&self, capacity: u32) {
         if self
             .stored_messages
@@ -222,26 +453,120 @@ impl RelaySession {
     }
 }
 
+impl RelaySession {
+    pub fn session_start(&self) -> BlockTime {
+        *self.session_start.read().unwrap()
+    }
+
+    pub fn round_start(&self) -> BlockTime {
+        *self.round_start.read().unwrap()
+    }
+
+    /// Starts the timeouts of the session and of its first round at the given block
+    pub fn start_session(&self, block: BlockTime) {
+        *self.session_start.write().unwrap() = block;
+        self.start_round(block);
+    }
+
+    /// Starts the timeout of the current round at the given block
+    pub fn start_round(&self, block: BlockTime) {
+        *self.round_start.write().unwrap() = block;
+    }
+
+    /// Returns the peers blamed for aborting the session
+    pub fn blame(&self) -> Vec<PeerIdentifier> {
+        self.blame.read().unwrap().clone()
+    }
+
+    /// Checks if the session stalled past either deadline at the given block.
+    /// Once every peer has registered the round deadline applies as well.
+    /// A session is done once its protocol has run all its rounds. If the protocol does not
+    /// restrict its rounds, a round after the first which no peer sent a message for
+    /// may be the end of the protocol, so it only times out with the session deadline
+    pub fn timed_out(
+        &self,
+        round_deadline: &Deadline,
+        session_deadline: &Deadline,
+        now: &BlockTime,
+    ) -> bool {
+        let round = self.round();
+        match self.state() {
+            RelaySessionState::Uninitialized => {}
+            RelaySessionState::Initialized => {
+                let silent = match self.round_count() {
+                    Some(round_count) if round >= round_count => return false,
+                    Some(_) => false,
+                    None => {
+                        round > 0
+                            && self
+                                .stored_messages
+                                .read()
+                                .unwrap()
+                                .get_number_messages(round)
+                                == 0
+                    }
+                };
+                if session_deadline.has_passed(&self.session_start(), now) {
+                    return true;
+                }
+                return !silent && round_deadline.has_passed(&self.round_start(), now);
+            }
+            _ => return false,
+        }
+        session_deadline.has_passed(&self.session_start(), now)
+    }
+
+    /// Aborts the session, blaming the registered peers which did not send a message
+    /// for the current round. A session aborted before every peer registered blames no peer
+    pub fn abort(&self) -> Vec<PeerIdentifier> {
+        let blame = if self.state() == RelaySessionState::Initialized {
+            let peers: Vec<PeerIdentifier> = self.peer_keys().keys().cloned().collect();
+            self.stored_messages
+                .read()
+                .unwrap()
+                .get_missing_peers(self.round(), &peers)
+        } else {
+            Vec::new()
+        };
+        *self.blame.write().unwrap() = blame.clone();
+        self.set_state(RelaySessionState::Aborted);
+        blame
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::RelaySession;
     use super::RelaySessionState;
 
-    use mmpc_server_common::protocol::ProtocolDescriptor;
-    use mmpc_server_common::ProtocolIdentifier;
+    use mmpc_server_common::error::RelayError;
+    use mmpc_server_common::identity::{generate_keypair, identity_key};
+    use mmpc_server_common::payload::Payload;
+    use mmpc_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
+    use mmpc_server_common::{ClientMessage, ProtocolIdentifier, RelayMessage};
+
+    use crate::fixtures::{self, TEST_PROTOCOL};
+    use crate::timeout::{BlockTime, Deadline};
 
-    use std::net::SocketAddr;
     use std::sync::Arc;
     use std::thread;
 
+    fn protocols() -> Arc<ProtocolRegistry> {
+        Arc::new(fixtures::protocols())
+    }
+
     #[test]
     fn test_add_peer() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 1;
-        let rs = RelaySession::new(capacity);
-        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 0).parse().unwrap();
-
-        let peer_num = rs.register_new_peer(client_addr, protocol_id, capacity, 0);
+        let rs = RelaySession::new(capacity, protocols());
+        let public_key = identity_key(&generate_keypair());
+
+        let peer_num = rs.register_new_peer(
+            public_key,
+            ProtocolDescriptor::new(protocol_id, capacity),
+            -1,
+        );
         assert_eq!(peer_num, Some(1));
     }
 
@@ -249,13 +574,17 @@ mod tests {
     fn test_add_multi_peers() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 5;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
 
         let mut peer_num: u32 = 0;
         for i in 0..capacity {
-            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
+            let public_key = identity_key(&generate_keypair());
             peer_num = rs
-                .register_new_peer(client_addr, protocol_id, capacity, 0)
+                .register_new_peer(
+                    public_key,
+                    ProtocolDescriptor::new(protocol_id, capacity),
+                    -1,
+                )
                 .expect("Unable to register");
             println!("Peer number is {}", peer_num);
         }
@@ -269,15 +598,19 @@ mod tests {
 
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 50;
-        let rs = Arc::new(RelaySession::new(capacity));
+        let rs = Arc::new(RelaySession::new(capacity, protocols()));
 
         for i in 0..capacity {
             let rs_inner = Arc::clone(&rs);
 
-            let client_addr: SocketAddr = format!("127.0.0.1:80{}", 30 + i).parse().unwrap();
+            let public_key = identity_key(&generate_keypair());
             children.push(thread::spawn(move || {
                 rs_inner
-                    .register_new_peer(client_addr, protocol_id, capacity, -1)
+                    .register_new_peer(
+                        public_key,
+                        ProtocolDescriptor::new(protocol_id, capacity),
+                        -1,
+                    )
                     .expect("Unable to register");
             }));
         }
@@ -292,22 +625,196 @@ mod tests {
 
     #[test]
     fn test_can_register_protocol_valid() {
-        let client_addr: SocketAddr = "127.0.0.1:8081".to_string().parse().unwrap();
+        let public_key = identity_key(&generate_keypair());
         let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
-        assert!(rs.can_register(&client_addr, protocol_descriptor))
+        let rs = RelaySession::new(capacity, protocols());
+        assert!(rs.can_register(&public_key, -1, protocol_descriptor))
     }
 
     #[test]
     fn test_can_register_protocol_invalid() {
-        let client_addr: SocketAddr = "127.0.0.1:8081".to_string().parse().unwrap();
+        let public_key = identity_key(&generate_keypair());
         let protocol_id: ProtocolIdentifier = 100 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        let rs = RelaySession::new(capacity, protocols());
+        assert!(!rs.can_register(&public_key, -1, protocol_descriptor))
+    }
+
+    #[test]
+    fn test_can_register_twice() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let rs = RelaySession::new(capacity, protocols());
+        let public_key = identity_key(&generate_keypair());
+        assert_eq!(
+            rs.register_new_peer(
+                public_key.clone(),
+                ProtocolDescriptor::new(protocol_id, capacity),
+                2
+            ),
+            Some(2)
+        );
+        assert_eq!(rs.public_key_of(2), Some(public_key.clone()));
+        assert_eq!(rs.public_key_of(1), None);
+        let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
+        // Same identity key
+        assert!(!rs.can_register(&public_key, -1, protocol_descriptor.clone()));
+        // Same peer index with another identity key
+        let other_key = identity_key(&generate_keypair());
+        assert!(!rs.can_register(&other_key, 2, protocol_descriptor.clone()));
+        assert!(rs.can_register(&other_key, 1, protocol_descriptor))
+    }
+
+    #[test]
+    fn test_register_with_share_index() {
+        let protocol_id = TEST_PROTOCOL;
+        // 2 signing peers of 3 key shares
+        let protocol = ProtocolDescriptor::new(protocol_id, 2).with_share_count(3);
+        let rs = RelaySession::new(2, protocols());
+        let public_key = identity_key(&generate_keypair());
+        // Share indices start from 1
+        assert!(!rs.can_register(&public_key, 0, protocol.clone()));
+        assert!(!rs.can_register(&public_key, 4, protocol.clone()));
+        assert_eq!(
+            rs.register_new_peer(public_key, protocol.clone(), 1),
+            Some(1)
+        );
+        // The share count of the session is fixed by the first peer
+        let public_key = identity_key(&generate_keypair());
+        assert!(!rs.can_register(&public_key, 3, ProtocolDescriptor::new(protocol_id, 2)));
+        assert_eq!(rs.register_new_peer(public_key, protocol, 3), Some(3));
+        assert_eq!(rs.state(), RelaySessionState::Initialized);
+        // Fewer shares than peers
+        let protocol = ProtocolDescriptor::new(protocol_id, 3).with_share_count(2);
+        let public_key = identity_key(&generate_keypair());
+        assert!(!RelaySession::new(3, protocols()).can_register(&public_key, -1, protocol));
+    }
+
+    #[test]
+    fn test_register_any_index_after_share_index() {
+        let protocol = ProtocolDescriptor::new(1, 3);
+        let rs = RelaySession::new(3, protocols());
+        let public_key = identity_key(&generate_keypair());
+        assert_eq!(
+            rs.register_new_peer(public_key, protocol.clone(), 2),
+            Some(2)
+        );
+        // Peers without an index get the free identifiers
+        let public_key = identity_key(&generate_keypair());
+        assert_eq!(
+            rs.register_new_peer(public_key, protocol.clone(), -1),
+            Some(1)
+        );
+        let public_key = identity_key(&generate_keypair());
+        assert_eq!(rs.register_new_peer(public_key, protocol, -1), Some(3));
+    }
+
+    #[test]
+    fn test_can_relay() {
+        let protocol_id = TEST_PROTOCOL;
+        let capacity: u32 = 2;
+        let mut rs = RelaySession::new(capacity, protocols());
+        for _ in 0..capacity {
+            rs.register_new_peer(
+                identity_key(&generate_keypair()),
+                ProtocolDescriptor::new(protocol_id, capacity),
+                -1,
+            );
+        }
+        let msg = RelayMessage::new(1, 1, protocol_id, 0);
+        assert_eq!(rs.can_relay(&msg), Ok(()));
+        // Peer which is not registered
+        assert_eq!(
+            rs.can_relay(&RelayMessage::new(3, 1, protocol_id, 0)),
+            Err(RelayError::NotAPeer)
+        );
+        // Another protocol
+        assert_eq!(
+            rs.can_relay(&RelayMessage::new(1, 1, protocol_id + 1, 0)),
+            Err(RelayError::WrongProtocol)
+        );
+        // Next round
+        assert_eq!(
+            rs.can_relay(&RelayMessage::new(1, 1, protocol_id, 1)),
+            Err(RelayError::WrongRound)
+        );
+        // Point-to-point message to a peer which is not registered
+        let mut p2p_msg = RelayMessage::new(1, 1, protocol_id, 0);
+        p2p_msg.p2p_messages.insert(2, String::from("encrypted"));
+        assert_eq!(rs.can_relay(&p2p_msg), Ok(()));
+        p2p_msg.p2p_messages.insert(3, String::from("encrypted"));
+        assert_eq!(rs.can_relay(&p2p_msg), Err(RelayError::UnknownRecipient));
+        // Second message for the same round
+        rs.update_stored_messages(0, 1, ClientMessage::new());
+        assert_eq!(rs.can_relay(&msg), Err(RelayError::DuplicateMessage));
+        assert_eq!(
+            rs.can_relay(&RelayMessage::new(2, 1, protocol_id, 0)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_can_relay_round_structure() {
+        // Threshold key generation runs 3 rounds
+        let protocol_id: ProtocolIdentifier = 3;
+        let capacity: u32 = 2;
+        let mut rs = RelaySession::new(capacity, protocols());
+        for _ in 0..capacity {
+            rs.register_new_peer(
+                identity_key(&generate_keypair()),
+                ProtocolDescriptor::new(protocol_id, capacity),
+                -1,
+            );
+        }
+        assert_eq!(rs.round_count(), Some(3));
+        let message = |round, payload: Payload| {
+            let mut msg = RelayMessage::new(1, 1, protocol_id, round);
+            msg.message = payload.encode();
+            msg
+        };
+        let commitment = Payload::Commitment(String::from("{}"));
+        assert_eq!(rs.can_relay(&message(0, commitment.clone())), Ok(()));
+        assert_eq!(
+            rs.can_relay(&message(0, Payload::PublicKey(String::from("{}")))),
+            Err(RelayError::UnexpectedMessage)
+        );
+        let mut p2p_msg = message(0, commitment.clone());
+        p2p_msg.p2p_messages.insert(2, String::from("encrypted"));
+        assert_eq!(rs.can_relay(&p2p_msg), Err(RelayError::UnexpectedMessage));
+
+        let deadline = Deadline {
+            blocks: Some(1),
+            seconds: None,
+        };
+        let later = BlockTime {
+            height: 10,
+            time: 10,
+        };
+        for round in 0..3 {
+            assert!(rs.timed_out(&deadline, &Deadline::default(), &later));
+            rs.update_stored_messages(round, 1, ClientMessage::new());
+            rs.update_stored_messages(round, 2, ClientMessage::new());
+            rs.try_increase_round(capacity);
+        }
+        // The protocol is done after its last round
+        assert!(!rs.timed_out(&deadline, &Deadline::default(), &later));
+        assert_eq!(
+            rs.can_relay(&message(3, commitment)),
+            Err(RelayError::WrongRound)
+        );
+    }
+
+    #[test]
+    fn test_register_threshold_protocol() {
+        let public_key = identity_key(&generate_keypair());
+        // 2 signing peers of 3 key shares, for a protocol without thresholds
+        let protocol = ProtocolDescriptor::new(2, 2).with_share_count(3);
+        assert!(!RelaySession::new(2, protocols()).can_register(&public_key, 1, protocol));
+        let protocol = ProtocolDescriptor::new(4, 2).with_share_count(3);
+        assert!(RelaySession::new(2, protocols()).can_register(&public_key, 1, protocol));
     }
 
     /////////////////////////// test register ///////////////////////////////////
@@ -315,19 +822,130 @@ mod tests {
     fn test_register_state() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
 
         // State is empty at first
         assert_eq!(RelaySessionState::Empty, rs.state());
         for i in 0..capacity - 1 {
-            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
-            rs.register_new_peer(client_addr, protocol_id, capacity, -1);
+            let public_key = identity_key(&generate_keypair());
+            rs.register_new_peer(
+                public_key,
+                ProtocolDescriptor::new(protocol_id, capacity),
+                -1,
+            );
             // State is not initialized when not all are connected
             assert_eq!(RelaySessionState::Uninitialized, rs.state());
         }
-        let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity - 1).parse().unwrap();
-        let messages = rs.register_new_peer(client_addr, protocol_id, capacity, -1);
+        let public_key = identity_key(&generate_keypair());
+        let messages = rs.register_new_peer(
+            public_key,
+            ProtocolDescriptor::new(protocol_id, capacity),
+            -1,
+        );
         // Once all are connected, state should initialize
         assert_eq!(RelaySessionState::Initialized, rs.state());
     }
+
+    /////////////////////////// test serialize ///////////////////////////////////
+    #[test]
+    fn test_restore_from_state() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let mut rs = RelaySession::new(capacity, protocols());
+        for i in 0..capacity {
+            let public_key = identity_key(&generate_keypair());
+            rs.register_new_peer(
+                public_key,
+                ProtocolDescriptor::new(protocol_id, capacity),
+                -1,
+            );
+        }
+        rs.update_stored_messages(0, 1, ClientMessage::new());
+
+        let restored = RelaySession::from_state(&rs.serialize_state(), protocols()).unwrap();
+        assert_eq!(restored.state(), RelaySessionState::Initialized);
+        assert_eq!(restored.get_number_of_active_peers(), capacity);
+        assert_eq!(restored.stored_messages().get_number_messages(0), 1);
+        assert_eq!(restored.state_hash(), rs.state_hash());
+    }
+
+    #[test]
+    fn test_session_timeout() {
+        let protocol_id = TEST_PROTOCOL;
+        let capacity: u32 = 2;
+        let mut rs = RelaySession::new(capacity, protocols());
+        let start = BlockTime { height: 1, time: 0 };
+        let deadline = Deadline {
+            blocks: None,
+            seconds: Some(10),
+        };
+        let later = BlockTime {
+            height: 2,
+            time: 10,
+        };
+        rs.start_session(start);
+        rs.register_new_peer(
+            identity_key(&generate_keypair()),
+            ProtocolDescriptor::new(protocol_id, capacity),
+            -1,
+        );
+        // A session stalled in registration blames no peer
+        assert!(rs.timed_out(&Deadline::default(), &deadline, &later));
+        assert!(!rs.timed_out(&deadline, &Deadline::default(), &later));
+
+        rs.register_new_peer(
+            identity_key(&generate_keypair()),
+            ProtocolDescriptor::new(protocol_id, capacity),
+            -1,
+        );
+        rs.update_stored_messages(0, 1, ClientMessage::new());
+        rs.update_stored_messages(0, 2, ClientMessage::new());
+        rs.try_increase_round(capacity);
+        // No peer sent a message for the next round, so the protocol may be done
+        assert!(!rs.timed_out(&deadline, &Deadline::default(), &later));
+
+        rs.update_stored_messages(1, 2, ClientMessage::new());
+        assert!(rs.timed_out(&deadline, &Deadline::default(), &later));
+        assert_eq!(rs.abort(), vec![1]);
+        assert_eq!(rs.state(), RelaySessionState::Aborted);
+        assert!(!rs.timed_out(&deadline, &deadline, &later));
+
+        let restored = RelaySession::from_state(&rs.serialize_state(), protocols()).unwrap();
+        assert_eq!(restored.blame(), vec![1]);
+        assert_eq!(restored.session_start(), start);
+    }
+
+    #[test]
+    fn test_silent_session_times_out() {
+        let capacity: u32 = 2;
+        let mut rs = RelaySession::new(capacity, protocols());
+        let start = BlockTime { height: 1, time: 0 };
+        let deadline = Deadline {
+            blocks: None,
+            seconds: Some(10),
+        };
+        let later = BlockTime {
+            height: 2,
+            time: 10,
+        };
+        rs.start_session(start);
+        for _ in 0..capacity {
+            rs.register_new_peer(
+                identity_key(&generate_keypair()),
+                ProtocolDescriptor::new(TEST_PROTOCOL, capacity),
+                -1,
+            );
+        }
+        rs.start_round(start);
+        rs.update_stored_messages(0, 1, ClientMessage::new());
+        rs.update_stored_messages(0, 2, ClientMessage::new());
+        rs.try_increase_round(capacity);
+        rs.start_round(start);
+
+        // All peers went silent, the protocol without rounds is not known to be done
+        assert!(!rs.timed_out(&deadline, &Deadline::default(), &start));
+        assert!(rs.timed_out(&Deadline::default(), &deadline, &later));
+        assert!(rs.timed_out(&deadline, &deadline, &later));
+        assert_eq!(rs.abort(), vec![1, 2]);
+    }
 }
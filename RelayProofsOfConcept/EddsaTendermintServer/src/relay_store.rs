@@ -0,0 +1,223 @@
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use mmpc_server_common::protocol::ProtocolRegistry;
+use mmpc_server_common::SessionIdentifier;
+
+use crate::relay_session::RelaySession;
+
+static COMMIT_INFO_KEY: &str = "commit_info";
+static SESSION_KEY_PREFIX: &str = "session/";
+
+/// The state of the application as of the last committed block
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommitInfo {
+    pub height: i64,
+
+    pub app_hash: Vec<u8>,
+
+    pub next_session_id: SessionIdentifier,
+}
+
+/// Errors reading the committed state back from the store
+#[derive(Debug)]
+pub enum StoreError {
+    Db(sled::Error),
+    // A record of the store does not decode, by its key
+    Corrupt(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Db(err) => write!(f, "{}", err),
+            StoreError::Corrupt(key) => write!(f, "Corrupt record {} in store", key),
+        }
+    }
+}
+
+impl Error for StoreError {}
+
+impl From<sled::Error> for StoreError {
+    fn from(err: sled::Error) -> StoreError {
+        StoreError::Db(err)
+    }
+}
+
+/// An embedded on-disk store of the relay sessions.
+/// The store is only written on commit, so after a restart it holds the state
+/// of the last committed block, and Tendermint replays any later blocks.
+/// Clones are handles to the same store
+#[derive(Clone)]
+pub struct RelayStore {
+    db: sled::Db,
+}
+
+impl RelayStore {
+    /// Opens the store at the given path, creating it if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> sled::Result<RelayStore> {
+        Ok(RelayStore {
+            db: sled::open(path)?,
+        })
+    }
+
+    /// Returns the last commit info, or None if nothing was committed yet
+    pub fn commit_info(&self) -> Result<Option<CommitInfo>, StoreError> {
+        match self.db.get(COMMIT_INFO_KEY)? {
+            Some(bytes) => {
+                let commit_info = serde_json::from_slice(&bytes)
+                    .map_err(|_| StoreError::Corrupt(String::from(COMMIT_INFO_KEY)))?;
+                Ok(Some(commit_info))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Loads all relay sessions stored at the last commit, of the given protocols.
+    /// A session which does not decode fails the load, as the app would not hash
+    /// to the committed app hash without it
+    pub fn sessions(
+        &self,
+        protocols: &Arc<ProtocolRegistry>,
+    ) -> Result<BTreeMap<SessionIdentifier, RelaySession>, StoreError> {
+        let mut relay_sessions = BTreeMap::new();
+        for entry in self.db.scan_prefix(SESSION_KEY_PREFIX) {
+            let (key, value) = entry?;
+            let corrupt = || StoreError::Corrupt(hex::encode(&key));
+            let session_id = session_id_from_key(&key).ok_or_else(corrupt)?;
+            let relay_session =
+                RelaySession::from_state(&value, Arc::clone(protocols)).map_err(|err| {
+                    error!("Unable to decode session {}: {}", session_id, err);
+                    corrupt()
+                })?;
+            relay_sessions.insert(session_id, relay_session);
+        }
+        info!("Loaded {} relay sessions from store", relay_sessions.len());
+        Ok(relay_sessions)
+    }
+
+    /// Atomically stores the updated sessions together with the commit info,
+    /// and flushes them to disk
+    pub fn commit(
+        &self,
+        commit_info: &CommitInfo,
+        relay_sessions: &BTreeMap<SessionIdentifier, RelaySession>,
+        updated_sessions: &BTreeSet<SessionIdentifier>,
+    ) -> sled::Result<()> {
+        let mut batch = sled::Batch::default();
+        for session_id in updated_sessions {
+            if let Some(relay_session) = relay_sessions.get(session_id) {
+                batch.insert(session_key(*session_id), relay_session.serialize_state());
+            }
+        }
+        batch.insert(
+            COMMIT_INFO_KEY,
+            serde_json::to_vec(commit_info).expect("Failed to serialize commit info"),
+        );
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        debug!(
+            "Stored {} updated sessions at height {}",
+            updated_sessions.len(),
+            commit_info.height
+        );
+        Ok(())
+    }
+}
+
+// Session keys are big endian, so sessions are scanned in order of their identifier
+fn session_key(session_id: SessionIdentifier) -> Vec<u8> {
+    let mut key = SESSION_KEY_PREFIX.as_bytes().to_vec();
+    key.extend_from_slice(&session_id.to_be_bytes());
+    key
+}
+
+fn session_id_from_key(key: &[u8]) -> Option<SessionIdentifier> {
+    if key.len() != SESSION_KEY_PREFIX.len() + 8 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&key[SESSION_KEY_PREFIX.len()..]);
+    Some(SessionIdentifier::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{session_id_from_key, session_key, CommitInfo, RelayStore, StoreError};
+    use crate::fixtures;
+    use crate::relay_session::RelaySession;
+
+    use mmpc_server_common::identity::{generate_keypair, identity_key};
+    use mmpc_server_common::protocol::ProtocolDescriptor;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_session_key() {
+        assert_eq!(session_id_from_key(&session_key(258)), Some(258));
+        assert_eq!(session_id_from_key(b"session/1"), None);
+        assert!(session_key(2) < session_key(256));
+    }
+
+    #[test]
+    fn test_commit_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let protocols = Arc::new(fixtures::protocols());
+        let mut relay_sessions = BTreeMap::new();
+        for session_id in 1..3 {
+            let rs = RelaySession::new(2, Arc::clone(&protocols));
+            let public_key = identity_key(&generate_keypair());
+            rs.register_new_peer(public_key, ProtocolDescriptor::new(1, 2), -1);
+            relay_sessions.insert(session_id, rs);
+        }
+        let commit_info = CommitInfo {
+            height: 7,
+            app_hash: vec![1, 2, 3],
+            next_session_id: 3,
+        };
+        let store = RelayStore::open(dir.path()).unwrap();
+        assert_eq!(store.commit_info().unwrap(), None);
+        let updated: BTreeSet<_> = relay_sessions.keys().cloned().collect();
+        store
+            .commit(&commit_info, &relay_sessions, &updated)
+            .unwrap();
+
+        // Restore through the same store, a handle dropped in the test may hold the
+        // lock of the files for a moment so the store is not opened again by its path
+        assert_eq!(store.commit_info().unwrap(), Some(commit_info));
+        let restored = store.sessions(&protocols).unwrap();
+        assert_eq!(restored.len(), 2);
+        for (session_id, rs) in restored {
+            assert_eq!(rs.state_hash(), relay_sessions[&session_id].state_hash());
+        }
+    }
+    #[test]
+    fn test_corrupt_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let protocols = Arc::new(fixtures::protocols());
+        let store = RelayStore::open(dir.path()).unwrap();
+        store
+            .db
+            .insert(session_key(1), b"not a session".to_vec())
+            .unwrap();
+        match store.sessions(&protocols) {
+            Err(StoreError::Corrupt(key)) => assert_eq!(key, hex::encode(session_key(1))),
+            _ => panic!("Loaded a corrupt session"),
+        }
+        store.db.remove(session_key(1)).unwrap();
+        // A key too short for a session identifier
+        store.db.insert("session/1", b"{}".to_vec()).unwrap();
+        assert!(store.sessions(&protocols).is_err());
+
+        store
+            .db
+            .insert("commit_info", b"not json".to_vec())
+            .unwrap();
+        assert!(store.commit_info().is_err());
+    }
+}
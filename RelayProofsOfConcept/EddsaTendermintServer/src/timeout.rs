@@ -0,0 +1,69 @@
+//! Timeouts of relay sessions, measured in blocks or in block time.
+//! Both are taken from the block header, so every replica aborts a session at the same block
+use serde::{Deserialize, Serialize};
+
+/// The height of a block, and its time in seconds since the epoch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockTime {
+    pub height: i64,
+
+    pub time: i64,
+}
+
+/// A deadline of a number of blocks, or of seconds of block time,
+/// which passes as soon as either of them passes. A deadline without either never passes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Deadline {
+    pub blocks: Option<i64>,
+
+    pub seconds: Option<i64>,
+}
+
+impl Deadline {
+    /// Returns true if the deadline passed since the start block
+    pub fn has_passed(&self, start: &BlockTime, now: &BlockTime) -> bool {
+        let blocks_passed = self
+            .blocks
+            .iter()
+            .any(|blocks| now.height - start.height >= *blocks);
+        let seconds_passed = self
+            .seconds
+            .iter()
+            .any(|seconds| now.time - start.time >= *seconds);
+        blocks_passed || seconds_passed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockTime, Deadline};
+
+    #[test]
+    fn test_deadline_has_passed() {
+        let start = BlockTime {
+            height: 10,
+            time: 1000,
+        };
+        let deadline = Deadline {
+            blocks: Some(5),
+            seconds: Some(30),
+        };
+        let soon = BlockTime {
+            height: 14,
+            time: 1029,
+        };
+        assert!(!deadline.has_passed(&start, &soon));
+        // Either the height or the time passing is enough
+        let later_height = BlockTime {
+            height: 15,
+            time: 1001,
+        };
+        assert!(deadline.has_passed(&start, &later_height));
+        let later_time = BlockTime {
+            height: 11,
+            time: 1030,
+        };
+        assert!(deadline.has_passed(&start, &later_time));
+        assert!(!Deadline::default().has_passed(&start, &later_height));
+    }
+}
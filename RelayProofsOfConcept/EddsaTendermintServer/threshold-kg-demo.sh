@@ -0,0 +1,23 @@
+echo "$0: THRESHOLD-EDDSA"
+#clean
+
+rm threshold-key-store*
+rm log-threshold-kg*.log
+rm session
+
+# Key shares are encrypted with this password
+export MMPC_KEY_PASSWORD=${MMPC_KEY_PASSWORD:-demo}
+
+n=3
+t=1
+
+echo "threshold keygen part"
+# The first client opens a new session, the rest join it
+cargo run -p mmpc-client --bin threshold-kg-client -- -I 1 --capacity $n --threshold $t &
+while [ ! -f session ]; do sleep 0.1; done
+SESSION=$(cat session)
+
+for i in $(seq 2 $n);
+do
+    cargo run -p mmpc-client --bin threshold-kg-client -- -I $i --capacity $n --threshold $t --session $SESSION &
+done
@@ -0,0 +1,25 @@
+echo "$0: THRESHOLD-EDDSA"
+#clean
+
+rm threshold-signature*
+rm log-threshold-sign*.log
+rm session
+
+# Key shares are encrypted with this password
+export MMPC_KEY_PASSWORD=${MMPC_KEY_PASSWORD:-demo}
+
+# Any t+1 of the key shares can sign, here shares 1 and 3 of a key with t=1 and n=3
+signers="1 3"
+n=2
+
+echo "threshold sign part"
+# The first client opens a new session, the rest join it
+first=${signers%% *}
+cargo run -p mmpc-client --bin threshold-sign-client -- -I $first -C $n -M "message"&
+while [ ! -f session ]; do sleep 0.1; done
+SESSION=$(cat session)
+
+for i in ${signers#* };
+do
+    cargo run -p mmpc-client --bin threshold-sign-client -- -I $i -C $n -S $SESSION -M "message"&
+done
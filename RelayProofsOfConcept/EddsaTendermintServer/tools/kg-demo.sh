@@ -1,9 +1,13 @@
 echo "$0: MP-EDDSA"
 #clean
 
-rm keys*
+rm key-store*
 rm log-kg*.log
 rm log-error*.log
+rm session
+
+# Key shares are encrypted with this password
+export MMPC_KEY_PASSWORD=${MMPC_KEY_PASSWORD:-demo}
 
 
 # First argument is the number fo nodes in the cluseter
@@ -13,13 +17,26 @@ k=${2:-4}
 
 cargo build --all
 
+# RPC endpoints of all the nodes, starting from a random one.
+# Clients fail over to the next node when the one they use stops answering
+endpoints() {
+    S=$(( ( RANDOM % $n ) ))
+    echo -n "127.0.0.1:$(( 46057 + $S * 100 ))"
+    for j in $(seq 1 $(( $n - 1 ))); do
+        echo -n ",127.0.0.1:$(( 46057 + ( ( $S + $j ) % $n ) * 100 ))"
+    done
+}
+
 echo "keygen part"
-for i in $(seq 1 $k);
+# The first client opens a new session, the rest join it
+./target/debug/kg-client -I 1 -C $k --proxy $(endpoints) &
+while [ ! -f session ]; do sleep 0.1; done
+SESSION=$(cat session)
+
+for i in $(seq 2 $k);
 do
-    S=$(( ( RANDOM % $n ) ))
-    PORT=$(( 46057 + $S * 100 ))
     #PORT="46157"
-    # cargo run -p mmpc-client --bin kg-client -- -I $i -C $n --proxy 127.0.0.1:$PORT -v &
-    #./target/debug/kg-client -I $i -C $k --proxy 127.0.0.1:$PORT &> log-error$i.log &
-    ./target/debug/kg-client -I $i -C $k --proxy 127.0.0.1:$PORT &
+    # cargo run -p mmpc-client --bin kg-client -- -I $i -C $n -S $SESSION --proxy 127.0.0.1:$PORT -v &
+    #./target/debug/kg-client -I $i -C $k -S $SESSION --proxy $(endpoints) &> log-error$i.log &
+    ./target/debug/kg-client -I $i -C $k -S $SESSION --proxy $(endpoints) &
 done
@@ -4,17 +4,34 @@ echo "$0: MP-EDDSA"
 rm signature*
 rm log-sign*.log
 rm log-error*.log
+rm session
+
+# Key shares are encrypted with this password
+export MMPC_KEY_PASSWORD=${MMPC_KEY_PASSWORD:-demo}
 
 # First argument is the number fo nodes in the cluseter
 n=${1:-4}
  # Second argument is the number of parties
 k=${2:-4}
 
+# RPC endpoints of all the nodes, starting from a random one.
+# Clients fail over to the next node when the one they use stops answering
+endpoints() {
+    S=$(( ( RANDOM % $n ) ))
+    echo -n "127.0.0.1:$(( 46057 + $S * 100 ))"
+    for j in $(seq 1 $(( $n - 1 ))); do
+        echo -n ",127.0.0.1:$(( 46057 + ( ( $S + $j ) % $n ) * 100 ))"
+    done
+}
+
 echo "sign part"
-for i in $(seq 1 $k);
+# The first client opens a new session, the rest join it
+./target/debug/sign-client -I 1 -C $k -M "message" --proxy $(endpoints) &
+while [ ! -f session ]; do sleep 0.1; done
+SESSION=$(cat session)
+
+for i in $(seq 2 $k);
 do
-    S=$(( ( RANDOM % $n ) ))
-    PORT=$(( 46057 + $S * 100 ))
-    # cargo run -p mmpc-client --bin  sign-client -- -I $i -C $n -M "message" --proxy 127.0.0.1:$PORT &
-    ./target/debug/sign-client -I $i -C $k -M "message" --proxy 127.0.0.1:$PORT &
+    # cargo run -p mmpc-client --bin  sign-client -- -I $i -C $n -S $SESSION -M "message" --proxy $(endpoints) &
+    ./target/debug/sign-client -I $i -C $k -S $SESSION -M "message" --proxy $(endpoints) &
 done
@@ -12,9 +12,10 @@ use tokio::net::TcpStream;
 use futures::sync::mpsc;
 use futures::{Future, Sink, Stream};
 
+use relay_server_common::identity::{generate_keypair, identity_key};
 use relay_server_common::{
-    ClientMessage, ClientToServerCodec, PeerIdentifier, ProtocolIdentifier, RelayMessage,
-    ServerMessage, ServerMessageType, ServerResponse,
+    ClientMessage, ClientToServerCodec, IdentityKey, PeerIdentifier, ProtocolIdentifier,
+    RelayMessage, RelayMode, ServerMessage, ServerMessageType, ServerResponse,
 };
 
 // ClientSession holds session data
@@ -40,12 +41,16 @@ impl ProtocolSession {
 #[derive(Default, Debug, Clone)]
 struct Client {
     pub session: ProtocolSession,
+    // Identity key the client registers with, the server checks the signature
+    // of every message of the client against it
+    pub public_key: IdentityKey,
 }
 
 impl Client {
-    pub fn new() -> Client {
+    pub fn new(public_key: IdentityKey) -> Client {
         Client {
             session: ProtocolSession::new(),
+            public_key,
         }
     }
 }
@@ -100,6 +105,7 @@ impl Client {
                         client_message.relay_message = Some(relay_message.clone());
                         return Ok(client_message);
                     }
+                    ServerResponse::Joined(..) => Ok(ClientMessage::new()),
                     _ => panic!("failed to register"),
                 }
             }
@@ -120,7 +126,9 @@ impl Client {
 
     pub fn generate_register_message(&self) -> ClientMessage {
         let mut msg = ClientMessage::new();
-        msg.register(self.session.protocol_id.clone(), 2);
+        msg.register(self.public_key.clone(), self.session.protocol_id.clone(), 2);
+        // The peers send one message at a time, in the order of their peer numbers
+        msg.set_mode(RelayMode::Turns);
         msg
     }
 }
@@ -137,12 +145,15 @@ fn main() {
     // Create the event loop and initiate the connection to the remote server
     let tcp = TcpStream::connect(&addr);
 
-    let session: Arc<Client> = Arc::new(Client::new());
+    let keypair = Arc::new(generate_keypair());
+    let session: Arc<Client> = Arc::new(Client::new(identity_key(&keypair)));
 
     let client = Arc::clone(&session);
+    let register_keypair = Arc::clone(&keypair);
     let handshake = tcp.and_then(move |stream| {
-        let handshake_io = Framed::new(stream, ClientToServerCodec::new(false));
-        let msg = client.generate_register_message();
+        let handshake_io = Framed::new(stream, ClientToServerCodec::new());
+        let mut msg = client.generate_register_message();
+        msg.sign(&register_keypair);
         handshake_io
             .send(msg)
             .map(|handshake_io| handshake_io.into_inner())
@@ -155,7 +166,7 @@ fn main() {
             let _msg = client.generate_register_message();
 
             let (to_server, from_server) =
-                Framed::new(socket, ClientToServerCodec::new(false)).split();
+                Framed::new(socket, ClientToServerCodec::new()).split();
             let (tx, rx) = mpsc::channel(0);
             let reader = from_server.for_each(move |msg| {
                 println!("Received {:?}", msg);
@@ -164,7 +175,10 @@ fn main() {
 
             let writer = rx
                 .map_err(|()| unreachable!("rx can't fail"))
-                .fold(to_server, |to_server, msg| to_server.send(msg))
+                .fold(to_server, move |to_server, mut msg: ClientMessage| {
+                    msg.sign(&keypair);
+                    to_server.send(msg)
+                })
                 .map(|_| ());
 
             reader
@@ -22,9 +22,10 @@ use futures::{Future, Sink, Stream};
 
 use structopt::StructOpt;
 
+use relay_server_common::identity::{generate_keypair, identity_key};
 use relay_server_common::{
-    ClientMessage, ClientToServerCodec, MessagePayload, PeerIdentifier, ProtocolIdentifier,
-    RelayMessage, ServerMessage, ServerMessageType, ServerResponse,
+    ClientMessage, ClientToServerCodec, IdentityKey, MessagePayload, PeerIdentifier,
+    ProtocolIdentifier, RelayMessage, RelayMode, ServerMessage, ServerMessageType, ServerResponse,
 };
 
 use curv::arithmetic::traits::Converter;
@@ -37,6 +38,7 @@ use multi_party_ed25519::protocols::aggsig::{
 };
 
 use relay_server_common::common::*;
+use relay_server_common::tls;
 
 use std::collections::HashMap;
 use std::fs;
@@ -60,6 +62,22 @@ struct Opt {
     /// Message to sign
     #[structopt(name = "MESSAGE")]
     message: String,
+
+    /// PEM CA certificate of the server, connects over TLS if set
+    #[structopt(long = "tls-ca", parse(from_os_str))]
+    tls_ca: Option<PathBuf>,
+
+    /// Name of the server in its certificate
+    #[structopt(long = "tls-server-name", default_value = "localhost")]
+    tls_server_name: String,
+
+    /// PEM certificate of this client, for servers which authenticate clients
+    #[structopt(long = "tls-cert", parse(from_os_str), requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate
+    #[structopt(long = "tls-key", parse(from_os_str), requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 }
 
 #[allow(non_snake_case)]
@@ -581,11 +599,19 @@ where
     pub data_manager: ProtocolDataManager<T>,
     pub last_message: RefCell<ClientMessage>,
     pub bc_dests: Vec<ProtocolIdentifier>,
+    // Identity key the client registers with, the server checks the signature
+    // of every message of the client against it
+    pub public_key: IdentityKey,
     pub timeout: u32,
 }
 
 impl<T: Peer> Client<T> {
-    pub fn new(protocol_id: ProtocolIdentifier, capacity: u32, message: Vec<u8>) -> Client<T>
+    pub fn new(
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        message: Vec<u8>,
+        public_key: IdentityKey,
+    ) -> Client<T>
     where
         T: Peer,
     {
@@ -595,6 +621,7 @@ impl<T: Peer> Client<T> {
             protocol_id,
             last_message: RefCell::new(ClientMessage::new()),
             bc_dests: (1..(capacity + 1)).collect(),
+            public_key,
             timeout: 100, // 3 second delay in sending messages
             data_manager: data_m,
         }
@@ -675,7 +702,13 @@ impl<T: Peer> Client<T> {
 
     pub fn generate_register_message(&mut self) -> ClientMessage {
         let mut msg = ClientMessage::new();
-        msg.register(self.protocol_id.clone(), self.data_manager.capacity.clone());
+        msg.register(
+            self.public_key.clone(),
+            self.protocol_id.clone(),
+            self.data_manager.capacity.clone(),
+        );
+        // The peers send one message at a time, in the order of their peer numbers
+        msg.set_mode(RelayMode::Turns);
         msg
     }
 }
@@ -789,6 +822,8 @@ impl<T: Peer> Client<T> {
                     }
                 }
             }
+            // The peer starts once the session is full and it gets its register response
+            ServerResponse::Joined(..) => Ok(ClientMessage::new()),
             ServerResponse::NoResponse => unimplemented!(),
         }
     }
@@ -816,7 +851,7 @@ enum MessagePayloadType {
 fn main() {
     let opt = Opt::from_args();
 
-    let addr = opt.address;
+    let addr = opt.address.clone();
 
     let protocol_identifier_arg = 1;
     let protocol_capapcity_arg = opt.capacity;
@@ -834,17 +869,36 @@ fn main() {
     let handle = core.handle();
     let tcp = TcpStream::connect(&addr, &handle);
 
+    // Run the connection over TLS if the client has the CA certificate of the server
+    let tls_config = opt.tls_ca.as_ref().map(|ca| {
+        let identity = match (&opt.tls_cert, &opt.tls_key) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            _ => None,
+        };
+        Arc::new(tls::client_config(ca, identity).expect("Unable to load the TLS certificates"))
+    });
+    let server_name = opt.tls_server_name.clone();
+    let tcp = tcp.and_then(move |stream| {
+        tls::connect(
+            stream,
+            tls_config.map(|config| (config, server_name.as_str())),
+        )
+    });
+
+    let keypair = generate_keypair();
     let session: std::sync::Arc<std::sync::Mutex<Client<EddsaPeer>>> =
         Arc::new(Mutex::new(Client::new(
             protocol_identifier_arg,
             protocol_capapcity_arg,
             message_to_sign,
+            identity_key(&keypair),
         )));
 
     let handshake = tcp.and_then(|stream| {
-        let handshake_io = Framed::new(stream, ClientToServerCodec::new(false));
+        let handshake_io = Framed::new(stream, ClientToServerCodec::new());
         let mut client = session.lock().unwrap();
-        let msg = client.generate_register_message();
+        let mut msg = client.generate_register_message();
+        msg.sign(&keypair);
         handshake_io
             .send(msg)
             .map(|handshake_io| handshake_io.into_inner())
@@ -855,7 +909,7 @@ fn main() {
         let mut client = session.lock().unwrap();
         let _msg = client.generate_register_message();
 
-        let (to_server, from_server) = Framed::new(socket, ClientToServerCodec::new(false)).split();
+        let (to_server, from_server) = Framed::new(socket, ClientToServerCodec::new()).split();
         let (tx, rx) = mpsc::channel(0);
         let reader = from_server.for_each(move |msg| {
             println!("Received {:?}", msg);
@@ -864,7 +918,10 @@ fn main() {
 
         let writer = rx
             .map_err(|()| unreachable!("rx can't fail"))
-            .fold(to_server, |to_server, msg| to_server.send(msg))
+            .fold(to_server, |to_server, mut msg: ClientMessage| {
+                msg.sign(&keypair);
+                to_server.send(msg)
+            })
             .map(|_| ());
 
         reader
@@ -10,7 +10,7 @@ kill -9 $(lsof -t -i:8080)
 n=2
 
 echo "keygen part"
-cargo run --package relay-server --bin server -- -P $n &
+cargo run --package relay-server --bin server &
 sleep 2
 for i in $(seq 1 $n);
 do
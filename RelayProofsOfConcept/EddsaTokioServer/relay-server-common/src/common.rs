@@ -7,6 +7,15 @@ pub static STATE_NOT_INITIALIZED: &str = "Relay sessions state is not initialize
 pub static RELAY_MESSAGE_DELIMITER: &str = ":::";
 pub static NOT_YOUR_TURN: &str = "Not this peers turn";
 pub static NOT_A_PEER: &str = "Not a peer";
+pub static UNKNOWN_SESSION: &str = "Relay session does not exist";
+pub static WRONG_ROUND: &str = "Message is not for the current round";
+pub static DUPLICATE_MESSAGE: &str = "Peer already sent a message for this round";
+pub static CANT_RECONNECT: &str = "Can't reconnect peer";
+pub static UNKNOWN_MESSAGE: &str = "Unknown or empty message";
+pub static FRAME_TOO_LARGE: &str = "Message exceeds the maximum frame size";
+pub static TOO_MANY_CONNECTIONS: &str = "Too many connections";
+pub static IDLE_TIMEOUT: &str = "Connection was idle for too long";
+pub static INVALID_SIGNATURE: &str = "Invalid signature";
 
 /// eddsa constants
 pub static PK_MESSAGE_PREFIX: &str = "PUBLIC_KEY";
@@ -0,0 +1,137 @@
+//! Errors the relay server responds with.
+//! An error is sent to the client as the ErrorResponse of its description,
+//! so clients can tell why a message or a connection was refused from the response
+use std::error::Error;
+use std::fmt;
+
+use crate::common::*;
+use crate::{ServerMessage, ServerResponse};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerError {
+    // Session errors
+    CantRegister,
+    CantRelay,
+    CantReconnect,
+    StateNotInitialized,
+    NotYourTurn,
+    NotAPeer,
+    UnknownSession,
+    WrongRound,
+    DuplicateMessage,
+    // The message is not signed by the identity key of its sender
+    InvalidSignature,
+
+    // Connection errors, the connection is closed after them
+    UnknownMessage,
+    FrameTooLarge,
+    TooManyConnections,
+    IdleTimeout,
+}
+
+const ALL_ERRORS: [ServerError; 14] = [
+    ServerError::CantRegister,
+    ServerError::CantRelay,
+    ServerError::CantReconnect,
+    ServerError::StateNotInitialized,
+    ServerError::NotYourTurn,
+    ServerError::NotAPeer,
+    ServerError::UnknownSession,
+    ServerError::WrongRound,
+    ServerError::DuplicateMessage,
+    ServerError::InvalidSignature,
+    ServerError::UnknownMessage,
+    ServerError::FrameTooLarge,
+    ServerError::TooManyConnections,
+    ServerError::IdleTimeout,
+];
+
+impl ServerError {
+    /// Returns the error of an error response, or None if it is not a server error
+    pub fn from_response(description: &str) -> Option<ServerError> {
+        ALL_ERRORS
+            .iter()
+            .find(|err| err.as_str() == description)
+            .copied()
+    }
+
+    /// Returns the error response sent to the client
+    pub fn response(self) -> ServerMessage {
+        let mut server_msg = ServerMessage::new();
+        server_msg.response = Some(ServerResponse::ErrorResponse(String::from(self.as_str())));
+        server_msg
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ServerError::CantRegister => CANT_REGISTER_RESPONSE,
+            ServerError::CantRelay => RELAY_ERROR_RESPONSE,
+            ServerError::CantReconnect => CANT_RECONNECT,
+            ServerError::StateNotInitialized => STATE_NOT_INITIALIZED,
+            ServerError::NotYourTurn => NOT_YOUR_TURN,
+            ServerError::NotAPeer => NOT_A_PEER,
+            ServerError::UnknownSession => UNKNOWN_SESSION,
+            ServerError::WrongRound => WRONG_ROUND,
+            ServerError::DuplicateMessage => DUPLICATE_MESSAGE,
+            ServerError::InvalidSignature => INVALID_SIGNATURE,
+            ServerError::UnknownMessage => UNKNOWN_MESSAGE,
+            ServerError::FrameTooLarge => FRAME_TOO_LARGE,
+            ServerError::TooManyConnections => TOO_MANY_CONNECTIONS,
+            ServerError::IdleTimeout => IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Error for ServerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{ServerError, ALL_ERRORS};
+    use crate::ServerResponse;
+
+    // The variant after each variant, in the order of ALL_ERRORS.
+    // A variant added to ServerError fails the build until it is added here
+    fn next(err: ServerError) -> Option<ServerError> {
+        match err {
+            ServerError::CantRegister => Some(ServerError::CantRelay),
+            ServerError::CantRelay => Some(ServerError::CantReconnect),
+            ServerError::CantReconnect => Some(ServerError::StateNotInitialized),
+            ServerError::StateNotInitialized => Some(ServerError::NotYourTurn),
+            ServerError::NotYourTurn => Some(ServerError::NotAPeer),
+            ServerError::NotAPeer => Some(ServerError::UnknownSession),
+            ServerError::UnknownSession => Some(ServerError::WrongRound),
+            ServerError::WrongRound => Some(ServerError::DuplicateMessage),
+            ServerError::DuplicateMessage => Some(ServerError::InvalidSignature),
+            ServerError::InvalidSignature => Some(ServerError::UnknownMessage),
+            ServerError::UnknownMessage => Some(ServerError::FrameTooLarge),
+            ServerError::FrameTooLarge => Some(ServerError::TooManyConnections),
+            ServerError::TooManyConnections => Some(ServerError::IdleTimeout),
+            ServerError::IdleTimeout => None,
+        }
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let mut variants = vec![ServerError::CantRegister];
+        while let Some(err) = next(*variants.last().unwrap()) {
+            variants.push(err);
+        }
+        assert_eq!(variants, ALL_ERRORS.to_vec());
+
+        for err in variants {
+            let description = match err.response().response {
+                Some(ServerResponse::ErrorResponse(description)) => description,
+                response => panic!("{:?} is not an error response", response),
+            };
+            assert_eq!(description, err.to_string());
+            assert_eq!(ServerError::from_response(&description), Some(err));
+        }
+        assert_eq!(ServerError::from_response("Not an error"), None);
+    }
+}
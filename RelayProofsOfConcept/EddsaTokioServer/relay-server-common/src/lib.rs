@@ -1,19 +1,28 @@
 use serde::{Deserialize, Serialize};
 use std::vec::Vec;
-use tokio_jsoncodec::Codec as JsonCodec;
+use mmpc_server_common::codec::NegotiatedCodec;
+use identity::Keypair;
+
+pub use identity::IdentityKey;
+pub use mmpc_server_common::identity;
 
 pub mod common;
+pub mod error;
 pub mod protocol;
+pub mod tls;
 
 pub type ProtocolIdentifier = u32;
 pub type PeerIdentifier = u32;
+pub type SessionIdentifier = u64;
 pub type MessagePayload = String;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RelayMessage {
     pub peer_number: PeerIdentifier,
     pub protocol_id: ProtocolIdentifier,
-    //pub round: u32,
+    // Round of the message in sessions relaying in rounds, ignored in turns
+    #[serde(default)]
+    pub round: u32,
     pub to: Vec<PeerIdentifier>,
     pub message: MessagePayload,
 }
@@ -23,23 +32,44 @@ impl RelayMessage {
         RelayMessage {
             peer_number,
             protocol_id,
+            round: 0,
             to: Vec::new(),
             message: String::from(""),
         }
     }
 
     pub fn set_message_params<S: Into<String>>(&mut self, to: Vec<PeerIdentifier>, message: S) {
-        //self.round = round_number;
         self.to = to;
         self.message = message.into();
     }
+
+    pub fn set_round(&mut self, round: u32) {
+        self.round = round;
+    }
+}
+
+/// How a session relays the messages of its peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum RelayMode {
+    // Peers send one at a time in the order of their peer numbers,
+    // and each message is relayed as it arrives
+    Turns,
+
+    // Peers send their message of a round at once, and the messages
+    // are relayed together once every peer sent its message of the round
+    #[default]
+    Rounds,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ServerResponse {
-    // Register response containing peer number
+    // Register response containing peer number, sent to every peer once the session is full
     Register(PeerIdentifier),
 
+    // Response to the registering peer with the session it joined, its peer number
+    // and the token it reconnects with, sent again once it reconnected
+    Joined(SessionIdentifier, PeerIdentifier, String),
+
     // Error message
     ErrorResponse(String),
 
@@ -66,7 +96,35 @@ impl AbortMessage {
 pub struct RegisterMessage {
     pub protocol_id: ProtocolIdentifier,
 
+    // The identity key the peer signs its messages with
+    #[serde(default)]
+    pub public_key: IdentityKey,
+
     pub capacity: u32,
+
+    // The session to join, if None the peer joins the open session of the protocol
+    // with this capacity, or opens a new one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<SessionIdentifier>,
+
+    // How the session relays messages, peers only join sessions of their mode
+    #[serde(default)]
+    pub mode: RelayMode,
+}
+
+/// Takes the place of a peer which lost its connection back, the server keeps it
+/// for its grace period. The server then sends the relay messages to the peer
+/// after the ones it received
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct ReconnectMessage {
+    pub session_id: SessionIdentifier,
+
+    pub peer_number: PeerIdentifier,
+
+    pub token: String,
+
+    // Number of relay messages the peer received in the session
+    pub received: u64,
 }
 
 #[derive(Debug, PartialEq)]
@@ -124,6 +182,13 @@ pub struct ClientMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_message: Option<RelayMessage>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect: Option<ReconnectMessage>,
+
+    // Hex encoded signature by the identity key of the sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 impl ClientMessage {
@@ -134,18 +199,92 @@ impl ClientMessage {
             abort: None,
 
             relay_message: None,
+
+            reconnect: None,
+
+            signature: None,
         }
     }
 
-    pub fn register(&mut self, protocol_id: ProtocolIdentifier, capacity: u32) {
+    pub fn reconnect(
+        &mut self,
+        session_id: SessionIdentifier,
+        peer_number: PeerIdentifier,
+        token: String,
+        received: u64,
+    ) {
+        self.reconnect = Some(ReconnectMessage {
+            session_id,
+            peer_number,
+            token,
+            received,
+        });
+    }
+
+    pub fn register(
+        &mut self,
+        public_key: IdentityKey,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) {
         self.register = Some(RegisterMessage {
             protocol_id,
+            public_key,
             capacity,
+            session_id: None,
+            mode: RelayMode::default(),
         });
     }
 
+    pub fn join(
+        &mut self,
+        public_key: IdentityKey,
+        session_id: SessionIdentifier,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) {
+        self.register = Some(RegisterMessage {
+            protocol_id,
+            public_key,
+            capacity,
+            session_id: Some(session_id),
+            mode: RelayMode::default(),
+        });
+    }
+
+    /// Registers to a session relaying in the given mode
+    pub fn set_mode(&mut self, mode: RelayMode) {
+        if let Some(register) = self.register.as_mut() {
+            register.mode = mode;
+        }
+    }
+
+    /// The bytes covered by the signature: the message serialized without its signature
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        unsigned.signature = None;
+        serde_json::to_vec(&unsigned).expect("Failed to serialize client message")
+    }
+
+    /// Signs the message with the identity key of the sender
+    pub fn sign(&mut self, keypair: &Keypair) {
+        self.signature = None;
+        self.signature = Some(identity::sign(keypair, &self.signed_bytes()));
+    }
+
+    /// Returns true if the message is signed by the given identity key
+    pub fn verify_signature(&self, public_key: &IdentityKey) -> bool {
+        match &self.signature {
+            Some(signature) => identity::verify(public_key, &self.signed_bytes(), signature),
+            None => false,
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.relay_message.is_none() && self.abort.is_none() && self.register.is_none()
+        self.relay_message.is_none()
+            && self.abort.is_none()
+            && self.register.is_none()
+            && self.reconnect.is_none()
     }
 
     pub fn are_equal_payloads(&self, msg: &ClientMessage) -> bool {
@@ -171,6 +310,9 @@ impl ClientMessage {
         if self.abort.is_some() {
             return ClientMessageType::Abort;
         }
+        if self.reconnect.is_some() {
+            return ClientMessageType::Reconnect;
+        }
         return ClientMessageType::Undefined;
     }
 }
@@ -180,6 +322,7 @@ pub enum ClientMessageType {
     Register,
     Abort,
     RelayMessage,
+    Reconnect,
     Undefined,
     Test,
 }
@@ -190,5 +333,38 @@ struct RegisterResponse {
 }
 
 // in: clientMessage out:serverMessage
-pub type ServerToClientCodec = JsonCodec<ClientMessage, ServerMessage>;
-pub type ClientToServerCodec = JsonCodec<ServerMessage, ClientMessage>;
+// Each connection is framed in the encoding its client registered with
+pub type ServerToClientCodec = NegotiatedCodec<ClientMessage, ServerMessage>;
+pub type ClientToServerCodec = NegotiatedCodec<ServerMessage, ClientMessage>;
+
+// The decode error of a message larger than the maximum frame size of the codec
+pub use mmpc_server_common::codec::FrameTooLarge;
+
+#[cfg(test)]
+mod tests {
+    use super::ClientMessage;
+    use mmpc_server_common::codec::{Codec, Encoding};
+    use mmpc_server_common::identity::{generate_keypair, identity_key};
+
+    #[test]
+    fn test_sign_client_message() {
+        let keypair = generate_keypair();
+        let public_key = identity_key(&keypair);
+        let mut msg = ClientMessage::new();
+        msg.register(public_key.clone(), 1, 2);
+        assert!(!msg.verify_signature(&public_key));
+        msg.sign(&keypair);
+        assert!(msg.verify_signature(&public_key));
+
+        // The signature covers the message, not its encoding on the wire
+        for encoding in [Encoding::Json, Encoding::Cbor].iter() {
+            let decoded: ClientMessage = encoding.decode(&encoding.encode(&msg)).unwrap();
+            assert!(decoded.verify_signature(&public_key));
+        }
+
+        let mut tampered = msg.clone();
+        tampered.register.as_mut().unwrap().capacity = 3;
+        assert!(!tampered.verify_signature(&public_key));
+        assert!(!msg.verify_signature(&identity_key(&generate_keypair())));
+    }
+}
@@ -1,14 +1,10 @@
-/// Structures for supported protocols for relay-server
+/// Structures for supported protocols for relay-server.
+/// The supported protocols are listed in a registry file the server loads on startup
 use log::debug;
-use serde::{Deserialize, Serialize};
-use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
 use std::sync::{Arc, RwLock};
 
 use crate::ProtocolIdentifier;
-
-static PROTOCOLS_F: &str = r#"./protocols.json"#;
+pub use mmpc_server_common::protocol::ProtocolRegistry;
 
 #[derive(Debug, Clone)]
 pub struct ProtocolDescriptor {
@@ -45,52 +41,11 @@ impl ProtocolDescriptor {
     }
 }
 
-/// Returns true if the protocol is a valid protocol as determined by the
-/// protocols.json file
-pub fn is_valid_protocol(p: &ProtocolDescriptor) -> bool {
-    let all_protocols = get_protocols();
-    match all_protocols {
-        Ok(_protocols) => {
-            for prot in _protocols.protocols {
-                debug!("Checking if fits protocol: {:?}", prot);
-                if prot.id == p.id {
-                    if prot.capacities.contains(&(p.capacity)) {
-                        return true;
-                    } else {
-                        return false;
-                    }
-                }
-            }
-            return false;
-        }
-        Err(_) => panic!("Corrupt protocols file"),
-    }
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct Protocolss {
-    pub protocols: Vec<Protocol>,
-}
-
-#[derive(Deserialize, Debug, Serialize)]
-struct Protocol {
-    pub id: u32,
-    pub capacities: Vec<u32>,
-    pub names: Vec<String>,
-}
-
-// Reutrn all avaliable protocols
-fn get_protocols() -> Result<Protocolss, Box<dyn Error>> {
-    debug!("Getting protocols");
-
-    // Open the file in read-only mode with buffer.
-    let path = PROTOCOLS_F;
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-
-    // Read the JSON contents of the file as an instance of `Protocols`.
-    let p = serde_json::from_reader(reader)?;
-
-    //debug!("Got protocols: {:?}", p);
-    Ok(p)
+/// Returns true if the protocol is in the registry,
+/// with a capacity a session of the protocol may have
+pub fn is_valid_protocol(protocols: &ProtocolRegistry, p: &ProtocolDescriptor) -> bool {
+    debug!("Checking if fits protocol: {:?}", p);
+    protocols.is_valid(&mmpc_server_common::protocol::ProtocolDescriptor::new(
+        p.id, p.capacity,
+    ))
 }
@@ -0,0 +1,205 @@
+//! TLS for the connections to the relay server, over rustls.
+//! The server proves itself with its certificate, and with a client CA it only takes
+//! clients with a certificate of that CA (mutual TLS). Keys and certificates are PEM files
+use futures::Future;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, NoServerSessionStorage,
+    PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+pub use tokio_rustls::{rustls, webpki};
+
+/// A connection to the relay server, in TLS or in the clear
+pub trait RelayStream: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> RelayStream for T {}
+
+fn invalid_data(err: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path)?))
+}
+
+/// Reads the certificate chain in the file
+pub fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    match certs(&mut open(path)?) {
+        Ok(ref chain) if chain.is_empty() => Err(invalid_data(format!(
+            "No certificate in {}",
+            path.display()
+        ))),
+        Ok(chain) => Ok(chain),
+        Err(()) => Err(invalid_data(format!(
+            "Invalid certificate in {}",
+            path.display()
+        ))),
+    }
+}
+
+/// Reads the first PKCS8 or RSA private key in the file
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut keys = pkcs8_private_keys(&mut open(path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?).unwrap_or_default();
+    }
+    match keys.into_iter().next() {
+        Some(key) => Ok(key),
+        None => Err(invalid_data(format!(
+            "No private key in {}",
+            path.display()
+        ))),
+    }
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut open(path)?) {
+        Ok((added, _)) if added > 0 => Ok(roots),
+        _ => Err(invalid_data(format!(
+            "No CA certificate in {}",
+            path.display()
+        ))),
+    }
+}
+
+/// TLS config of the server, with its certificate chain and key.
+/// With a client CA, clients must authenticate with a certificate of the CA
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<ServerConfig> {
+    let client_auth = match client_ca {
+        Some(client_ca) => AllowAnyAuthenticatedClient::new(load_roots(client_ca)?),
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(client_auth);
+    if client_ca.is_some() {
+        // a resumed session carries no client certificate,
+        // so every connection authenticates the client in full
+        config.session_storage = Arc::new(NoServerSessionStorage {});
+    }
+    config
+        .set_single_cert(load_certs(cert)?, load_private_key(key)?)
+        .map_err(|err| invalid_data(err.to_string()))?;
+    Ok(config)
+}
+
+/// TLS config of a client, which trusts the server certificates of the CA.
+/// The client authenticates with its certificate and key if given, for mutual TLS
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> io::Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.root_store = load_roots(ca)?;
+    if let Some((cert, key)) = identity {
+        config.set_single_client_cert(load_certs(cert)?, load_private_key(key)?);
+    }
+    Ok(config)
+}
+
+/// Runs the TLS handshake with the server named `server_name` over the stream,
+/// or returns the stream as is without a config
+pub fn connect<S>(
+    stream: S,
+    tls: Option<(Arc<ClientConfig>, &str)>,
+) -> Box<dyn Future<Item = Box<dyn RelayStream>, Error = io::Error> + Send>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (config, server_name) = match tls {
+        Some(tls) => tls,
+        None => return Box::new(futures::future::ok(Box::new(stream) as Box<dyn RelayStream>)),
+    };
+    let server_name = match DNSNameRef::try_from_ascii_str(server_name) {
+        Ok(server_name) => server_name,
+        Err(_) => {
+            return Box::new(futures::future::err(invalid_data(format!(
+                "Invalid server name {}",
+                server_name
+            ))))
+        }
+    };
+    let handshake = TlsConnector::from(config).connect(server_name, stream);
+    Box::new(handshake.map(|stream| Box::new(stream) as Box<dyn RelayStream>))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_config, load_certs, load_private_key, server_config};
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    // A CA, and a certificate for localhost it issued with its key
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    #[test]
+    fn test_load_fixtures() {
+        assert_eq!(load_certs(&testdata("server.pem")).unwrap().len(), 1);
+        assert!(load_private_key(&testdata("server.key")).is_ok());
+        assert!(server_config(&testdata("server.pem"), &testdata("server.key"), None).is_ok());
+        assert!(server_config(
+            &testdata("server.pem"),
+            &testdata("server.key"),
+            Some(&testdata("ca.pem"))
+        )
+        .is_ok());
+        assert!(client_config(&testdata("ca.pem"), None).is_ok());
+    }
+
+    #[test]
+    fn test_load_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty.pem");
+        fs::write(&empty, "").unwrap();
+        let missing = dir.path().join("missing.pem");
+
+        let err = load_certs(&empty).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("No certificate"));
+        assert_eq!(
+            load_certs(&missing).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        // A key is not a certificate, nor a certificate a key
+        assert!(load_certs(&testdata("server.key")).is_err());
+        let err = load_private_key(&empty).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(load_private_key(&testdata("server.pem")).is_err());
+        assert_eq!(
+            load_private_key(&missing).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_server_config_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty.pem");
+        fs::write(&empty, "").unwrap();
+        let cert = testdata("server.pem");
+        let key = testdata("server.key");
+
+        assert!(server_config(&empty, &key, None).is_err());
+        assert!(server_config(&cert, &empty, None).is_err());
+        let err = server_config(&cert, &cert, None).err().unwrap();
+        assert!(err.to_string().starts_with("No private key"));
+        // A client CA without a certificate
+        let err = server_config(&cert, &key, Some(&empty)).err().unwrap();
+        assert!(err.to_string().starts_with("No CA certificate"));
+        assert!(client_config(&empty, None).is_err());
+    }
+}
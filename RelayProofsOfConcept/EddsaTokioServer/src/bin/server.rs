@@ -1,6 +1,15 @@
 //! Implementation of a server designed to work
 //! as a relay between Peers communicating in a MPC protocol.
 //! A protocol is represented by a unique identifier and a capacity
+//! The supported protocols are loaded once on startup from `--protocols`
+//! The server runs any number of sessions at once, each opened when
+//! the first peer of its protocol and capacity registers
+//! A peer which loses its connection can reconnect to its place within
+//! the grace period, after which its session is aborted
+//! Connections are served over TLS with `--tls-cert` and `--tls-key`, and with
+//! `--client-ca` each peer is bound to the client certificate it registered with
+//! Connections are limited in number, in the size of their messages, and in how long
+//! they may stay idle or take to read their messages, see `--help` for the limits
 //! A client that wishes to communicate with othr peers via the server
 //! must build its messages in the Codec supplied in relay_server_common lib
 //!
@@ -10,9 +19,13 @@
 //! this will run a client that utilizes the server in some way
 //!
 use clap::{App, Arg, ArgMatches};
-use relay_server::RelayServer;
+use relay_server::{Limits, RelayServer};
+use relay_server_common::protocol::ProtocolRegistry;
 use std::io;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
 fn arg_matches<'a>() -> ArgMatches<'a> {
     App::new("relay-server")
@@ -22,10 +35,69 @@ fn arg_matches<'a>() -> ArgMatches<'a> {
                 .value_name("<HOST:PORT>"),
         )
         .arg(
-            Arg::with_name("capacity")
-                .default_value("2")
-                .short("P")
-                .long("participants"),
+            Arg::with_name("grace-period")
+                .long("grace-period")
+                .default_value("30")
+                .value_name("SECONDS")
+                .help("How long a peer which lost its connection may take to reconnect before its session is aborted"),
+        )
+        .arg(
+            Arg::with_name("protocols")
+                .long("protocols")
+                .default_value("protocols.json")
+                .value_name("PATH")
+                .help("Path of the registry of supported protocols"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .value_name("PATH")
+                .requires("tls-key")
+                .help("PEM certificate chain of the server, serves connections over TLS"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .value_name("PATH")
+                .requires("tls-cert")
+                .help("PEM private key of the server certificate"),
+        )
+        .arg(
+            Arg::with_name("client-ca")
+                .long("client-ca")
+                .value_name("PATH")
+                .requires("tls-cert")
+                .help("PEM CA certificate of the clients, which then authenticate with a certificate of the CA"),
+        )
+        .arg(
+            Arg::with_name("max-frame-size")
+                .long("max-frame-size")
+                .value_name("BYTES")
+                .help("Bytes a message may take at most, a larger one closes its connection [default: 1048576]"),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .long("max-connections")
+                .value_name("COUNT")
+                .help("Connections served at once, further ones are refused [default: 1024]"),
+        )
+        .arg(
+            Arg::with_name("max-connections-per-ip")
+                .long("max-connections-per-ip")
+                .value_name("COUNT")
+                .help("Connections served at once from a single IP address [default: 64]"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .help("How long a connection may send nothing before it is closed [default: 600]"),
+        )
+        .arg(
+            Arg::with_name("send-timeout")
+                .long("send-timeout")
+                .value_name("SECONDS")
+                .help("How long a client may take to read a message before it is evicted [default: 10]"),
         )
         .arg(
             Arg::with_name("verbose")
@@ -37,6 +109,35 @@ fn arg_matches<'a>() -> ArgMatches<'a> {
         .get_matches()
 }
 
+// Parses the value of an optional argument
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Unable to parse {}", name))
+    })
+}
+
+fn limits(matches: &ArgMatches) -> Limits {
+    let mut limits = Limits::default();
+    if let Some(max_frame_size) = parse_arg(matches, "max-frame-size") {
+        limits.max_frame_size = max_frame_size;
+    }
+    if let Some(max_connections) = parse_arg(matches, "max-connections") {
+        limits.max_connections = max_connections;
+    }
+    if let Some(max_connections_per_ip) = parse_arg(matches, "max-connections-per-ip") {
+        limits.max_connections_per_ip = max_connections_per_ip;
+    }
+    if let Some(idle_timeout) = parse_arg(matches, "idle-timeout") {
+        limits.idle_timeout = Duration::from_secs(idle_timeout);
+    }
+    if let Some(send_timeout) = parse_arg(matches, "send-timeout") {
+        limits.send_timeout = Duration::from_secs(send_timeout);
+    }
+    limits
+}
+
 fn setup_logging(verbosity: u64) -> Result<(), fern::InitError> {
     let mut base_config = fern::Dispatch::new();
 
@@ -101,16 +202,31 @@ fn main() {
         .parse()
         .expect("Unable to parse socket address");
 
-    let capacity: u32 = matches
-        .value_of("capacity")
+    let grace_period: u64 = matches
+        .value_of("grace-period")
         .unwrap()
         .parse()
-        .expect("Invalid number of participants");
+        .expect("Unable to parse grace period");
 
     let verbosity: u64 = matches.occurrences_of("verbose");
 
     setup_logging(verbosity).expect("failed to initialize logging.");
 
-    let server = RelayServer::new(addr);
-    server.start_server(capacity);
+    let protocols_path = matches.value_of("protocols").unwrap();
+    let protocols = ProtocolRegistry::load(protocols_path)
+        .unwrap_or_else(|err| panic!("Unable to load protocols from {}: {}", protocols_path, err));
+
+    let mut server =
+        RelayServer::with_grace_period(addr, Duration::from_secs(grace_period), protocols)
+            .with_limits(limits(&matches));
+    if let (Some(cert), Some(key)) = (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        let config = relay_server_common::tls::server_config(
+            Path::new(cert),
+            Path::new(key),
+            matches.value_of("client-ca").map(Path::new),
+        )
+        .expect("Unable to load the TLS certificate");
+        server = server.with_tls(config);
+    }
+    server.start_server();
 }
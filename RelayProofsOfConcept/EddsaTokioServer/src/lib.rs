@@ -1,4 +1,8 @@
+mod limits;
+mod lobby;
 mod relay_server;
 mod relay_session;
 
+pub use crate::limits::Limits;
+pub use crate::lobby::{Disconnection, Lobby};
 pub use crate::relay_server::RelayServer;
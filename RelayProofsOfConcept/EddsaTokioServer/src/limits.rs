@@ -0,0 +1,137 @@
+//! Limits the relay server puts on its connections, so a client which misbehaves,
+//! on purpose or by a bug, costs no more than its own connection.
+//! A connection which breaks a limit is closed, and if it was a peer it keeps
+//! its place for the grace period like any other peer which lost its connection
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use relay_server_common::error::ServerError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Bytes a message may take at most
+    pub max_frame_size: usize,
+
+    /// Connections the server serves at once
+    pub max_connections: usize,
+
+    /// Connections the server serves at once from a single IP address
+    pub max_connections_per_ip: usize,
+
+    /// How long a connection may send nothing, or take to finish its TLS handshake
+    pub idle_timeout: Duration,
+
+    /// Messages queued to a client before the peers relaying to it wait for it to read them
+    pub send_buffer: usize,
+
+    /// How long a client may take to read a message, a slower one is evicted
+    pub send_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_frame_size: 1024 * 1024,
+            max_connections: 1024,
+            max_connections_per_ip: 64,
+            idle_timeout: Duration::from_secs(600),
+            send_buffer: 64,
+            send_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+// The number of connections served, in all and by IP address
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// The connections the server serves at once
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    counts: Mutex<Counts>,
+}
+
+/// The place of a connection within the limits, freed once it is dropped
+#[derive(Debug)]
+pub(crate) struct ConnectionSlot {
+    connections: Arc<Connections>,
+    ip: IpAddr,
+}
+
+impl ConnectionSlot {
+    /// Takes a slot for a new connection from the IP address,
+    /// or returns TooManyConnections if there is none left
+    pub fn acquire(
+        connections: &Arc<Connections>,
+        ip: IpAddr,
+        limits: &Limits,
+    ) -> Result<ConnectionSlot, ServerError> {
+        let mut counts = connections.counts.lock().unwrap();
+        let of_ip = counts.per_ip.get(&ip).cloned().unwrap_or(0);
+        if counts.total >= limits.max_connections || of_ip >= limits.max_connections_per_ip {
+            return Err(ServerError::TooManyConnections);
+        }
+        counts.total += 1;
+        counts.per_ip.insert(ip, of_ip + 1);
+        Ok(ConnectionSlot {
+            connections: Arc::clone(connections),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.connections.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(of_ip) = counts.per_ip.get_mut(&self.ip) {
+            *of_ip -= 1;
+            if *of_ip == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionSlot, Connections, Limits};
+    use relay_server_common::error::ServerError;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_connection_limits() {
+        let limits = Limits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            ..Limits::default()
+        };
+        let connections = Arc::new(Connections::default());
+        let first: IpAddr = "127.0.0.1".parse().unwrap();
+        let second: IpAddr = "127.0.0.2".parse().unwrap();
+
+        let slot = ConnectionSlot::acquire(&connections, first, &limits).unwrap();
+        let _other = ConnectionSlot::acquire(&connections, first, &limits).unwrap();
+        // an address takes no more than its own limit
+        assert_eq!(
+            ConnectionSlot::acquire(&connections, first, &limits).unwrap_err(),
+            ServerError::TooManyConnections
+        );
+        let _third = ConnectionSlot::acquire(&connections, second, &limits).unwrap();
+        // nor the server more than its limit
+        assert_eq!(
+            ConnectionSlot::acquire(&connections, second, &limits).unwrap_err(),
+            ServerError::TooManyConnections
+        );
+
+        // a closed connection frees its slot
+        drop(slot);
+        assert!(ConnectionSlot::acquire(&connections, first, &limits).is_ok());
+    }
+}
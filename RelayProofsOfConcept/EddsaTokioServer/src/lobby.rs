@@ -0,0 +1,715 @@
+//! The lobby of the relay server, which opens relay sessions on demand.
+//! A peer registers either to a session by its identifier, or to the open session
+//! of its protocol and capacity, which is opened if there is none.
+//! Any number of sessions run at once, each one a RelaySession of its own.
+//! A peer which loses its connection keeps its place for the grace period of the lobby,
+//! and its session is aborted only if it does not reconnect by then
+use futures::sync::mpsc;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::relay_session::{Client, RelaySession, RelaySessionState};
+use relay_server_common::error::ServerError;
+use relay_server_common::protocol::ProtocolRegistry;
+use relay_server_common::{
+    ClientMessage, ClientMessageType, PeerIdentifier, ReconnectMessage, RegisterMessage,
+    RelayMessage, ServerMessage, ServerResponse, SessionIdentifier,
+};
+
+// A connection to the server, with the session it registered to
+#[derive(Clone, Debug)]
+struct Connection {
+    client: Client,
+    session_id: Option<SessionIdentifier>,
+}
+
+/// A peer which lost its connection, expired once the grace period is over
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Disconnection {
+    pub session_id: SessionIdentifier,
+
+    pub peer_id: PeerIdentifier,
+
+    // the disconnections of the peer so far, a later one expires on its own
+    disconnections: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Lobby {
+    connections: Arc<RwLock<HashMap<SocketAddr, Connection>>>,
+
+    sessions: Arc<RwLock<HashMap<SessionIdentifier, RelaySession>>>,
+
+    // Identifier of the next session to open, identifiers are never reused
+    next_session_id: Arc<RwLock<SessionIdentifier>>,
+
+    // How long a peer which lost its connection may take to reconnect
+    grace_period: Duration,
+
+    // The protocols sessions are opened for, loaded once on startup
+    protocols: Arc<ProtocolRegistry>,
+}
+
+impl Lobby {
+    /// Creates a lobby with no sessions of the given protocols,
+    /// where a peer leaving aborts its session at once
+    pub fn new(protocols: ProtocolRegistry) -> Lobby {
+        Lobby::with_grace_period(Duration::from_secs(0), protocols)
+    }
+
+    /// Creates a lobby with no sessions of the given protocols, where a peer
+    /// which lost its connection can reconnect within the grace period
+    pub fn with_grace_period(grace_period: Duration, protocols: ProtocolRegistry) -> Lobby {
+        Lobby {
+            connections: Arc::new(RwLock::new(HashMap::new())),
+
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+
+            next_session_id: Arc::new(RwLock::new(1)),
+
+            grace_period,
+
+            protocols: Arc::new(protocols),
+        }
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// Inserts a new connection to the lobby.
+    /// the connection is not in any session until it sends a register message
+    pub fn insert_new_connection(&self, addr: SocketAddr, client: Client) {
+        self.connections.write().unwrap().insert(
+            addr,
+            Connection {
+                client,
+                session_id: None,
+            },
+        );
+    }
+
+    /// Registers the connection to the session of the register message, or to the open
+    /// session of its protocol and capacity, opening a new session if there is none.
+    /// Returns a Joined response to the peer, and the register messages of all the peers
+    /// of the session if it is now full
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        register: &RegisterMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let connection = match self.connections.read().unwrap().get(&addr) {
+            Some(connection) => connection.clone(),
+            None => return vec![],
+        };
+        if let Some(session_id) = connection.session_id {
+            warn!("{} already registered to session {}", addr, session_id);
+            return vec![error_response(
+                &connection.client,
+                ServerError::CantRegister,
+            )];
+        }
+        // Registrations hold the sessions lock, so an open session never takes
+        // more peers than its capacity
+        let mut sessions = self.sessions.write().unwrap();
+        // A new session takes its identifier only once the peer registered to it
+        let (session_id, session) = match register.session_id {
+            Some(session_id) => match sessions.get(&session_id) {
+                Some(session) if session.mode() == register.mode => {
+                    (Some(session_id), session.clone())
+                }
+                Some(_) => {
+                    warn!("{} requested session {} in another mode", addr, session_id);
+                    return vec![error_response(
+                        &connection.client,
+                        ServerError::CantRegister,
+                    )];
+                }
+                None => {
+                    warn!("{} requested unknown session {}", addr, session_id);
+                    return vec![error_response(
+                        &connection.client,
+                        ServerError::UnknownSession,
+                    )];
+                }
+            },
+            None => {
+                let open = sessions.iter().find(|(_, session)| {
+                    session.is_open(register.protocol_id, register.capacity, register.mode)
+                });
+                match open {
+                    Some((session_id, session)) => (Some(*session_id), session.clone()),
+                    None => (
+                        None,
+                        RelaySession::with_mode(
+                            register.capacity,
+                            register.mode,
+                            Arc::clone(&self.protocols),
+                        ),
+                    ),
+                }
+            }
+        };
+        let client = connection
+            .client
+            .clone()
+            .with_public_key(register.public_key.clone());
+        session.insert_new_connection(addr, client);
+        let register_messages = session.register(addr, register.protocol_id, register.capacity);
+        match session.get_peer_by_address(&addr) {
+            Some(peer) => {
+                let peer_id = peer.peer_id;
+                let session_id = session_id.unwrap_or_else(|| {
+                    let session_id = self.new_session_id();
+                    info!(
+                        "Opened session {} of protocol {} for {} peers, relaying in {:?}",
+                        session_id, register.protocol_id, register.capacity, register.mode
+                    );
+                    sessions.insert(session_id, session.clone());
+                    session_id
+                });
+                if let Some(connection) = self.connections.write().unwrap().get_mut(&addr) {
+                    connection.session_id = Some(session_id);
+                }
+                info!("{} joined session {} as peer {}", addr, session_id, peer_id);
+                let mut server_msg = ServerMessage::new();
+                server_msg.response = Some(ServerResponse::Joined(
+                    session_id,
+                    peer_id,
+                    peer.token().to_string(),
+                ));
+                let mut messages = vec![(server_msg, connection.client.sender())];
+                messages.extend(register_messages);
+                messages
+            }
+            None => {
+                session.remove(&addr);
+                vec![error_response(
+                    &connection.client,
+                    ServerError::CantRegister,
+                )]
+            }
+        }
+    }
+
+    /// Moves a peer which lost its connection to this connection, in the same place
+    /// of its session. Returns a Joined response to the peer, followed by the messages
+    /// it missed while away
+    pub fn reconnect(
+        &self,
+        addr: SocketAddr,
+        reconnect: &ReconnectMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let connection = match self.connections.read().unwrap().get(&addr) {
+            Some(connection) => connection.clone(),
+            None => return vec![],
+        };
+        if let Some(session_id) = connection.session_id {
+            warn!("{} already registered to session {}", addr, session_id);
+            return vec![error_response(
+                &connection.client,
+                ServerError::CantReconnect,
+            )];
+        }
+        let session_id = reconnect.session_id;
+        let session = match self.sessions.read().unwrap().get(&session_id) {
+            Some(session) => session.clone(),
+            None => {
+                warn!("{} reconnected to unknown session {}", addr, session_id);
+                return vec![error_response(
+                    &connection.client,
+                    ServerError::UnknownSession,
+                )];
+            }
+        };
+        let missed = session.reconnect_peer(
+            addr,
+            connection.client.clone(),
+            reconnect.peer_number,
+            &reconnect.token,
+            reconnect.received,
+        );
+        match missed {
+            Some(missed) => {
+                if let Some(connection) = self.connections.write().unwrap().get_mut(&addr) {
+                    connection.session_id = Some(session_id);
+                }
+                info!(
+                    "{} reconnected to session {} as peer {}",
+                    addr, session_id, reconnect.peer_number
+                );
+                let mut server_msg = ServerMessage::new();
+                server_msg.response = Some(ServerResponse::Joined(
+                    session_id,
+                    reconnect.peer_number,
+                    reconnect.token.clone(),
+                ));
+                let mut messages = vec![(server_msg, connection.client.sender())];
+                messages.extend(missed);
+                messages
+            }
+            None => {
+                warn!(
+                    "{} can not reconnect as peer {} of session {}",
+                    addr, reconnect.peer_number, session_id
+                );
+                vec![error_response(
+                    &connection.client,
+                    ServerError::CantReconnect,
+                )]
+            }
+        }
+    }
+
+    /// Relays the message in the session of the sender,
+    /// connections which did not register are not peers of any session
+    pub fn relay_message(
+        &self,
+        from: &SocketAddr,
+        msg: RelayMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.session_of(from) {
+            Some(session) => session.relay_message(from, msg),
+            None => match self.connections.read().unwrap().get(from) {
+                Some(connection) => vec![error_response(&connection.client, ServerError::NotAPeer)],
+                None => vec![],
+            },
+        }
+    }
+
+    /// Aborts the session of the peer
+    /// Return an abort message to all the peers of the session
+    pub fn abort(&self, addr: SocketAddr) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.session_of(&addr) {
+            Some(session) => session.abort(addr),
+            None => vec![],
+        }
+    }
+
+    /// Removes a closed connection. A peer leaving aborts its session,
+    /// and the session is closed once all its connections are
+    pub fn disconnect(
+        &self,
+        addr: SocketAddr,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let connection = self.connections.write().unwrap().remove(&addr);
+        let session_id = match connection.and_then(|connection| connection.session_id) {
+            Some(session_id) => session_id,
+            None => return vec![],
+        };
+        let mut sessions = self.sessions.write().unwrap();
+        let session = match sessions.get(&session_id) {
+            Some(session) => session.clone(),
+            None => return vec![],
+        };
+        let messages = session.abort(addr);
+        session.remove(&addr);
+        if session.is_empty() {
+            info!("Closed session {}", session_id);
+            sessions.remove(&session_id);
+        }
+        messages
+    }
+
+    /// Keeps the place of a peer which lost its connection for the grace period,
+    /// rather than aborting its session. Returns the disconnection to expire once the
+    /// grace period is over, or None if the connection is to be disconnected at once
+    pub fn hold(&self, addr: SocketAddr) -> Option<Disconnection> {
+        if self.grace_period == Duration::from_secs(0) {
+            return None;
+        }
+        let session_id = self.connections.read().unwrap().get(&addr)?.session_id?;
+        let session = self.sessions.read().unwrap().get(&session_id)?.clone();
+        if session.state() == RelaySessionState::Aborted {
+            return None;
+        }
+        let (peer_id, disconnections) = session.disconnect_peer(&addr)?;
+        self.connections.write().unwrap().remove(&addr);
+        info!(
+            "Peer {} of session {} lost its connection, holding its place for {:?}",
+            peer_id, session_id, self.grace_period
+        );
+        Some(Disconnection {
+            session_id,
+            peer_id,
+            disconnections,
+        })
+    }
+
+    /// Aborts the session of a peer which did not reconnect within the grace period.
+    /// Return an abort message to all the peers of the session, none if the peer reconnected
+    pub fn expire(
+        &self,
+        disconnection: Disconnection,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let mut sessions = self.sessions.write().unwrap();
+        let session = match sessions.get(&disconnection.session_id) {
+            Some(session) => session.clone(),
+            None => return vec![],
+        };
+        let messages = session.expire_peer(disconnection.peer_id, disconnection.disconnections);
+        if !messages.is_empty() {
+            warn!(
+                "Peer {} of session {} did not reconnect in time",
+                disconnection.peer_id, disconnection.session_id
+            );
+        }
+        if session.is_empty() {
+            info!("Closed session {}", disconnection.session_id);
+            sessions.remove(&disconnection.session_id);
+        }
+        messages
+    }
+
+    /// Checks the message is signed by the identity key of its sender: the key its
+    /// register message declares, or the key the peer registered with otherwise.
+    /// Messages of connections which are not peers are refused by their handlers
+    pub fn verify(&self, addr: &SocketAddr, msg: &ClientMessage) -> Result<(), ServerError> {
+        let public_key = match msg.msg_type() {
+            ClientMessageType::Register => msg
+                .register
+                .as_ref()
+                .map(|register| register.public_key.clone()),
+            ClientMessageType::Reconnect => msg.reconnect.as_ref().and_then(|reconnect| {
+                let sessions = self.sessions.read().unwrap();
+                let session = sessions.get(&reconnect.session_id)?;
+                session.public_key_of_peer(reconnect.peer_number)
+            }),
+            ClientMessageType::RelayMessage | ClientMessageType::Abort => self
+                .session_of(addr)
+                .and_then(|session| session.public_key_of(addr)),
+            _ => None,
+        };
+        match public_key {
+            Some(public_key) if !msg.verify_signature(&public_key) => {
+                Err(ServerError::InvalidSignature)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Try return a Sender of a connection by its address
+    pub fn get_sender_by_address(&self, addr: &SocketAddr) -> Option<mpsc::Sender<ServerMessage>> {
+        self.connections
+            .read()
+            .unwrap()
+            .get(addr)
+            .map(|connection| connection.client.sender())
+    }
+
+    /// Returns the number of sessions running
+    pub fn session_count(&self) -> usize {
+        self.sessions.read().unwrap().len()
+    }
+
+    // Returns the session the connection registered to
+    fn session_of(&self, addr: &SocketAddr) -> Option<RelaySession> {
+        let session_id = self.connections.read().unwrap().get(addr)?.session_id?;
+        self.sessions.read().unwrap().get(&session_id).cloned()
+    }
+
+    fn new_session_id(&self) -> SessionIdentifier {
+        let mut next_session_id = self.next_session_id.write().unwrap();
+        let session_id = *next_session_id;
+        *next_session_id += 1;
+        session_id
+    }
+}
+
+// An error response to the client
+fn error_response(
+    client: &Client,
+    err: ServerError,
+) -> (ServerMessage, mpsc::Sender<ServerMessage>) {
+    (err.response(), client.sender())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lobby;
+    use crate::relay_session::Client;
+
+    use futures::sync::mpsc;
+
+    use rand::Rng;
+    use relay_server_common::common::{CANT_RECONNECT, CANT_REGISTER_RESPONSE, UNKNOWN_SESSION};
+    use relay_server_common::error::ServerError;
+    use relay_server_common::identity::{generate_keypair, identity_key};
+    use relay_server_common::protocol::ProtocolRegistry;
+    use relay_server_common::{
+        ClientMessage, ProtocolIdentifier, ReconnectMessage, RegisterMessage, RelayMessage,
+        RelayMode, ServerMessage, ServerResponse, SessionIdentifier,
+    };
+
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn protocols() -> ProtocolRegistry {
+        ProtocolRegistry::load(concat!(env!("CARGO_MANIFEST_DIR"), "/protocols.json")).unwrap()
+    }
+
+    fn connect(lobby: &Lobby, port: u16) -> SocketAddr {
+        let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (tx, _) = mpsc::channel(0);
+        lobby.insert_new_connection(client_addr, Client::new(tx));
+        client_addr
+    }
+
+    fn register_message(
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        session_id: Option<SessionIdentifier>,
+    ) -> RegisterMessage {
+        // each peer registers with an identity key of its own
+        RegisterMessage {
+            protocol_id,
+            public_key: hex::encode(rand::thread_rng().gen::<[u8; 32]>()),
+            capacity,
+            session_id,
+            mode: RelayMode::Turns,
+        }
+    }
+
+    // Returns the session and peer number of the Joined response
+    fn joined(messages: &[(ServerMessage, mpsc::Sender<ServerMessage>)]) -> Option<(u64, u32)> {
+        messages.iter().find_map(|(msg, _)| match msg.response {
+            Some(ServerResponse::Joined(session_id, peer_id, _)) => Some((session_id, peer_id)),
+            _ => None,
+        })
+    }
+
+    fn error(messages: &[(ServerMessage, mpsc::Sender<ServerMessage>)]) -> Option<String> {
+        messages.iter().find_map(|(msg, _)| match &msg.response {
+            Some(ServerResponse::ErrorResponse(err)) => Some(err.clone()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_peers_share_open_session() {
+        let lobby = Lobby::new(protocols());
+        let capacity = 3;
+        let mut sessions = vec![];
+        for i in 0..capacity {
+            let client_addr = connect(&lobby, 8080 + i as u16);
+            let messages = lobby.register(client_addr, &register_message(1, capacity, None));
+            let (session_id, peer_id) = joined(&messages).expect("Unable to register");
+            assert_eq!(peer_id, i + 1);
+            sessions.push(session_id);
+            // The last peer gets the register messages of all peers with its response
+            if i + 1 == capacity {
+                assert_eq!(messages.len(), 1 + capacity as usize);
+            } else {
+                assert_eq!(messages.len(), 1);
+            }
+        }
+        assert!(sessions.iter().all(|session_id| *session_id == sessions[0]));
+        assert_eq!(lobby.session_count(), 1);
+    }
+
+    #[test]
+    fn test_full_session_opens_new_session() {
+        let lobby = Lobby::new(protocols());
+        let first = connect(&lobby, 8081);
+        let second = connect(&lobby, 8082);
+        let first = joined(&lobby.register(first, &register_message(1, 1, None))).unwrap();
+        let second = joined(&lobby.register(second, &register_message(1, 1, None))).unwrap();
+        assert_ne!(first.0, second.0);
+        assert_eq!(lobby.session_count(), 2);
+    }
+
+    #[test]
+    fn test_protocols_run_in_separate_sessions() {
+        let lobby = Lobby::new(protocols());
+        let first = connect(&lobby, 8081);
+        let second = connect(&lobby, 8082);
+        let third = connect(&lobby, 8083);
+        let first = joined(&lobby.register(first, &register_message(1, 2, None))).unwrap();
+        let second = joined(&lobby.register(second, &register_message(1, 3, None))).unwrap();
+        let third = joined(&lobby.register(third, &register_message(0, 2, None))).unwrap();
+        assert_ne!(first.0, second.0);
+        assert_ne!(first.0, third.0);
+        assert_eq!(lobby.session_count(), 3);
+    }
+
+    #[test]
+    fn test_modes_run_in_separate_sessions() {
+        let lobby = Lobby::new(protocols());
+        let first = connect(&lobby, 8081);
+        let second = connect(&lobby, 8082);
+        let third = connect(&lobby, 8083);
+        let in_rounds = RegisterMessage {
+            mode: RelayMode::Rounds,
+            ..register_message(1, 2, None)
+        };
+        let first = joined(&lobby.register(first, &register_message(1, 2, None))).unwrap();
+        let second = joined(&lobby.register(second, &in_rounds)).unwrap();
+        assert_ne!(first.0, second.0);
+        // A peer can not join a session of another mode by its identifier
+        let messages = lobby.register(third, &register_message(1, 2, Some(second.0)));
+        assert_eq!(error(&messages), Some(String::from(CANT_REGISTER_RESPONSE)));
+    }
+
+    #[test]
+    fn test_join_session_by_id() {
+        let lobby = Lobby::new(protocols());
+        let first = connect(&lobby, 8081);
+        let second = connect(&lobby, 8082);
+        let (session_id, _) =
+            joined(&lobby.register(first, &register_message(1, 2, None))).unwrap();
+        let messages = lobby.register(second, &register_message(1, 2, Some(session_id)));
+        assert_eq!(joined(&messages), Some((session_id, 2)));
+
+        let third = connect(&lobby, 8083);
+        let messages = lobby.register(third, &register_message(1, 2, Some(session_id + 1)));
+        assert_eq!(error(&messages), Some(String::from(UNKNOWN_SESSION)));
+        // The session is full
+        let messages = lobby.register(third, &register_message(1, 2, Some(session_id)));
+        assert_eq!(error(&messages), Some(String::from(CANT_REGISTER_RESPONSE)));
+    }
+
+    #[test]
+    fn test_register_once() {
+        let lobby = Lobby::new(protocols());
+        let client_addr = connect(&lobby, 8081);
+        assert!(joined(&lobby.register(client_addr, &register_message(1, 2, None))).is_some());
+        let messages = lobby.register(client_addr, &register_message(1, 2, None));
+        assert_eq!(error(&messages), Some(String::from(CANT_REGISTER_RESPONSE)));
+    }
+
+    #[test]
+    fn test_invalid_protocol_opens_no_session() {
+        let lobby = Lobby::new(protocols());
+        let client_addr = connect(&lobby, 8081);
+        let messages = lobby.register(client_addr, &register_message(100, 2, None));
+        assert_eq!(error(&messages), Some(String::from(CANT_REGISTER_RESPONSE)));
+        assert_eq!(lobby.session_count(), 0);
+
+        // The failed registration took no session identifier
+        let messages = lobby.register(client_addr, &register_message(1, 1, None));
+        assert_eq!(joined(&messages), Some((1, 1)));
+    }
+
+    #[test]
+    fn test_disconnect_closes_session() {
+        let lobby = Lobby::new(protocols());
+        let first = connect(&lobby, 8081);
+        let second = connect(&lobby, 8082);
+        lobby.register(first, &register_message(1, 2, None));
+        lobby.register(second, &register_message(1, 2, None));
+
+        // The first peer to leave aborts the session for the other peers
+        let messages = lobby.disconnect(first);
+        assert!(messages.iter().all(|(msg, _)| msg.abort.is_some()));
+        assert_eq!(lobby.session_count(), 1);
+        lobby.disconnect(second);
+        assert_eq!(lobby.session_count(), 0);
+    }
+
+    fn token(messages: &[(ServerMessage, mpsc::Sender<ServerMessage>)]) -> String {
+        messages
+            .iter()
+            .find_map(|(msg, _)| match &msg.response {
+                Some(ServerResponse::Joined(_, _, token)) => Some(token.clone()),
+                _ => None,
+            })
+            .expect("No Joined response")
+    }
+
+    #[test]
+    fn test_reconnect_within_grace_period() {
+        let lobby = Lobby::with_grace_period(Duration::from_secs(30), protocols());
+        let first = connect(&lobby, 8081);
+        let second = connect(&lobby, 8082);
+        let messages = lobby.register(first, &register_message(1, 2, None));
+        let (session_id, peer_id) = joined(&messages).unwrap();
+        let token = token(&messages);
+        lobby.register(second, &register_message(1, 2, None));
+
+        let disconnection = lobby.hold(first).expect("Peer is not held");
+        let back = connect(&lobby, 9081);
+        let mut reconnect = ReconnectMessage {
+            session_id,
+            peer_number: peer_id,
+            token: String::from("wrong token"),
+            received: 0,
+        };
+        assert_eq!(
+            error(&lobby.reconnect(back, &reconnect)),
+            Some(String::from(CANT_RECONNECT))
+        );
+        reconnect.token = token;
+        let messages = lobby.reconnect(back, &reconnect);
+        assert_eq!(joined(&messages), Some((session_id, peer_id)));
+        // The peer reconnected, so the grace period expires with no abort
+        assert!(lobby.expire(disconnection).is_empty());
+        assert_eq!(lobby.session_count(), 1);
+    }
+
+    #[test]
+    fn test_expire_aborts_session() {
+        let lobby = Lobby::with_grace_period(Duration::from_secs(30), protocols());
+        let first = connect(&lobby, 8081);
+        let second = connect(&lobby, 8082);
+        lobby.register(first, &register_message(1, 2, None));
+        lobby.register(second, &register_message(1, 2, None));
+
+        let disconnection = lobby.hold(first).expect("Peer is not held");
+        let messages = lobby.expire(disconnection);
+        assert!(!messages.is_empty());
+        assert!(messages.iter().all(|(msg, _)| msg.abort.is_some()));
+        // The other peer is not held in an aborted session
+        assert!(lobby.hold(second).is_none());
+        lobby.disconnect(second);
+        assert_eq!(lobby.session_count(), 0);
+    }
+    #[test]
+    fn test_verify_signatures() {
+        let lobby = Lobby::with_grace_period(Duration::from_secs(30), protocols());
+        let first = connect(&lobby, 8081);
+        let keypair = generate_keypair();
+        let mut msg = ClientMessage::new();
+        msg.register(identity_key(&keypair), 1, 2);
+        assert_eq!(
+            lobby.verify(&first, &msg),
+            Err(ServerError::InvalidSignature)
+        );
+        msg.sign(&generate_keypair());
+        assert_eq!(
+            lobby.verify(&first, &msg),
+            Err(ServerError::InvalidSignature)
+        );
+        msg.sign(&keypair);
+        assert_eq!(lobby.verify(&first, &msg), Ok(()));
+        let messages = lobby.register(first, msg.register.as_ref().unwrap());
+        let (session_id, peer_id) = joined(&messages).unwrap();
+
+        // Relay messages are signed by the key the peer registered with
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(RelayMessage::new(peer_id, 1));
+        msg.sign(&generate_keypair());
+        assert_eq!(
+            lobby.verify(&first, &msg),
+            Err(ServerError::InvalidSignature)
+        );
+        msg.sign(&keypair);
+        assert_eq!(lobby.verify(&first, &msg), Ok(()));
+
+        // A peer reconnects with its key, whatever its new connection
+        let token = token(&messages);
+        lobby.hold(first).expect("Peer is not held");
+        let back = connect(&lobby, 9081);
+        let mut msg = ClientMessage::new();
+        msg.reconnect(session_id, peer_id, token, 0);
+        msg.sign(&generate_keypair());
+        assert_eq!(
+            lobby.verify(&back, &msg),
+            Err(ServerError::InvalidSignature)
+        );
+        msg.sign(&keypair);
+        assert_eq!(lobby.verify(&back, &msg), Ok(()));
+    }
+}
@@ -1,153 +1,152 @@
+use futures::future::{self, Either};
 use futures::stream;
-use futures::sync::mpsc;
+use futures::sync::{mpsc, oneshot};
 use futures::{Future, Sink, Stream};
 use log::{debug, error, info, warn};
+use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tokio::codec::Framed;
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{timeout, Delay};
+use tokio::util::{FutureExt, StreamExt};
+use tokio_rustls::rustls::{ServerConfig, Session};
+use tokio_rustls::TlsAcceptor;
 
-use crate::relay_session::{Client, RelaySession};
-use relay_server_common::{ClientMessageType, ServerMessage, ServerToClientCodec};
+use crate::limits::{ConnectionSlot, Connections, Limits};
+use crate::lobby::Lobby;
+use crate::relay_session::Client;
+use relay_server_common::error::ServerError;
+use relay_server_common::protocol::ProtocolRegistry;
+use relay_server_common::{ClientMessageType, FrameTooLarge, ServerMessage, ServerToClientCodec};
 
 pub struct RelayServer {
-    pub rs: Option<RelaySession>,
+    pub lobby: Lobby,
     addr: std::net::SocketAddr,
+    // Serves connections over TLS if set
+    tls: Option<Arc<ServerConfig>>,
+    // Limits of the connections to the server
+    limits: Limits,
 }
 
 impl RelayServer {
-    pub fn new(addr: SocketAddr) -> RelayServer {
+    /// A relay server opening sessions of the given protocols
+    pub fn new(addr: SocketAddr, protocols: ProtocolRegistry) -> RelayServer {
         RelayServer {
-            rs: None,
+            lobby: Lobby::new(protocols),
             addr: addr,
+            tls: None,
+            limits: Limits::default(),
         }
     }
 
-    /// Starts the relay server
-    pub fn start_server(&self, capacity: u32) {
+    /// A relay server where peers which lose their connection can reconnect
+    /// within the grace period, before their session is aborted
+    pub fn with_grace_period(
+        addr: SocketAddr,
+        grace_period: Duration,
+        protocols: ProtocolRegistry,
+    ) -> RelayServer {
+        RelayServer {
+            lobby: Lobby::with_grace_period(grace_period, protocols),
+            addr,
+            tls: None,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Serves connections over TLS with the given config, which authenticates
+    /// clients as well if it has a client CA
+    pub fn with_tls(mut self, config: ServerConfig) -> RelayServer {
+        self.tls = Some(Arc::new(config));
+        self
+    }
+
+    /// Limits the connections to the server, their messages and how long they may stall
+    pub fn with_limits(mut self, limits: Limits) -> RelayServer {
+        self.limits = limits;
+        self
+    }
+
+    /// Starts the relay server, sessions are opened as peers register
+    pub fn start_server(&self) {
         // Create the event loop and TCP listener we'll accept connections on.
         // let mut core = Core::new().unwrap();
         // let handle = core.handle();
 
         let listener = TcpListener::bind(&self.addr).unwrap();
-        info!("Listening on: {}", &self.addr);
+        match self.tls {
+            Some(_) => info!("Listening on: {} over TLS", &self.addr),
+            None => info!("Listening on: {}", &self.addr),
+        }
+
+        // The lobby opens a relay session for each group of peers that registers
+        let lobby = Arc::new(self.lobby.clone());
+
+        let acceptor = self.tls.clone().map(TlsAcceptor::from);
 
-        // Create the session fot the relay server
-        // TODO: Relay sessions should start when a new client connects
-        let relay_session = Arc::new(RelaySession::new(capacity));
+        let limits = self.limits;
+        let connections = Arc::new(Connections::default());
 
         let srv = listener
             .incoming()
             .for_each(move |socket| {
                 // Got a new connection
                 info!("Server got a new connection");
-                // TODO TODO TODO
-                let addr = socket.peer_addr().unwrap();
-
-                // Frame the socket with JSON codec
-                //let framed_socket = ServerToClientCodec::new(false).framed(socket);
-                let framed_socket = Framed::new(socket, ServerToClientCodec::new(false));
-
-                // obtain a clone of the RelaySession
-                let relay_session_inner = Arc::clone(&relay_session); //relay_session.clone();
-
-                // create a channel of communication with the (potential) peer
-                let (tx, rx) = mpsc::channel(0);
-
-                // insert this client to the servers active_connections
-                relay_session_inner.insert_new_connection(addr.clone(), Client::new(tx));
-
-                // split the socket to reading part (stream) and writing part (sink)
-                let (to_client, from_client) = framed_socket.split();
-
-                // define future for receiving half
-                let relay_session_inner = Arc::clone(&relay_session);
-                let reader = from_client.for_each(move |msg| {
-                    let msg_type = msg.msg_type();
-
-                    // this is our main logic for receiving messages from peer
-                    match msg_type {
-                        ClientMessageType::Register => {
-                            let register = msg.register.unwrap();
-                            info!(
-                                "Got register message. protocol id requested: {}",
-                                register.protocol_id
-                            );
-                            let messages_to_send = relay_session_inner.register(
-                                addr,
-                                register.protocol_id,
-                                register.capacity,
-                            );
-                            RelayServer::send_messages(&messages_to_send)
-                        }
-                        ClientMessageType::RelayMessage => {
-                            let peer = relay_session_inner
-                                .get_peer_by_address(&addr)
-                                .unwrap_or_else(|| panic!("not a peer"));
-                            info!("Got relay message from {}", peer.peer_id);
-                            let relay_msg = msg.relay_message.unwrap().clone();
-                            let messages_to_send =
-                                relay_session_inner.relay_message(&addr, relay_msg);
-                            RelayServer::send_messages(&messages_to_send)
-                        }
-                        ClientMessageType::Abort => {
-                            let peer = relay_session_inner
-                                .get_peer_by_address(&addr)
-                                .unwrap_or_else(|| panic!("not a peer"));
-                            debug!("Got abort message from {}", peer.peer_id);
-                            let messages_to_send = relay_session_inner.abort(addr);
-                            RelayServer::send_messages(&messages_to_send)
-                        }
-                        ClientMessageType::Test => {
-                            let sender = relay_session_inner
-                                .get_sender_by_address(&addr)
-                                .unwrap_or_else(|| panic!("not a peer"));
-                            let msg = ServerMessage::new();
-                            RelayServer::send_single_message(sender, msg)
-                        }
-                        ClientMessageType::Undefined => {
-                            warn!("Got unknown or empty message");
-                            let messages_to_send = relay_session_inner.abort(addr);
-                            RelayServer::send_messages(&messages_to_send)
+                let addr = match socket.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(err) => {
+                        warn!("Connection closed before it was served: {}", err);
+                        return Ok(());
+                    }
+                };
+                let slot = match ConnectionSlot::acquire(&connections, addr.ip(), &limits) {
+                    Ok(slot) => slot,
+                    Err(err) => {
+                        warn!("Refused connection from {}: {}", addr, err);
+                        // over TLS the connection is closed before the handshake, rather than
+                        // spending a handshake on it, so the client sees its handshake fail
+                        // and gets no error response
+                        if acceptor.is_none() {
+                            RelayServer::refuse(socket, err, limits);
                         }
+                        return Ok(());
                     }
-                });
-
-                // define future for sending half
-                let writer = rx
-                    .map_err(|()| unreachable!("rx can't fail"))
-                    // fold on a stream (rx) takes an initial value (to_client, a Sink)
-                    // and run the given closure, for each value passed from the stream (message to send to
-                    // the client)
-                    .fold(to_client, |to_client, msg| to_client.send(msg))
-                    // this map will cleanly drop the writing half of the socket when done with all processing
-                    .map(|_| ());
-
-                // if any of the reading/writing half is done - the whole connection is finished
-                // this makes select a sensible combinator
-                let connection = reader.select(writer);
-
-                // map & map_err here are used for the case reading half or writing half is dropped
-                // in which case we will be dropping the other half as well
-                let relay_session_inner = Arc::clone(&relay_session);
-                tokio::spawn(
-                    connection
-                        .map(|_| ())
-                        .map_err(|(err, _)| {
-                            error!("ERROR OCCURED: {:?}", err);
-                            err
-                        })
-                        .then(move |_| {
-                            // connection is closed
-                            warn!("Disconnected");
-
-                            // this means either a peer disconnected - same as abort,
-                            // or an active connection closed - which is allowed
-                            let messages_to_send = relay_session_inner.abort(addr);
-                            RelayServer::send_messages(&messages_to_send)
-                        }),
-                );
+                };
+                match &acceptor {
+                    // the connection is served once the TLS handshake is done
+                    Some(acceptor) => {
+                        let lobby = Arc::clone(&lobby);
+                        tokio::spawn(
+                            acceptor
+                                .accept(socket)
+                                .timeout(limits.idle_timeout)
+                                .map(move |stream| {
+                                    let certificate = stream
+                                        .get_ref()
+                                        .1
+                                        .get_peer_certificates()
+                                        .and_then(|chain| chain.into_iter().next())
+                                        .map(|certificate| certificate.0);
+                                    RelayServer::serve(
+                                        &lobby,
+                                        stream,
+                                        addr,
+                                        certificate,
+                                        limits,
+                                        slot,
+                                    )
+                                })
+                                .map_err(move |err| {
+                                    warn!("TLS handshake with {} failed: {}", addr, err)
+                                }),
+                        );
+                    }
+                    None => RelayServer::serve(&lobby, socket, addr, None, limits, slot),
+                }
 
                 Ok(())
             })
@@ -157,8 +156,231 @@ impl RelayServer {
         tokio::run(srv);
     }
 
+    // Closes a connection over the limits with the error,
+    // sent in JSON as the client did not register yet.
+    // Only plain connections are refused with the error, see the caller for TLS
+    fn refuse(socket: TcpStream, err: ServerError, limits: Limits) {
+        tokio::spawn(
+            Framed::new(socket, ServerToClientCodec::new())
+                .send(err.response())
+                .timeout(limits.send_timeout)
+                .then(|_| Ok(())),
+        );
+    }
+
+    // Serves a connection to the server, the certificate is the one
+    // the client authenticated with over mutual TLS.
+    // The slot of the connection is freed once it is closed
+    fn serve<S>(
+        lobby: &Arc<Lobby>,
+        socket: S,
+        addr: SocketAddr,
+        certificate: Option<Vec<u8>>,
+        limits: Limits,
+        slot: ConnectionSlot,
+    ) where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        // Frame the socket in the encoding of the client, a message
+        // larger than the maximum frame size closes the connection
+        let framed_socket = Framed::new(
+            socket,
+            ServerToClientCodec::new().with_max_frame_size(limits.max_frame_size),
+        );
+
+        // obtain a clone of the Lobby
+        let lobby_inner = Arc::clone(lobby);
+
+        // create a channel of communication with the (potential) peer,
+        // which queues up to the send buffer before its senders wait for it
+        let (tx, rx) = mpsc::channel(limits.send_buffer);
+
+        // the error the connection is closed with, once its client broke a limit
+        let (close_tx, close_rx) = oneshot::channel();
+
+        // insert this client to the servers active_connections
+        lobby_inner.insert_new_connection(addr, Client::with_certificate(tx, certificate));
+
+        // split the socket to reading part (stream) and writing part (sink)
+        let (to_client, from_client) = framed_socket.split();
+
+        // define future for receiving half
+        let lobby_inner = Arc::clone(lobby);
+        // a connection which sends nothing for the idle timeout is closed
+        let from_client = from_client.timeout(limits.idle_timeout);
+        let reader = from_client.for_each(move |msg| {
+            // a message which is not signed by its sender is refused, the session goes on
+            if let Err(err) = lobby_inner.verify(&addr, &msg) {
+                warn!("Got a message from {} not signed by its sender", addr);
+                let messages_to_send = lobby_inner
+                    .get_sender_by_address(&addr)
+                    .map(|sender| vec![(err.response(), sender)])
+                    .unwrap_or_default();
+                return RelayServer::send_messages(&messages_to_send);
+            }
+            let msg_type = msg.msg_type();
+
+            // this is our main logic for receiving messages from peer
+            match msg_type {
+                ClientMessageType::Register => {
+                    let register = msg.register.unwrap();
+                    info!(
+                        "Got register message. protocol id requested: {}",
+                        register.protocol_id
+                    );
+                    let messages_to_send = lobby_inner.register(addr, &register);
+                    RelayServer::send_messages(&messages_to_send)
+                }
+                ClientMessageType::RelayMessage => {
+                    let relay_msg = msg.relay_message.unwrap().clone();
+                    info!("Got relay message from {}", relay_msg.peer_number);
+                    let messages_to_send = lobby_inner.relay_message(&addr, relay_msg);
+                    RelayServer::send_messages(&messages_to_send)
+                }
+                ClientMessageType::Reconnect => {
+                    let reconnect = msg.reconnect.unwrap();
+                    info!(
+                        "Got reconnect message from peer {} of session {}",
+                        reconnect.peer_number, reconnect.session_id
+                    );
+                    let messages_to_send = lobby_inner.reconnect(addr, &reconnect);
+                    RelayServer::send_messages(&messages_to_send)
+                }
+                ClientMessageType::Abort => {
+                    debug!("Got abort message from {}", addr);
+                    let messages_to_send = lobby_inner.abort(addr);
+                    RelayServer::send_messages(&messages_to_send)
+                }
+                ClientMessageType::Test => {
+                    let messages_to_send = lobby_inner
+                        .get_sender_by_address(&addr)
+                        .map(|sender| vec![(ServerMessage::new(), sender)])
+                        .unwrap_or_default();
+                    RelayServer::send_messages(&messages_to_send)
+                }
+                ClientMessageType::Undefined => {
+                    // an error to the sender, the session goes on
+                    warn!("Got unknown or empty message from {}", addr);
+                    let messages_to_send = lobby_inner
+                        .get_sender_by_address(&addr)
+                        .map(|sender| vec![(ServerError::UnknownMessage.response(), sender)])
+                        .unwrap_or_default();
+                    RelayServer::send_messages(&messages_to_send)
+                }
+            }
+        });
+
+        // a client which broke a limit is sent the error by the writer,
+        // which then closes the connection
+        let reader = reader.or_else(move |err| match RelayServer::closing_error(err) {
+            Ok(error) => {
+                let _ = close_tx.send(error);
+                Either::A(future::empty())
+            }
+            Err(err) => Either::B(future::err(err)),
+        });
+
+        // define future for sending half
+        let closing = close_rx
+            .map(Some)
+            .or_else(|_| Ok::<_, ()>(None))
+            .into_stream()
+            .filter_map(|error| error);
+        let writer = rx
+            .map(|msg| (msg, None))
+            .select(closing.map(|error| (error.response(), Some(error))))
+            .map_err(|()| unreachable!("rx can't fail"))
+            // fold on a stream (rx) takes an initial value (to_client, a Sink)
+            // and run the given closure, for each value passed from the stream (message to send to
+            // the client)
+            .fold(to_client, move |to_client, (msg, closing)| {
+                to_client
+                    .send(msg)
+                    // a client which does not read a message within the send timeout
+                    // is evicted, rather than holding up the peers relaying to it
+                    .timeout(limits.send_timeout)
+                    .then(move |sent| match (sent, closing) {
+                        (Ok(to_client), None) => Ok(to_client),
+                        (Ok(_), Some(error)) => {
+                            Err(io::Error::new(io::ErrorKind::ConnectionAborted, error))
+                        }
+                        (Err(ref err), _) if err.is_elapsed() => Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "Client is too slow to read its messages",
+                        )),
+                        (Err(err), _) => Err(RelayServer::timeout_error(err)),
+                    })
+            })
+            // this map will cleanly drop the writing half of the socket when done with all processing
+            .map(|_| ());
+
+        // if any of the reading/writing half is done - the whole connection is finished
+        // this makes select a sensible combinator
+        let connection = reader.select(writer);
+
+        // map & map_err here are used for the case reading half or writing half is dropped
+        // in which case we will be dropping the other half as well
+        let lobby_inner = Arc::clone(lobby);
+        tokio::spawn(
+            connection
+                .map(|_| ())
+                .map_err(|(err, _)| {
+                    error!("ERROR OCCURED: {:?}", err);
+                    err
+                })
+                .then(move |_| {
+                    // connection is closed
+                    warn!("Disconnected");
+                    drop(slot);
+
+                    // a peer which disconnected keeps its place for the grace period,
+                    // and its session is aborted if it does not reconnect by then.
+                    // any other connection is removed at once
+                    let sends: Box<dyn Future<Item = (), Error = ()> + Send> =
+                        match lobby_inner.hold(addr) {
+                            Some(disconnection) => {
+                                let expiry = Instant::now() + lobby_inner.grace_period();
+                                Box::new(Delay::new(expiry).then(move |_| {
+                                    let messages_to_send = lobby_inner.expire(disconnection);
+                                    RelayServer::send_messages(&messages_to_send)
+                                }))
+                            }
+                            None => {
+                                let messages_to_send = lobby_inner.disconnect(addr);
+                                RelayServer::send_messages(&messages_to_send)
+                            }
+                        };
+                    sends
+                }),
+        );
+    }
+
+    // The error a connection is closed with after its client broke a limit
+    // or sent a malformed message, or the error of the connection if it failed
+    fn closing_error(err: timeout::Error<io::Error>) -> Result<ServerError, io::Error> {
+        if err.is_elapsed() {
+            return Ok(ServerError::IdleTimeout);
+        }
+        match RelayServer::timeout_error(err) {
+            ref err if FrameTooLarge::is(err) => Ok(ServerError::FrameTooLarge),
+            ref err if err.kind() == io::ErrorKind::InvalidData => Ok(ServerError::UnknownMessage),
+            err => Err(err),
+        }
+    }
+
+    // The error of a stream or future under a timeout, which did not elapse
+    fn timeout_error(err: timeout::Error<io::Error>) -> io::Error {
+        if err.is_timer() {
+            let err = err.into_timer().expect("Error is of the timer");
+            return io::Error::new(io::ErrorKind::Other, err);
+        }
+        err.into_inner().expect("Timeout did not elapse")
+    }
+
     // Recieves a vector of tuples, of a message and a Sink,
-    // Sends the message to the the Sink
+    // Sends the message to the the Sink.
+    // A recipient whose send buffer is full holds up the reader of the sender until
+    // it reads a message, or is evicted after the send timeout
     pub fn send_messages<E: 'static + Send>(
         messages_to_send: &Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>,
     ) -> Box<dyn Future<Item = (), Error = E> + Send> {
@@ -1,26 +1,61 @@
 use futures::sync::mpsc;
 use log::{debug, info, warn};
-use std::collections::HashMap;
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 
 use relay_server_common::{
-    AbortMessage, PeerIdentifier, ProtocolIdentifier, RelayMessage, ServerMessage, ServerResponse,
+    AbortMessage, IdentityKey, PeerIdentifier, ProtocolIdentifier, RelayMessage, RelayMode,
+    ServerMessage, ServerResponse,
 };
 
-use relay_server_common::common::{NOT_A_PEER, NOT_YOUR_TURN, STATE_NOT_INITIALIZED};
+use relay_server_common::common::{
+    DUPLICATE_MESSAGE, NOT_A_PEER, NOT_YOUR_TURN, STATE_NOT_INITIALIZED, WRONG_ROUND,
+};
+use relay_server_common::error::ServerError;
+
+use relay_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
 
-use relay_server_common::protocol::ProtocolDescriptor;
+// How many delivered messages a session keeps for the peers which reconnect
+const MAX_DELIVERED: usize = 1024;
 
 // Represents the communication channel to remote client
 #[derive(Clone, Debug)]
 pub struct Client {
     tx: mpsc::Sender<ServerMessage>,
+    // DER of the certificate the client authenticated with over mutual TLS
+    certificate: Option<Vec<u8>>,
+    // The identity key the client registered with, which signs its messages
+    public_key: Option<IdentityKey>,
 }
 
 impl Client {
     pub fn new(tx: mpsc::Sender<ServerMessage>) -> Client {
-        Client { tx }
+        Client::with_certificate(tx, None)
+    }
+
+    /// A client which authenticated with the given certificate, the peer it registers as
+    /// is bound to the certificate
+    pub fn with_certificate(
+        tx: mpsc::Sender<ServerMessage>,
+        certificate: Option<Vec<u8>>,
+    ) -> Client {
+        Client {
+            tx,
+            certificate,
+            public_key: None,
+        }
+    }
+
+    /// The client registering with the given identity key
+    pub fn with_public_key(mut self, public_key: IdentityKey) -> Client {
+        self.public_key = Some(public_key);
+        self
+    }
+
+    pub fn sender(&self) -> mpsc::Sender<ServerMessage> {
+        self.tx.clone()
     }
 }
 
@@ -29,6 +64,11 @@ pub struct Peer {
     pub peer_id: PeerIdentifier,
     client: Client,
     pub registered: bool,
+    // false while the peer lost its connection, its place is kept until it reconnects
+    pub connected: bool,
+    pub disconnections: u32,
+    // secret the peer gets once registered, and reconnects with
+    token: String,
 }
 
 impl Peer {
@@ -37,8 +77,15 @@ impl Peer {
             peer_id: 0,
             client,
             registered: false,
+            connected: true,
+            disconnections: 0,
+            token: String::new(),
         }
     }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -52,6 +99,56 @@ pub enum RelaySessionState {
     Aborted,
 }
 
+// The messages of the current round of a session relaying in rounds
+#[derive(Debug, Default)]
+struct RelayRound {
+    number: u32,
+
+    messages: BTreeMap<PeerIdentifier, RelayMessage>,
+}
+
+// The last relay messages delivered, replayed to the peers which reconnect,
+// with the number of messages to each peer which are no longer kept
+#[derive(Debug, Default)]
+struct DeliveredLog {
+    messages: VecDeque<RelayMessage>,
+
+    trimmed: HashMap<PeerIdentifier, u64>,
+}
+
+impl DeliveredLog {
+    fn push(&mut self, msg: RelayMessage) {
+        if self.messages.len() == MAX_DELIVERED {
+            if let Some(oldest) = self.messages.pop_front() {
+                for peer_id in oldest.to {
+                    *self.trimmed.entry(peer_id).or_default() += 1;
+                }
+            }
+        }
+        self.messages.push_back(msg);
+    }
+
+    // The messages to the peer after the first `received` of them,
+    // None if some of them are no longer kept
+    fn missed(&self, peer_id: PeerIdentifier, received: u64) -> Option<Vec<RelayMessage>> {
+        let trimmed = self.trimmed.get(&peer_id).cloned().unwrap_or_default();
+        let kept = received.checked_sub(trimmed)?;
+        Some(
+            self.messages
+                .iter()
+                .filter(|msg| msg.to.contains(&peer_id))
+                .skip(kept as usize)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.trimmed.clear();
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RelaySession {
     peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
@@ -61,18 +158,28 @@ pub struct RelaySession {
     protocol: Arc<RwLock<ProtocolDescriptor>>,
 
     state: Arc<RwLock<RelaySessionState>>,
+
+    mode: RelayMode,
+
+    round: Arc<RwLock<RelayRound>>,
+
+    delivered: Arc<RwLock<DeliveredLog>>,
+
+    // The protocols the server supports, the first peer opens the session for one of them
+    protocols: Arc<ProtocolRegistry>,
+}
+
+// The number of peers registered to the session
+fn active_peers(peers: &HashMap<SocketAddr, Peer>) -> u32 {
+    peers.values().filter(|p| p.registered).count() as u32
 }
 
 impl RelaySession {
     /// Returns the current number of active peers.
     /// If a peer disconnects, it should be removed from the active peers
+    #[cfg(test)]
     fn get_number_of_active_peers(&self) -> u32 {
-        self.peers
-            .read()
-            .unwrap()
-            .iter()
-            .filter(|(_, p)| p.registered)
-            .fold(0, |acc, _| acc + 1)
+        active_peers(&self.peers.read().unwrap())
     }
 
     /// Register a new peer to this relay session
@@ -85,21 +192,19 @@ impl RelaySession {
         protocol_id: ProtocolIdentifier,
         capacity: u32,
     ) -> Option<u32> {
-        let _addr = &addr;
-        let number_of_active_peers = self.get_number_of_active_peers();
-
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        info!("-----------------PEERS: {:?}---------------", self.peers);
-        match self.can_register(_addr, protocol_descriptor) {
+        // checked and registered under the same lock, so peers registering at once
+        // get distinct numbers, and a connection closed meanwhile is not registered
+        let mut peers = self.peers.write().unwrap();
+        let number_of_active_peers = active_peers(&peers);
+        match self.can_register_in(&peers, &addr, protocol_descriptor) {
             true => {
-                let mut peers = self.peers.write().unwrap();
-                let peer = peers
-                    .get_mut(_addr)
-                    .unwrap_or_else(|| panic!("No conection"));
+                let peer = peers.get_mut(&addr)?;
 
                 // activate this connection as a peer
                 peer.registered = true;
                 peer.peer_id = number_of_active_peers + 1;
+                peer.token = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
                 // if needed, set the ProtocolDescriptor for this sessuib
                 // and change the state
                 let state = self.state();
@@ -125,13 +230,24 @@ impl RelaySession {
 
     /// Checks if it is possible for this address
     /// to register as a peer in this session
+    #[cfg(test)]
     fn can_register(&self, addr: &SocketAddr, protocol: ProtocolDescriptor) -> bool {
+        self.can_register_in(&self.peers.read().unwrap(), addr, protocol)
+    }
+
+    // Checks with the peers of the session, which the caller holds locked
+    fn can_register_in(
+        &self,
+        peers: &HashMap<SocketAddr, Peer>,
+        addr: &SocketAddr,
+        protocol: ProtocolDescriptor,
+    ) -> bool {
         match self.state() {
             // if this is the first peer to register
             // check that the protocol is valid
             RelaySessionState::Empty => {
                 debug!("Checking if protocol description is valid");
-                if !relay_server_common::protocol::is_valid_protocol(&protocol) {
+                if !relay_server_common::protocol::is_valid_protocol(&self.protocols, &protocol) {
                     warn!("Protocol is invalid");
 
                     return false;
@@ -153,10 +269,29 @@ impl RelaySession {
             }
         }
         // register the peer iff it has an active connection and did not register yet
-        if let Some(peer) = self.peers.read().unwrap().get(addr) {
-            return !peer.registered;
+        let peer = match peers.get(addr) {
+            Some(peer) if !peer.registered => peer,
+            _ => return false,
+        };
+        // a certificate is bound to a single peer of the session
+        if peer.client.certificate.is_some()
+            && peers.values().any(|other| {
+                other.registered && other.client.certificate == peer.client.certificate
+            })
+        {
+            warn!("Certificate of {:} is bound to another peer", addr);
+            return false;
+        }
+        // as is an identity key, so a peer can not sign for two places
+        if peer.client.public_key.is_some()
+            && peers
+                .values()
+                .any(|other| other.registered && other.client.public_key == peer.client.public_key)
+        {
+            warn!("Identity key of {:} is bound to another peer", addr);
+            return false;
         }
-        false
+        true
     }
 
     /// Check if this relay message sent from the given SocketAddr
@@ -182,22 +317,100 @@ impl RelaySession {
         // if peer is present and registered
         if let Some(p) = peer {
             if p.registered && p.peer_id == sender {
-                // check if it is this peers turn
-                if self.protocol().next() == p.peer_id {
-                    return Ok(());
-                } else {
-                    return Err(NOT_YOUR_TURN);
-                }
+                return match self.mode {
+                    RelayMode::Turns => self.can_relay_in_turn(p.peer_id),
+                    RelayMode::Rounds => self.can_relay_in_round(p.peer_id, msg.round),
+                };
             }
         }
         return Err(NOT_A_PEER);
     }
+
+    // check if it is this peers turn
+    fn can_relay_in_turn(&self, peer_id: PeerIdentifier) -> Result<(), &'static str> {
+        if self.protocol().next() == peer_id {
+            Ok(())
+        } else {
+            Err(NOT_YOUR_TURN)
+        }
+    }
+
+    // check the message is the first message of the peer in the current round
+    fn can_relay_in_round(&self, peer_id: PeerIdentifier, round: u32) -> Result<(), &'static str> {
+        let current = self.round.read().unwrap();
+        debug!("Current round: {:}", current.number);
+        if round != current.number {
+            Err(WRONG_ROUND)
+        } else if current.messages.contains_key(&peer_id) {
+            Err(DUPLICATE_MESSAGE)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Logs the message as delivered, and returns it with a Sender to each of its recipients
+    fn messages_to_recipients(
+        &self,
+        msg: &RelayMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let mut server_msg = ServerMessage::new();
+        server_msg.relay_message = Some(msg.clone());
+        let peers = self.peers.read().unwrap();
+        // logged under the peers lock, so a peer which reconnects meanwhile
+        // gets the message either from the log or from this delivery, not both
+        self.delivered.write().unwrap().push(msg.clone());
+        debug!(
+            "Sending relay message from peer {:?} to: {:?}",
+            msg.peer_number, msg.to
+        );
+        peers
+            .values()
+            .filter(|peer| {
+                let id = &(peer.peer_id as PeerIdentifier);
+                msg.to.contains(id) && peer.registered
+            })
+            .map(|peer| (server_msg.clone(), peer.client.tx.clone()))
+            .collect()
+    }
+
+    // Holds the message until every peer sent its message of the round,
+    // then relays all the messages of the round at once
+    fn collect_round_message(
+        &self,
+        msg: RelayMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let mut round = self.round.write().unwrap();
+        debug!(
+            "Peer {:} sent its message of round {:}",
+            msg.peer_number, round.number
+        );
+        round.messages.insert(msg.peer_number, msg);
+        if round.messages.len() < self.protocol().capacity as usize {
+            return vec![];
+        }
+        info!("Round {:} is complete", round.number);
+        round.number += 1;
+        let messages = std::mem::replace(&mut round.messages, BTreeMap::new());
+        messages
+            .values()
+            .flat_map(|msg| self.messages_to_recipients(msg))
+            .collect()
+    }
 }
 
 impl RelaySession {
     /// Creates a new Relay Session with default (empty) fields
-    /// and an Empty state
-    pub fn new(capacity: u32) -> RelaySession {
+    /// and an Empty state, for one of the given protocols
+    pub fn new(capacity: u32, protocols: Arc<ProtocolRegistry>) -> RelaySession {
+        RelaySession::with_mode(capacity, RelayMode::Turns, protocols)
+    }
+
+    /// Creates a new Relay Session relaying messages in the given mode
+    pub fn with_mode(
+        capacity: u32,
+        mode: RelayMode,
+        protocols: Arc<ProtocolRegistry>,
+    ) -> RelaySession {
         RelaySession {
             peers: Arc::new(RwLock::new(HashMap::new())),
 
@@ -208,6 +421,14 @@ impl RelaySession {
             )),
 
             state: Arc::new(RwLock::new(RelaySessionState::Empty)),
+
+            mode,
+
+            round: Arc::new(RwLock::new(RelayRound::default())),
+
+            delivered: Arc::new(RwLock::new(DeliveredLog::default())),
+
+            protocols,
         }
     }
 
@@ -219,18 +440,27 @@ impl RelaySession {
     }
 
     /// Removes a connection from the peers collection
-    fn remove(&self, addr: &SocketAddr) -> Option<Peer> {
+    pub fn remove(&self, addr: &SocketAddr) -> Option<Peer> {
         self.peers.write().unwrap().remove(addr)
     }
 
-    /// Try reutrn a Sender of a specific peer by its address.
-    /// The Sender can be used to send ServerMessages to the server
-    pub fn get_sender_by_address(&self, addr: &SocketAddr) -> Option<mpsc::Sender<ServerMessage>> {
-        if let Some(peer) = self.get_peer_by_address(&addr) {
-            Some(peer.client.tx.clone())
-        } else {
-            None
-        }
+    /// Returns true once every connection of the session is closed
+    pub fn is_empty(&self) -> bool {
+        self.peers.read().unwrap().is_empty()
+    }
+
+    /// Returns true if peers can still join the session to run the given protocol
+    /// in the given mode
+    pub fn is_open(&self, protocol_id: ProtocolIdentifier, capacity: u32, mode: RelayMode) -> bool {
+        let protocol = self.protocol();
+        self.state() == RelaySessionState::Uninitialized
+            && protocol.id == protocol_id
+            && protocol.capacity == capacity
+            && self.mode == mode
+    }
+
+    pub fn mode(&self) -> RelayMode {
+        self.mode
     }
 
     /// Receives the sender's address and a message
@@ -242,29 +472,28 @@ impl RelaySession {
         msg: RelayMessage,
     ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
         let mut server_msg = ServerMessage::new();
-        let sender = self.get_peer_by_address(from).unwrap();
+        let sender = match self.get_peer_by_address(from) {
+            Some(sender) => sender,
+            None => {
+                // a connection which did not register is not a peer of the session
+                warn!("{:} is not a peer of the session", from);
+                return match self.peers.read().unwrap().get(from) {
+                    Some(peer) => vec![(ServerError::NotAPeer.response(), peer.client.sender())],
+                    None => vec![],
+                };
+            }
+        };
         let sender_id = sender.peer_id;
         let can_relay = self.can_relay(from, &msg);
         match can_relay {
-            Ok(()) => {
-                server_msg.relay_message = Some(msg.clone());
-                let peers = self.peers.read().unwrap();
-                let messages_to_send = peers
-                    .values()
-                    .filter(|peer| {
-                        let id = &(peer.peer_id as PeerIdentifier);
-                        msg.to.contains(id) && peer.registered
-                    })
-                    .map(|peer| (server_msg.clone(), peer.client.tx.clone()))
-                    .collect();
-                self.protocol.write().unwrap().advance_turn();
-
-                debug!(
-                    "Sending relay message from peer {:?} to: {:?}",
-                    sender_id, msg.to
-                );
-                messages_to_send
-            }
+            Ok(()) => match self.mode {
+                RelayMode::Turns => {
+                    let messages_to_send = self.messages_to_recipients(&msg);
+                    self.protocol.write().unwrap().advance_turn();
+                    messages_to_send
+                }
+                RelayMode::Rounds => self.collect_round_message(msg),
+            },
             Err(err_msg) => {
                 // send an error response to sender
                 warn!("Peer {:} can not relay", sender_id);
@@ -282,7 +511,12 @@ impl RelaySession {
         protocol_id: ProtocolIdentifier,
         capacity: u32,
     ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
-        self.register_new_peer(addr, protocol_id, capacity);
+        if self
+            .register_new_peer(addr, protocol_id, capacity)
+            .is_none()
+        {
+            return vec![];
+        }
         // Send message to all
         match self.state() {
             RelaySessionState::Initialized => {
@@ -311,6 +545,8 @@ impl RelaySession {
             Some(p) => {
                 server_msg.abort = Some(AbortMessage::new(p.peer_id, self.protocol().id));
                 self.set_state(RelaySessionState::Aborted);
+                // an aborted session takes no reconnection, its messages are not needed anymore
+                self.delivered.write().unwrap().clear();
                 let peers = self.peers.read().unwrap();
                 peers
                     .iter()
@@ -321,6 +557,120 @@ impl RelaySession {
         }
     }
 
+    /// Marks the peer of this address as disconnected, keeping its place in the session.
+    /// Returns its peer number, and the number of times it disconnected so far
+    pub fn disconnect_peer(&self, addr: &SocketAddr) -> Option<(PeerIdentifier, u32)> {
+        let mut peers = self.peers.write().unwrap();
+        let peer = peers.get_mut(addr).filter(|peer| peer.registered)?;
+        peer.connected = false;
+        peer.disconnections += 1;
+        Some((peer.peer_id, peer.disconnections))
+    }
+
+    /// Moves a disconnected peer to a new connection, given the token it registered with
+    /// and the certificate it is bound to.
+    /// Returns the messages the peer missed: its register message if the session is full,
+    /// and the relay messages to the peer after the first `received` of them.
+    /// The peer can not reconnect once the session no longer keeps all of them
+    pub fn reconnect_peer(
+        &self,
+        addr: SocketAddr,
+        client: Client,
+        peer_id: PeerIdentifier,
+        token: &str,
+        received: u64,
+    ) -> Option<Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>> {
+        let state = self.state();
+        if state == RelaySessionState::Aborted {
+            return None;
+        }
+        let mut peers = self.peers.write().unwrap();
+        let old_addr = peers
+            .iter()
+            .find(|(_, peer)| {
+                peer.registered
+                    && !peer.connected
+                    && peer.peer_id == peer_id
+                    && peer.token == token
+                    && peer.client.certificate == client.certificate
+            })
+            .map(|(old_addr, _)| *old_addr)?;
+        let missed = self.delivered.read().unwrap().missed(peer_id, received);
+        let missed = match missed {
+            Some(missed) => missed,
+            None => {
+                warn!("Messages missed by peer {} are no longer kept", peer_id);
+                return None;
+            }
+        };
+        let mut peer = peers.remove(&old_addr)?;
+        // the peer keeps the identity key it registered with
+        peer.client = Client {
+            public_key: peer.client.public_key.take(),
+            ..client
+        };
+        peer.connected = true;
+        let tx = peer.client.sender();
+        peers.insert(addr, peer);
+
+        let mut messages = vec![];
+        if state == RelaySessionState::Initialized {
+            let mut server_msg = ServerMessage::new();
+            server_msg.response = Some(ServerResponse::Register(peer_id));
+            messages.push((server_msg, tx.clone()));
+        }
+        messages.extend(missed.into_iter().map(|msg| {
+            let mut server_msg = ServerMessage::new();
+            server_msg.relay_message = Some(msg);
+            (server_msg, tx.clone())
+        }));
+        Some(messages)
+    }
+
+    /// Aborts the session if the peer is still disconnected since the given disconnection,
+    /// and removes it. Return an abort message to all peers, none if the peer reconnected
+    pub fn expire_peer(
+        &self,
+        peer_id: PeerIdentifier,
+        disconnections: u32,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let addr = self
+            .peers
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(_, peer)| {
+                peer.registered
+                    && !peer.connected
+                    && peer.peer_id == peer_id
+                    && peer.disconnections == disconnections
+            })
+            .map(|(addr, _)| *addr);
+        match addr {
+            Some(addr) => {
+                let messages = self.abort(addr);
+                self.remove(&addr);
+                messages
+            }
+            None => vec![],
+        }
+    }
+
+    /// Returns the identity key of the peer at this address
+    pub fn public_key_of(&self, addr: &SocketAddr) -> Option<IdentityKey> {
+        self.get_peer_by_address(addr)?.client.public_key
+    }
+
+    /// Returns the identity key the peer with this number registered with
+    pub fn public_key_of_peer(&self, peer_id: PeerIdentifier) -> Option<IdentityKey> {
+        self.peers
+            .read()
+            .unwrap()
+            .values()
+            .find(|peer| peer.registered && peer.peer_id == peer_id)
+            .and_then(|peer| peer.client.public_key.clone())
+    }
+
     /// get a copy of Peer that addr represents
     pub fn get_peer_by_address(&self, addr: &SocketAddr) -> Option<Peer> {
         match self.peers.read().unwrap().get(addr) {
@@ -354,26 +704,37 @@ impl RelaySession {
 #[cfg(test)]
 mod tests {
     use super::Client;
+    use super::DeliveredLog;
     use super::RelaySession;
     use super::RelaySessionState;
+    use super::MAX_DELIVERED;
 
     use futures::sync::mpsc;
 
-    use relay_server_common::common::{NOT_A_PEER, NOT_YOUR_TURN, STATE_NOT_INITIALIZED};
-    use relay_server_common::protocol::ProtocolDescriptor;
+    use relay_server_common::common::{
+        DUPLICATE_MESSAGE, NOT_A_PEER, NOT_YOUR_TURN, STATE_NOT_INITIALIZED, WRONG_ROUND,
+    };
+    use relay_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
     use relay_server_common::{
-        ClientMessage, PeerIdentifier, ProtocolIdentifier, RelayMessage, ServerMessageType,
+        ClientMessage, PeerIdentifier, ProtocolIdentifier, RelayMessage, RelayMode,
+        ServerMessageType, ServerResponse,
     };
 
     use std::net::SocketAddr;
     use std::sync::Arc;
     use std::thread;
 
+    fn protocols() -> Arc<ProtocolRegistry> {
+        Arc::new(
+            ProtocolRegistry::load(concat!(env!("CARGO_MANIFEST_DIR"), "/protocols.json")).unwrap(),
+        )
+    }
+
     #[test]
     fn test_add_peer() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 1;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
         let client_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
         let (tx, _) = mpsc::channel(0);
         rs.insert_new_connection(client_addr.clone(), Client::new(tx));
@@ -386,7 +747,7 @@ mod tests {
     fn test_add_multi_peers() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 5;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
 
         let mut peer_num: u32 = 0;
         for i in 0..capacity {
@@ -407,7 +768,7 @@ mod tests {
 
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 50;
-        let rs = Arc::new(RelaySession::new(capacity));
+        let rs = Arc::new(RelaySession::new(capacity, protocols()));
 
         for i in 0..capacity {
             let rs_inner = Arc::clone(&rs);
@@ -436,7 +797,7 @@ mod tests {
         let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
         let (tx, _) = mpsc::channel(0);
         rs.insert_new_connection(client_addr.clone(), Client::new(tx));
         assert!(rs.can_register(&client_addr, protocol_descriptor))
@@ -448,7 +809,7 @@ mod tests {
         let protocol_id: ProtocolIdentifier = 100 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
         let (tx, _) = mpsc::channel(0);
         rs.insert_new_connection(client_addr.clone(), Client::new(tx));
         assert!(!rs.can_register(&client_addr, protocol_descriptor))
@@ -460,8 +821,12 @@ mod tests {
         let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        let rs = RelaySession::new(capacity, protocols());
+        assert!(!rs.can_register(&client_addr, protocol_descriptor));
+        assert_eq!(
+            rs.register_new_peer(client_addr, protocol_id, capacity),
+            None
+        );
     }
 
     #[test]
@@ -470,7 +835,7 @@ mod tests {
         let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
         rs.register(client_addr, protocol_id, capacity);
         assert!(!rs.can_register(&client_addr, protocol_descriptor))
     }
@@ -480,7 +845,7 @@ mod tests {
     fn test_register_state() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
 
         // State is empty at first
         assert_eq!(RelaySessionState::Empty, rs.state());
@@ -509,7 +874,7 @@ mod tests {
     fn test_abort() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
 
         // State is empty at first
         for i in 0..capacity - 1 {
@@ -550,7 +915,7 @@ mod tests {
     fn test_can_relay() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
 
         // Add all but the last peer to the session
         for i in 0..capacity - 1 {
@@ -595,7 +960,7 @@ mod tests {
     fn test_relay_message() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
-        let rs = RelaySession::new(capacity);
+        let rs = RelaySession::new(capacity, protocols());
 
         // Add all peers to the session
         for i in 0..capacity {
@@ -611,4 +976,232 @@ mod tests {
         assert_eq!(messages_to_send.len(), 3);
     }
 
+    #[test]
+    fn test_relay_message_from_non_peer() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = RelaySession::new(capacity, protocols());
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 0).parse().unwrap();
+        let (tx, _) = mpsc::channel(0);
+        rs.insert_new_connection(client_addr, Client::new(tx));
+
+        // a connection which did not register gets an error rather than relaying
+        let msg = prepare_relay_message(1, protocol_id, &vec![2]);
+        let messages_to_send = rs.relay_message(&client_addr, msg.relay_message.unwrap());
+        assert_eq!(messages_to_send.len(), 1);
+        match &messages_to_send[0].0.response {
+            Some(ServerResponse::ErrorResponse(err)) => assert_eq!(err, NOT_A_PEER),
+            _ => panic!("Expected an error response"),
+        }
+
+        // and a message from an unknown address is dropped
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        let msg = prepare_relay_message(2, protocol_id, &vec![1]);
+        assert!(rs
+            .relay_message(&client_addr, msg.relay_message.unwrap())
+            .is_empty());
+    }
+
+    /////////////////////////// test relaying in rounds ///////////////////////////////////
+    fn prepare_session_in_rounds(capacity: u32) -> RelaySession {
+        let protocol_id: ProtocolIdentifier = 1;
+        let rs = RelaySession::with_mode(capacity, RelayMode::Rounds, protocols());
+        for i in 0..capacity {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
+            let (tx, _) = mpsc::channel(0);
+            rs.insert_new_connection(client_addr.clone(), Client::new(tx));
+            rs.register(client_addr, protocol_id, capacity);
+        }
+        rs
+    }
+
+    fn prepare_round_message(peer_id: PeerIdentifier, round: u32, capacity: u32) -> RelayMessage {
+        let client_message = prepare_relay_message(peer_id, 1, &(1..=capacity).collect());
+        let mut relay_message = client_message.relay_message.unwrap();
+        relay_message.set_round(round);
+        relay_message
+    }
+
+    #[test]
+    fn test_relay_round_once_complete() {
+        let capacity: u32 = 3;
+        let rs = prepare_session_in_rounds(capacity);
+        for round in 0..2 {
+            // Peers send out of order, and nothing is relayed until the round is complete
+            for peer_id in (2..=capacity).rev() {
+                let client_addr: SocketAddr =
+                    format!("127.0.0.1:808{}", peer_id - 1).parse().unwrap();
+                let msg = prepare_round_message(peer_id, round, capacity);
+                assert!(rs.relay_message(&client_addr, msg).is_empty());
+            }
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", 0).parse().unwrap();
+            let msg = prepare_round_message(1, round, capacity);
+            let messages_to_send = rs.relay_message(&client_addr, msg);
+            // Every peer receives the messages of all peers
+            assert_eq!(messages_to_send.len(), (capacity * capacity) as usize);
+            for (msg, _) in messages_to_send {
+                assert_eq!(msg.relay_message.unwrap().round, round);
+            }
+        }
+    }
+
+    #[test]
+    fn test_can_relay_in_round() {
+        let capacity: u32 = 2;
+        let rs = prepare_session_in_rounds(capacity);
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        assert_eq!(
+            Err(WRONG_ROUND),
+            rs.can_relay(&client_addr, &prepare_round_message(2, 1, capacity))
+        );
+        rs.relay_message(&client_addr, prepare_round_message(2, 0, capacity));
+        assert_eq!(
+            Err(DUPLICATE_MESSAGE),
+            rs.can_relay(&client_addr, &prepare_round_message(2, 0, capacity))
+        );
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 0).parse().unwrap();
+        assert_eq!(
+            Ok(()),
+            rs.can_relay(&client_addr, &prepare_round_message(1, 0, capacity))
+        );
+    }
+
+    /////////////////////////// test reconnect ///////////////////////////////////
+    #[test]
+    fn test_reconnect_peer_replays_missed_messages() {
+        let capacity: u32 = 2;
+        let rs = prepare_session_in_rounds(capacity);
+        let away: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        let token = rs.get_peer_by_address(&away).unwrap().token().to_string();
+        assert_eq!(rs.disconnect_peer(&away), Some((2, 1)));
+
+        // The round completes while the second peer is away
+        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 0).parse().unwrap();
+        rs.relay_message(&away, prepare_round_message(2, 0, capacity));
+        rs.relay_message(&client_addr, prepare_round_message(1, 0, capacity));
+
+        let back: SocketAddr = "127.0.0.1:9081".parse().unwrap();
+        let (tx, _) = mpsc::channel(0);
+        assert!(rs
+            .reconnect_peer(back, Client::new(tx.clone()), 2, "wrong token", 0)
+            .is_none());
+        // The peer received the first message of the round before it lost its connection
+        let messages = rs
+            .reconnect_peer(back, Client::new(tx), 2, &token, 1)
+            .expect("Unable to reconnect");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0.msg_type(), ServerMessageType::Response);
+        assert_eq!(messages[1].0.msg_type(), ServerMessageType::RelayMessage);
+        assert!(rs.get_peer_by_address(&away).is_none());
+        assert_eq!(rs.get_peer_by_address(&back).unwrap().peer_id, 2);
+        // A connected peer can not be taken over
+        let (tx, _) = mpsc::channel(0);
+        assert!(rs
+            .reconnect_peer(client_addr, Client::new(tx), 2, &token, 0)
+            .is_none());
+    }
+
+    #[test]
+    fn test_delivered_messages_are_capped() {
+        let capacity: u32 = 2;
+        let mut delivered = DeliveredLog::default();
+        for round in 0..MAX_DELIVERED as u32 + 2 {
+            delivered.push(prepare_round_message(1, round, capacity));
+        }
+        assert_eq!(delivered.messages.len(), MAX_DELIVERED);
+        // The first two messages are no longer kept for a peer which missed them
+        assert!(delivered.missed(2, 1).is_none());
+        let missed = delivered.missed(2, 2).unwrap();
+        assert_eq!(missed.len(), MAX_DELIVERED);
+        assert_eq!(missed[0].round, 2);
+        let missed = delivered.missed(2, MAX_DELIVERED as u64).unwrap();
+        assert_eq!(missed.len(), 2);
+        assert_eq!(missed[0].round, MAX_DELIVERED as u32);
+        // Messages to other peers are not counted
+        assert!(delivered.missed(3, 0).unwrap().is_empty());
+
+        delivered.clear();
+        assert!(delivered.missed(2, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_certificate_binds_peer() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let rs = RelaySession::with_mode(capacity, RelayMode::Rounds, protocols());
+        let certificates = vec![Some(vec![1]), Some(vec![1]), Some(vec![2])];
+        for (i, certificate) in certificates.into_iter().enumerate() {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
+            let (tx, _) = mpsc::channel(0);
+            rs.insert_new_connection(client_addr, Client::with_certificate(tx, certificate));
+            rs.register(client_addr, protocol_id, capacity);
+        }
+        // The second client has the certificate of the first peer
+        let second: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        assert!(rs.get_peer_by_address(&second).is_none());
+        rs.remove(&second);
+
+        // The peer reconnects with its certificate only
+        let away: SocketAddr = format!("127.0.0.1:808{}", 2).parse().unwrap();
+        let token = rs.get_peer_by_address(&away).unwrap().token().to_string();
+        rs.disconnect_peer(&away);
+        let back: SocketAddr = "127.0.0.1:9082".parse().unwrap();
+        let (tx, _) = mpsc::channel(0);
+        let client = Client::with_certificate(tx, Some(vec![1]));
+        assert!(rs.reconnect_peer(back, client, 2, &token, 0).is_none());
+        let (tx, _) = mpsc::channel(0);
+        let client = Client::with_certificate(tx, Some(vec![2]));
+        assert!(rs.reconnect_peer(back, client, 2, &token, 0).is_some());
+    }
+
+    #[test]
+    fn test_identity_key_binds_peer() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let rs = RelaySession::with_mode(capacity, RelayMode::Rounds, protocols());
+        let keys = vec!["first", "first", "second"];
+        for (i, key) in keys.into_iter().enumerate() {
+            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
+            let (tx, _) = mpsc::channel(0);
+            let client = Client::new(tx).with_public_key(String::from(key));
+            rs.insert_new_connection(client_addr, client);
+            rs.register(client_addr, protocol_id, capacity);
+        }
+        // The second client has the identity key of the first peer
+        let second: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        assert!(rs.get_peer_by_address(&second).is_none());
+        rs.remove(&second);
+        assert_eq!(rs.public_key_of_peer(2), Some(String::from("second")));
+
+        // The peer keeps its key over a new connection
+        let away: SocketAddr = format!("127.0.0.1:808{}", 2).parse().unwrap();
+        let token = rs.get_peer_by_address(&away).unwrap().token().to_string();
+        rs.disconnect_peer(&away);
+        let back: SocketAddr = "127.0.0.1:9082".parse().unwrap();
+        let (tx, _) = mpsc::channel(0);
+        assert!(rs
+            .reconnect_peer(back, Client::new(tx), 2, &token, 0)
+            .is_some());
+        assert_eq!(rs.public_key_of(&back), Some(String::from("second")));
+    }
+
+    #[test]
+    fn test_expire_peer() {
+        let capacity: u32 = 2;
+        let rs = prepare_session_in_rounds(capacity);
+        let away: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
+        let token = rs.get_peer_by_address(&away).unwrap().token().to_string();
+        rs.disconnect_peer(&away);
+
+        // The peer reconnected and lost its connection again, only the last disconnection expires
+        let back: SocketAddr = "127.0.0.1:9081".parse().unwrap();
+        let (tx, _) = mpsc::channel(0);
+        rs.reconnect_peer(back, Client::new(tx), 2, &token, 0);
+        assert!(rs.expire_peer(2, 1).is_empty());
+        assert_eq!(rs.disconnect_peer(&back), Some((2, 2)));
+        let messages = rs.expire_peer(2, 2);
+        assert_eq!(messages.len(), capacity as usize);
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        assert!(rs.get_peer_by_address(&back).is_none());
+    }
 }